@@ -0,0 +1,293 @@
+use std::path::Path;
+
+use clap::Args;
+use clap::ValueEnum;
+use crabml::bail;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml::tokenizer::TokenID;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::CpuLlamaModel;
+use crabml_llama2::model::CpuLlamaModelLoader;
+use serde_json::json;
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// The checkpoint file to benchmark
+    #[arg(short, long)]
+    model: String,
+
+    /// The prompt lengths to test the prompt processing, like 128,512
+    #[arg(short = 'p', long, value_delimiter = ',', default_value = "512")]
+    n_prompt: Vec<usize>,
+
+    /// The numbers of tokens to test the text generation, like 64,128
+    #[arg(short = 'n', long, value_delimiter = ',', default_value = "128")]
+    n_gen: Vec<usize>,
+
+    /// The thread counts to sweep, like 1,2,4
+    #[arg(short = 'T', long, value_delimiter = ',', default_value = "2")]
+    threads: Vec<usize>,
+
+    /// The number of repetitions of each test
+    #[arg(short, long, default_value_t = 5)]
+    repetitions: usize,
+
+    /// Skip the warmup run before each test
+    #[arg(long, default_value_t = false)]
+    no_warmup: bool,
+
+    #[arg(short, long, default_value_t = BenchOutput::Md)]
+    output: BenchOutput,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum BenchOutput {
+    Md,
+    Json,
+}
+
+impl std::fmt::Display for BenchOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BenchOutput::Md => write!(f, "md"),
+            BenchOutput::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum BenchTest {
+    /// prompt processing, the prompt is forwarded in batches
+    Prompt(usize),
+    /// text generation, the tokens are forwarded one by one
+    Gen(usize),
+}
+
+impl BenchTest {
+    fn n_tokens(&self) -> usize {
+        match self {
+            BenchTest::Prompt(n) | BenchTest::Gen(n) => *n,
+        }
+    }
+}
+
+impl std::fmt::Display for BenchTest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BenchTest::Prompt(n) => write!(f, "pp {}", n),
+            BenchTest::Gen(n) => write!(f, "tg {}", n),
+        }
+    }
+}
+
+struct BenchResult {
+    threads: usize,
+    test: BenchTest,
+    samples_ns: Vec<u64>,
+}
+
+impl BenchResult {
+    fn samples_ts(&self) -> Vec<f64> {
+        let n_tokens = self.test.n_tokens() as f64;
+        self.samples_ns
+            .iter()
+            .map(|ns| n_tokens * 1e9 / *ns as f64)
+            .collect()
+    }
+
+    /// the mean and the sample standard deviation of the tokens per second
+    fn avg_stddev_ts(&self) -> (f64, f64) {
+        let samples = self.samples_ts();
+        let n = samples.len() as f64;
+        let avg = samples.iter().sum::<f64>() / n;
+        if samples.len() < 2 {
+            return (avg, 0.0);
+        }
+        let variance = samples.iter().map(|ts| (ts - avg).powi(2)).sum::<f64>() / (n - 1.0);
+        (avg, variance.sqrt())
+    }
+}
+
+/// runs the prompt processing and text generation tests like llama-bench. the time is taken
+/// from the forward_walltime of the TensorMetrics, so the sampling is not counted.
+pub fn run_bench(args: &BenchArgs) -> Result<()> {
+    if args.repetitions == 0 {
+        bail!(ErrorKind::BadInput, "repetitions must be at least 1");
+    }
+    let tests = args
+        .n_prompt
+        .iter()
+        .filter(|n| **n > 0)
+        .map(|n| BenchTest::Prompt(*n))
+        .chain(
+            args.n_gen
+                .iter()
+                .filter(|n| **n > 0)
+                .map(|n| BenchTest::Gen(*n)),
+        )
+        .collect::<Vec<_>>();
+    if tests.is_empty() {
+        bail!(ErrorKind::BadInput, "no test to run");
+    }
+
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+    let model_name = gf
+        .metadata()
+        .get_string("general.name")
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| {
+            Path::new(&args.model)
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default()
+        });
+    let model_size = gf
+        .tensor_infos()
+        .iter()
+        .map(|info| info.data().len())
+        .sum::<usize>();
+    let model_params = gf
+        .tensor_infos()
+        .iter()
+        .map(|info| info.dimensions().iter().product::<usize>())
+        .sum::<usize>();
+
+    let mut results = vec![];
+    for threads in args.threads.iter() {
+        let thread_num = if *threads == 0 {
+            num_cpus::get()
+        } else {
+            *threads
+        };
+        let model = CpuLlamaModelLoader::new()
+            .with_thread_num(thread_num)
+            .load(&gf)?;
+        for test in tests.iter() {
+            // the generation starts after a bos token, which takes one more position
+            if test.n_tokens() + 1 >= model.conf.seq_len {
+                bail!(
+                    ErrorKind::BadInput,
+                    "the test {} exceeds the context length {}",
+                    test,
+                    model.conf.seq_len
+                );
+            }
+            if !args.no_warmup {
+                run_test(&model, *test)?;
+            }
+            let samples_ns = (0..args.repetitions)
+                .map(|_| run_test(&model, *test))
+                .collect::<Result<Vec<_>>>()?;
+            let result = BenchResult {
+                threads: thread_num,
+                test: *test,
+                samples_ns,
+            };
+            let (avg, stddev) = result.avg_stddev_ts();
+            eprintln!(
+                "threads={} {}: {:.2} ± {:.2} t/s",
+                thread_num, test, avg, stddev
+            );
+            results.push(result);
+        }
+    }
+
+    match args.output {
+        BenchOutput::Md => print_markdown(&model_name, model_size, model_params, &results),
+        BenchOutput::Json => print_json(&model_name, model_size, model_params, &results),
+    }
+    Ok(())
+}
+
+/// runs the test in a fresh context, returns the nanoseconds spent in the forward passes.
+fn run_test(model: &CpuLlamaModel, test: BenchTest) -> Result<u64> {
+    let vocab_size = model.conf.vocab_size;
+    let bos_token = model.tokenizer.bos_token();
+    let mut runner = Llama2Runner::new(model, model.conf.seq_len, true)?;
+    match test {
+        BenchTest::Prompt(n) => {
+            // the content of the prompt doesn't matter, only spread the tokens over the vocab
+            let tokens = (0..n)
+                .map(|i| (bos_token + 1 + i * 31) % vocab_size)
+                .collect::<Vec<TokenID>>();
+            runner.metrics.reset();
+            runner.prefill_tokens(&tokens, true)?;
+        }
+        BenchTest::Gen(n) => {
+            let (_, _, mut token) = runner.prefill_tokens(&[bos_token], false)?;
+            runner.metrics.reset();
+            // keep feeding the sampled token, so the eos token won't stop the generation
+            for _ in 0..n {
+                (_, _, token) = runner.prefill_tokens(&[token], false)?;
+            }
+        }
+    }
+    Ok(runner.metrics.forward_walltime.as_nanos())
+}
+
+pub(crate) fn format_size(bytes: usize) -> String {
+    let mib = bytes as f64 / 1024.0 / 1024.0;
+    if mib >= 1024.0 {
+        format!("{:.2} GiB", mib / 1024.0)
+    } else {
+        format!("{:.2} MiB", mib)
+    }
+}
+
+pub(crate) fn format_params(params: usize) -> String {
+    let params = params as f64;
+    if params >= 1e9 {
+        format!("{:.2} B", params / 1e9)
+    } else if params >= 1e6 {
+        format!("{:.2} M", params / 1e6)
+    } else {
+        format!("{:.2} K", params / 1e3)
+    }
+}
+
+fn print_markdown(model: &str, size: usize, params: usize, results: &[BenchResult]) {
+    println!("| model | size | params | threads | test | t/s |");
+    println!("| ----- | ---: | -----: | ------: | ---: | --: |");
+    for result in results {
+        let (avg, stddev) = result.avg_stddev_ts();
+        println!(
+            "| {} | {} | {} | {} | {} | {:.2} ± {:.2} |",
+            model,
+            format_size(size),
+            format_params(params),
+            result.threads,
+            result.test,
+            avg,
+            stddev
+        );
+    }
+}
+
+fn print_json(model: &str, size: usize, params: usize, results: &[BenchResult]) {
+    let items = results
+        .iter()
+        .map(|result| {
+            let (n_prompt, n_gen) = match result.test {
+                BenchTest::Prompt(n) => (n, 0),
+                BenchTest::Gen(n) => (0, n),
+            };
+            let (avg, stddev) = result.avg_stddev_ts();
+            json!({
+                "model": model,
+                "model_size": size,
+                "model_n_params": params,
+                "n_threads": result.threads,
+                "n_prompt": n_prompt,
+                "n_gen": n_gen,
+                "samples_ns": result.samples_ns,
+                "samples_ts": result.samples_ts(),
+                "avg_ts": avg,
+                "stddev_ts": stddev,
+            })
+        })
+        .collect::<Vec<_>>();
+    println!("{}", serde_json::to_string_pretty(&items).unwrap());
+}
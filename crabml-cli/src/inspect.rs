@@ -0,0 +1,349 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use clap::Args;
+use crabml::error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFile;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFMetadataArray;
+use crabml::gguf::GGUFMetadataValue;
+use crabml_llama2::model::CpuLlamaModel;
+use crabml_llama2::model::CpuLlamaModelLoader;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::bench::format_params;
+use crate::bench::format_size;
+
+// the number of items to show for the metadata arrays, like the vocab of tokenizer
+const ARRAY_PREVIEW_LEN: usize = 8;
+
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    /// The GGUF file to inspect
+    model: String,
+
+    /// Print the result as JSON
+    #[arg(long, default_value_t = false)]
+    json: bool,
+}
+
+/// prints the metadata, the model config, the tokenizer and the tensors of a GGUF file.
+pub fn run_inspect(args: &InspectArgs) -> Result<()> {
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+    let model = CpuLlamaModelLoader::new().with_thread_num(1).load(&gf)?;
+
+    // the output may be piped into a closed reader like `head`, which is an error to return
+    let mut w = std::io::stdout().lock();
+    let written = if args.json {
+        let value = inspect_json(&gf, &model);
+        writeln!(w, "{}", serde_json::to_string_pretty(&value).unwrap())
+    } else {
+        write_inspect(&gf, &model, &mut w)
+    };
+    written.map_err(|err| error!(ErrorKind::IOError, "failed to write the output: {}", err))
+}
+
+fn write_inspect(gf: &GGUFFile, model: &CpuLlamaModel, w: &mut impl Write) -> std::io::Result<()> {
+    writeln!(w, "architecture: {}", gf.architecture())?;
+    writeln!(w, "version: {:?}", gf.version())?;
+    writeln!(w)?;
+    writeln!(w, "metadata:")?;
+    write_metadata(gf, w)?;
+    writeln!(w)?;
+
+    let conf = &model.conf;
+    writeln!(w, "config:")?;
+    let rows = vec![
+        ("architecture", format!("{:?}", conf.architecture)),
+        ("model_name", conf.model_name.clone()),
+        ("embedding_dim", conf.embedding_dim.to_string()),
+        ("hidden_dim", conf.hidden_dim.to_string()),
+        ("n_layers", conf.n_layers.to_string()),
+        ("n_heads", conf.n_heads.to_string()),
+        ("n_kv_heads", conf.n_kv_heads.to_string()),
+        ("head_size", conf.head_size().to_string()),
+        ("vocab_size", conf.vocab_size.to_string()),
+        ("seq_len", conf.seq_len.to_string()),
+        ("rms_norm_eps", conf.rms_norm_eps.to_string()),
+        (
+            "rope_dim",
+            conf.rope_dim
+                .map(|d| d.to_string())
+                .unwrap_or("-".to_string()),
+        ),
+        ("rope_freq_base", conf.rope_freq_base.to_string()),
+        ("rope_scaling", format!("{:?}", conf.rope_scaling)),
+        ("n_experts", conf.n_experts.to_string()),
+        ("n_experts_used", conf.n_experts_used.to_string()),
+        (
+            "sliding_window",
+            conf.sliding_window
+                .map(|w| w.to_string())
+                .unwrap_or("-".to_string()),
+        ),
+    ];
+    for (key, value) in rows {
+        writeln!(w, "  {:<16} {}", key, value)?;
+    }
+    writeln!(w)?;
+
+    writeln!(
+        w,
+        "tokenizer: {:?}, {} tokens",
+        model.tokenizer.kind(),
+        model.tokenizer.vocab().len()
+    )?;
+    let (n_params, n_bytes) = tensors_total(gf);
+    writeln!(w, "parameters: {} ({})", format_params(n_params), n_params)?;
+    writeln!(w, "size: {} ({} bytes)", format_size(n_bytes), n_bytes)?;
+    writeln!(w)?;
+
+    writeln!(w, "tensors:")?;
+    write_tensors(gf, w)?;
+    writeln!(w)?;
+
+    writeln!(w, "bytes by type:")?;
+    for (typ, (count, bytes)) in bytes_by_type(gf) {
+        writeln!(
+            w,
+            "  {:<6} {:>4} tensors {:>14} bytes {:>12}",
+            typ,
+            count,
+            bytes,
+            format_size(bytes)
+        )?;
+    }
+    Ok(())
+}
+
+/// writes the metadata sorted by the key, the arrays are shortened to a preview.
+pub fn write_metadata(gf: &GGUFFile, w: &mut impl Write) -> std::io::Result<()> {
+    let metadata = gf.metadata().as_hashmap();
+    let mut keys = metadata.keys().collect::<Vec<_>>();
+    keys.sort();
+    let width = keys.iter().map(|k| k.len()).max().unwrap_or(0);
+    for key in keys {
+        let value = format_value(&metadata[key]);
+        writeln!(w, "  {:<width$} {}", key, value, width = width)?;
+    }
+    Ok(())
+}
+
+/// writes the name, type, shape and byte size of the tensors as aligned columns.
+pub fn write_tensors(gf: &GGUFFile, w: &mut impl Write) -> std::io::Result<()> {
+    let rows = gf
+        .tensor_infos()
+        .iter()
+        .map(|info| {
+            (
+                info.name().to_string(),
+                info.typ().to_string(),
+                format!("{:?}", info.dimensions()),
+                info.data().len(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let name_width = rows.iter().map(|r| r.0.len()).max().unwrap_or(0);
+    let shape_width = rows.iter().map(|r| r.2.len()).max().unwrap_or(0);
+    for (name, typ, shape, bytes) in rows {
+        writeln!(
+            w,
+            "  {:<name_width$} {:<6} {:<shape_width$} {:>12}",
+            name,
+            typ,
+            shape,
+            bytes,
+            name_width = name_width,
+            shape_width = shape_width
+        )?;
+    }
+    Ok(())
+}
+
+fn inspect_json(gf: &GGUFFile, model: &CpuLlamaModel) -> Value {
+    let metadata = gf
+        .metadata()
+        .as_hashmap()
+        .iter()
+        .map(|(key, value)| (key.clone(), value_json(value)))
+        .collect::<BTreeMap<_, _>>();
+    let tensors = gf
+        .tensor_infos()
+        .iter()
+        .map(|info| {
+            json!({
+                "name": info.name(),
+                "type": info.typ().to_string(),
+                "shape": info.dimensions(),
+                "n_params": info.dimensions().iter().product::<usize>(),
+                "bytes": info.data().len(),
+            })
+        })
+        .collect::<Vec<_>>();
+    let bytes_by_type = bytes_by_type(gf)
+        .into_iter()
+        .map(|(typ, (count, bytes))| (typ, json!({"n_tensors": count, "bytes": bytes})))
+        .collect::<BTreeMap<_, _>>();
+    let (n_params, n_bytes) = tensors_total(gf);
+
+    let conf = &model.conf;
+    json!({
+        "architecture": gf.architecture(),
+        "version": format!("{:?}", gf.version()),
+        "metadata": metadata,
+        "config": {
+            "architecture": format!("{:?}", conf.architecture),
+            "model_name": conf.model_name,
+            "embedding_dim": conf.embedding_dim,
+            "hidden_dim": conf.hidden_dim,
+            "n_layers": conf.n_layers,
+            "n_heads": conf.n_heads,
+            "n_kv_heads": conf.n_kv_heads,
+            "head_size": conf.head_size(),
+            "vocab_size": conf.vocab_size,
+            "seq_len": conf.seq_len,
+            "rms_norm_eps": conf.rms_norm_eps,
+            "rope_dim": conf.rope_dim,
+            "rope_freq_base": conf.rope_freq_base,
+            "rope_scaling": format!("{:?}", conf.rope_scaling),
+            "n_experts": conf.n_experts,
+            "n_experts_used": conf.n_experts_used,
+            "sliding_window": conf.sliding_window,
+        },
+        "tokenizer": {
+            "kind": format!("{:?}", model.tokenizer.kind()),
+            "n_tokens": model.tokenizer.vocab().len(),
+            "bos_token": model.tokenizer.bos_token(),
+            "eos_token": model.tokenizer.eos_token(),
+        },
+        "n_params": n_params,
+        "bytes": n_bytes,
+        "bytes_by_type": bytes_by_type,
+        "tensors": tensors,
+    })
+}
+
+fn tensors_total(gf: &GGUFFile) -> (usize, usize) {
+    gf.tensor_infos()
+        .iter()
+        .fold((0, 0), |(params, bytes), info| {
+            (
+                params + info.dimensions().iter().product::<usize>(),
+                bytes + info.data().len(),
+            )
+        })
+}
+
+// the number of tensors and the bytes of each GGMLType
+fn bytes_by_type(gf: &GGUFFile) -> BTreeMap<String, (usize, usize)> {
+    let mut totals = BTreeMap::new();
+    for info in gf.tensor_infos() {
+        let entry = totals.entry(info.typ().to_string()).or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += info.data().len();
+    }
+    totals
+}
+
+fn format_value(value: &GGUFMetadataValue) -> String {
+    match value {
+        GGUFMetadataValue::String(s) => format!("{:?}", s),
+        GGUFMetadataValue::Bool(b) => (*b != 0).to_string(),
+        GGUFMetadataValue::Array(arr) => {
+            let mut preview = array_head(arr)
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>();
+            if arr.len() > ARRAY_PREVIEW_LEN {
+                preview.push("...".to_string());
+            }
+            format!("[{:?}; {}] [{}]", arr.typ(), arr.len(), preview.join(", "))
+        }
+        _ => value_json(value).to_string(),
+    }
+}
+
+/// the arrays are kept as the element type, the length and the first items, which avoids
+/// printing the whole vocab of the tokenizer.
+fn value_json(value: &GGUFMetadataValue) -> Value {
+    match value {
+        GGUFMetadataValue::U8(v) => json!(v),
+        GGUFMetadataValue::I8(v) => json!(v),
+        GGUFMetadataValue::U16(v) => json!(v),
+        GGUFMetadataValue::I16(v) => json!(v),
+        GGUFMetadataValue::U32(v) => json!(v),
+        GGUFMetadataValue::I32(v) => json!(v),
+        GGUFMetadataValue::U64(v) => json!(v),
+        GGUFMetadataValue::I64(v) => json!(v),
+        GGUFMetadataValue::F32(v) => json!(v),
+        GGUFMetadataValue::F64(v) => json!(v),
+        GGUFMetadataValue::Bool(v) => json!(*v != 0),
+        GGUFMetadataValue::String(v) => json!(v),
+        GGUFMetadataValue::Array(arr) => {
+            json!({
+                "type": format!("{:?}", arr.typ()),
+                "len": arr.len(),
+                "head": array_head(arr),
+            })
+        }
+    }
+}
+
+// only the first items are converted, the arrays like the vocab can be huge
+fn array_head(arr: &GGUFMetadataArray) -> Vec<Value> {
+    let n = ARRAY_PREVIEW_LEN;
+    match arr {
+        GGUFMetadataArray::U8Array(arr) => arr.iter().take(n).map(|v| json!(v)).collect(),
+        GGUFMetadataArray::I8Array(arr) => arr.iter().take(n).map(|v| json!(v)).collect(),
+        GGUFMetadataArray::U16Array(arr) => arr.iter().take(n).map(|v| json!(v)).collect(),
+        GGUFMetadataArray::I16Array(arr) => arr.iter().take(n).map(|v| json!(v)).collect(),
+        GGUFMetadataArray::U32Array(arr) => arr.iter().take(n).map(|v| json!(v)).collect(),
+        GGUFMetadataArray::I32Array(arr) => arr.iter().take(n).map(|v| json!(v)).collect(),
+        GGUFMetadataArray::U64Array(arr) => arr.iter().take(n).map(|v| json!(v)).collect(),
+        GGUFMetadataArray::I64Array(arr) => arr.iter().take(n).map(|v| json!(v)).collect(),
+        GGUFMetadataArray::F32Array(arr) => arr.iter().take(n).map(|v| json!(v)).collect(),
+        GGUFMetadataArray::F64Array(arr) => arr.iter().take(n).map(|v| json!(v)).collect(),
+        GGUFMetadataArray::BoolArray(arr) => arr.iter().take(n).map(|v| json!(*v != 0)).collect(),
+        GGUFMetadataArray::StringArray(arr) => arr.iter().take(n).map(|v| json!(v)).collect(),
+        GGUFMetadataArray::NestedArray(arr) => arr
+            .iter()
+            .take(n)
+            .map(|nested| Value::Array(array_head(nested)))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::error::Result;
+    use crabml::gguf::GGUFFileLoader;
+
+    use super::*;
+
+    #[test]
+    fn test_write_metadata() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+
+        // the vocab is shortened to the preview
+        let mut buf = vec![];
+        write_metadata(&gf, &mut buf).unwrap();
+        let output = String::from_utf8(buf).unwrap();
+        let line = output
+            .lines()
+            .find(|l| l.trim_start().starts_with("tokenizer.ggml.tokens"))
+            .unwrap();
+        assert!(line.contains("[String; 512]"), "{}", line);
+        assert_eq!(line.matches(", ").count(), ARRAY_PREVIEW_LEN);
+        assert!(line.ends_with("...]"));
+
+        // the error of a closed output is returned instead of panicking
+        let mut closed = [0u8; 16];
+        assert!(write_metadata(&gf, &mut &mut closed[..]).is_err());
+        Ok(())
+    }
+}
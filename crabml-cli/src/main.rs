@@ -1,24 +1,49 @@
 #[cfg(not(target_env = "msvc"))]
 extern crate jemallocator;
 
+mod bench;
+mod inspect;
+mod perplexity;
+mod quantize;
+
 use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Instant;
 
+use bench::run_bench;
+use bench::BenchArgs;
 use clap::Parser;
+use clap::Subcommand;
 use clap::ValueEnum;
+use crabml::bail;
+use crabml::error;
+use crabml::error::ErrorKind;
 use crabml::error::Result;
+use crabml::gguf::GGMLType;
 use crabml::gguf::GGUFFile;
 use crabml::gguf::GGUFFileLoader;
-use crabml::gguf::GGUFMetadataValueType;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorMetrics;
+use crabml::tokenizer::TokenID;
+use crabml_llama2::json_schema::json_schema_to_grammar;
 use crabml_llama2::llama2::Llama2Runner;
 use crabml_llama2::model::CpuLlamaModelLoader;
+use crabml_llama2::speculative::SpeculativeRunner;
 use crabml_llama2::GpuLlamaModel;
+use crabml_llama2::Grammar;
 use crabml_llama2::Llama2Chat;
+use crabml_llama2::Llama2SamplerOptions;
 use crabml_wgpu::WgpuTensor;
 use crabml_wgpu::WgpuTensorDevice;
 use crabml_wgpu::WgpuTensorDeviceOptions;
+use inspect::run_inspect;
+use inspect::InspectArgs;
+use perplexity::run_perplexity;
+use perplexity::PerplexityArgs;
+use quantize::run_quantize;
+use quantize::QuantizeArgs;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
@@ -27,7 +52,11 @@ use rustyline::Editor;
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
 struct CommandArgs {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// The checkpoint file to load
     #[arg(short, long, default_value_t = format!("./testdata/tinyllamas-stories-15m-f32.gguf"))]
     model: String,
@@ -43,6 +72,112 @@ struct CommandArgs {
     #[arg(short, long, default_value_t = 1.0)]
     temperature: f32,
 
+    /// Keep only the k most likely tokens, 0 disables it
+    #[arg(long, default_value_t = 0)]
+    top_k: usize,
+
+    /// Drop the tokens whose probability is less than min-p * the max probability, 0 disables it
+    #[arg(long, default_value_t = 0.0)]
+    min_p: f32,
+
+    /// Locally typical sampling, 1.0 disables it
+    #[arg(long, default_value_t = 1.0)]
+    typical_p: f32,
+
+    /// Tail free sampling, 1.0 disables it
+    #[arg(long, default_value_t = 1.0)]
+    tfs_z: f32,
+
+    /// The number of recent tokens to penalize, 0 disables the penalties
+    #[arg(long, default_value_t = 64)]
+    repeat_last_n: usize,
+
+    /// Penalize the repeated tokens in the recent tokens, 1.0 disables it
+    #[arg(long, default_value_t = 1.0)]
+    repeat_penalty: f32,
+
+    /// Penalize the recent tokens by their frequency, 0.0 disables it
+    #[arg(long, default_value_t = 0.0)]
+    frequency_penalty: f32,
+
+    /// Penalize the tokens present in the recent tokens, 0.0 disables it
+    #[arg(long, default_value_t = 0.0)]
+    presence_penalty: f32,
+
+    /// Apply the penalties on the newline and the special tokens too
+    #[arg(long, default_value_t = false)]
+    penalize_nl: bool,
+
+    /// The seed of the sampling, the same seed on the same inputs always gives the same output
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// The order of the samplers, k: top-k, f: tail free, y: typical, p: top-p, m: min-p, t: temperature
+    #[arg(long, default_value_t = format!("kfypmt"))]
+    sampling_seq: String,
+
+    /// Add a bias to the logit of a token like 1234+2.0, or ban it like 1234-inf, can be repeated
+    #[arg(long, value_parser = parse_logit_bias)]
+    logit_bias: Vec<(TokenID, f32)>,
+
+    /// Never sample the tokens of the string, can be repeated
+    #[arg(long)]
+    banned_string: Vec<String>,
+
+    /// Constrain the generated text to a GBNF grammar like llama.cpp
+    #[arg(long)]
+    grammar: Option<String>,
+
+    /// Load the GBNF grammar from a file
+    #[arg(long)]
+    grammar_file: Option<String>,
+
+    /// Constrain the generated text to the JSON values matching the JSON schema
+    #[arg(long)]
+    json_schema: Option<String>,
+
+    /// Save the kv cache of the prompt into the file, and reuse it on the next run if the
+    /// prompt starts with the cached one
+    #[arg(long)]
+    prompt_cache: Option<String>,
+
+    /// Keep generating when the context is full, by dropping the older half of the tokens
+    /// after the first --keep tokens
+    #[arg(long, default_value_t = false)]
+    context_shift: bool,
+
+    /// The number of tokens at the start of the context to keep on the context shift
+    #[arg(long, default_value_t = 1)]
+    keep: usize,
+
+    /// Only keep the tokens in the sliding window of the model in the kv cache, to bound the
+    /// memory on the long generations of the models like mistral
+    #[arg(long, default_value_t = false)]
+    rolling_kv_cache: bool,
+
+    /// The type of the kv cache on cpu, like f32, f16, q8_0 or q4_0. defaults to f16, while
+    /// wgpu only supports f32
+    #[arg(long)]
+    kv_cache_type: Option<String>,
+
+    /// The draft model sharing the vocab with the model, to speed up the generation on cpu
+    /// with the speculative decoding
+    #[arg(long)]
+    draft_model: Option<String>,
+
+    /// The number of tokens to draft on each step of the speculative decoding
+    #[arg(long, default_value_t = 5)]
+    draft: usize,
+
+    /// Generate with the beam search of the width, and print the hypotheses with their scores
+    #[arg(long)]
+    beam_width: Option<usize>,
+
+    /// The exponent of the length to normalize the scores of the beam search, the longer
+    /// hypotheses are preferred with a larger value
+    #[arg(long, default_value_t = 1.0)]
+    length_penalty: f32,
+
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
 
@@ -63,6 +198,21 @@ struct CommandArgs {
     device: DeviceType,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Quantize a F32 or F16 model into a new GGUF file
+    Quantize(QuantizeArgs),
+
+    /// Compute the perplexity of a model on a text file, with the same chunking as llama.cpp
+    Perplexity(PerplexityArgs),
+
+    /// Benchmark the prompt processing and text generation throughput, like llama-bench
+    Bench(BenchArgs),
+
+    /// Print the metadata, the config, the tokenizer and the tensors of a GGUF file
+    Inspect(InspectArgs),
+}
+
 #[derive(Clone, Debug, ValueEnum)]
 enum DeviceType {
     Cpu,
@@ -81,6 +231,8 @@ impl std::fmt::Display for DeviceType {
 fn run<T: Tensor>(runner: &mut Llama2Runner<T>, args: &CommandArgs) -> Result<()> {
     if args.chat {
         run_chat(runner, args)?;
+    } else if let Some(beam_width) = args.beam_width {
+        run_beam(runner, args, beam_width)?;
     } else {
         run_generate(runner, args)?;
     }
@@ -137,7 +289,10 @@ fn run_generate<U: Tensor>(runner: &mut Llama2Runner<U>, args: &CommandArgs) ->
     let metrics = runner.metrics.clone();
     let prefill_started_at = Instant::now();
     let prompt = args.prompt.clone().unwrap_or("".to_string());
-    let (prefill_pos, _prev_token, token) = runner.prefill(&prompt, true, false)?;
+    let (prefill_pos, _prev_token, token) = match &args.prompt_cache {
+        Some(path) => prefill_with_prompt_cache(runner, &prompt, path)?,
+        None => runner.prefill(&prompt, true, true)?,
+    };
     let prefill_elapsed = prefill_started_at.elapsed();
     if args.verbose {
         dump_metrics(&runner.metrics);
@@ -185,6 +340,103 @@ fn run_generate<U: Tensor>(runner: &mut Llama2Runner<U>, args: &CommandArgs) ->
     Ok(())
 }
 
+fn run_beam<U: Tensor>(
+    runner: &mut Llama2Runner<U>,
+    args: &CommandArgs,
+    beam_width: usize,
+) -> Result<()> {
+    let started_at = Instant::now();
+    let prompt = args.prompt.clone().unwrap_or("".to_string());
+    let hypotheses = runner.generate_beam(&prompt, beam_width, args.steps, args.length_penalty)?;
+    let elapsed = started_at.elapsed();
+
+    for (i, hypothesis) in hypotheses.iter().enumerate() {
+        println!(
+            "#{} score: {:.4}, normalized: {:.4}, {} tokens",
+            i,
+            hypothesis.score,
+            hypothesis.normalized_score,
+            hypothesis.tokens.len()
+        );
+        println!("{}{}", prompt, hypothesis.text);
+        println!();
+    }
+    println!(
+        "beam width: {}, {}ms, {} threads",
+        beam_width,
+        elapsed.as_millis(),
+        args.threads
+    );
+    Ok(())
+}
+
+fn run_speculative<U: Tensor, V: Tensor>(
+    runner: &mut SpeculativeRunner<U, V>,
+    args: &CommandArgs,
+) -> Result<()> {
+    let prefill_started_at = Instant::now();
+    let prompt = args.prompt.clone().unwrap_or("".to_string());
+    let (prefill_pos, _prev_token, token) = runner.prefill(&prompt, true)?;
+    let prefill_elapsed = prefill_started_at.elapsed();
+
+    let mut generated_tokens = 0;
+    let generation_started_at = Instant::now();
+    print!("{}", &prompt);
+    for token in runner.generate(token, Some(args.steps)) {
+        generated_tokens += 1;
+        print!("{}", token?);
+        std::io::stdout().flush().unwrap();
+    }
+    let generation_elapsed = generation_started_at.elapsed().as_secs_f64();
+    let generated_tokens_per_second = generated_tokens as f64 / generation_elapsed;
+
+    let stats = runner.stats();
+    println!();
+    println!(
+        "prompt: {} tokens, {}ms",
+        prefill_pos,
+        prefill_elapsed.as_millis()
+    );
+    println!(
+        "{} tokens/s, {} threads",
+        generated_tokens_per_second, args.threads
+    );
+    println!(
+        "drafted: {} tokens, accepted: {} tokens ({:.1}%)",
+        stats.n_drafted,
+        stats.n_accepted,
+        stats.acceptance_rate() * 100.0
+    );
+    Ok(())
+}
+
+// restore the kv cache from the session file and reuse its common prefix with the prompt,
+// the session file is updated if the prompt is changed
+fn prefill_with_prompt_cache<U: Tensor>(
+    runner: &mut Llama2Runner<U>,
+    prompt: &str,
+    path: &str,
+) -> Result<(usize, usize, usize)> {
+    let tokens = runner.tokenizer().encode(prompt, true, false)?;
+    if Path::new(path).exists() {
+        runner.load_session(path)?;
+        let n_reused = runner
+            .tokens()
+            .iter()
+            .zip(tokens.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        eprintln!("reused {} tokens from the prompt cache {}", n_reused, path);
+    }
+
+    let changed = runner.tokens() != tokens;
+    let prefilled = runner.prefill_with_prefix_cache(&tokens, true)?;
+    if changed {
+        runner.save_session(path)?;
+    }
+    Ok(prefilled)
+}
+
 fn dump_metrics(metrics: &TensorMetrics) {
     println!();
     if metrics.forward_walltime.as_millis() == 0.0 {
@@ -204,24 +456,105 @@ fn dump_metrics(metrics: &TensorMetrics) {
     );
 }
 
-fn dump_gguf_metadata(gf: &GGUFFile) {
-    for (key, value) in gf.metadata().as_hashmap() {
-        if value.typ() != GGUFMetadataValueType::Array {
-            eprintln!("{}: {:?}", key, value);
+fn dump_gguf_metadata(gf: &GGUFFile) -> Result<()> {
+    let mut w = std::io::stderr().lock();
+    inspect::write_metadata(gf, &mut w)
+        .and_then(|_| inspect::write_tensors(gf, &mut w))
+        .map_err(|err| error!(ErrorKind::IOError, "failed to dump the metadata: {}", err))
+}
+
+// parse the logit bias like 1234+2.0 or 1234-inf into the token id and the bias
+fn parse_logit_bias(s: &str) -> std::result::Result<(TokenID, f32), String> {
+    let sign_pos = match s.find(['+', '-']) {
+        Some(pos) if pos > 0 => pos,
+        _ => {
+            return Err(format!(
+                "expected TOKEN_ID+BIAS or TOKEN_ID-BIAS, got {}",
+                s
+            ))
         }
+    };
+    let (token, bias) = s.split_at(sign_pos);
+    let token = token
+        .parse()
+        .map_err(|_| format!("invalid token id: {}", token))?;
+    let bias = bias
+        .trim_start_matches('+')
+        .parse()
+        .map_err(|_| format!("invalid bias: {}", bias))?;
+    Ok((token, bias))
+}
+
+fn sampler_options(args: &CommandArgs) -> Llama2SamplerOptions {
+    let mut options = Llama2SamplerOptions::default()
+        .with_temperature(args.temperature)
+        .with_top_p(args.probability)
+        .with_top_k(args.top_k)
+        .with_min_p(args.min_p)
+        .with_typical_p(args.typical_p)
+        .with_tfs_z(args.tfs_z)
+        .with_repeat_last_n(args.repeat_last_n)
+        .with_repeat_penalty(args.repeat_penalty)
+        .with_frequency_penalty(args.frequency_penalty)
+        .with_presence_penalty(args.presence_penalty)
+        .with_penalize_nl(args.penalize_nl)
+        .with_seed(args.seed)
+        .with_sequence(&args.sampling_seq);
+    for (token, bias) in args.logit_bias.iter() {
+        options = options.with_logit_bias(*token, *bias);
+    }
+    for banned in args.banned_string.iter() {
+        options = options.with_banned_string(banned);
     }
-    for tensor in gf.tensor_infos() {
-        eprintln!(
-            "- {} \t\t\t {} \t {:?}",
-            tensor.name(),
-            tensor.typ(),
-            tensor.dimensions()
+    options
+}
+
+fn kv_cache_dtype(args: &CommandArgs, default: GGMLType) -> Result<GGMLType> {
+    match &args.kv_cache_type {
+        Some(typ) => GGMLType::from_str(typ),
+        None => Ok(default),
+    }
+}
+
+fn load_grammar(args: &CommandArgs) -> Result<Option<Arc<Grammar>>> {
+    let src = match (&args.grammar, &args.grammar_file, &args.json_schema) {
+        (None, None, None) => return Ok(None),
+        (Some(grammar), None, None) => grammar.clone(),
+        (None, Some(path), None) => std::fs::read_to_string(path).map_err(|err| {
+            error!(
+                ErrorKind::IOError,
+                "failed to read the grammar file {}: {}", path, err
+            )
+        })?,
+        (None, None, Some(schema)) => {
+            let schema = serde_json::from_str(schema)
+                .map_err(|err| error!(ErrorKind::BadInput, "invalid json schema: {}", err))?;
+            json_schema_to_grammar(&schema)?
+        }
+        _ => bail!(
+            ErrorKind::BadInput,
+            "only one of --grammar, --grammar-file and --json-schema is allowed"
+        ),
+    };
+    if args.chat {
+        bail!(
+            ErrorKind::BadInput,
+            "the grammar is not supported in the chat mode"
         );
     }
+    Ok(Some(Arc::new(Grammar::parse(&src)?)))
 }
 
 fn main() -> Result<()> {
     let args = CommandArgs::parse();
+    if let Some(command) = &args.command {
+        return match command {
+            Command::Quantize(args) => run_quantize(args),
+            Command::Perplexity(args) => run_perplexity(args),
+            Command::Bench(args) => run_bench(args),
+            Command::Inspect(args) => run_inspect(args),
+        };
+    }
     let start_time = Instant::now();
 
     let mut thread_num = args.threads;
@@ -235,19 +568,72 @@ fn main() -> Result<()> {
     let gf = gl.open()?;
 
     if args.verbose {
-        dump_gguf_metadata(&gf);
+        dump_gguf_metadata(&gf)?;
+    }
+    let grammar = load_grammar(&args)?;
+    if args.chat && args.prompt_cache.is_some() {
+        bail!(
+            ErrorKind::BadInput,
+            "the prompt cache is not supported in the chat mode"
+        );
     }
+    if args.beam_width.is_some()
+        && (args.chat || args.prompt_cache.is_some() || args.draft_model.is_some())
+    {
+        bail!(
+            ErrorKind::BadInput,
+            "the beam search is not supported with the chat mode, the prompt cache or the draft model"
+        );
+    }
+    if args.draft_model.is_some()
+        && (args.chat || args.prompt_cache.is_some() || !matches!(args.device, DeviceType::Cpu))
+    {
+        bail!(
+            ErrorKind::BadInput,
+            "the draft model is only supported on generating with cpu, without the chat mode and the prompt cache"
+        );
+    }
+    let draft_gl = args
+        .draft_model
+        .as_ref()
+        .map(|path| GGUFFileLoader::new(path, args.mlock))
+        .transpose()?;
+    let draft_gf = draft_gl.as_ref().map(|gl| gl.open()).transpose()?;
 
     let model_cpu = CpuLlamaModelLoader::new()
         .with_thread_num(thread_num)
-        .with_temperature(args.temperature)
-        .with_probability(args.probability)
+        .with_sampler_options(sampler_options(&args))
         .load(&gf)?;
     let conf = model_cpu.conf.clone();
 
     match args.device {
         DeviceType::Cpu => {
-            let mut runner = Llama2Runner::new(&model_cpu, conf.seq_len, true)?;
+            let kv_cache_dtype = kv_cache_dtype(&args, GGMLType::F16)?;
+            let mut runner =
+                Llama2Runner::new_with_kv_cache_dtype(&model_cpu, conf.seq_len, kv_cache_dtype)?;
+            if let Some(grammar) = grammar {
+                runner = runner.with_grammar(grammar);
+            }
+            if args.context_shift {
+                runner = runner.with_context_shift(args.keep);
+            }
+            if args.rolling_kv_cache {
+                runner = runner.with_rolling_kv_cache()?;
+            }
+            if let Some(draft_gf) = &draft_gf {
+                let draft_model = CpuLlamaModelLoader::new()
+                    .with_thread_num(thread_num)
+                    .with_sampler_options(sampler_options(&args))
+                    .load(draft_gf)?;
+                let draft = Llama2Runner::new_with_kv_cache_dtype(
+                    &draft_model,
+                    draft_model.conf.seq_len,
+                    kv_cache_dtype,
+                )?;
+                let mut runner = SpeculativeRunner::new(runner, draft, args.draft)?;
+                eprintln!("model loaded: {}ms", start_time.elapsed().as_millis());
+                return run_speculative(&mut runner, &args);
+            }
             eprintln!("model loaded: {}ms", start_time.elapsed().as_millis());
             run(&mut runner, &args)?;
         }
@@ -257,7 +643,24 @@ fn main() -> Result<()> {
             );
             let model_wgpu = GpuLlamaModel::<WgpuTensor>::from_cpu(&model_cpu, device_wgpu)?;
 
-            let mut runner = Llama2Runner::new(&model_wgpu, conf.seq_len, false)?;
+            let kv_cache_dtype = kv_cache_dtype(&args, GGMLType::F32)?;
+            if kv_cache_dtype != GGMLType::F32 {
+                bail!(
+                    ErrorKind::BadInput,
+                    "only the f32 kv cache is supported on wgpu"
+                );
+            }
+            let mut runner =
+                Llama2Runner::new_with_kv_cache_dtype(&model_wgpu, conf.seq_len, kv_cache_dtype)?;
+            if let Some(grammar) = grammar {
+                runner = runner.with_grammar(grammar);
+            }
+            if args.context_shift {
+                runner = runner.with_context_shift(args.keep);
+            }
+            if args.rolling_kv_cache {
+                runner = runner.with_rolling_kv_cache()?;
+            }
             run(&mut runner, &args)?;
         }
     }
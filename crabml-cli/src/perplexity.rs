@@ -0,0 +1,129 @@
+use std::io::Write;
+use std::time::Instant;
+
+use clap::Args;
+use crabml::bail;
+use crabml::error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::model::CpuLlamaModelLoader;
+
+#[derive(Args, Debug)]
+pub struct PerplexityArgs {
+    /// The checkpoint file to evaluate
+    #[arg(short, long)]
+    model: String,
+
+    /// The text file to compute the perplexity on, like wiki.test.raw
+    #[arg(short, long)]
+    file: String,
+
+    /// The number of tokens in each chunk
+    #[arg(long, default_value_t = 512)]
+    ctx: usize,
+
+    /// Only evaluate the first N chunks, 0 evaluates all of them
+    #[arg(long, default_value_t = 0)]
+    chunks: usize,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+}
+
+/// computes the perplexity with the same chunking as llama.cpp: the text is split into chunks
+/// of `ctx` tokens, each chunk starts with a bos token and is evaluated in a fresh context, and
+/// only the tokens in the second half of the chunk are scored, so every scored token has at
+/// least `ctx / 2` tokens before it.
+pub fn run_perplexity(args: &PerplexityArgs) -> Result<()> {
+    let start_time = Instant::now();
+    let thread_num = if args.threads == 0 {
+        num_cpus::get()
+    } else {
+        args.threads
+    };
+
+    let gl = GGUFFileLoader::new(&args.model, false)?;
+    let gf = gl.open()?;
+    let model = CpuLlamaModelLoader::new()
+        .with_thread_num(thread_num)
+        .load(&gf)?;
+    if args.ctx < 2 || args.ctx > model.conf.seq_len {
+        bail!(
+            ErrorKind::BadInput,
+            "ctx must be in [2, {}], but got {}",
+            model.conf.seq_len,
+            args.ctx
+        );
+    }
+
+    let text = std::fs::read_to_string(&args.file).map_err(|err| {
+        error!(
+            ErrorKind::IOError,
+            "failed to read the file {}: {}", args.file, err
+        )
+    })?;
+    let tokens = model.tokenizer.encode(&text, true, false)?;
+    if tokens.len() < 2 * args.ctx {
+        bail!(
+            ErrorKind::BadInput,
+            "you need at least {} tokens to evaluate perplexity with a context of {}, but the file has {} tokens",
+            2 * args.ctx,
+            args.ctx,
+            tokens.len()
+        );
+    }
+
+    let mut n_chunks = tokens.len() / args.ctx;
+    if args.chunks > 0 {
+        n_chunks = n_chunks.min(args.chunks);
+    }
+    eprintln!(
+        "calculating perplexity over {} chunks, ctx={}, {} tokens",
+        n_chunks,
+        args.ctx,
+        tokens.len()
+    );
+
+    let first = args.ctx / 2;
+    let mut nll = 0.0;
+    let mut nll2 = 0.0;
+    let mut count = 0;
+    // the kv cache is allocated once, and each chunk starts over from the position 0
+    let mut runner = Llama2Runner::new(&model, args.ctx, true)?;
+    for (i, chunk) in tokens.chunks_exact(args.ctx).take(n_chunks).enumerate() {
+        let mut chunk = chunk.to_vec();
+        chunk[0] = model.tokenizer.bos_token();
+
+        runner.reset()?;
+        let logprobs = runner.eval_logprobs(&chunk, 0)?;
+        // logprobs[j] is the log probability of chunk[j + 1]
+        for logprob in logprobs[first..].iter() {
+            let logprob = *logprob as f64;
+            nll -= logprob;
+            nll2 += logprob * logprob;
+            count += 1;
+        }
+        print!("[{}]{:.4},", i + 1, (nll / count as f64).exp());
+        std::io::stdout().flush().unwrap();
+    }
+    println!();
+
+    // the standard error of the mean nll, which is propagated into the ppl
+    let nll_mean = nll / count as f64;
+    let ppl = nll_mean.exp();
+    let variance = nll2 / count as f64 - nll_mean * nll_mean;
+    if variance > 0.0 && count > 1 {
+        let err = (variance / (count - 1) as f64).sqrt();
+        println!("Final estimate: PPL = {:.4} +/- {:.5}", ppl, err * ppl);
+    } else {
+        println!("Final estimate: PPL = {:.4}", ppl);
+    }
+    eprintln!(
+        "evaluated {} tokens: {}ms",
+        n_chunks * args.ctx,
+        start_time.elapsed().as_millis()
+    );
+    Ok(())
+}
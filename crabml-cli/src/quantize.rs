@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Instant;
+
+use clap::Args;
+use crabml::bail;
+use crabml::cpu::CpuTensorBuf;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFFileLoader;
+use crabml::gguf::GGUFFileWriter;
+use crabml::gguf::GGUFMetadataValue;
+use crabml::gguf::GGUFTensorInfo;
+use crabml::gguf::KEY_GENERAL_FILE_TYPE;
+use crabml::gguf::KEY_GENERAL_QUANTIZATION_VERSION;
+
+// the quantization version of the block layouts, the same as ggml's GGML_QNT_VERSION
+const GGML_QUANTIZATION_VERSION: u32 = 2;
+
+#[derive(Args, Debug)]
+pub struct QuantizeArgs {
+    /// The F32 or F16 model to quantize
+    input: String,
+
+    /// The path to write the quantized model
+    output: String,
+
+    /// The type to quantize the weights into, like q8_0, q4_0 or q4_k
+    #[arg(short = 't', long = "type")]
+    typ: String,
+
+    /// Override the type of a tensor, like `output.weight=q6_k`, can be repeated
+    #[arg(long = "tensor-type", value_name = "NAME=TYPE")]
+    tensor_types: Vec<String>,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+}
+
+pub fn run_quantize(args: &QuantizeArgs) -> Result<()> {
+    let start_time = Instant::now();
+    let typ = parse_target_type(&args.typ)?;
+    let overrides = parse_tensor_types(&args.tensor_types)?;
+    let thread_num = if args.threads == 0 {
+        num_cpus::get()
+    } else {
+        args.threads
+    };
+
+    let gl = GGUFFileLoader::new(&args.input, false)?;
+    let gf = gl.open()?;
+
+    // decide the target type of each tensor before doing any work, so a bad override fails fast
+    let tensor_infos = gf.tensor_infos();
+    for name in overrides.keys() {
+        if !tensor_infos.iter().any(|ti| ti.name() == name) {
+            bail!(
+                ErrorKind::BadInput,
+                "tensor {} is not found in the model",
+                name
+            );
+        }
+    }
+    let target_types = tensor_infos
+        .iter()
+        .map(|ti| target_type(ti, overrides.get(ti.name()).copied().unwrap_or(typ)))
+        .collect::<Result<Vec<_>>>()?;
+
+    // quantize the tensors in parallel, each thread takes the tensors at i % thread_num
+    let mut quantized: Vec<Option<Vec<u8>>> = vec![None; tensor_infos.len()];
+    std::thread::scope(|s| {
+        let handles = (0..thread_num)
+            .map(|thread_idx| {
+                let target_types = &target_types;
+                s.spawn(move || {
+                    tensor_infos
+                        .iter()
+                        .enumerate()
+                        .skip(thread_idx)
+                        .step_by(thread_num)
+                        .map(|(i, ti)| Ok((i, quantize_tensor(ti, target_types[i])?)))
+                        .collect::<Result<Vec<_>>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            for (i, buf) in handle.join().unwrap()? {
+                quantized[i] = buf;
+            }
+        }
+        Ok::<(), crabml::error::Error>(())
+    })?;
+
+    let mut writer = GGUFFileWriter::from_gguf_file(&gf);
+    for (i, ti) in tensor_infos.iter().enumerate() {
+        if let Some(buf) = quantized[i].as_ref() {
+            writer.set_tensor_info(GGUFTensorInfo::new(
+                ti.name().to_string(),
+                ti.dimensions().to_vec(),
+                target_types[i],
+                buf,
+            ));
+        }
+        eprintln!(
+            "- {:<32} {:>5} -> {:<5} {:?}",
+            ti.name(),
+            ti.typ().to_string(),
+            target_types[i].to_string(),
+            ti.dimensions()
+        );
+    }
+    writer.set_metadata(
+        KEY_GENERAL_FILE_TYPE,
+        GGUFMetadataValue::U32(file_type(typ).unwrap()),
+    );
+    writer.set_metadata(
+        KEY_GENERAL_QUANTIZATION_VERSION,
+        GGUFMetadataValue::U32(GGML_QUANTIZATION_VERSION),
+    );
+    writer.write_to_file(&args.output)?;
+
+    eprintln!(
+        "quantized {} to {} as {}: {}ms",
+        args.input,
+        args.output,
+        typ,
+        start_time.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+fn parse_tensor_types(args: &[String]) -> Result<HashMap<String, GGMLType>> {
+    args.iter()
+        .map(|arg| match arg.split_once('=') {
+            Some((name, typ)) => Ok((name.to_string(), parse_target_type(typ)?)),
+            None => bail!(
+                ErrorKind::BadInput,
+                "invalid tensor type override: {}, expected NAME=TYPE",
+                arg
+            ),
+        })
+        .collect()
+}
+
+/// the weights can only be quantized into the types with a vec_dot on the weights, Q8_1 and
+/// Q8_K are only used on the activations.
+fn parse_target_type(s: &str) -> Result<GGMLType> {
+    let typ = GGMLType::from_str(s)?;
+    if file_type(typ).is_none() {
+        bail!(
+            ErrorKind::BadInput,
+            "can not quantize the weights into {}, expected one of F16, Q4_0, Q4_1, Q5_0, Q5_1, \
+             Q8_0, Q2_K, Q3_K, Q4_K, Q5_K or Q6_K",
+            typ
+        );
+    }
+    Ok(typ)
+}
+
+/// only the 2d weights and the 3d experts of the moe models are quantized, the norms, biases
+/// and the routers are kept as is. the rows must be aligned with the block size, otherwise the
+/// tensor falls back to F16.
+fn target_type(ti: &GGUFTensorInfo, typ: GGMLType) -> Result<GGMLType> {
+    if ti.typ() != GGMLType::F32 && ti.typ() != GGMLType::F16 {
+        bail!(
+            ErrorKind::BadInput,
+            "tensor {} is {}, only F32 or F16 models can be quantized",
+            ti.name(),
+            ti.typ()
+        );
+    }
+    if ti.dimensions().len() < 2 || ti.name().ends_with("ffn_gate_inp.weight") {
+        return Ok(ti.typ());
+    }
+    if ti.dimensions()[0] % typ.block_size() != 0 {
+        eprintln!(
+            "tensor {} has rows of {} elements, which can not be quantized into {}, fallback to F16",
+            ti.name(),
+            ti.dimensions()[0],
+            typ
+        );
+        return Ok(GGMLType::F16);
+    }
+    Ok(typ)
+}
+
+fn quantize_tensor(ti: &GGUFTensorInfo, typ: GGMLType) -> Result<Option<Vec<u8>>> {
+    if typ == ti.typ() {
+        return Ok(None);
+    }
+    let buf = CpuTensorBuf::from_raw_bytes(ti.data(), ti.typ())?.dequantize(GGMLType::F32)?;
+    let buf = buf.quantize(typ)?;
+    Ok(Some(buf.as_bytes().to_vec()))
+}
+
+/// the llama_ftype in llama.cpp, which describes the type of the most tensors in the file.
+/// it's None for the types which can not be the target of the quantization.
+fn file_type(typ: GGMLType) -> Option<u32> {
+    match typ {
+        GGMLType::F16 => Some(1),
+        GGMLType::Q4_0 => Some(2),
+        GGMLType::Q4_1 => Some(3),
+        GGMLType::Q8_0 => Some(7),
+        GGMLType::Q5_0 => Some(8),
+        GGMLType::Q5_1 => Some(9),
+        GGMLType::Q2K => Some(10),
+        GGMLType::Q3K => Some(12),
+        GGMLType::Q4K => Some(15),
+        GGMLType::Q5K => Some(17),
+        GGMLType::Q6K => Some(18),
+        GGMLType::F32
+        | GGMLType::Q8_1
+        | GGMLType::Q8K
+        | GGMLType::I8
+        | GGMLType::I16
+        | GGMLType::I32
+        | GGMLType::COUNT => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::error::ErrorKind;
+    use crabml::error::Result;
+    use crabml::gguf::GGMLType;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml_llama2::llama2::Llama2Runner;
+    use crabml_llama2::model::CpuLlamaModelLoader;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_tensor_types() -> Result<()> {
+        assert_eq!(parse_target_type("q4_k")?, GGMLType::Q4K);
+        assert_eq!(parse_target_type("F16")?, GGMLType::F16);
+        for typ in ["q8_1", "q8_k", "f32", "i8", "q9_0"] {
+            assert_eq!(
+                parse_target_type(typ).unwrap_err().kind,
+                ErrorKind::BadInput
+            );
+        }
+
+        let overrides = parse_tensor_types(&[
+            "output.weight=q6_k".to_string(),
+            "token_embd.weight=Q8_0".to_string(),
+        ])?;
+        assert_eq!(overrides["output.weight"], GGMLType::Q6K);
+        assert_eq!(overrides["token_embd.weight"], GGMLType::Q8_0);
+        for arg in ["output.weight", "output.weight=q8_k"] {
+            let err = parse_tensor_types(&[arg.to_string()]).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::BadInput);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_target_type() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let ti = |name: &str| gf.get_tensor_info(name).unwrap();
+
+        // the rows of 64 elements fit the blocks of Q8_0, but not the ones of the k-quants
+        assert_eq!(
+            target_type(&ti("blk.0.attn_q.weight"), GGMLType::Q8_0)?,
+            GGMLType::Q8_0
+        );
+        assert_eq!(
+            target_type(&ti("blk.0.attn_q.weight"), GGMLType::Q4K)?,
+            GGMLType::F16
+        );
+        // the norms are kept as is
+        assert_eq!(
+            target_type(&ti("blk.0.attn_norm.weight"), GGMLType::Q8_0)?,
+            GGMLType::F32
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantize_and_generate() -> Result<()> {
+        let input = "../testdata/tinyllamas-stories-260k-f32.gguf".to_string();
+        let output =
+            std::env::temp_dir().join(format!("crabml-quantize-{}.gguf", std::process::id()));
+        let output = output.to_str().unwrap().to_string();
+        run_quantize(&QuantizeArgs {
+            input: input.clone(),
+            output: output.clone(),
+            typ: "q8_0".to_string(),
+            tensor_types: vec!["output.weight=f16".to_string()],
+            threads: 2,
+        })?;
+
+        let gl = GGUFFileLoader::new(&output, false)?;
+        let gf = gl.open()?;
+        std::fs::remove_file(&output).unwrap();
+        assert_eq!(gf.metadata().get_u32(KEY_GENERAL_FILE_TYPE), Some(7));
+        assert_eq!(
+            gf.get_tensor_info("blk.0.attn_q.weight").unwrap().typ(),
+            GGMLType::Q8_0
+        );
+        assert_eq!(
+            gf.get_tensor_info("output.weight").unwrap().typ(),
+            GGMLType::F16
+        );
+
+        // the quantized model stays close to the f32 one
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let gl_f32 = GGUFFileLoader::new(&input, false)?;
+        let gf_f32 = gl_f32.open()?;
+        let lm_f32 = CpuLlamaModelLoader::new().load(&gf_f32)?;
+        let tokens = [1, 400, 401, 402];
+        let logprobs = Llama2Runner::new(&lm, 200, false)?.eval_logprobs(&tokens, 0)?;
+        let logprobs_f32 = Llama2Runner::new(&lm_f32, 200, false)?.eval_logprobs(&tokens, 0)?;
+        for (a, b) in logprobs.iter().zip(logprobs_f32.iter()) {
+            assert!((a - b).abs() < 0.5, "{} vs {}", a, b);
+        }
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let output = runner
+            .prefill_and_generate("Lily is a cute cat", 10)?
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output.len(), 10);
+        Ok(())
+    }
+}
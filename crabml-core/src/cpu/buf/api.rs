@@ -87,10 +87,13 @@ impl<'a> CpuTensorBuf<'a> {
     }
 
     pub fn is_owned(&self) -> bool {
-        matches!(
-            self,
-            CpuTensorBuf::F32(Cow::Owned(_)) | CpuTensorBuf::F16(Cow::Owned(_))
-        )
+        match self {
+            CpuTensorBuf::F32(Cow::Owned(_)) | CpuTensorBuf::F16(Cow::Owned(_)) => true,
+            // the quantized kv caches are owned
+            CpuTensorBuf::Q8_0(buf) => matches!(buf.blocks, Cow::Owned(_)),
+            CpuTensorBuf::Q4_0(buf) => matches!(buf.blocks, Cow::Owned(_)),
+            _ => false,
+        }
     }
 
     pub fn is_quantized(&self) -> bool {
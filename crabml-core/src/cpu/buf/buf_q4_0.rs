@@ -52,6 +52,22 @@ impl<'a> QuantBufQ4_0<'_> {
         Self { blocks: bs.into() }
     }
 
+    /// an owned buffer of zeros, it's used as the quantized kv cache.
+    pub fn zeros(len: usize) -> Self {
+        assert_eq!(len % 32, 0);
+        Self {
+            blocks: vec![BlockQ4_0::zeroed(); len / 32].into(),
+        }
+    }
+
+    /// quantize the data into the blocks starting at the offset, the buffer gets copied if
+    /// it's not owned.
+    pub fn quantize_into(&mut self, offset: usize, data: &[f32]) {
+        assert_eq!(offset % 32, 0);
+        let bs = quantize_f32_q4_0(data);
+        self.blocks.to_mut()[offset / 32..offset / 32 + bs.len()].copy_from_slice(&bs);
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         bytemuck::cast_slice(&self.blocks)
     }
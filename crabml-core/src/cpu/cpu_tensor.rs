@@ -3,12 +3,15 @@ use std::borrow::Cow;
 use crate::bail;
 use crate::cpu::buf::buf_f16::alloc_f16_buf;
 use crate::cpu::buf::CpuTensorBuf;
+use crate::cpu::buf::QuantBufQ4_0;
+use crate::cpu::buf::QuantBufQ8_0;
 use crate::cpu::primitives;
 use crate::cpu::CpuTensorDeviceRef;
 use crate::error::ErrorKind;
 use crate::error::Result;
 use crate::gguf::GGMLType;
 use crate::tensor::RopeMode;
+use crate::tensor::RopeParams;
 use crate::tensor::Tensor;
 use crate::tensor::TensorStrider;
 
@@ -126,18 +129,41 @@ impl<'a> CpuTensor<'a> {
 impl<'a> Tensor for CpuTensor<'a> {
     type DeviceRef = CpuTensorDeviceRef<'a>;
 
+    /// copy the bytes into an owned tensor, it's used on restoring the kv caches.
     fn from_cpu(
-        _buf: &[u8],
-        _shape: &[usize],
-        _dtype: GGMLType,
-        _device: Self::DeviceRef,
+        buf: &[u8],
+        shape: &[usize],
+        dtype: GGMLType,
+        device: Self::DeviceRef,
     ) -> Result<Self> {
-        unreachable!("no need to implement from_cpu for CpuTensor")
+        if dtype != GGMLType::F32 {
+            bail!(ErrorKind::TensorError, "only f32 is supported on from_cpu");
+        }
+        let buf = buf
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        Self::new(buf, shape, device)
     }
 
     fn alloc(shape: &[usize], dtype: GGMLType, device: Self::DeviceRef) -> Result<Self> {
-        if dtype != GGMLType::F32 && dtype != GGMLType::F16 {
-            bail!(ErrorKind::TensorError, "only f32/f16 is supported");
+        match dtype {
+            GGMLType::F32 | GGMLType::F16 => {}
+            // the quantized kv caches, each row should be filled with whole blocks
+            GGMLType::Q8_0 | GGMLType::Q4_0 => {
+                if shape.last().map(|n| n % 32 != 0).unwrap_or(true) {
+                    bail!(
+                        ErrorKind::TensorError,
+                        "the last dimension of {:?} is not a multiple of 32 on {}",
+                        shape,
+                        dtype
+                    );
+                }
+            }
+            _ => bail!(
+                ErrorKind::TensorError,
+                "only f32/f16/q8_0/q4_0 is supported"
+            ),
         }
 
         let buf_size = shape.iter().product();
@@ -153,6 +179,8 @@ impl<'a> Tensor for CpuTensor<'a> {
                 let vec = Cow::Owned(vec_f16);
                 CpuTensorBuf::F16(vec)
             }
+            GGMLType::Q8_0 => CpuTensorBuf::Q8_0(QuantBufQ8_0::zeros(buf_size)),
+            GGMLType::Q4_0 => CpuTensorBuf::Q4_0(QuantBufQ4_0::zeros(buf_size)),
             _ => unreachable!(),
         };
 
@@ -255,10 +283,13 @@ impl<'a> Tensor for CpuTensor<'a> {
         if !self.is_owned() {
             bail!(ErrorKind::TensorError, "tensor not owned on concatenate");
         }
-        if self.dtype() != GGMLType::F32 && self.dtype() != GGMLType::F16 {
+        if !matches!(
+            self.dtype(),
+            GGMLType::F32 | GGMLType::F16 | GGMLType::Q8_0 | GGMLType::Q4_0
+        ) {
             bail!(
                 ErrorKind::TensorError,
-                "only f32/f16 is supported on concatenate",
+                "only f32/f16/q8_0/q4_0 is supported on concatenate",
             )
         }
         if rhs.dtype() != GGMLType::F32 && rhs.dtype() != GGMLType::F16 {
@@ -296,7 +327,10 @@ impl<'a> Tensor for CpuTensor<'a> {
         if self.is_contiguous() {
             return Ok(self);
         }
-        assert!(self.dtype() == GGMLType::F32 || self.dtype() == GGMLType::F16);
+        assert!(matches!(
+            self.dtype(),
+            GGMLType::F32 | GGMLType::F16 | GGMLType::Q8_0 | GGMLType::Q4_0
+        ));
 
         let mut out = CpuTensor::alloc(self.shape(), self.dtype(), self.device())?;
         primitives::contiguous(&self.buf, &self.strider, &mut out.buf);
@@ -332,14 +366,58 @@ impl<'a> Tensor for CpuTensor<'a> {
 
     fn dup(&self) -> Result<Self> {
         let _t = self.device.metrics.dup_walltime.track();
-        let buf = self.buf.iter_f32().collect::<Vec<_>>();
-        Self::new(buf, self.shape(), self.device.clone())
+        let buf = match &self.buf {
+            CpuTensorBuf::F32(buf) => CpuTensorBuf::F32(Cow::Owned(buf.to_vec())),
+            CpuTensorBuf::F16(buf) => CpuTensorBuf::F16(Cow::Owned(buf.to_vec())),
+            CpuTensorBuf::Q8_0(buf) => CpuTensorBuf::Q8_0(QuantBufQ8_0 {
+                blocks: Cow::Owned(buf.blocks.to_vec()),
+            }),
+            CpuTensorBuf::Q4_0(buf) => CpuTensorBuf::Q4_0(QuantBufQ4_0 {
+                blocks: Cow::Owned(buf.blocks.to_vec()),
+            }),
+            _ => bail!(
+                ErrorKind::TensorError,
+                "only f32/f16/q8_0/q4_0 is supported on dup"
+            ),
+        };
+        Ok(Self {
+            buf,
+            strider: self.strider.clone(),
+            device: self.device.clone(),
+            name: None,
+        })
     }
 
     fn export(&self, dst: &mut [f32]) -> Result<()> {
         let _t = self.device.metrics.export_walltime.track();
         assert!(self.is_contiguous());
 
+        // the f16 and quantized kv caches are exported on saving the sessions
+        match &self.buf {
+            CpuTensorBuf::F16(buf) => {
+                dst.iter_mut().zip(buf.iter()).for_each(|(dst, src)| {
+                    *dst = src.to_f32();
+                });
+                return Ok(());
+            }
+            CpuTensorBuf::Q8_0(buf) => {
+                dst.iter_mut()
+                    .zip(buf.dequantize(0))
+                    .for_each(|(dst, src)| {
+                        *dst = src;
+                    });
+                return Ok(());
+            }
+            CpuTensorBuf::Q4_0(buf) => {
+                dst.iter_mut()
+                    .zip(buf.dequantize(0))
+                    .for_each(|(dst, src)| {
+                        *dst = src;
+                    });
+                return Ok(());
+            }
+            _ => {}
+        }
         dst.iter_mut()
             .zip(self.buf.iter_f32())
             .for_each(|(dst, src)| {
@@ -428,11 +506,18 @@ impl<'a> Tensor for CpuTensor<'a> {
         Ok(self)
     }
 
-    fn rope_inplace(mut self, mode: RopeMode, pos: usize, rope_dims: usize) -> Result<Self> {
+    fn causal_mask_inplace(mut self, pos: usize, window: Option<usize>) -> Result<Self> {
+        let _t = self.device.metrics.causal_mask_walltime.track();
+        let strider1 = self.strider().clone();
+        primitives::causal_mask_inplace(self.buf_mut(), &strider1, pos, window)?;
+        Ok(self)
+    }
+
+    fn rope_inplace(mut self, mode: RopeMode, pos: usize, rope: &RopeParams) -> Result<Self> {
         let _t = self.device.metrics.rope_walltime.track();
         let strider1 = self.strider().clone();
         let buf1 = self.buf_mut();
-        primitives::rope_inplace(buf1, &strider1, mode, pos, rope_dims)?;
+        primitives::rope_inplace(buf1, &strider1, mode, pos, rope)?;
         Ok(self)
     }
 
@@ -443,6 +528,15 @@ impl<'a> Tensor for CpuTensor<'a> {
         primitives::rms_norm_inplace(buf1, &strider1, eps)?;
         Ok(self)
     }
+
+    fn layer_norm_inplace(mut self, weight: &Self, bias: &Self, eps: f32) -> Result<Self> {
+        let _t = self.device.metrics.rms_norm_walltime.track();
+        assert!(weight.is_contiguous() && bias.is_contiguous());
+        let strider1 = self.strider().clone();
+        let buf1 = self.buf_mut();
+        primitives::layer_norm_inplace(buf1, &strider1, &weight.buf, &bias.buf, eps)?;
+        Ok(self)
+    }
 }
 
 #[cfg(test)]
@@ -451,6 +545,7 @@ mod tests {
 
     use super::*;
     use crate::cpu::CpuTensorDevice;
+    use crate::cpu::CpuTensorDeviceOptions;
 
     #[test]
     fn test_tensor_view() -> Result<()> {
@@ -505,13 +600,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_layer_norm() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let t1 = CpuTensor::new(
+            vec![1.0, 2.0, 3.0, 4.0, 2.0, 2.0, 2.0, 2.0],
+            &[2, 4],
+            device.clone(),
+        )?;
+        let weight = CpuTensor::new(vec![1.0, 2.0, 1.0, 1.0], &[4], device.clone())?;
+        let bias = CpuTensor::new(vec![0.0, 0.0, 1.0, 0.0], &[4], device.clone())?;
+        let t1 = t1.layer_norm_inplace(&weight, &bias, 1e-5)?;
+
+        // a constant row is normalized to zeros, only the bias is left
+        assert_relative_eq!(
+            &t1.to_vec()[..],
+            &[-1.341635, -0.894423, 1.447212, 1.341635, 0.0, 0.0, 1.0, 0.0][..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_rope() -> Result<()> {
         let device = CpuTensorDevice::new();
         let v1 = (0..32).map(|v| v as f32).collect::<Vec<_>>();
         let t1 = CpuTensor::new(v1, &[2, 16], device.clone())?;
 
-        let r1 = t1.rope_inplace(RopeMode::Llama, 1, 2)?;
+        let r1 = t1.rope_inplace(RopeMode::Llama, 1, &RopeParams::new(2))?;
         let out = r1.to_vec();
         assert_relative_eq!(
             &out[..],
@@ -523,6 +639,19 @@ mod tests {
             epsilon = 1e-5
         );
 
+        // the position 2 with the linear scaling of 2 is rotated like the position 1
+        let v2 = (0..32).map(|v| v as f32).collect::<Vec<_>>();
+        let t2 = CpuTensor::new(v2, &[2, 16], device.clone())?;
+        let rope = RopeParams::new(16)
+            .with_freq_base(500000.0)
+            .with_linear_scaling(2.0);
+        let r2 = t2.rope_inplace(RopeMode::Neox, 2, &rope)?;
+        let v3 = (0..32).map(|v| v as f32).collect::<Vec<_>>();
+        let t3 = CpuTensor::new(v3, &[2, 16], device.clone())?;
+        let rope = RopeParams::new(16).with_freq_base(500000.0);
+        let r3 = t3.rope_inplace(RopeMode::Neox, 1, &rope)?;
+        assert_relative_eq!(&r2.to_vec()[..], &r3.to_vec()[..], epsilon = 1e-5);
+
         Ok(())
     }
 
@@ -554,6 +683,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_causal_mask() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        // (n_heads=1, n_batch=2, seq_len=3), the first query is at position 1
+        let t1 = CpuTensor::new(
+            vec![1.0, 2.0, 3.0, 1.0, 2.0, 3.0],
+            &[1, 2, 3],
+            device.clone(),
+        )?;
+        let t1 = t1.causal_mask_inplace(1, None)?.softmax_inplace(2)?;
+
+        assert_relative_eq!(
+            &t1.to_vec()[..],
+            &[0.26894142, 0.7310586, 0.0, 0.09003057, 0.24472848, 0.66524094][..],
+            epsilon = 1e-3
+        );
+
+        // with a window of 2, the second query does not see the first key
+        let t2 = CpuTensor::new(vec![1.0, 2.0, 3.0, 1.0, 2.0, 3.0], &[1, 2, 3], device)?;
+        let t2 = t2.causal_mask_inplace(1, Some(2))?.softmax_inplace(2)?;
+        assert_relative_eq!(
+            &t2.to_vec()[..],
+            &[0.26894142, 0.7310586, 0.0, 0.0, 0.26894142, 0.7310586][..],
+            epsilon = 1e-3
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_silu() -> Result<()> {
         let device = CpuTensorDevice::new();
@@ -604,4 +761,96 @@ mod tests {
         // todo:
         Ok(())
     }
+
+    #[test]
+    fn test_dup() -> Result<()> {
+        let device = CpuTensorDevice::new();
+        let mut t1 = CpuTensor::alloc(&[2, 4, 2], GGMLType::F32, device.clone())?.resize(1, 0)?;
+        let row = |v: f32| CpuTensor::new(vec![v; 4], &[2, 1, 2], device.clone());
+        t1.concatenate(&row(1.0)?, 1)?;
+
+        // the dup of the resized tensor keeps the capacity, and is not shared with the origin
+        let mut t2 = t1.dup()?;
+        t2.concatenate(&row(2.0)?, 1)?;
+        assert_eq!(t1.shape(), &[2, 1, 2]);
+        assert_eq!(t1.contiguous()?.to_vec(), vec![1.0; 4]);
+        assert_eq!(t2.shape(), &[2, 2, 2]);
+        assert_eq!(t2.contiguous()?.to_vec(), vec![
+            1.0, 1.0, 2.0, 2.0, 1.0, 1.0, 2.0, 2.0
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantized_kv_cache() -> Result<()> {
+        // 4 query heads share 2 kv heads, 5 tokens are cached in the kv cache of 8
+        let (n_heads, n_kv_heads, n_batch, head_dim) = (4, 2, 3, 64);
+        let (seq_len, n_tokens) = (8, 5);
+        let values = |n: usize, seed: usize| -> Vec<f32> {
+            (0..n)
+                .map(|i| ((i * 37 + seed) % 101) as f32 / 101.0 - 0.5)
+                .collect()
+        };
+        let q = values(n_heads * n_batch * head_dim, 1);
+        let k = values(n_kv_heads * n_tokens * head_dim, 2);
+        let v = values(n_kv_heads * n_tokens * head_dim, 3);
+
+        // the attention in plain loops, the query head h reads the kv head h / 2
+        let mut expected = vec![0.0; n_heads * n_batch * head_dim];
+        for h in 0..n_heads {
+            let kv_h = h / (n_heads / n_kv_heads);
+            for b in 0..n_batch {
+                let q_row = &q[(h * n_batch + b) * head_dim..][..head_dim];
+                let scores = (0..n_tokens)
+                    .map(|t| {
+                        let k_row = &k[(kv_h * n_tokens + t) * head_dim..][..head_dim];
+                        q_row.iter().zip(k_row).map(|(a, b)| a * b).sum::<f32>()
+                    })
+                    .collect::<Vec<_>>();
+                let max = scores.iter().cloned().fold(f32::MIN, f32::max);
+                let exps = scores.iter().map(|s| (s - max).exp()).collect::<Vec<_>>();
+                let sum = exps.iter().sum::<f32>();
+                for (t, exp) in exps.iter().enumerate() {
+                    let v_row = &v[(kv_h * n_tokens + t) * head_dim..][..head_dim];
+                    let out = &mut expected[(h * n_batch + b) * head_dim..][..head_dim];
+                    for (o, v) in out.iter_mut().zip(v_row) {
+                        *o += exp / sum * v;
+                    }
+                }
+            }
+        }
+
+        // the kv heads of the quantized caches are split across the threads
+        let cases = [
+            (GGMLType::F32, 1e-3, 1),
+            (GGMLType::F16, 1e-2, 1),
+            (GGMLType::Q8_0, 2e-2, 1),
+            (GGMLType::Q4_0, 1e-1, 1),
+            (GGMLType::Q8_0, 2e-2, 3),
+            (GGMLType::Q4_0, 1e-1, 3),
+        ];
+        for (dtype, epsilon, thread_num) in cases {
+            let device = CpuTensorDevice::with_options(
+                CpuTensorDeviceOptions::default().with_thread_num(thread_num),
+            );
+            let shape = [n_kv_heads, seq_len, head_dim];
+            let mut k_cache = CpuTensor::alloc(&shape, dtype, device.clone())?.resize(1, 0)?;
+            let mut v_cache = CpuTensor::alloc(&shape, dtype, device.clone())?.resize(1, 0)?;
+            let shape = [n_kv_heads, n_tokens, head_dim];
+            k_cache.concatenate(&CpuTensor::new(k.clone(), &shape, device.clone())?, 1)?;
+            v_cache.concatenate(&CpuTensor::new(v.clone(), &shape, device.clone())?, 1)?;
+
+            let q = CpuTensor::new(q.clone(), &[n_heads, n_batch, head_dim], device.clone())?;
+            let attn = q
+                .batch_matmul(&k_cache.clone().transpose(&[0, 2, 1])?)?
+                .softmax_inplace(2)?;
+            let out = attn.batch_matmul(&v_cache)?;
+            assert_relative_eq!(out.to_vec()[..], expected[..], epsilon = epsilon);
+
+            let mut keys = vec![0.0; k.len()];
+            k_cache.contiguous()?.export(&mut keys)?;
+            assert_relative_eq!(keys[..], k[..], epsilon = epsilon);
+        }
+        Ok(())
+    }
 }
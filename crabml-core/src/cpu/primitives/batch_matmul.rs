@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use half::f16;
 
 use crate::cpu::buf::buf_f16::quantize_f32_f16;
@@ -11,9 +13,11 @@ use crate::tensor::TensorStrider;
 /// A (b, m, n) @ B (b, k, n) -> C (b, m, n)
 ///
 /// A is expected to be contiguous, B is allowed to be strided, but B should
-/// be contiguous on the K dimension or N dimension.
+/// be contiguous on the K dimension or N dimension. B can be quantized in Q8_0
+/// or Q4_0 like the kv caches, the contiguous dimension should be aligned to the
+/// blocks.
 pub fn batch_matmul<'a>(
-    _device: &CpuTensorDeviceRef<'a>,
+    device: &CpuTensorDeviceRef<'a>,
     bufa: &CpuTensorBuf<'a>,
     bufb: &CpuTensorBuf<'a>,
     bufc: &mut CpuTensorBuf<'a>,
@@ -25,7 +29,10 @@ pub fn batch_matmul<'a>(
     assert!(strider1.is_contiguous());
     assert!(strider2.strides()[1] == 1 || strider2.strides()[2] == 1);
     assert!(bufa.dtype() == GGMLType::F32 || bufa.dtype() == GGMLType::F16);
-    assert!(bufb.dtype() == GGMLType::F32 || bufb.dtype() == GGMLType::F16);
+    assert!(matches!(
+        bufb.dtype(),
+        GGMLType::F32 | GGMLType::F16 | GGMLType::Q8_0 | GGMLType::Q4_0
+    ));
 
     match bufb {
         CpuTensorBuf::F32(bufb) => batch_matmul_naive_f32(
@@ -39,6 +46,14 @@ pub fn batch_matmul<'a>(
             let bufa = quantize_f32_f16(bufa.as_f32_ref());
             batch_matmul_simd_f16(&bufa, bufb, bufc.as_f32_mut(), strider1, strider2)
         }
+        CpuTensorBuf::Q8_0(_) | CpuTensorBuf::Q4_0(_) => batch_matmul_quantized(
+            device,
+            bufa.as_f32_ref(),
+            bufb,
+            bufc.as_f32_mut(),
+            strider1,
+            strider2,
+        ),
         _ => unreachable!(),
     }
 }
@@ -54,6 +69,7 @@ fn batch_matmul_naive_f32(
     let (a_batch, b_batch) = (stride1.shape()[0], stride2.shape()[0]);
     assert!(a_batch >= b_batch);
     let (m, k, n) = (stride1.shape()[1], stride1.shape()[2], stride2.shape()[2]);
+    let batch_broadcast = a_batch / b_batch;
     for bi in 0..a_batch {
         for mi in 0..m {
             for ni in 0..n {
@@ -61,7 +77,7 @@ fn batch_matmul_naive_f32(
                     bufc[bi * (m * n) + mi * n + ni] += bufa[bi * stride1.strides()[0]
                         + mi * stride1.strides()[1]
                         + ki * stride1.strides()[2]]
-                        * bufb[(bi % b_batch) * stride2.strides()[0]
+                        * bufb[(bi / batch_broadcast) * stride2.strides()[0]
                             + ki * stride2.strides()[1]
                             + ni * stride2.strides()[2]];
                 }
@@ -129,3 +145,79 @@ fn batch_matmul_simd_f16(
         unreachable!()
     }
 }
+
+fn batch_matmul_quantized(
+    device: &CpuTensorDeviceRef,
+    bufa: &[f32],        // bA x m x k
+    bufb: &CpuTensorBuf, // bB x k x n, bA is multiple of bB
+    bufc: &mut [f32],    // bA x m x n
+    stride1: &TensorStrider,
+    stride2: &TensorStrider,
+) {
+    let (a_batch, b_batch) = (stride1.shape()[0], stride2.shape()[0]);
+    assert!(a_batch >= b_batch);
+    let (m, k, n) = (stride1.shape()[1], stride1.shape()[2], stride2.shape()[2]);
+    let (stride_bb, stride_bk, stride_bn) = (
+        stride2.strides()[0],
+        stride2.strides()[1],
+        stride2.strides()[2],
+    );
+    let batch_broadcast = a_batch / b_batch;
+
+    // the batches of A sharing a batch of B are in a group, which is the output of a kv head
+    // on the attention. each thread takes a range of the groups in C.
+    let group_len = batch_broadcast * m * n;
+    let groups_per_thread = b_batch.div_ceil(device.thread_num());
+    let chunk_len = groups_per_thread * group_len;
+
+    // if matrix B is contiguous on the k dimension, quantize A into Q8_0 once for all the
+    // threads to vec_dot with the blocks of B. if it's contiguous on the n dimension,
+    // dequantize the rows of B one by one, and accumulate them into C.
+    if stride_bk == 1 {
+        let bufa = &CpuTensorBuf::from(bufa).quantize(GGMLType::Q8_0).unwrap();
+        device.thread_pool().lock().unwrap().scoped(|s| {
+            for (t, chunk) in bufc.chunks_mut(chunk_len).enumerate() {
+                s.spawn(move || {
+                    chunk.iter_mut().enumerate().for_each(|(j, bufcp)| {
+                        let i = t * chunk_len + j;
+                        let ni = i % n;
+                        let mi = (i - ni) / n % m;
+                        let bi_a = (i - ni - mi * n) / (m * n);
+                        let offset_a = bi_a * (m * k) + mi * k;
+                        let offset_b = (bi_a / batch_broadcast) * stride_bb + ni * stride_bn;
+                        *bufcp = bufb.vec_dot(offset_b, bufa, offset_a, k);
+                    });
+                });
+            }
+        });
+    } else if stride_bn == 1 {
+        device.thread_pool().lock().unwrap().scoped(|s| {
+            for (t, chunk) in bufc.chunks_mut(chunk_len).enumerate() {
+                s.spawn(move || {
+                    let mut row = CpuTensorBuf::F32(Cow::Owned(vec![0.0; n]));
+                    for (g, group) in chunk.chunks_mut(group_len).enumerate() {
+                        let bi_b = t * groups_per_thread + g;
+                        for ki in 0..k {
+                            row.copy_from(bufb, bi_b * stride_bb + ki * stride_bk, 0, n)
+                                .unwrap();
+                            let row = row.as_f32_ref();
+                            for bi in 0..batch_broadcast {
+                                let bi_a = bi_b * batch_broadcast + bi;
+                                for mi in 0..m {
+                                    let a = bufa[bi_a * (m * k) + mi * k + ki];
+                                    let offset_c = bi * (m * n) + mi * n;
+                                    group[offset_c..offset_c + n]
+                                        .iter_mut()
+                                        .zip(row.iter())
+                                        .for_each(|(c, b)| *c += a * b);
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+    } else {
+        unreachable!()
+    }
+}
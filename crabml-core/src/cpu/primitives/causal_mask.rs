@@ -0,0 +1,44 @@
+use crate::bail;
+use crate::cpu::buf::CpuTensorBuf;
+use crate::error::ErrorKind;
+use crate::error::Result;
+use crate::gguf::GGMLType;
+use crate::tensor::TensorStrider;
+
+/// the attention scores are in the shape of (n_heads, n_batch, seq_len), the query at row bi
+/// is at the position of pos + bi, it's not allowed to attend the keys after that position.
+/// with a sliding window, it only attends the last `window` keys up to its position.
+pub fn causal_mask_inplace(
+    buf: &mut CpuTensorBuf,
+    strider: &TensorStrider,
+    pos: usize,
+    window: Option<usize>,
+) -> Result<()> {
+    assert!(strider.is_contiguous());
+    assert!(buf.dtype() == GGMLType::F32);
+
+    if strider.dims() != 3 {
+        bail!(
+            ErrorKind::TensorError,
+            "causal mask only supports 3d tensor, got {:?}",
+            strider.shape()
+        );
+    }
+
+    let (n_heads, n_batch, seq_len) = (strider.shape()[0], strider.shape()[1], strider.shape()[2]);
+    let buf = buf.as_f32_mut();
+    for hi in 0..n_heads {
+        for bi in 0..n_batch {
+            let row_offset = hi * n_batch * seq_len + bi * seq_len;
+            let row = &mut buf[row_offset..row_offset + seq_len];
+            let visible = (pos + bi + 1).min(seq_len);
+            let start = match window {
+                Some(window) => (pos + bi + 1).saturating_sub(window).min(visible),
+                None => 0,
+            };
+            row[..start].fill(f32::NEG_INFINITY);
+            row[visible..].fill(f32::NEG_INFINITY);
+        }
+    }
+    Ok(())
+}
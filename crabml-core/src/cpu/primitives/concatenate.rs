@@ -64,6 +64,24 @@ pub fn concatenate_inplace<'a>(
                 )?
             }
         }
+        (CpuTensorBuf::Q8_0(buf1), CpuTensorBuf::F32(buf2)) => concatenate_3d_quantized_f32(
+            buf2,
+            strider1.shape(),
+            strider2.shape(),
+            strider1.strides(),
+            strider2.strides(),
+            axis,
+            |offset, row| buf1.quantize_into(offset, row),
+        )?,
+        (CpuTensorBuf::Q4_0(buf1), CpuTensorBuf::F32(buf2)) => concatenate_3d_quantized_f32(
+            buf2,
+            strider1.shape(),
+            strider2.shape(),
+            strider1.strides(),
+            strider2.strides(),
+            axis,
+            |offset, row| buf1.quantize_into(offset, row),
+        )?,
         (buf1, buf2) => {
             bail!(
                 ErrorKind::TensorError,
@@ -203,6 +221,48 @@ pub fn concatenate_3d_dense_f16_f32(
     Ok(new_shape)
 }
 
+/// quantize the appended rows on the last axis block by block, like the kv caches of
+/// (n_kv_heads, seq, head_dim). the rows have to be contiguous and aligned to the blocks.
+#[allow(clippy::too_many_arguments)]
+pub fn concatenate_3d_quantized_f32(
+    buf2: &[f32],
+    shape1: &[usize],
+    shape2: &[usize],
+    strides1: &[usize],
+    strides2: &[usize],
+    axis: usize,
+    mut quantize_row: impl FnMut(usize, &[f32]),
+) -> Result<Vec<usize>> {
+    if shape1.len() != 3
+        || axis == 2
+        || strides1[2] != 1
+        || strides2[2] != 1
+        || shape2[2] % 32 != 0
+        || strides1[0] % 32 != 0
+        || strides1[1] % 32 != 0
+    {
+        bail!(
+            ErrorKind::TensorError,
+            "can not concatenate into the quantized tensor of shape {:?} strides {:?}",
+            shape1,
+            strides1
+        );
+    }
+    let buf1_offset = shape1[axis] * strides1[axis];
+
+    for x in 0..shape2[0] {
+        for y in 0..shape2[1] {
+            let buf1_base = buf1_offset + x * strides1[0] + y * strides1[1];
+            let buf2_base = x * strides2[0] + y * strides2[1];
+            quantize_row(buf1_base, &buf2[buf2_base..buf2_base + shape2[2]]);
+        }
+    }
+
+    let mut new_shape = shape1.to_vec();
+    new_shape[axis] += shape2[axis];
+    Ok(new_shape)
+}
+
 #[cfg(test)]
 mod test {
     use super::concatenate_2d;
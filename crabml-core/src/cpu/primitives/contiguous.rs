@@ -13,10 +13,40 @@ pub fn contiguous(bufa: &CpuTensorBuf, stride_a: &TensorStrider, bufb: &mut CpuT
         (CpuTensorBuf::F16(bufa), CpuTensorBuf::F16(Cow::Owned(bufb))) => {
             contiguous_buf(bufa, bufb, stride_a.shape(), stride_a.strides())
         }
+        (CpuTensorBuf::Q8_0(bufa), CpuTensorBuf::Q8_0(bufb)) => {
+            contiguous_blocks(&bufa.blocks, bufb.blocks.to_mut(), stride_a)
+        }
+        (CpuTensorBuf::Q4_0(bufa), CpuTensorBuf::Q4_0(bufb)) => {
+            contiguous_blocks(&bufa.blocks, bufb.blocks.to_mut(), stride_a)
+        }
         _ => unreachable!(),
     }
 }
 
+// copy the quantized blocks row by row, the rows on the last axis should be contiguous and
+// aligned to the blocks of 32 elements, like the views on the kv caches.
+fn contiguous_blocks<T: Copy>(a: &[T], b: &mut [T], stride_a: &TensorStrider) {
+    let shape = stride_a.shape();
+    let strides = stride_a.strides();
+    let dims = shape.len();
+    assert!(strides[dims - 1] == 1 && shape[dims - 1] % 32 == 0);
+    let row_blocks = shape[dims - 1] / 32;
+    let (n_outer, stride_outer) = match dims {
+        2 => (1, 0),
+        _ => (shape[0], strides[0]),
+    };
+    let (n_rows, stride_row) = (shape[dims - 2], strides[dims - 2]);
+
+    let mut index = 0;
+    for i in 0..n_outer {
+        for j in 0..n_rows {
+            let offset = (i * stride_outer + j * stride_row) / 32;
+            b[index..index + row_blocks].copy_from_slice(&a[offset..offset + row_blocks]);
+            index += row_blocks;
+        }
+    }
+}
+
 pub fn contiguous_buf<T: Copy + Send + Sync>(
     a: &[T],
     b: &mut [T],
@@ -0,0 +1,39 @@
+use crate::cpu::buf::CpuTensorBuf;
+use crate::error::Result;
+use crate::gguf::GGMLType;
+use crate::tensor::TensorStrider;
+
+// normalize each row to the zero mean and the unit variance, then scale and shift it by
+// the weight and the bias.
+pub fn layer_norm_inplace(
+    buf: &mut CpuTensorBuf<'_>,
+    strider: &TensorStrider,
+    weight: &CpuTensorBuf<'_>,
+    bias: &CpuTensorBuf<'_>,
+    eps: f32,
+) -> Result<()> {
+    assert!(strider.is_contiguous());
+    assert!(strider.shape().len() == 1 || strider.shape().len() == 2);
+    assert!(buf.dtype() == GGMLType::F32);
+    assert!(weight.dtype() == GGMLType::F32 && bias.dtype() == GGMLType::F32);
+
+    let (rows, cols) = if strider.shape().len() == 1 {
+        (1, strider.shape()[0])
+    } else {
+        (strider.shape()[0], strider.shape()[1])
+    };
+    assert!(weight.len() == cols && bias.len() == cols);
+
+    let (weight, bias) = (weight.as_f32_ref(), bias.as_f32_ref());
+    let buf = buf.as_f32_mut();
+    for row in buf.chunks_exact_mut(cols).take(rows) {
+        let mean = row.iter().sum::<f32>() / cols as f32;
+        let var = row.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / cols as f32;
+        let scale = 1.0 / (var + eps).sqrt();
+        for ((v, w), b) in row.iter_mut().zip(weight.iter()).zip(bias.iter()) {
+            *v = (*v - mean) * scale * w + b;
+        }
+    }
+
+    Ok(())
+}
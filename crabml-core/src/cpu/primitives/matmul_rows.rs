@@ -0,0 +1,117 @@
+use crate::cpu::buf::CpuTensorBuf;
+use crate::cpu::CpuTensorDeviceRef;
+
+// the rows of A and B visited together, a row of A is dotted with TILE_B rows of B in a row.
+const TILE_M: usize = 16;
+const TILE_B: usize = 8;
+
+/// matmul on batched inputs like the prompt prefill, every element of C is the `vec_dot` of a
+/// row of A and a row of B.
+/// (m, k) @ (b, k) -> (b, m)
+///
+/// the rows of A are split into ranges for every thread, and B is quantized once for all the
+/// threads. the tiles only order the pairs of rows, there's no blocking on k nor packing of B.
+pub fn matmul_rows_2d_2d(
+    device: &CpuTensorDeviceRef,
+    bufa: &CpuTensorBuf,     // (m, k)
+    bufb: &CpuTensorBuf,     // (b, k)
+    bufc: &mut CpuTensorBuf, // (b, m)
+    m: usize,
+    k: usize,
+) {
+    let metrics = device.metrics.clone();
+    let bufc = bufc.as_f32_mut();
+    let b = bufc.len() / m;
+
+    let bufb = &{
+        let _t = metrics.matmul_quantize_walltime.track();
+        bufb.quantize(bufa.vec_dot_rhs_dtype()).unwrap()
+    };
+
+    let _t = metrics.matmul_walltime.track();
+
+    // each thread owns a range of rows in A, rounded up to the tile size
+    let thread_num = device.thread_num();
+    let rows_per_thread = m.div_ceil(thread_num).div_ceil(TILE_M) * TILE_M;
+    let ranges = (0..m)
+        .step_by(rows_per_thread)
+        .map(|m_start| (m_start, (m_start + rows_per_thread).min(m)))
+        .collect::<Vec<_>>();
+
+    // the results of each thread are laid out as (b, m_end - m_start), and scattered into C
+    // after all the threads finished.
+    let mut partials = ranges
+        .iter()
+        .map(|(m_start, m_end)| vec![0.0; b * (m_end - m_start)])
+        .collect::<Vec<_>>();
+
+    device.thread_pool().lock().unwrap().scoped(|s| {
+        partials
+            .iter_mut()
+            .zip(ranges.iter())
+            .for_each(|(partial, &(m_start, m_end))| {
+                s.spawn(move || {
+                    let m_len = m_end - m_start;
+                    for mt in (m_start..m_end).step_by(TILE_M) {
+                        let mt_end = (mt + TILE_M).min(m_end);
+                        for bt in (0..b).step_by(TILE_B) {
+                            let bt_end = (bt + TILE_B).min(b);
+                            for mi in mt..mt_end {
+                                for bi in bt..bt_end {
+                                    partial[bi * m_len + mi - m_start] =
+                                        bufa.vec_dot(mi * k, bufb, bi * k, k);
+                                }
+                            }
+                        }
+                    }
+                });
+            });
+    });
+
+    for (partial, &(m_start, m_end)) in partials.iter().zip(ranges.iter()) {
+        let m_len = m_end - m_start;
+        for bi in 0..b {
+            bufc[bi * m + m_start..bi * m + m_end]
+                .copy_from_slice(&partial[bi * m_len..(bi + 1) * m_len]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use crate::cpu::CpuTensor;
+    use crate::cpu::CpuTensorDevice;
+    use crate::cpu::CpuTensorDeviceOptions;
+    use crate::error::Result;
+    use crate::tensor::Tensor;
+
+    #[test]
+    fn test_matmul_rows_matches_gemv() -> Result<()> {
+        let device =
+            CpuTensorDevice::with_options(CpuTensorDeviceOptions::default().with_thread_num(3));
+        let (m, k, b) = (70, 64, 11);
+        let va = (0..m * k).map(|i| (i % 13) as f32 * 0.1 - 0.5).collect();
+        let vb = (0..b * k)
+            .map(|i| (i % 7) as f32 * 0.2 - 0.3)
+            .collect::<Vec<f32>>();
+        let a = CpuTensor::new(va, &[m, k], device.clone())?;
+
+        // (b, k) goes through the batched path, each (k, ) row goes through the GEMV path
+        let batched = CpuTensor::new(vb.clone(), &[b, k], device.clone())?;
+        let c = a.matmul_vec(&batched)?;
+        assert_eq!(c.shape(), &[b, m]);
+
+        for bi in 0..b {
+            let row = CpuTensor::new(vb[bi * k..(bi + 1) * k].to_vec(), &[k], device.clone())?;
+            let expected = a.matmul_vec(&row)?;
+            assert_relative_eq!(
+                &c.buf().as_f32_ref()[bi * m..(bi + 1) * m],
+                expected.buf().as_f32_ref(),
+                epsilon = 1e-5
+            );
+        }
+        Ok(())
+    }
+}
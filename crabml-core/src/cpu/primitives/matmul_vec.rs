@@ -1,9 +1,10 @@
+use super::matmul_rows::matmul_rows_2d_2d;
 use crate::cpu::buf::CpuTensorBuf;
 use crate::cpu::CpuTensorDeviceRef;
 use crate::tensor::metrics::TimeMetric;
 use crate::tensor::TensorStrider;
 
-/// only dense GEMV is supported
+/// only dense GEMV is supported, the batched case is dispatched to a row by row matmul
 /// (m, k) @ k -> (m, )
 /// (m, k) @ (b, k) -> (b, m)
 pub fn matmul_vec<'a>(
@@ -19,7 +20,12 @@ pub fn matmul_vec<'a>(
     assert!(strider1.shape().last() == strider2.shape().last());
 
     let (m, k) = (strider1.shape()[0], strider1.shape()[1]);
-    gemv_dense_2d_2d(device, bufa, bufb, bufc, m, k);
+    let b = strider2.len() / k;
+    if b > 1 {
+        matmul_rows_2d_2d(device, bufa, bufb, bufc, m, k);
+    } else {
+        gemv_dense_2d_2d(device, bufa, bufb, bufc, m, k);
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -1,13 +1,17 @@
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
 use std::mem;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use int_enum::IntEnum;
 use memmap2::Mmap;
 
 use crate::bail;
+use crate::error;
 use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::error::Result;
@@ -132,6 +136,63 @@ impl Display for GGMLType {
     }
 }
 
+impl FromStr for GGMLType {
+    type Err = Error;
+
+    /// parse the type name like "Q4_K" or "q8_0", case insensitive.
+    fn from_str(s: &str) -> Result<Self> {
+        (0..GGMLType::COUNT.int_value())
+            .filter_map(|v| GGMLType::from_int(v).ok())
+            .find(|t| t.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| error!(ErrorKind::BadInput, "unknown ggml type: {}", s))
+    }
+}
+
+impl GGMLType {
+    /// the number of elements in a block
+    pub fn block_size(&self) -> usize {
+        match *self {
+            GGMLType::F32 | GGMLType::F16 => 1,
+            GGMLType::I8 | GGMLType::I16 | GGMLType::I32 => 1,
+            GGMLType::Q4_0 | GGMLType::Q4_1 => 32,
+            GGMLType::Q5_0 | GGMLType::Q5_1 => 32,
+            GGMLType::Q8_0 | GGMLType::Q8_1 => 32,
+            GGMLType::Q2K | GGMLType::Q3K | GGMLType::Q4K => 256,
+            GGMLType::Q5K | GGMLType::Q6K | GGMLType::Q8K => 256,
+            GGMLType::COUNT => unreachable!(),
+        }
+    }
+
+    /// the bytes of a block
+    pub fn type_size(&self) -> usize {
+        match *self {
+            GGMLType::F32 => 4,
+            GGMLType::F16 => 2,
+            GGMLType::I8 => 1,
+            GGMLType::I16 => 2,
+            GGMLType::I32 => 4,
+            GGMLType::Q4_0 => 18,
+            GGMLType::Q4_1 => 20,
+            GGMLType::Q5_0 => 22,
+            GGMLType::Q5_1 => 24,
+            GGMLType::Q8_0 => 34,
+            GGMLType::Q8_1 => 36,
+            GGMLType::Q2K => 84,
+            GGMLType::Q3K => 110,
+            GGMLType::Q4K => 144,
+            GGMLType::Q5K => 176,
+            GGMLType::Q6K => 210,
+            GGMLType::Q8K => 292,
+            GGMLType::COUNT => unreachable!(),
+        }
+    }
+
+    /// the bytes of a tensor with n_elms elements in this type
+    pub fn bytes_size(&self, n_elms: usize) -> usize {
+        n_elms / self.block_size() * self.type_size()
+    }
+}
+
 impl TryFrom<u32> for GGMLType {
     type Error = Error;
 
@@ -244,6 +305,49 @@ pub enum GGUFMetadataArray<'a> {
     NestedArray(Vec<GGUFMetadataArray<'a>>),
 }
 
+impl GGUFMetadataArray<'_> {
+    /// the type of the elements in the array
+    pub fn typ(&self) -> GGUFMetadataValueType {
+        match self {
+            GGUFMetadataArray::U8Array(_) => GGUFMetadataValueType::U8,
+            GGUFMetadataArray::I8Array(_) => GGUFMetadataValueType::I8,
+            GGUFMetadataArray::U16Array(_) => GGUFMetadataValueType::U16,
+            GGUFMetadataArray::I16Array(_) => GGUFMetadataValueType::I16,
+            GGUFMetadataArray::U32Array(_) => GGUFMetadataValueType::U32,
+            GGUFMetadataArray::I32Array(_) => GGUFMetadataValueType::I32,
+            GGUFMetadataArray::U64Array(_) => GGUFMetadataValueType::U64,
+            GGUFMetadataArray::I64Array(_) => GGUFMetadataValueType::I64,
+            GGUFMetadataArray::F32Array(_) => GGUFMetadataValueType::F32,
+            GGUFMetadataArray::F64Array(_) => GGUFMetadataValueType::F64,
+            GGUFMetadataArray::BoolArray(_) => GGUFMetadataValueType::Bool,
+            GGUFMetadataArray::StringArray(_) => GGUFMetadataValueType::String,
+            GGUFMetadataArray::NestedArray(_) => GGUFMetadataValueType::Array,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            GGUFMetadataArray::U8Array(arr) => arr.len(),
+            GGUFMetadataArray::I8Array(arr) => arr.len(),
+            GGUFMetadataArray::U16Array(arr) => arr.len(),
+            GGUFMetadataArray::I16Array(arr) => arr.len(),
+            GGUFMetadataArray::U32Array(arr) => arr.len(),
+            GGUFMetadataArray::I32Array(arr) => arr.len(),
+            GGUFMetadataArray::U64Array(arr) => arr.len(),
+            GGUFMetadataArray::I64Array(arr) => arr.len(),
+            GGUFMetadataArray::F32Array(arr) => arr.len(),
+            GGUFMetadataArray::F64Array(arr) => arr.len(),
+            GGUFMetadataArray::BoolArray(arr) => arr.len(),
+            GGUFMetadataArray::StringArray(arr) => arr.len(),
+            GGUFMetadataArray::NestedArray(arr) => arr.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 pub struct GGUFBufReader<'a> {
     cursor: &'a [u8],
     read_bytes: usize,
@@ -489,6 +593,20 @@ impl<'a> GGUFMetadata<'a> {
     }
 }
 
+fn alignment_of(value: Option<&GGUFMetadataValue>) -> u64 {
+    match value {
+        Some(GGUFMetadataValue::U64(v)) => *v,
+        Some(GGUFMetadataValue::U32(v)) => *v as u64,
+        Some(GGUFMetadataValue::U16(v)) => *v as u64,
+        Some(GGUFMetadataValue::U8(v)) => *v as u64,
+        Some(GGUFMetadataValue::I64(v)) if *v > 0 => *v as u64,
+        Some(GGUFMetadataValue::I32(v)) if *v > 0 => *v as u64,
+        Some(GGUFMetadataValue::I16(v)) if *v > 0 => *v as u64,
+        Some(GGUFMetadataValue::I8(v)) if *v > 0 => *v as u64,
+        _ => GGUF_DEFAULT_ALIGNMENT,
+    }
+}
+
 struct GGUFHeader<'a> {
     // Magic number to announce that this is a GGUF file.
     // Must be `GGUF` at the byte level: `0x47` `0x47` `0x55` `0x46`.
@@ -573,17 +691,7 @@ impl<'a> GGUFHeader<'a> {
     /// but it must be a multiple of 8. Some writers may not write the alignment. If the alignment is not specified,
     /// assume it is 32.
     pub fn alignment(&self) -> u64 {
-        match self.metadata.as_hashmap().get(KEY_GENERAL_ALIGNMENT) {
-            Some(GGUFMetadataValue::U64(v)) => *v,
-            Some(GGUFMetadataValue::U32(v)) => *v as u64,
-            Some(GGUFMetadataValue::U16(v)) => *v as u64,
-            Some(GGUFMetadataValue::U8(v)) => *v as u64,
-            Some(GGUFMetadataValue::I64(v)) if *v > 0 => *v as u64,
-            Some(GGUFMetadataValue::I32(v)) if *v > 0 => *v as u64,
-            Some(GGUFMetadataValue::I16(v)) if *v > 0 => *v as u64,
-            Some(GGUFMetadataValue::I8(v)) if *v > 0 => *v as u64,
-            _ => GGUF_DEFAULT_ALIGNMENT,
-        }
+        alignment_of(self.metadata.as_hashmap().get(KEY_GENERAL_ALIGNMENT))
     }
 
     /// describes what architecture this model implements. All lowercase ASCII, with only [a-z0-9]+ characters
@@ -720,7 +828,7 @@ impl<'a> GGUFFile<'a> {
         // find the tensor_data position
         let position = buf.read_bytes();
         let alignment = header.alignment() as usize;
-        let next_position = position - (position % alignment) + alignment;
+        let next_position = position.div_ceil(alignment) * alignment;
         let _ = buf.read(next_position - position)?;
         let tensor_data = buf.cursor();
 
@@ -739,13 +847,22 @@ impl<'a> GGUFFile<'a> {
         tensor_data: &'a [u8],
     ) -> Result<Vec<GGUFTensorInfo<'a>>> {
         let mut result = Vec::with_capacity(tensor_infos.len());
-        for (i, tensor_info) in tensor_infos.iter().enumerate() {
-            let next_offset = if i + 1 >= tensor_infos.len() {
-                tensor_data.len()
-            } else {
-                tensor_infos[i + 1].offset as usize
-            };
-            let data = &tensor_data[tensor_info.offset as usize..next_offset];
+        for tensor_info in tensor_infos.iter() {
+            // the data is sliced without the padding, the quantized buffers expect the exact
+            // bytes of their blocks
+            let n_elms = tensor_info.dimensions.iter().product::<usize>();
+            let offset = tensor_info.offset as usize;
+            let end = offset + tensor_info.typ.bytes_size(n_elms);
+            if end > tensor_data.len() {
+                bail!(
+                    ErrorKind::FormatError,
+                    "tensor {} is out of the file: {} > {}",
+                    tensor_info.name,
+                    end,
+                    tensor_data.len()
+                );
+            }
+            let data = &tensor_data[offset..end];
 
             let item = GGUFTensorInfo::new(
                 tensor_info.name.clone(),
@@ -832,6 +949,289 @@ impl GGUFFileLoader {
     }
 }
 
+/// GGUFFileWriter serializes the metadata and tensors into a GGUF v3 file. the tensor data is
+/// borrowed, the caller is expected to keep the re-quantized or patched buffers alive until
+/// the file is written.
+pub struct GGUFFileWriter<'a> {
+    metadata: Vec<(String, GGUFMetadataValue<'a>)>,
+    tensor_infos: Vec<GGUFTensorInfo<'a>>,
+}
+
+impl Default for GGUFFileWriter<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> GGUFFileWriter<'a> {
+    pub fn new() -> Self {
+        Self {
+            metadata: vec![],
+            tensor_infos: vec![],
+        }
+    }
+
+    /// copy all the metadata and tensors from a loaded file. the reader does not keep the order
+    /// of the metadata, so the keys are sorted to make the output deterministic.
+    pub fn from_gguf_file(gf: &GGUFFile<'a>) -> Self {
+        let mut metadata = gf
+            .header
+            .metadata
+            .as_hashmap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+        metadata.sort_by(|a, b| a.0.cmp(&b.0));
+        Self {
+            metadata,
+            tensor_infos: gf.tensor_infos.clone(),
+        }
+    }
+
+    pub fn metadata(&self) -> &[(String, GGUFMetadataValue<'a>)] {
+        &self.metadata
+    }
+
+    pub fn tensor_infos(&self) -> &[GGUFTensorInfo<'a>] {
+        &self.tensor_infos
+    }
+
+    /// set the metadata value, replace the existing one with the same key.
+    pub fn set_metadata(&mut self, key: &str, value: GGUFMetadataValue<'a>) {
+        match self.metadata.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value,
+            None => self.metadata.push((key.to_string(), value)),
+        }
+    }
+
+    pub fn remove_metadata(&mut self, key: &str) {
+        self.metadata.retain(|(k, _)| k != key);
+    }
+
+    /// add a tensor, replace the existing one with the same name.
+    pub fn set_tensor_info(&mut self, tensor_info: GGUFTensorInfo<'a>) {
+        match self
+            .tensor_infos
+            .iter_mut()
+            .find(|ti| ti.name() == tensor_info.name())
+        {
+            Some(ti) => *ti = tensor_info,
+            None => self.tensor_infos.push(tensor_info),
+        }
+    }
+
+    pub fn remove_tensor_info(&mut self, name: &str) {
+        self.tensor_infos.retain(|ti| ti.name() != name);
+    }
+
+    pub fn alignment(&self) -> u64 {
+        let value = self
+            .metadata
+            .iter()
+            .find(|(k, _)| k == KEY_GENERAL_ALIGNMENT)
+            .map(|(_, v)| v);
+        alignment_of(value)
+    }
+
+    pub fn write_to_file(&self, path: &str) -> Result<()> {
+        let file = File::create(path).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to create the file: {}", path),
+            cause: Some(Arc::new(err)),
+        })?;
+        let mut w = BufWriter::new(file);
+        self.write(&mut w)?;
+        w.flush().map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: format!("failed to flush the file: {}", path),
+            cause: Some(Arc::new(err)),
+        })
+    }
+
+    pub fn write(&self, w: &mut impl Write) -> Result<()> {
+        let alignment = self.alignment() as usize;
+        if alignment == 0 || alignment % 8 != 0 {
+            bail!(
+                ErrorKind::BadInput,
+                "alignment must be a multiple of 8, got {}",
+                alignment
+            );
+        }
+
+        // the data of each tensor may contain the padding of the original file, only the bytes
+        // described by its dimensions are written.
+        let tensor_datas = self
+            .tensor_infos
+            .iter()
+            .map(|ti| {
+                let n_elms = ti.dimensions().iter().product::<usize>();
+                let size = ti.typ().bytes_size(n_elms);
+                if ti.data().len() < size {
+                    bail!(
+                        ErrorKind::BadInput,
+                        "tensor {} expects {} bytes of data, got {}",
+                        ti.name(),
+                        size,
+                        ti.data().len()
+                    );
+                }
+                Ok(&ti.data()[..size])
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut gw = GGUFMetadataWriter::new(w);
+
+        // header
+        gw.write_u32(GGUF_MAGIC)?;
+        gw.write_u32(GGUFVersion::V3.int_value())?;
+        gw.write_u64(self.tensor_infos.len() as u64)?;
+        gw.write_u64(self.metadata.len() as u64)?;
+        for (key, value) in self.metadata.iter() {
+            gw.write_string(key)?;
+            gw.write_value(value)?;
+        }
+
+        // tensor infos, the offsets are relative to the start of the tensor data
+        let mut offset = 0;
+        for (ti, data) in self.tensor_infos.iter().zip(tensor_datas.iter()) {
+            if ti.name().len() > 64 {
+                bail!(
+                    ErrorKind::BadInput,
+                    "tensor name {} is longer than 64 bytes",
+                    ti.name()
+                );
+            }
+            gw.write_string(ti.name())?;
+            gw.write_u32(ti.dimensions().len() as u32)?;
+            for dim in ti.dimensions() {
+                gw.write_u64(*dim as u64)?;
+            }
+            gw.write_u32(ti.typ().int_value())?;
+            gw.write_u64(offset as u64)?;
+            offset += data.len().div_ceil(alignment) * alignment;
+        }
+
+        // tensor data, every tensor is padded to the alignment
+        gw.write_padding(alignment)?;
+        for data in tensor_datas {
+            gw.write_bytes(data)?;
+            gw.write_padding(alignment)?;
+        }
+        Ok(())
+    }
+}
+
+struct GGUFMetadataWriter<'w, W: Write> {
+    w: &'w mut W,
+    written_bytes: usize,
+}
+
+macro_rules! define_gguf_metadata_value_write_fn {
+    ($write_array_func:ident, $write_item_func:ident, $typ:ty) => {
+        fn $write_array_func(&mut self, arr: &[$typ]) -> Result<()> {
+            for v in arr {
+                self.$write_item_func(*v)?;
+            }
+            Ok(())
+        }
+
+        fn $write_item_func(&mut self, v: $typ) -> Result<()> {
+            self.write_bytes(&v.to_le_bytes())
+        }
+    };
+}
+
+impl<'w, W: Write> GGUFMetadataWriter<'w, W> {
+    fn new(w: &'w mut W) -> Self {
+        Self {
+            w,
+            written_bytes: 0,
+        }
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> Result<()> {
+        self.w.write_all(buf).map_err(|err| Error {
+            kind: ErrorKind::IOError,
+            message: "failed to write gguf".to_string(),
+            cause: Some(Arc::new(err)),
+        })?;
+        self.written_bytes += buf.len();
+        Ok(())
+    }
+
+    fn write_padding(&mut self, alignment: usize) -> Result<()> {
+        let padding = self.written_bytes.div_ceil(alignment) * alignment - self.written_bytes;
+        self.write_bytes(&vec![0; padding])
+    }
+
+    define_gguf_metadata_value_write_fn!(write_u8_array, write_u8, u8);
+    define_gguf_metadata_value_write_fn!(write_i8_array, write_i8, i8);
+    define_gguf_metadata_value_write_fn!(write_u16_array, write_u16, u16);
+    define_gguf_metadata_value_write_fn!(write_i16_array, write_i16, i16);
+    define_gguf_metadata_value_write_fn!(write_u32_array, write_u32, u32);
+    define_gguf_metadata_value_write_fn!(write_i32_array, write_i32, i32);
+    define_gguf_metadata_value_write_fn!(write_u64_array, write_u64, u64);
+    define_gguf_metadata_value_write_fn!(write_i64_array, write_i64, i64);
+    define_gguf_metadata_value_write_fn!(write_f32_array, write_f32, f32);
+    define_gguf_metadata_value_write_fn!(write_f64_array, write_f64, f64);
+
+    fn write_string(&mut self, s: &str) -> Result<()> {
+        self.write_u64(s.len() as u64)?;
+        self.write_bytes(s.as_bytes())
+    }
+
+    fn write_value(&mut self, value: &GGUFMetadataValue) -> Result<()> {
+        self.write_u32(value.typ().int_value())?;
+        match value {
+            GGUFMetadataValue::U8(v) => self.write_u8(*v),
+            GGUFMetadataValue::I8(v) => self.write_i8(*v),
+            GGUFMetadataValue::U16(v) => self.write_u16(*v),
+            GGUFMetadataValue::I16(v) => self.write_i16(*v),
+            GGUFMetadataValue::U32(v) => self.write_u32(*v),
+            GGUFMetadataValue::I32(v) => self.write_i32(*v),
+            GGUFMetadataValue::U64(v) => self.write_u64(*v),
+            GGUFMetadataValue::I64(v) => self.write_i64(*v),
+            GGUFMetadataValue::F32(v) => self.write_f32(*v),
+            GGUFMetadataValue::F64(v) => self.write_f64(*v),
+            GGUFMetadataValue::Bool(v) => self.write_u8(*v),
+            GGUFMetadataValue::String(v) => self.write_string(v),
+            GGUFMetadataValue::Array(arr) => self.write_array(arr),
+        }
+    }
+
+    // the same layout as read_array: the element type, the length, then the elements. each
+    // element of a nested array is an array with its own type and length.
+    fn write_array(&mut self, arr: &GGUFMetadataArray) -> Result<()> {
+        self.write_u32(arr.typ().int_value())?;
+        self.write_u64(arr.len() as u64)?;
+        match arr {
+            GGUFMetadataArray::U8Array(arr) => self.write_u8_array(arr),
+            GGUFMetadataArray::I8Array(arr) => self.write_i8_array(arr),
+            GGUFMetadataArray::U16Array(arr) => self.write_u16_array(arr),
+            GGUFMetadataArray::I16Array(arr) => self.write_i16_array(arr),
+            GGUFMetadataArray::U32Array(arr) => self.write_u32_array(arr),
+            GGUFMetadataArray::I32Array(arr) => self.write_i32_array(arr),
+            GGUFMetadataArray::U64Array(arr) => self.write_u64_array(arr),
+            GGUFMetadataArray::I64Array(arr) => self.write_i64_array(arr),
+            GGUFMetadataArray::F32Array(arr) => self.write_f32_array(arr),
+            GGUFMetadataArray::F64Array(arr) => self.write_f64_array(arr),
+            GGUFMetadataArray::BoolArray(arr) => self.write_u8_array(arr),
+            GGUFMetadataArray::StringArray(arr) => {
+                for s in arr {
+                    self.write_string(s)?;
+                }
+                Ok(())
+            }
+            GGUFMetadataArray::NestedArray(arr) => {
+                for a in arr {
+                    self.write_array(a)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -988,4 +1388,81 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_write_roundtrip() -> Result<()> {
+        let loader = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = loader.open()?;
+
+        let path = std::env::temp_dir().join("crabml-test-write-roundtrip.gguf");
+        let path = path.to_str().unwrap();
+        GGUFFileWriter::from_gguf_file(&gf).write_to_file(path)?;
+
+        let loader2 = GGUFFileLoader::new(path, false)?;
+        let gf2 = loader2.open()?;
+        assert_eq!(gf2.version().int_value(), 3);
+        assert_eq!(gf2.metadata().as_hashmap(), gf.metadata().as_hashmap());
+        assert_eq!(gf2.tensor_infos().len(), gf.tensor_infos().len());
+        for (ti, ti2) in gf.tensor_infos().iter().zip(gf2.tensor_infos().iter()) {
+            assert_eq!(ti2.name(), ti.name());
+            assert_eq!(ti2.dimensions(), ti.dimensions());
+            assert_eq!(ti2.typ(), ti.typ());
+            let size = ti.typ().bytes_size(ti.dimensions().iter().product());
+            assert_eq!(ti2.data()[..size], ti.data()[..size]);
+        }
+
+        std::fs::remove_file(path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_metadata() -> Result<()> {
+        let data = (0..48u8).collect::<Vec<_>>();
+        let mut w = GGUFFileWriter::new();
+        w.set_metadata(KEY_GENERAL_ARCHITECTURE, GGUFMetadataValue::String("llama"));
+        w.set_metadata(KEY_GENERAL_ALIGNMENT, GGUFMetadataValue::U32(64));
+        w.set_metadata("test.u8", GGUFMetadataValue::U8(1));
+        w.set_metadata("test.i16", GGUFMetadataValue::I16(-2));
+        w.set_metadata("test.u64", GGUFMetadataValue::U64(3));
+        w.set_metadata("test.f64", GGUFMetadataValue::F64(4.5));
+        w.set_metadata("test.bool", GGUFMetadataValue::Bool(1));
+        w.set_metadata("test.removed", GGUFMetadataValue::Bool(1));
+        w.remove_metadata("test.removed");
+        w.set_metadata(
+            "test.nested",
+            GGUFMetadataValue::Array(GGUFMetadataArray::NestedArray(vec![
+                GGUFMetadataArray::StringArray(vec!["a", "bc"]),
+                GGUFMetadataArray::U32Array(&[1, 2, 3]),
+                GGUFMetadataArray::NestedArray(vec![]),
+            ])),
+        );
+        w.set_tensor_info(GGUFTensorInfo::new(
+            "a".to_string(),
+            vec![4, 2],
+            GGMLType::F32,
+            &data[0..32],
+        ));
+        w.set_tensor_info(GGUFTensorInfo::new(
+            "b".to_string(),
+            vec![8],
+            GGMLType::F16,
+            &data[32..48],
+        ));
+
+        let mut buf = vec![];
+        w.write(&mut buf)?;
+        let gf = GGUFFile::decode(&mut GGUFBufReader::new(&buf))?;
+
+        assert_eq!(gf.header.alignment(), 64);
+        assert_eq!(gf.metadata().as_hashmap().len(), 8);
+        for (k, v) in w.metadata() {
+            assert_eq!(gf.metadata().as_hashmap().get(k), Some(v));
+        }
+        assert_eq!(gf.tensor_infos()[0].dimensions(), &[4, 2]);
+        assert_eq!(gf.tensor_infos()[0].data(), &data[0..32]);
+        assert_eq!(gf.tensor_infos()[1].typ(), GGMLType::F16);
+        assert_eq!(gf.tensor_infos()[1].data(), &data[32..48]);
+        assert_eq!(buf.len() % 64, 0);
+        Ok(())
+    }
 }
@@ -1,3 +1,4 @@
+use super::rope::RopeParams;
 use super::strider::TensorStrider;
 use crate::error::Result;
 use crate::gguf::GGMLType;
@@ -19,7 +20,8 @@ pub trait Tensor: Sized + Clone {
     ) -> Result<Self>;
 
     /// alloc an owned tensor, only used on storing activations and kv caches.
-    /// only F32 and F16 are supported.
+    /// only F32 and F16 are supported, the cpu backend also supports Q8_0 and Q4_0 for
+    /// the kv caches.
     fn alloc(shape: &[usize], dtype: GGMLType, device: Self::DeviceRef) -> Result<Self>;
 
     /// resize the tensor to a smaller size, the underlying storage is not changed,
@@ -51,15 +53,27 @@ pub trait Tensor: Sized + Clone {
 
     fn export(&self, buf: &mut [f32]) -> Result<()>;
 
-    /// duplicate the tensor and the underlying storage
+    /// duplicate the tensor and the underlying storage. the whole storage is copied with the
+    /// strides, so the dup of a resized kv cache can still be concatenated to its capacity.
     fn dup(&self) -> Result<Self>;
 
-    fn rope_inplace(self, mode: RopeMode, pos: usize, rope_dims: usize) -> Result<Self>;
+    /// rotate the vectors in shape of (n_batch, n_heads, head_dim), the row i is at position
+    /// pos + i.
+    fn rope_inplace(self, mode: RopeMode, pos: usize, rope: &RopeParams) -> Result<Self>;
 
     fn rms_norm_inplace(self, eps: f32) -> Result<Self>;
 
+    /// normalize each row to the zero mean and the unit variance, then scale it by the weight
+    /// and shift it by the bias, both in the shape of (n_dims,).
+    fn layer_norm_inplace(self, weight: &Self, bias: &Self, eps: f32) -> Result<Self>;
+
     fn softmax_inplace(self, axis: usize) -> Result<Self>;
 
+    /// mask the attention scores in shape of (n_heads, n_batch, seq_len) before softmax,
+    /// the query at row i is at position pos + i, it only attends the keys up to that position.
+    /// with a sliding window of w, the keys before position pos + i + 1 - w are masked too.
+    fn causal_mask_inplace(self, pos: usize, window: Option<usize>) -> Result<Self>;
+
     fn silu_inplace(self) -> Result<Self>;
 
     fn gelu_inplace(self) -> Result<Self>;
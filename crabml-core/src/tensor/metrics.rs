@@ -10,6 +10,7 @@ pub struct TensorMetrics {
     pub mul_walltime: TimeMetric,
     pub rope_walltime: TimeMetric,
     pub softmax_walltime: TimeMetric,
+    pub causal_mask_walltime: TimeMetric,
     pub activate_walltime: TimeMetric,
     pub matmul_walltime: TimeMetric,
     pub matmul_non_compute_walltime: TimeMetric,
@@ -36,6 +37,7 @@ impl TensorMetrics {
         self.mul_walltime.reset();
         self.rope_walltime.reset();
         self.softmax_walltime.reset();
+        self.causal_mask_walltime.reset();
         self.matmul_walltime.reset();
         self.dequantize_walltime.reset();
         self.activate_walltime.reset();
@@ -88,6 +90,10 @@ impl TensorMetrics {
                 "softmax_walltime".to_string(),
                 self.softmax_walltime.as_millis(),
             ),
+            (
+                "causal_mask_walltime".to_string(),
+                self.causal_mask_walltime.as_millis(),
+            ),
             ("mul_walltime".to_string(), self.mul_walltime.as_millis()),
             (
                 "matmul_walltime".to_string(),
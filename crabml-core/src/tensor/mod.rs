@@ -1,8 +1,10 @@
 mod api;
 pub mod metrics;
+mod rope;
 mod strider;
 
 pub use api::RopeMode;
 pub use api::Tensor;
 pub use metrics::TensorMetrics;
+pub use rope::RopeParams;
 pub use strider::TensorStrider;
@@ -0,0 +1,151 @@
+use std::sync::Arc;
+
+/// the parameters of the rotary position embedding, like the rope_ext of ggml. the angle of
+/// the pair i of a head at the position p is p * freq_base ^ (-2i / head_dim), which is
+/// interpolated by the linear scaling, or mixed with the extrapolated one by yarn on the models
+/// finetuned to the longer contexts. the ntk-aware scaling only enlarges the base, and the
+/// longrope of phi3 divides the angle of each pair by its own factor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RopeParams {
+    pub n_dims: usize, // the number of the rotated dims of each head
+    pub freq_base: f32,
+    pub freq_scale: f32,  // 1 / the scaling factor of the linear and yarn scaling
+    pub ext_factor: f32,  // the mix of the extrapolation on yarn, 0 on the other scalings
+    pub attn_factor: f32, // scales the magnitude of the rotated values
+    // the pairs between them are ramped from the extrapolation to the interpolation on yarn
+    pub corr_dims: [f32; 2],
+    pub freq_factors: Option<Arc<[f32]>>, // the factor of each pair on longrope
+}
+
+impl RopeParams {
+    pub fn new(n_dims: usize) -> Self {
+        Self {
+            n_dims,
+            freq_base: 10000.0,
+            freq_scale: 1.0,
+            ext_factor: 0.0,
+            attn_factor: 1.0,
+            corr_dims: [0.0, 0.0],
+            freq_factors: None,
+        }
+    }
+
+    pub fn with_freq_base(mut self, freq_base: f32) -> Self {
+        self.freq_base = freq_base;
+        self
+    }
+
+    /// interpolate the positions by the factor, like the position interpolation of meta.
+    pub fn with_linear_scaling(mut self, factor: f32) -> Self {
+        self.freq_scale = 1.0 / factor;
+        self
+    }
+
+    /// enlarge the base by factor ^ (n_dims / (n_dims - 2)), so the low frequencies are
+    /// interpolated while the high frequencies are almost kept.
+    pub fn with_ntk_scaling(mut self, factor: f32) -> Self {
+        let n_dims = self.n_dims as f32;
+        self.freq_base *= factor.powf(n_dims / (n_dims - 2.0));
+        self
+    }
+
+    /// the yarn scaling from the original context length: the pairs rotating more than
+    /// beta_fast times in the original context are extrapolated, the ones rotating less than
+    /// beta_slow times are interpolated, and the ones between are ramped. it should be called
+    /// after the base is set.
+    pub fn with_yarn_scaling(
+        mut self,
+        factor: f32,
+        n_ctx_orig: usize,
+        attn_factor: f32,
+        beta_fast: f32,
+        beta_slow: f32,
+    ) -> Self {
+        let corr_dim = |n_rot: f32| {
+            self.n_dims as f32 * (n_ctx_orig as f32 / (n_rot * 2.0 * std::f32::consts::PI)).ln()
+                / (2.0 * self.freq_base.ln())
+        };
+        let start = corr_dim(beta_fast).floor().max(0.0);
+        let end = corr_dim(beta_slow).ceil().min(self.n_dims as f32 - 1.0);
+        self.freq_scale = 1.0 / factor;
+        self.ext_factor = 1.0;
+        self.attn_factor = attn_factor;
+        self.corr_dims = [start, end];
+        self
+    }
+
+    /// the longrope scaling of phi3, the factors are n_dims / 2 long. the model has a table
+    /// of the short factors and a table of the long factors, the caller picks one of them
+    /// by the context length. the length of the factors is checked on loading the model.
+    pub fn with_long_rope_scaling(mut self, freq_factors: Arc<[f32]>, attn_factor: f32) -> Self {
+        debug_assert_eq!(freq_factors.len(), self.n_dims / 2);
+        self.freq_factors = Some(freq_factors);
+        self.attn_factor = attn_factor;
+        self
+    }
+
+    /// the angle of the pair i of a head at the position. the position can be a delta, as the
+    /// angle is linear to it.
+    pub fn theta(&self, pos: f32, i: usize, head_dim: usize) -> f32 {
+        let freq_factor = self.freq_factors.as_ref().map_or(1.0, |f| f[i]);
+        let theta_extrap =
+            pos * self.freq_base.powf(-2.0 * i as f32 / head_dim as f32) / freq_factor;
+        let theta_interp = self.freq_scale * theta_extrap;
+        if self.ext_factor == 0.0 {
+            return theta_interp;
+        }
+        let [low, high] = self.corr_dims;
+        let y = (i as f32 - low) / (high - low).max(0.001);
+        let ramp_mix = (1.0 - y.clamp(0.0, 1.0)) * self.ext_factor;
+        theta_interp * (1.0 - ramp_mix) + theta_extrap * ramp_mix
+    }
+
+    /// the scale applied on the cos and sin of the angles.
+    pub fn mscale(&self) -> f32 {
+        if self.ext_factor == 0.0 {
+            return self.attn_factor;
+        }
+        self.attn_factor * (1.0 + 0.1 * (1.0 / self.freq_scale).ln())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_rope_params() {
+        let rope = RopeParams::new(128);
+        assert_relative_eq!(rope.theta(3.0, 0, 128), 3.0);
+        assert_relative_eq!(rope.theta(3.0, 32, 128), 0.03);
+        assert_relative_eq!(rope.mscale(), 1.0);
+
+        let linear = rope.clone().with_linear_scaling(4.0);
+        assert_relative_eq!(linear.theta(3.0, 32, 128), 0.0075);
+
+        // the highest frequency is kept, the lowest one is interpolated by the factor
+        let ntk = rope.clone().with_ntk_scaling(4.0);
+        assert_relative_eq!(ntk.theta(3.0, 0, 128), 3.0);
+        assert_relative_eq!(
+            ntk.theta(3.0, 63, 128),
+            rope.theta(3.0, 63, 128) / 4.0,
+            epsilon = 1e-3
+        );
+
+        // the high frequencies are extrapolated, the low ones are interpolated
+        let yarn = rope.clone().with_yarn_scaling(4.0, 4096, 1.0, 32.0, 1.0);
+        assert_eq!(yarn.corr_dims, [20.0, 46.0]);
+        assert_relative_eq!(yarn.theta(3.0, 10, 128), rope.theta(3.0, 10, 128));
+        assert_relative_eq!(yarn.theta(3.0, 50, 128), rope.theta(3.0, 50, 128) / 4.0);
+        assert_relative_eq!(yarn.mscale(), 1.0 + 0.1 * 4.0_f32.ln());
+
+        // each pair is slowed down by its own factor
+        let factors = (0..64).map(|i| 1.0 + i as f32).collect::<Vec<_>>();
+        let long_rope = rope.clone().with_long_rope_scaling(factors.into(), 1.2);
+        assert_relative_eq!(long_rope.theta(3.0, 0, 128), 3.0);
+        assert_relative_eq!(long_rope.theta(3.0, 32, 128), 0.03 / 33.0);
+        assert_relative_eq!(long_rope.mscale(), 1.2);
+    }
+}
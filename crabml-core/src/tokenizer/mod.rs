@@ -1,6 +1,7 @@
 mod tokenizer_gpt2;
 mod tokenizer_llama;
 
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use tokenizer_gpt2::Gpt2Tokenizer;
@@ -12,7 +13,10 @@ pub type TokenID = usize;
 
 pub struct Tokenizer {
     tokens: Arc<Vec<String>>,
+    bos_token: TokenID,
     eos_token: TokenID,
+    newline_token: Option<TokenID>,
+    special_tokens: HashSet<TokenID>,
     inner: TokenizerInner,
 }
 
@@ -43,11 +47,7 @@ impl Tokenizer {
             eos_token,
         ));
 
-        Self {
-            tokens,
-            eos_token,
-            inner,
-        }
+        Self::new_inner(tokens, bos_token, eos_token, inner)
     }
 
     pub fn new_gpt2(
@@ -63,13 +63,35 @@ impl Tokenizer {
             bos_token,
             eos_token,
         ));
+        Self::new_inner(tokens, bos_token, eos_token, inner)
+    }
+
+    fn new_inner(
+        tokens: Arc<Vec<String>>,
+        bos_token: TokenID,
+        eos_token: TokenID,
+        inner: TokenizerInner,
+    ) -> Self {
+        // llama takes the byte token <0x0A> as the newline, while gpt2 maps "\n" to "Ċ"
+        let newline_token = tokens
+            .iter()
+            .position(|t| t == "<0x0A>" || t == "Ċ" || t == "\n");
         Self {
             tokens,
+            bos_token,
             eos_token,
+            newline_token,
+            special_tokens: HashSet::from([bos_token, eos_token]),
             inner,
         }
     }
 
+    /// mark the control tokens like <|im_end|> as special, bos and eos are always special.
+    pub fn with_special_tokens(mut self, tokens: impl IntoIterator<Item = TokenID>) -> Self {
+        self.special_tokens.extend(tokens);
+        self
+    }
+
     pub fn kind(&self) -> TokenizerKind {
         match &self.inner {
             TokenizerInner::Llama(_) => TokenizerKind::Llama,
@@ -81,20 +103,37 @@ impl Tokenizer {
         &self.tokens
     }
 
+    pub fn bos_token(&self) -> TokenID {
+        self.bos_token
+    }
+
     pub fn eos_token(&self) -> TokenID {
         self.eos_token
     }
 
+    pub fn newline_token(&self) -> Option<TokenID> {
+        self.newline_token
+    }
+
+    pub fn is_special_token(&self, token: TokenID) -> bool {
+        self.special_tokens.contains(&token)
+    }
+
     pub fn token(&self, token_id: TokenID) -> String {
         self.tokens[token_id].clone()
     }
 
-    /// TODO: make it consume an Iterator<Item=Result<TokenID>>
-    pub fn decode(&self, token: TokenID, decode_buf: &mut Utf8Buf) -> Result<String> {
-        let bytes = match &self.inner {
+    /// the raw bytes of a token, which might be a partial utf-8 character.
+    pub fn token_bytes(&self, token: TokenID) -> Vec<u8> {
+        match &self.inner {
             TokenizerInner::Llama(inner) => inner.decode(token),
             TokenizerInner::GPT2(inner) => inner.decode(token),
-        };
+        }
+    }
+
+    /// TODO: make it consume an Iterator<Item=Result<TokenID>>
+    pub fn decode(&self, token: TokenID, decode_buf: &mut Utf8Buf) -> Result<String> {
+        let bytes = self.token_bytes(token);
         Ok(decode_buf.step(&bytes))
     }
 
@@ -0,0 +1,244 @@
+use crabml::bail;
+use crabml::error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tensor::Tensor;
+use crabml::tokenizer::TokenID;
+
+use crate::llama2::Llama2Runner;
+
+/// the index of the slot a sequence takes in the batch.
+pub type SeqId = usize;
+
+/// a token generated for a sequence on a step of the batch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchToken {
+    pub seq_id: SeqId,
+
+    pub token: TokenID,
+
+    pub piece: String,
+
+    /// no more tokens will be generated for the sequence, it stops on the eos token, the max
+    /// number of tokens or the end of the context
+    pub finished: bool,
+}
+
+struct BatchSeq<T: Tensor> {
+    runner: Llama2Runner<T>,
+    next_token: TokenID, // sampled, but not passed through the model yet
+    remaining: usize,
+    finished: bool,
+}
+
+/// serves several independent sequences with one model, which is the continuous batching of
+/// vllm or the slots of llama.cpp's server. each sequence is a runner with its own kv cache,
+/// position, sampler state and grammar. the prompt is prefilled when a sequence is added, then
+/// on each step the next tokens of all the unfinished sequences are passed through the model
+/// in one forward, while the attention of each token only reads the kv cache of its own
+/// sequence. the sequences can be added and removed between the steps.
+pub struct Llama2BatchRunner<T: Tensor> {
+    seqs: Vec<Option<BatchSeq<T>>>,
+}
+
+impl<T: Tensor> Llama2BatchRunner<T> {
+    pub fn new(max_seqs: usize) -> Self {
+        Self {
+            seqs: (0..max_seqs).map(|_| None).collect(),
+        }
+    }
+
+    /// prefill the prompt on the runner and put it into a free slot, the first token is
+    /// yielded on the next step. the runner should be created from the same model as the other
+    /// sequences. the kv cache of the common prefix is reused if the runner has been taken
+    /// from a removed sequence.
+    pub fn add_sequence(
+        &mut self,
+        mut runner: Llama2Runner<T>,
+        prompt_tokens: &[TokenID],
+        max_tokens: usize,
+    ) -> Result<SeqId> {
+        if max_tokens == 0 {
+            bail!(ErrorKind::BadInput, "expected at least 1 token to generate");
+        }
+        if runner.has_context_shift() {
+            bail!(
+                ErrorKind::BadInput,
+                "the context shift is not supported on the batched runner"
+            );
+        }
+        if let Some(seq) = self.seqs.iter().flatten().next() {
+            if !runner.shares_model_with(&seq.runner) {
+                bail!(
+                    ErrorKind::BadInput,
+                    "the sequences should be created from the same model"
+                );
+            }
+        }
+        let seq_id = self
+            .seqs
+            .iter()
+            .position(|seq| seq.is_none())
+            .ok_or_else(|| {
+                error!(
+                    ErrorKind::BadInput,
+                    "all the {} slots are taken",
+                    self.seqs.len()
+                )
+            })?;
+
+        let (_, _, token) = runner.prefill_with_prefix_cache(prompt_tokens, true)?;
+        self.seqs[seq_id] = Some(BatchSeq {
+            runner,
+            next_token: token,
+            remaining: max_tokens,
+            finished: false,
+        });
+        Ok(seq_id)
+    }
+
+    /// free the slot of the sequence, and return its runner to be reused.
+    pub fn remove_sequence(&mut self, seq_id: SeqId) -> Option<Llama2Runner<T>> {
+        self.seqs.get_mut(seq_id)?.take().map(|seq| seq.runner)
+    }
+
+    pub fn runner(&self, seq_id: SeqId) -> Option<&Llama2Runner<T>> {
+        self.seqs.get(seq_id)?.as_ref().map(|seq| &seq.runner)
+    }
+
+    /// the number of the sequences which are still generating.
+    pub fn n_active(&self) -> usize {
+        self.seqs
+            .iter()
+            .flatten()
+            .filter(|seq| !seq.finished)
+            .count()
+    }
+
+    /// yield the next token of each unfinished sequence, and pass these tokens through the
+    /// model in one batched forward to sample the tokens of the next step.
+    pub fn step(&mut self) -> Result<Vec<BatchToken>> {
+        let mut output = vec![];
+        let mut batch = vec![];
+        for (seq_id, seq) in self.seqs.iter_mut().enumerate() {
+            let seq = match seq {
+                Some(seq) if !seq.finished => seq,
+                _ => continue,
+            };
+            let piece = seq.runner.decode(seq.next_token)?;
+            seq.remaining -= 1;
+            seq.finished =
+                seq.remaining == 0 || seq.runner.kv_cache_len() + 1 >= seq.runner.conf().seq_len;
+            output.push(BatchToken {
+                seq_id,
+                token: seq.next_token,
+                piece,
+                finished: seq.finished,
+            });
+            if !seq.finished {
+                batch.push((seq, output.len() - 1));
+            }
+        }
+        if batch.is_empty() {
+            return Ok(output);
+        }
+
+        let tokens = batch
+            .iter()
+            .map(|(seq, _)| seq.next_token)
+            .collect::<Vec<_>>();
+        let mut runners = batch
+            .iter_mut()
+            .map(|(seq, _)| &mut seq.runner)
+            .collect::<Vec<_>>();
+        Llama2Runner::forward_batch(&mut runners, &tokens)?;
+
+        for (seq, i) in batch {
+            let token = seq.runner.sample()?;
+            if token == seq.runner.tokenizer().eos_token() {
+                seq.finished = true;
+                output[i].finished = true;
+            } else {
+                seq.next_token = token;
+            }
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::gguf::GGUFFileLoader;
+
+    use super::*;
+    use crate::model::CpuLlamaModelLoader;
+
+    #[test]
+    fn test_batch_runner() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+
+        let prompts = [
+            ("Lily is a cute cat", 30),
+            ("Once upon a time", 20),
+            ("Tom and his dog", 25),
+            ("The sun is", 15),
+        ];
+        let mut expected = vec![];
+        for (prompt, steps) in prompts {
+            let mut runner = Llama2Runner::new(&lm, 200, false)?;
+            let output = runner
+                .prefill_and_generate(prompt, steps)?
+                .collect::<Result<Vec<String>>>()?
+                .join("");
+            expected.push(output);
+        }
+
+        // the last prompt takes the slot of the second sequence after it is finished
+        let mut batch = Llama2BatchRunner::new(3);
+        let mut outputs = vec![String::new(); prompts.len()];
+        let mut prompt_of = vec![];
+        for (i, (prompt, steps)) in prompts[..3].iter().enumerate() {
+            let runner = Llama2Runner::new(&lm, 200, false)?;
+            let tokens = runner.tokenizer().encode(prompt, true, false)?;
+            prompt_of.push(batch.add_sequence(runner, &tokens, *steps)?);
+            assert_eq!(prompt_of[i], i);
+        }
+        let runner = Llama2Runner::new(&lm, 200, false)?;
+        let err = batch.add_sequence(runner, &[1], 10).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+
+        while batch.n_active() > 0 {
+            for token in batch.step()? {
+                outputs[prompt_of[token.seq_id]].push_str(&token.piece);
+                if token.finished && prompt_of[token.seq_id] == 1 {
+                    let runner = batch.remove_sequence(token.seq_id).unwrap();
+                    let (prompt, steps) = prompts[3];
+                    let tokens = runner.tokenizer().encode(prompt, true, false)?;
+                    let seq_id = batch.add_sequence(runner, &tokens, steps)?;
+                    assert_eq!(seq_id, token.seq_id);
+                    prompt_of[seq_id] = 3;
+                }
+            }
+        }
+        assert_eq!(outputs, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_batch_runner_with_other_model() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm1 = CpuLlamaModelLoader::new().load(&gf)?;
+        let lm2 = CpuLlamaModelLoader::new().load(&gf)?;
+
+        let mut batch = Llama2BatchRunner::new(2);
+        batch.add_sequence(Llama2Runner::new(&lm1, 200, false)?, &[1], 10)?;
+        let err = batch
+            .add_sequence(Llama2Runner::new(&lm2, 200, false)?, &[1], 10)
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        Ok(())
+    }
+}
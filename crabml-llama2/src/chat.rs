@@ -1,15 +1,26 @@
 use crabml::error::Result;
 use crabml::tensor::Tensor;
+use crabml::tokenizer::TokenID;
 
 use crate::llama2::Llama2Runner;
 use crate::model::ModelArchitecture;
 
+/// a finished round of the dialog with its reply.
+#[derive(Debug, Clone)]
+pub struct ChatRound {
+    pub system_prompt: Option<String>,
+    pub prompt: String,
+    pub reply: String,
+}
+
 pub struct Llama2Chat<'a, T: Tensor> {
     inner: &'a mut Llama2Runner<T>,
     prompt: String,
     system_prompt: Option<String>,
+    history: Option<Vec<ChatRound>>,
     stats: Llama2ChatReplyIteratorStats,
     chat_template: ChatTemplate,
+    prompt_len: usize,
 }
 
 impl<'a, T: Tensor> Llama2Chat<'a, T> {
@@ -26,18 +37,37 @@ impl<'a, T: Tensor> Llama2Chat<'a, T> {
             inner: runner,
             prompt: prompt.into(),
             system_prompt,
+            history: None,
             stats: Default::default(),
             chat_template,
+            prompt_len: 0,
         })
     }
 
+    /// take the rounds before the prompt as the whole dialog, instead of continuing from the
+    /// context in the runner. the kv cache of the common prefix with the previous dialog is
+    /// reused, so regenerating the last reply or editing the last message is nearly free.
+    pub fn with_history(mut self, history: Vec<ChatRound>) -> Self {
+        self.history = Some(history);
+        self
+    }
+
     pub fn reply(&mut self) -> Result<Llama2ChatReplyIterator> {
         let templated_prompt =
             self.chat_template
                 .apply(&self.prompt, self.system_prompt.as_deref(), true);
 
-        let bos = self.inner.kv_cache_len() == 0;
-        let (pos, _prev_token, token) = self.inner.prefill(&templated_prompt, bos, false)?;
+        let (pos, _prev_token, token) = match &self.history {
+            Some(history) => {
+                let tokens = self.dialog_tokens(history, &templated_prompt)?;
+                self.inner.prefill_with_prefix_cache(&tokens, true)?
+            }
+            None => {
+                let bos = self.inner.kv_cache_len() == 0;
+                self.inner.prefill(&templated_prompt, bos, true)?
+            }
+        };
+        self.prompt_len = pos;
         let iter = self.inner.generate(pos, token, None);
         let chat_iter = Llama2ChatReplyIterator::new(
             Box::new(iter),
@@ -47,6 +77,49 @@ impl<'a, T: Tensor> Llama2Chat<'a, T> {
         Ok(chat_iter)
     }
 
+    // encode the rounds one by one like replaying them, so the tokens are the same as the
+    // dialog fed round by round
+    fn dialog_tokens(&self, history: &[ChatRound], templated_prompt: &str) -> Result<Vec<TokenID>> {
+        let tokenizer = self.inner.tokenizer();
+        let mut tokens = vec![];
+        for round in history {
+            let text = format!(
+                "{}{}{}",
+                self.chat_template
+                    .apply(&round.prompt, round.system_prompt.as_deref(), true),
+                round.reply,
+                self.chat_template.stop_mark()
+            );
+            tokens.extend(tokenizer.encode(&text, tokens.is_empty(), false)?);
+        }
+        tokens.extend(tokenizer.encode(templated_prompt, tokens.is_empty(), false)?);
+        Ok(tokens)
+    }
+
+    /// the number of tokens in the context before the reply, including the history.
+    pub fn prompt_len(&self) -> usize {
+        self.prompt_len
+    }
+
+    /// feed a previous round of the dialog with its known reply instead of generating it,
+    /// it's useful to restore a conversation from its history.
+    pub fn replay(&mut self, reply: &str) -> Result<()> {
+        let templated_prompt =
+            self.chat_template
+                .apply(&self.prompt, self.system_prompt.as_deref(), true);
+        let templated_prompt = format!(
+            "{}{}{}",
+            templated_prompt,
+            reply,
+            self.chat_template.stop_mark()
+        );
+
+        let bos = self.inner.kv_cache_len() == 0;
+        self.inner.prefill(&templated_prompt, bos, true)?;
+        self.stats.has_stop_mark = true;
+        Ok(())
+    }
+
     /// the reply might ended with <eos>, but not <end_of_turn>, so we need to append the <end_of_turn>
     pub fn finish(&mut self) -> Result<()> {
         if !self.stats.has_stop_mark {
@@ -193,6 +266,7 @@ pub enum ChatTemplate {
     Llama3,
     ChatML,
     Gemma,
+    Phi3,
 }
 
 impl ChatTemplate {
@@ -211,6 +285,8 @@ impl ChatTemplate {
             Ok(ChatTemplate::ChatML)
         } else if model_name.contains("llama3") || chat_tmpl.contains("<|start_header_id|>") {
             Ok(ChatTemplate::Llama3)
+        } else if model_arch == ModelArchitecture::Phi3 || chat_tmpl.contains("<|assistant|>") {
+            Ok(ChatTemplate::Phi3)
         } else {
             // take llama2 as fallback.
             Ok(ChatTemplate::Llama2)
@@ -223,6 +299,7 @@ impl ChatTemplate {
             ChatTemplate::Gemma => "<end_of_turn>",
             ChatTemplate::Llama3 => "<|eot_id|>",
             ChatTemplate::ChatML => "<|im_end|>",
+            ChatTemplate::Phi3 => "<|end|>",
         }
     }
 
@@ -290,6 +367,19 @@ impl ChatTemplate {
                     system_prompt, prompt, assistant_prefix
                 )
             }
+            ChatTemplate::Phi3 => {
+                let system_prompt = system_prompt
+                    .map(|s| format!("<|system|>\n{}<|end|>\n", s))
+                    .unwrap_or("".to_string());
+                let assistant_prefix = match append_assistant_prefix {
+                    true => "<|assistant|>\n",
+                    false => "",
+                };
+                format!(
+                    "{}<|user|>\n{}<|end|>\n{}",
+                    system_prompt, prompt, assistant_prefix
+                )
+            }
         }
     }
 }
@@ -299,9 +389,11 @@ mod tests {
     use crabml::error::Result;
     use crabml::gguf::GGUFFileLoader;
 
+    use crate::chat::ChatTemplate;
     use crate::chat::Llama2Chat;
     use crate::llama2::Llama2Runner;
     use crate::model::CpuLlamaModelLoader;
+    use crate::model::ModelArchitecture;
 
     #[test]
     #[ignore]
@@ -319,4 +411,24 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_phi3_chat_template() -> Result<()> {
+        let tmpl = ChatTemplate::heuristic_guess("Phi3", ModelArchitecture::Phi3, "")?;
+        assert_eq!(tmpl, ChatTemplate::Phi3);
+        let tmpl = ChatTemplate::heuristic_guess(
+            "Phi-3-mini",
+            ModelArchitecture::Llama,
+            "{{ '<|user|>\n' + message['content'] + '<|end|>\n<|assistant|>\n' }}",
+        )?;
+        assert_eq!(tmpl, ChatTemplate::Phi3);
+
+        assert_eq!(
+            tmpl.apply("hi", Some("be brief"), true),
+            "<|system|>\nbe brief<|end|>\n<|user|>\nhi<|end|>\n<|assistant|>\n"
+        );
+        assert_eq!(tmpl.apply("hi", None, false), "<|user|>\nhi<|end|>\n");
+        assert_eq!(tmpl.stop_mark(), "<|end|>");
+        Ok(())
+    }
 }
@@ -0,0 +1,921 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crabml::bail;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tokenizer::TokenID;
+use crabml::tokenizer::Tokenizer;
+
+/// an element in a sequence of a rule, which is either a char class or a reference to a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum GrammarElement {
+    /// a literal char is a class with a single range, and `.` is a negated class without ranges
+    Char {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    RuleRef(usize),
+}
+
+impl GrammarElement {
+    fn char(c: char) -> Self {
+        Self::Char {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Char { ranges, negated } => {
+                ranges.iter().any(|(start, end)| *start <= c && c <= *end) != *negated
+            }
+            Self::RuleRef(_) => false,
+        }
+    }
+
+    // whether any char in the range of code points might match, a negated class is only
+    // rejected when one of its ranges covers the whole range
+    fn matches_range(&self, lo: u32, hi: u32) -> bool {
+        match self {
+            Self::Char { ranges, negated } => match negated {
+                false => ranges
+                    .iter()
+                    .any(|(start, end)| *start as u32 <= hi && lo <= *end as u32),
+                true => !ranges
+                    .iter()
+                    .any(|(start, end)| *start as u32 <= lo && hi <= *end as u32),
+            },
+            Self::RuleRef(_) => false,
+        }
+    }
+}
+
+type GrammarSequence = Vec<GrammarElement>;
+
+/// points to an element in a sequence of a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct GrammarPos {
+    rule: usize,
+    alt: usize,
+    elem: usize,
+}
+
+/// a stack of the positions to match, the top is always a char element after being advanced,
+/// and an empty stack means the grammar has been completed.
+type GrammarStack = Vec<GrammarPos>;
+
+/// the grammar in llama.cpp's GBNF format, like:
+///
+/// ```text
+/// root   ::= answer "."
+/// answer ::= "yes" | "no" | [0-9]+
+/// ```
+///
+/// the repetitions and groups are rewritten into the generated rules, so every rule is a list of
+/// alternative sequences of the char classes and the rule references.
+#[derive(Debug)]
+pub struct Grammar {
+    rule_names: Vec<String>,
+    rules: Vec<Vec<GrammarSequence>>,
+    root: usize,
+}
+
+impl Grammar {
+    pub fn parse(src: &str) -> Result<Self> {
+        GrammarParser::new(src).parse()
+    }
+
+    pub fn rule_names(&self) -> &[String] {
+        &self.rule_names
+    }
+
+    /// whether the whole text is a complete parse of the grammar.
+    pub fn matches(&self, text: &str) -> bool {
+        self.accept_str(text).iter().any(|s| s.is_empty())
+    }
+
+    fn accept_str(&self, text: &str) -> Vec<GrammarStack> {
+        let mut stacks = self.initial_stacks();
+        for c in text.chars() {
+            stacks = self.accept_char(&stacks, c);
+        }
+        stacks
+    }
+
+    fn initial_stacks(&self) -> Vec<GrammarStack> {
+        let mut stacks = vec![];
+        for alt in 0..self.rules[self.root].len() {
+            let pos = GrammarPos {
+                rule: self.root,
+                alt,
+                elem: 0,
+            };
+            self.advance_stack(vec![pos], &mut stacks);
+        }
+        stacks
+    }
+
+    /// expand the rule references on the top of the stack until a char element is on the top,
+    /// every alternative of the referenced rule makes a new stack.
+    fn advance_stack(&self, mut stack: GrammarStack, out: &mut Vec<GrammarStack>) {
+        let top = match stack.last() {
+            Some(top) => *top,
+            None => {
+                if !out.contains(&stack) {
+                    out.push(stack);
+                }
+                return;
+            }
+        };
+
+        let seq = &self.rules[top.rule][top.alt];
+        match seq.get(top.elem) {
+            None => {
+                stack.pop();
+                self.advance_stack(stack, out);
+            }
+            Some(GrammarElement::Char { .. }) => {
+                if !out.contains(&stack) {
+                    out.push(stack);
+                }
+            }
+            Some(GrammarElement::RuleRef(rule)) => {
+                // the reference on the tail of a sequence is popped directly, so a right
+                // recursive rule like `item*` does not grow the stack
+                stack.pop();
+                if top.elem + 1 < seq.len() {
+                    stack.push(GrammarPos {
+                        elem: top.elem + 1,
+                        ..top
+                    });
+                }
+                for alt in 0..self.rules[*rule].len() {
+                    let mut stack = stack.clone();
+                    stack.push(GrammarPos {
+                        rule: *rule,
+                        alt,
+                        elem: 0,
+                    });
+                    self.advance_stack(stack, out);
+                }
+            }
+        }
+    }
+
+    fn accept_char(&self, stacks: &[GrammarStack], c: char) -> Vec<GrammarStack> {
+        let mut out = vec![];
+        for stack in stacks {
+            let top = match stack.last() {
+                Some(top) => *top,
+                None => continue,
+            };
+            if self.rules[top.rule][top.alt][top.elem].matches(c) {
+                let mut stack = stack.clone();
+                stack.pop();
+                stack.push(GrammarPos {
+                    elem: top.elem + 1,
+                    ..top
+                });
+                self.advance_stack(stack, &mut out);
+            }
+        }
+        out
+    }
+
+    fn accepts_range(&self, stacks: &[GrammarStack], lo: u32, hi: u32) -> bool {
+        stacks.iter().any(|stack| match stack.last() {
+            Some(top) => self.rules[top.rule][top.alt][top.elem].matches_range(lo, hi),
+            None => false,
+        })
+    }
+
+    fn nullable_rules(&self) -> Vec<bool> {
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (rule, alts) in self.rules.iter().enumerate() {
+                if nullable[rule] {
+                    continue;
+                }
+                let is_nullable = alts.iter().any(|seq| {
+                    seq.iter().all(|elem| match elem {
+                        GrammarElement::RuleRef(r) => nullable[*r],
+                        GrammarElement::Char { .. } => false,
+                    })
+                });
+                if is_nullable {
+                    nullable[rule] = true;
+                    changed = true;
+                }
+            }
+        }
+        nullable
+    }
+
+    // a left recursive rule would expand forever on advancing the stacks
+    fn check_left_recursion(&self) -> Result<()> {
+        let nullable = self.nullable_rules();
+        let mut states = vec![VisitState::Unvisited; self.rules.len()];
+        for rule in 0..self.rules.len() {
+            self.visit_leftmost(rule, &nullable, &mut states)?;
+        }
+        Ok(())
+    }
+
+    fn visit_leftmost(
+        &self,
+        rule: usize,
+        nullable: &[bool],
+        states: &mut [VisitState],
+    ) -> Result<()> {
+        match states[rule] {
+            VisitState::Visiting => bail!(
+                ErrorKind::BadInput,
+                "rule {} is left recursive",
+                self.rule_names[rule]
+            ),
+            VisitState::Visited => return Ok(()),
+            VisitState::Unvisited => {}
+        }
+
+        states[rule] = VisitState::Visiting;
+        for seq in self.rules[rule].iter() {
+            for elem in seq.iter() {
+                match elem {
+                    GrammarElement::RuleRef(r) => {
+                        self.visit_leftmost(*r, nullable, states)?;
+                        if !nullable[*r] {
+                            break;
+                        }
+                    }
+                    GrammarElement::Char { .. } => break,
+                }
+            }
+        }
+        states[rule] = VisitState::Visited;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VisitState {
+    Unvisited,
+    Visiting,
+    Visited,
+}
+
+struct GrammarParser {
+    src: Vec<char>,
+    pos: usize,
+    symbol_ids: HashMap<String, usize>,
+    rule_names: Vec<String>,
+    rules: Vec<Option<Vec<GrammarSequence>>>,
+}
+
+impl GrammarParser {
+    fn new(src: &str) -> Self {
+        Self {
+            src: src.chars().collect(),
+            pos: 0,
+            symbol_ids: HashMap::new(),
+            rule_names: vec![],
+            rules: vec![],
+        }
+    }
+
+    fn parse(mut self) -> Result<Grammar> {
+        self.skip_space(true);
+        while self.peek().is_some() {
+            self.parse_rule()?;
+        }
+
+        for (rule, alts) in self.rules.iter().enumerate() {
+            if alts.is_none() {
+                bail!(
+                    ErrorKind::BadInput,
+                    "undefined rule: {}",
+                    self.rule_names[rule]
+                );
+            }
+        }
+        let root = match self.symbol_ids.get("root") {
+            Some(root) => *root,
+            None => bail!(ErrorKind::BadInput, "the grammar has no root rule"),
+        };
+
+        let grammar = Grammar {
+            rule_names: self.rule_names,
+            rules: self.rules.into_iter().map(|r| r.unwrap()).collect(),
+            root,
+        };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+
+    fn parse_rule(&mut self) -> Result<()> {
+        let name = self.parse_name()?;
+        self.skip_space(false);
+        for expected in "::=".chars() {
+            if self.next() != Some(expected) {
+                bail!(
+                    ErrorKind::BadInput,
+                    "expecting ::= after the rule name {} at {}",
+                    name,
+                    self.pos
+                );
+            }
+        }
+        self.skip_space(true);
+
+        let rule = self.symbol_id(&name);
+        let alts = self.parse_alternates(&name, false)?;
+        if self.rules[rule].is_some() {
+            bail!(ErrorKind::BadInput, "rule {} is defined twice", name);
+        }
+        self.rules[rule] = Some(alts);
+
+        // a rule ends with a newline or the end of the grammar
+        match self.peek() {
+            None | Some('\r') | Some('\n') => {}
+            Some(c) => bail!(
+                ErrorKind::BadInput,
+                "expecting newline or end at {}, got '{}'",
+                self.pos,
+                c
+            ),
+        }
+        self.skip_space(true);
+        Ok(())
+    }
+
+    fn parse_alternates(&mut self, rule_name: &str, nested: bool) -> Result<Vec<GrammarSequence>> {
+        let mut alts = vec![self.parse_sequence(rule_name, nested)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alts.push(self.parse_sequence(rule_name, nested)?);
+        }
+        Ok(alts)
+    }
+
+    fn parse_sequence(&mut self, rule_name: &str, nested: bool) -> Result<GrammarSequence> {
+        let mut seq = vec![];
+        // the start of the last symbol in the sequence, the repetitions apply to it
+        let mut last_sym_start = 0;
+        while let Some(c) = self.peek() {
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    last_sym_start = seq.len();
+                    while self.peek() != Some('"') {
+                        let c = self.parse_char("string")?;
+                        seq.push(GrammarElement::char(c));
+                    }
+                    self.pos += 1;
+                }
+                '[' => {
+                    self.pos += 1;
+                    last_sym_start = seq.len();
+                    let negated = self.peek() == Some('^');
+                    if negated {
+                        self.pos += 1;
+                    }
+                    let mut ranges = vec![];
+                    while self.peek() != Some(']') {
+                        let start = self.parse_char("char class")?;
+                        let end = if self.peek() == Some('-') && self.peek_at(1) != Some(']') {
+                            self.pos += 1;
+                            self.parse_char("char class")?
+                        } else {
+                            start
+                        };
+                        ranges.push((start, end));
+                    }
+                    self.pos += 1;
+                    seq.push(GrammarElement::Char { ranges, negated });
+                }
+                '.' => {
+                    self.pos += 1;
+                    last_sym_start = seq.len();
+                    seq.push(GrammarElement::Char {
+                        ranges: vec![],
+                        negated: true,
+                    });
+                }
+                '(' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let rule = self.generate_symbol(rule_name);
+                    let alts = self.parse_alternates(rule_name, true)?;
+                    self.rules[rule] = Some(alts);
+                    if self.next() != Some(')') {
+                        bail!(ErrorKind::BadInput, "expecting ')' at {}", self.pos);
+                    }
+                    last_sym_start = seq.len();
+                    seq.push(GrammarElement::RuleRef(rule));
+                }
+                '*' | '+' | '?' => {
+                    self.pos += 1;
+                    let (min, max) = match c {
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        _ => (0, Some(1)),
+                    };
+                    self.handle_repetition(&mut seq, last_sym_start, min, max, rule_name)?;
+                }
+                '{' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let min = self.parse_int()?;
+                    self.skip_space(true);
+                    let max = if self.peek() == Some(',') {
+                        self.pos += 1;
+                        self.skip_space(true);
+                        match self.peek() {
+                            Some(c) if c.is_ascii_digit() => Some(self.parse_int()?),
+                            _ => None,
+                        }
+                    } else {
+                        Some(min)
+                    };
+                    self.skip_space(true);
+                    if self.next() != Some('}') {
+                        bail!(ErrorKind::BadInput, "expecting '}}' at {}", self.pos);
+                    }
+                    self.handle_repetition(&mut seq, last_sym_start, min, max, rule_name)?;
+                }
+                c if is_word_char(c) => {
+                    let name = self.parse_name()?;
+                    last_sym_start = seq.len();
+                    seq.push(GrammarElement::RuleRef(self.symbol_id(&name)));
+                }
+                _ => break,
+            }
+            self.skip_space(nested);
+        }
+        Ok(seq)
+    }
+
+    /// rewrite `item{min,max}` into `min` copies of the item, then the optional ones. like
+    /// `item{1,3}` becomes `item r2`, where `r2 ::= item r1 |` and `r1 ::= item |`, and the
+    /// unbounded `item{1,}` becomes `item r` where `r ::= item r |`.
+    fn handle_repetition(
+        &mut self,
+        seq: &mut GrammarSequence,
+        last_sym_start: usize,
+        min: usize,
+        max: Option<usize>,
+        rule_name: &str,
+    ) -> Result<()> {
+        if last_sym_start >= seq.len() {
+            bail!(
+                ErrorKind::BadInput,
+                "expecting an item before the repetition at {}",
+                self.pos
+            );
+        }
+        let item = seq.split_off(last_sym_start);
+        for _ in 0..min {
+            seq.extend(item.iter().cloned());
+        }
+
+        match max {
+            None => {
+                let rule = self.generate_symbol(rule_name);
+                let mut recursive = item;
+                recursive.push(GrammarElement::RuleRef(rule));
+                self.rules[rule] = Some(vec![recursive, vec![]]);
+                seq.push(GrammarElement::RuleRef(rule));
+            }
+            Some(max) => {
+                if max < min {
+                    bail!(
+                        ErrorKind::BadInput,
+                        "the max repetition {} is less than the min {}",
+                        max,
+                        min
+                    );
+                }
+                let mut last_rule = None;
+                for _ in min..max {
+                    let rule = self.generate_symbol(rule_name);
+                    let mut optional = item.clone();
+                    if let Some(last_rule) = last_rule {
+                        optional.push(GrammarElement::RuleRef(last_rule));
+                    }
+                    self.rules[rule] = Some(vec![optional, vec![]]);
+                    last_rule = Some(rule);
+                }
+                if let Some(last_rule) = last_rule {
+                    seq.push(GrammarElement::RuleRef(last_rule));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_word_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            bail!(ErrorKind::BadInput, "expecting a name at {}", self.pos);
+        }
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    fn parse_int(&mut self) -> Result<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits = self.src[start..self.pos].iter().collect::<String>();
+        match digits.parse() {
+            Ok(n) => Ok(n),
+            Err(_) => bail!(ErrorKind::BadInput, "expecting an integer at {}", start),
+        }
+    }
+
+    fn parse_char(&mut self, context: &str) -> Result<char> {
+        let c = match self.next() {
+            Some(c) => c,
+            None => bail!(ErrorKind::BadInput, "unexpected end of the {}", context),
+        };
+        if c != '\\' {
+            return Ok(c);
+        }
+        match self.next() {
+            Some('x') => self.parse_hex(2),
+            Some('u') => self.parse_hex(4),
+            Some('U') => self.parse_hex(8),
+            Some('t') => Ok('\t'),
+            Some('r') => Ok('\r'),
+            Some('n') => Ok('\n'),
+            Some(c @ ('\\' | '"' | '[' | ']' | '-' | '^')) => Ok(c),
+            Some(c) => bail!(
+                ErrorKind::BadInput,
+                "unknown escape \\{} at {}",
+                c,
+                self.pos
+            ),
+            None => bail!(ErrorKind::BadInput, "unexpected end of the {}", context),
+        }
+    }
+
+    fn parse_hex(&mut self, len: usize) -> Result<char> {
+        let start = self.pos;
+        let end = (start + len).min(self.src.len());
+        let hex = self.src[start..end].iter().collect::<String>();
+        self.pos = end;
+        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+            Some(c) if hex.len() == len => Ok(c),
+            _ => bail!(ErrorKind::BadInput, "invalid hex escape at {}", start),
+        }
+    }
+
+    fn skip_space(&mut self, newline_ok: bool) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\r' && c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c == ' ' || c == '\t' || (newline_ok && (c == '\r' || c == '\n')) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn symbol_id(&mut self, name: &str) -> usize {
+        if let Some(id) = self.symbol_ids.get(name) {
+            return *id;
+        }
+        let id = self.rules.len();
+        self.symbol_ids.insert(name.to_string(), id);
+        self.rule_names.push(name.to_string());
+        self.rules.push(None);
+        id
+    }
+
+    fn generate_symbol(&mut self, base: &str) -> usize {
+        let name = format!("{}_{}", base, self.rules.len());
+        self.symbol_id(&name)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.src.get(self.pos + offset).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        c
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// tracks the parse state of the generated tokens, and masks the logits of the tokens which
+/// can not extend a valid parse before sampling.
+pub struct GrammarMatcher {
+    grammar: Arc<Grammar>,
+    stacks: Vec<GrammarStack>,
+
+    // a token may end with an incomplete utf-8 char, the bytes are kept until the next token
+    partial_utf8: Vec<u8>,
+
+    // the bytes of every token in the vocab, the special tokens are left empty
+    token_bytes: Vec<Vec<u8>>,
+    eos_token: TokenID,
+
+    // the number of the tokens accepted since the start of the grammar
+    n_accepted: usize,
+}
+
+impl GrammarMatcher {
+    pub fn new(grammar: Arc<Grammar>, tokenizer: &Tokenizer) -> Self {
+        let token_bytes = (0..tokenizer.vocab().len())
+            .map(|token| match tokenizer.is_special_token(token) {
+                true => vec![],
+                false => tokenizer.token_bytes(token),
+            })
+            .collect();
+        let stacks = grammar.initial_stacks();
+        Self {
+            grammar,
+            stacks,
+            partial_utf8: vec![],
+            token_bytes,
+            eos_token: tokenizer.eos_token(),
+            n_accepted: 0,
+        }
+    }
+
+    pub fn n_accepted(&self) -> usize {
+        self.n_accepted
+    }
+
+    /// go back to the start of the grammar, as if no token is accepted.
+    pub fn restart(&mut self) {
+        self.stacks = self.grammar.initial_stacks();
+        self.partial_utf8.clear();
+        self.n_accepted = 0;
+    }
+
+    /// whether the text accepted so far is a complete parse of the grammar, the eos token is
+    /// only allowed after the grammar is completed.
+    pub fn is_complete(&self) -> bool {
+        self.partial_utf8.is_empty() && self.stacks.iter().any(|s| s.is_empty())
+    }
+
+    pub fn mask_logits(&self, logits: &mut [f32]) -> Result<()> {
+        let mut any_allowed = false;
+        for (token, logit) in logits.iter_mut().enumerate() {
+            if *logit == f32::NEG_INFINITY {
+                continue;
+            }
+            let allowed = match token == self.eos_token {
+                true => self.is_complete(),
+                false => self.accept_bytes(&self.token_bytes[token]).is_some(),
+            };
+            if allowed {
+                any_allowed = true;
+            } else {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        if !any_allowed {
+            bail!(ErrorKind::Unexpected, "no token is allowed by the grammar");
+        }
+        Ok(())
+    }
+
+    pub fn accept_token(&mut self, token: TokenID) -> Result<()> {
+        if token == self.eos_token {
+            if !self.is_complete() {
+                bail!(
+                    ErrorKind::Unexpected,
+                    "got eos before the grammar is completed"
+                );
+            }
+            return Ok(());
+        }
+        match self.accept_bytes(&self.token_bytes[token]) {
+            Some((stacks, partial_utf8)) => {
+                self.stacks = stacks;
+                self.partial_utf8 = partial_utf8;
+                self.n_accepted += 1;
+                Ok(())
+            }
+            None => bail!(
+                ErrorKind::Unexpected,
+                "token {} is not allowed by the grammar",
+                token
+            ),
+        }
+    }
+
+    /// returns the new stacks and the bytes of the incomplete trailing char after accepting
+    /// the bytes, or None if the bytes can not extend the parse.
+    fn accept_bytes(&self, bytes: &[u8]) -> Option<(Vec<GrammarStack>, Vec<u8>)> {
+        if bytes.is_empty() {
+            return None;
+        }
+        let mut buf = self.partial_utf8.clone();
+        buf.extend_from_slice(bytes);
+        let (text, rest) = match std::str::from_utf8(&buf) {
+            Ok(text) => (text, &buf[buf.len()..]),
+            Err(err) if err.error_len().is_none() => {
+                let (valid, rest) = buf.split_at(err.valid_up_to());
+                (std::str::from_utf8(valid).unwrap(), rest)
+            }
+            Err(_) => return None,
+        };
+
+        let mut stacks: Option<Vec<GrammarStack>> = None;
+        for c in text.chars() {
+            let next = self
+                .grammar
+                .accept_char(stacks.as_deref().unwrap_or(&self.stacks), c);
+            if next.is_empty() {
+                return None;
+            }
+            stacks = Some(next);
+        }
+        let stacks = stacks.unwrap_or_else(|| self.stacks.clone());
+
+        // the incomplete char needs a stack which is able to take one of the chars it may be
+        if !rest.is_empty() {
+            let (lo, hi) = partial_utf8_range(rest);
+            if !self.grammar.accepts_range(&stacks, lo, hi) {
+                return None;
+            }
+        }
+        Some((stacks, rest.to_vec()))
+    }
+}
+
+/// the range of the code points which the incomplete utf-8 bytes might be decoded into.
+fn partial_utf8_range(bytes: &[u8]) -> (u32, u32) {
+    // the overlong encodings are invalid, so a char of n bytes has a min code point
+    let (len, mask, min) = match bytes[0] {
+        b if b >= 0xF0 => (4, 0x07, 0x10000),
+        b if b >= 0xE0 => (3, 0x0F, 0x800),
+        _ => (2, 0x1F, 0x80),
+    };
+    let mut value = (bytes[0] & mask) as u32;
+    for b in bytes[1..].iter() {
+        value = (value << 6) | (*b & 0x3F) as u32;
+    }
+    let remaining_bits = 6 * (len - bytes.len()) as u32;
+    let lo = value << remaining_bits;
+    (lo.max(min), lo | ((1 << remaining_bits) - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crabml::error::ErrorKind;
+    use crabml::tokenizer::Tokenizer;
+
+    use super::Grammar;
+    use super::GrammarMatcher;
+
+    const JSON_GRAMMAR: &str = r#"
+root   ::= object
+value  ::= object | array | string | number | ("true" | "false" | "null") ws
+
+object ::=
+  "{" ws (
+            string ":" ws value
+    ("," ws string ":" ws value)*
+  )? "}" ws
+
+array  ::=
+  "[" ws (
+            value
+    ("," ws value)*
+  )? "]" ws
+
+string ::=
+  "\"" (
+    [^"\\\x7F\x00-\x1F] |
+    "\\" (["\\bfnrt] | "u" [0-9a-fA-F]{4}) # escapes
+  )* "\"" ws
+
+number ::= ("-"? ([0-9] | [1-9] [0-9]{0,15})) ("." [0-9]+)? ([eE] [-+]? [0-9] [1-9]{0,15})? ws
+
+# Optional space: by convention, applied in this grammar after literal chars when allowed
+ws ::= | " " | "\n" [ \t]{0,20}
+"#;
+
+    // returns None if the text is rejected, or whether the text is a complete parse
+    fn accepts(grammar: &Grammar, text: &str) -> Option<bool> {
+        let stacks = grammar.accept_str(text);
+        match stacks.is_empty() {
+            true => None,
+            false => Some(stacks.iter().any(|s| s.is_empty())),
+        }
+    }
+
+    #[test]
+    fn test_grammar_parse() {
+        let grammar = Grammar::parse(JSON_GRAMMAR).unwrap();
+        assert_eq!(
+            accepts(&grammar, r#"{"a": [1, 2.5, -3e1], "b": {"c": null}}"#),
+            Some(true)
+        );
+        assert_eq!(accepts(&grammar, r#"{"a": "x\né"}"#), Some(true));
+        assert_eq!(accepts(&grammar, r#"{"a": tr"#), Some(false));
+        assert_eq!(accepts(&grammar, r#"{"a" 1}"#), None);
+        assert_eq!(accepts(&grammar, r#"[1]"#), None);
+        assert_eq!(accepts(&grammar, "{\"\u{1}\": 1}"), None);
+    }
+
+    #[test]
+    fn test_grammar_repetitions() {
+        let grammar = Grammar::parse(
+            r#"root ::= "a"{2,3} "b"? [0-9]+ . "é"* # comment
+"#,
+        )
+        .unwrap();
+        assert_eq!(accepts(&grammar, "aa1x"), Some(true));
+        assert_eq!(accepts(&grammar, "aaab12xéé"), Some(true));
+        assert_eq!(accepts(&grammar, "a1x"), None);
+        assert_eq!(accepts(&grammar, "aaaa1x"), None);
+        assert_eq!(accepts(&grammar, "aab"), Some(false));
+    }
+
+    #[test]
+    fn test_grammar_errors() {
+        let cases = [
+            ("root ::= foo", "undefined rule: foo"),
+            ("foo ::= \"a\"", "the grammar has no root rule"),
+            ("root ::= root \"a\" | \"b\"", "rule root is left recursive"),
+            ("root ::= (\"a\"", "expecting ')'"),
+            (
+                "root ::= \"a\"{3,1}",
+                "the max repetition 1 is less than the min 3",
+            ),
+            ("root ::= *", "expecting an item before the repetition"),
+        ];
+        for (src, message) in cases {
+            let err = Grammar::parse(src).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::BadInput);
+            assert!(err.message.starts_with(message), "{}", err.message);
+        }
+    }
+
+    #[test]
+    fn test_grammar_matcher() {
+        let vocab = [
+            "<unk>", "<s>", "</s>", "▁yes", "yes", "no", ".", "<0xC3>", "<0xA9>", "<0xE4>",
+        ];
+        let tokenizer = Tokenizer::new_llama(
+            vocab.iter().map(|s| s.to_string()).collect(),
+            vec![0.0; vocab.len()],
+            1,
+            2,
+        )
+        .with_special_tokens([0, 1, 2]);
+        let grammar = Arc::new(Grammar::parse(r#"root ::= ("yes" | "no" | "é") "."?"#).unwrap());
+        let mut matcher = GrammarMatcher::new(grammar, &tokenizer);
+
+        let allowed = |matcher: &GrammarMatcher| {
+            let mut logits = vec![0.0; vocab.len()];
+            matcher.mask_logits(&mut logits).unwrap();
+            (0..vocab.len())
+                .filter(|i| logits[*i] == 0.0)
+                .collect::<Vec<_>>()
+        };
+        // the leading 0xE4 byte can not become "é", and the special tokens are never allowed
+        assert_eq!(allowed(&matcher), vec![4, 5, 7]);
+
+        matcher.accept_token(7).unwrap();
+        assert!(!matcher.is_complete());
+        assert_eq!(allowed(&matcher), vec![8]);
+
+        matcher.accept_token(8).unwrap();
+        assert!(matcher.is_complete());
+        assert_eq!(allowed(&matcher), vec![2, 6]);
+
+        matcher.accept_token(6).unwrap();
+        assert_eq!(allowed(&matcher), vec![2]);
+        assert!(matcher.accept_token(5).is_err());
+        matcher.accept_token(2).unwrap();
+    }
+}
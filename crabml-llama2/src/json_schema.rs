@@ -0,0 +1,450 @@
+use std::collections::HashSet;
+
+use crabml::bail;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use serde_json::Value;
+
+// the primitive rules follow llama.cpp's json-schema-to-grammar, each one is listed with the
+// rules it depends on
+const PRIMITIVE_RULES: &[(&str, &str, &[&str])] = &[
+    ("space", r#"| " " | "\n" [ \t]{0,20}"#, &[]),
+    ("boolean", r#"("true" | "false") space"#, &["space"]),
+    ("null", r#""null" space"#, &["space"]),
+    ("integral-part", r#"[0] | [1-9] [0-9]{0,15}"#, &[]),
+    ("decimal-part", r#"[0-9]{1,16}"#, &[]),
+    (
+        "number",
+        r#"("-"? integral-part) ("." decimal-part)? ([eE] [-+]? integral-part)? space"#,
+        &["integral-part", "decimal-part", "space"],
+    ),
+    ("integer", r#"("-"? integral-part) space"#, &[
+        "integral-part",
+        "space",
+    ]),
+    (
+        "char",
+        r#"[^"\\\x7F\x00-\x1F] | [\\] (["\\bfnrt] | "u" [0-9a-fA-F]{4})"#,
+        &[],
+    ),
+    ("string", r#""\"" char* "\"" space"#, &["char", "space"]),
+    (
+        "value",
+        r#"object | array | string | number | boolean | null"#,
+        &["object", "array", "string", "number", "boolean", "null"],
+    ),
+    (
+        "object",
+        r#""{" space ( string ":" space value ("," space string ":" space value)* )? "}" space"#,
+        &["string", "value", "space"],
+    ),
+    (
+        "array",
+        r#""[" space ( value ("," space value)* )? "]" space"#,
+        &["value", "space"],
+    ),
+];
+
+/// converts a JSON schema into a GBNF grammar, which constrains the generated text to the JSON
+/// values matching the schema. it supports the types, properties, required, items, prefixItems,
+/// minItems/maxItems, minLength/maxLength, enum, const, anyOf/oneOf and the local `$ref`s.
+pub fn json_schema_to_grammar(schema: &Value) -> Result<String> {
+    let mut converter = SchemaConverter::new(schema);
+    converter.visit(schema, "root")?;
+    Ok(converter.format())
+}
+
+struct SchemaConverter<'a> {
+    root_schema: &'a Value,
+    rules: Vec<(String, String)>,
+    refs: HashSet<String>,
+}
+
+impl<'a> SchemaConverter<'a> {
+    fn new(root_schema: &'a Value) -> Self {
+        Self {
+            root_schema,
+            rules: vec![],
+            refs: HashSet::new(),
+        }
+    }
+
+    fn format(&self) -> String {
+        let mut grammar = String::new();
+        for (name, body) in self.rules.iter() {
+            grammar += &format!("{} ::= {}\n", name, body);
+        }
+        grammar
+    }
+
+    /// adds the rule and returns its name, a different rule under the same name gets a suffix.
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        let name = sanitize_rule_name(name);
+        let mut key = name.clone();
+        let mut i = 0;
+        loop {
+            match self.rules.iter().find(|(n, _)| *n == key) {
+                None => {
+                    self.rules.push((key.clone(), body));
+                    return key;
+                }
+                Some((_, existing)) if *existing == body => return key,
+                Some(_) => {
+                    i += 1;
+                    key = format!("{}{}", name, i);
+                }
+            }
+        }
+    }
+
+    fn add_primitive(&mut self, name: &str) -> String {
+        if self.rules.iter().any(|(n, _)| n == name) {
+            return name.to_string();
+        }
+        let (_, body, deps) = PRIMITIVE_RULES.iter().find(|(n, _, _)| *n == name).unwrap();
+        self.rules.push((name.to_string(), body.to_string()));
+        for dep in deps.iter() {
+            self.add_primitive(dep);
+        }
+        name.to_string()
+    }
+
+    fn add_primitive_rule(&mut self, name: &str, primitive: &str) -> String {
+        let primitive = self.add_primitive(primitive);
+        self.add_rule(name, primitive)
+    }
+
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.add_primitive_rule(name, "value")),
+            Value::Object(schema) => schema,
+            _ => bail!(ErrorKind::BadInput, "invalid schema: {}", schema),
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            let ref_rule = self.visit_ref(reference)?;
+            return Ok(self.add_rule(name, ref_rule));
+        }
+        if let Some(value) = schema.get("const") {
+            let body = format!("{} space", json_literal(value));
+            self.add_primitive("space");
+            return Ok(self.add_rule(name, body));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = match values.as_array() {
+                Some(values) if !values.is_empty() => values,
+                _ => bail!(ErrorKind::BadInput, "enum should be a non-empty array"),
+            };
+            let alts = values.iter().map(json_literal).collect::<Vec<_>>();
+            self.add_primitive("space");
+            return Ok(self.add_rule(name, format!("({}) space", alts.join(" | "))));
+        }
+        if let Some(schemas) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            return self.visit_alternatives(schemas, name);
+        }
+
+        match schema.get("type") {
+            None if schema.contains_key("properties") => self.visit_object(schema, name),
+            None if schema.contains_key("items") || schema.contains_key("prefixItems") => {
+                self.visit_array(schema, name)
+            }
+            None => Ok(self.add_primitive_rule(name, "value")),
+            Some(Value::Array(types)) => {
+                let mut alts = vec![];
+                for (i, typ) in types.iter().enumerate() {
+                    let mut sub_schema = schema.clone();
+                    sub_schema.insert("type".to_string(), typ.clone());
+                    let sub_name = format!("{}-{}", name, i);
+                    alts.push(self.visit(&Value::Object(sub_schema), &sub_name)?);
+                }
+                Ok(self.add_rule(name, alts.join(" | ")))
+            }
+            Some(Value::String(typ)) => match typ.as_str() {
+                "object" => self.visit_object(schema, name),
+                "array" => self.visit_array(schema, name),
+                "string" => self.visit_string(schema, name),
+                "number" | "integer" | "boolean" | "null" => Ok(self.add_primitive_rule(name, typ)),
+                _ => bail!(ErrorKind::BadInput, "unsupported type: {}", typ),
+            },
+            Some(typ) => bail!(ErrorKind::BadInput, "invalid type: {}", typ),
+        }
+    }
+
+    fn visit_ref(&mut self, reference: &Value) -> Result<String> {
+        let reference = match reference.as_str() {
+            Some(reference) => reference,
+            None => bail!(ErrorKind::BadInput, "$ref should be a string"),
+        };
+        let (defs, def_name) = match reference.strip_prefix("#/").and_then(|r| r.split_once('/')) {
+            Some((defs, def_name)) if defs == "definitions" || defs == "$defs" => (defs, def_name),
+            _ => bail!(ErrorKind::BadInput, "unsupported $ref: {}", reference),
+        };
+
+        // the recursive refs are resolved by the rule name, the rule is added on the first visit
+        let rule_name = sanitize_rule_name(&format!("ref-{}", def_name));
+        if !self.refs.insert(reference.to_string()) {
+            return Ok(rule_name);
+        }
+        let root_schema = self.root_schema;
+        let target = match root_schema.get(defs).and_then(|d| d.get(def_name)) {
+            Some(target) => target,
+            None => bail!(ErrorKind::BadInput, "unresolved $ref: {}", reference),
+        };
+        self.visit(target, &rule_name)
+    }
+
+    fn visit_alternatives(&mut self, schemas: &Value, name: &str) -> Result<String> {
+        let schemas = match schemas.as_array() {
+            Some(schemas) if !schemas.is_empty() => schemas,
+            _ => bail!(ErrorKind::BadInput, "anyOf should be a non-empty array"),
+        };
+        let mut alts = vec![];
+        for (i, schema) in schemas.iter().enumerate() {
+            alts.push(self.visit(schema, &format!("{}-{}", name, i))?);
+        }
+        Ok(self.add_rule(name, alts.join(" | ")))
+    }
+
+    fn visit_object(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String> {
+        let properties = match schema.get("properties") {
+            Some(Value::Object(properties)) => properties,
+            Some(_) => bail!(ErrorKind::BadInput, "properties should be an object"),
+            None => return Ok(self.add_primitive_rule(name, "object")),
+        };
+        let required = match schema.get("required") {
+            Some(Value::Array(required)) => required
+                .iter()
+                .filter_map(|r| r.as_str())
+                .collect::<HashSet<_>>(),
+            _ => HashSet::new(),
+        };
+
+        self.add_primitive("space");
+        let mut required_kvs = vec![];
+        let mut optional_kvs = vec![];
+        for (prop_name, prop_schema) in properties.iter() {
+            let value_rule = self.visit(prop_schema, &format!("{}-{}", name, prop_name))?;
+            let kv_body = format!(
+                "{} space \":\" space {}",
+                json_literal(&Value::String(prop_name.clone())),
+                value_rule
+            );
+            let kv_rule = self.add_rule(&format!("{}-{}-kv", name, prop_name), kv_body);
+            match required.contains(prop_name.as_str()) {
+                true => required_kvs.push(kv_rule),
+                false => optional_kvs.push(kv_rule),
+            }
+        }
+
+        let mut body = "\"{\" space ".to_string();
+        body += &required_kvs.join(" \",\" space ");
+        if !optional_kvs.is_empty() {
+            // the optional properties are kept in order, and any subset of them is allowed
+            let mut alts = vec![];
+            for i in 0..optional_kvs.len() {
+                alts.push(self.optional_kvs_chain(&optional_kvs[i..], false));
+            }
+            body += match required_kvs.is_empty() {
+                true => format!(" ( {} )?", alts.join(" | ")),
+                false => format!(" ( \",\" space ( {} ) )?", alts.join(" | ")),
+            }
+            .as_str();
+        }
+        body += " \"}\" space";
+        Ok(self.add_rule(name, body))
+    }
+
+    fn optional_kvs_chain(&mut self, kvs: &[String], first_is_optional: bool) -> String {
+        let mut chain = match first_is_optional {
+            true => format!("( \",\" space {} )?", kvs[0]),
+            false => kvs[0].clone(),
+        };
+        if kvs.len() > 1 {
+            let rest = self.optional_kvs_chain(&kvs[1..], true);
+            chain += " ";
+            chain += &self.add_rule(&format!("{}-rest", kvs[0]), rest);
+        }
+        chain
+    }
+
+    fn visit_array(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String> {
+        self.add_primitive("space");
+        let items = schema.get("prefixItems").or_else(|| schema.get("items"));
+        if let Some(Value::Array(items)) = items {
+            let mut item_rules = vec![];
+            for (i, item) in items.iter().enumerate() {
+                item_rules.push(self.visit(item, &format!("{}-{}", name, i))?);
+            }
+            let body = format!(
+                "\"[\" space {} \"]\" space",
+                item_rules.join(" \",\" space ")
+            );
+            return Ok(self.add_rule(name, body));
+        }
+
+        let item_rule = match items {
+            Some(item) => self.visit(item, &format!("{}-item", name))?,
+            None => self.add_primitive("value"),
+        };
+        let min = schema.get("minItems").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+        let max = schema
+            .get("maxItems")
+            .and_then(|v| v.as_u64())
+            .map(|v| v as usize);
+        let body = match max {
+            Some(0) => "\"[\" space \"]\" space".to_string(),
+            _ => {
+                let items = format!(
+                    "{} ( \",\" space {} ){}",
+                    item_rule,
+                    item_rule,
+                    repetition(min.saturating_sub(1), max.map(|max| max - 1))
+                );
+                match min {
+                    0 => format!("\"[\" space ( {} )? \"]\" space", items),
+                    _ => format!("\"[\" space {} \"]\" space", items),
+                }
+            }
+        };
+        Ok(self.add_rule(name, body))
+    }
+
+    fn visit_string(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String> {
+        let min = schema.get("minLength").and_then(|v| v.as_u64());
+        let max = schema.get("maxLength").and_then(|v| v.as_u64());
+        if min.is_none() && max.is_none() {
+            return Ok(self.add_primitive_rule(name, "string"));
+        }
+        let char_rule = self.add_primitive("char");
+        self.add_primitive("space");
+        let body = format!(
+            "\"\\\"\" {}{} \"\\\"\" space",
+            char_rule,
+            repetition(min.unwrap_or(0) as usize, max.map(|max| max as usize))
+        );
+        Ok(self.add_rule(name, body))
+    }
+}
+
+fn sanitize_rule_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+            true => c,
+            false => '-',
+        })
+        .collect()
+}
+
+fn repetition(min: usize, max: Option<usize>) -> String {
+    match max {
+        Some(max) => format!("{{{},{}}}", min, max),
+        None => format!("{{{},}}", min),
+    }
+}
+
+/// the JSON text of the value as a GBNF string literal.
+fn json_literal(value: &Value) -> String {
+    let mut literal = String::from("\"");
+    for c in value.to_string().chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::json_schema_to_grammar;
+    use crate::grammar::Grammar;
+
+    #[test]
+    fn test_json_schema_to_grammar() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string", "maxLength": 5},
+                "age": {"type": "integer"},
+                "tags": {"type": "array", "items": {"enum": ["a", "b"]}, "minItems": 1, "maxItems": 2},
+                "pet": {"$ref": "#/$defs/pet"}
+            },
+            "required": ["name", "age"],
+            "$defs": {
+                "pet": {"anyOf": [{"const": "cat"}, {"type": ["boolean", "null"]}]}
+            }
+        });
+        let src = json_schema_to_grammar(&schema).unwrap();
+        let grammar = Grammar::parse(&src).unwrap();
+
+        let cases = [
+            (r#"{"age": 3, "name": "Lily"}"#, true),
+            (r#"{"age": 3, "name": "Lily", "pet": "cat"}"#, true),
+            (
+                r#"{"age": 3, "name": "Lily", "pet": null, "tags": ["a", "b"]}"#,
+                true,
+            ),
+            (
+                r#"{"age": 3, "name": "Lily", "tags": ["a"], "pet": true}"#,
+                false,
+            ),
+            (r#"{"age": 3, "name": "Lily", "tags": []}"#, false),
+            (
+                r#"{"age": 3, "name": "Lily", "tags": ["a", "b", "a"]}"#,
+                false,
+            ),
+            (r#"{"age": 3, "name": "Lily Lily"}"#, false),
+            (r#"{"age": 3.5, "name": "Lily"}"#, false),
+            (r#"{"name": "Lily"}"#, false),
+            (r#"{"age": 3, "name": "Lily", "pet": "dog"}"#, false),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(grammar.matches(text), expected, "{}\n{}", text, src);
+        }
+    }
+
+    #[test]
+    fn test_json_schema_to_grammar_optional() {
+        let schema = json!({
+            "properties": {
+                "a": {"type": "number"},
+                "b": {"type": "string"},
+                "c": {"type": "array", "prefixItems": [{"type": "integer"}, {"type": "string"}]}
+            }
+        });
+        let grammar = Grammar::parse(&json_schema_to_grammar(&schema).unwrap()).unwrap();
+        for text in [
+            "{}",
+            r#"{"b": "x"}"#,
+            r#"{"a": 1.5e3, "c": [1, "x"]}"#,
+            r#"{"a": 1, "b": "x", "c": [1, "x"]}"#,
+        ] {
+            assert!(grammar.matches(text), "{}", text);
+        }
+        for text in [r#"{"b": "x", "a": 1}"#, r#"{"c": [1]}"#, r#"{"a": 1,}"#] {
+            assert!(!grammar.matches(text), "{}", text);
+        }
+
+        let err = json_schema_to_grammar(&json!({"$ref": "#/$defs/foo"})).unwrap_err();
+        assert_eq!(err.message, "unresolved $ref: #/$defs/foo");
+    }
+}
@@ -1,10 +1,20 @@
+pub mod batch;
 pub mod chat;
+pub mod grammar;
+pub mod json_schema;
 pub mod llama2;
 pub mod model;
 pub mod sampler;
+mod session;
+pub mod speculative;
 
+pub use batch::Llama2BatchRunner;
+pub use chat::ChatRound;
 pub use chat::Llama2Chat;
+pub use grammar::Grammar;
 pub use model::CpuLlamaModel;
 pub use model::GpuLlamaModel;
 pub use model::LlamaModel;
 pub use sampler::Llama2Sampler;
+pub use sampler::Llama2SamplerOptions;
+pub use speculative::SpeculativeRunner;
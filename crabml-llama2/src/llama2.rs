@@ -6,16 +6,30 @@ use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::gguf::GGMLType;
 use crabml::tensor::RopeMode;
+use crabml::tensor::RopeParams;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorMetrics;
+use crabml::tokenizer::TokenID;
 use crabml::tokenizer::Tokenizer;
 use crabml::tokenizer::Utf8Buf;
+use rand::rngs::StdRng;
 
+use crate::grammar::Grammar;
+use crate::grammar::GrammarMatcher;
 use crate::model::LlamaConfig;
 use crate::model::LlamaModel;
 use crate::model::LlamaWeights;
 use crate::model::ModelArchitecture;
 use crate::sampler::Llama2Sampler;
+use crate::sampler::Llama2SamplerRef;
+use crate::session::model_fingerprint;
+use crate::session::Session;
+
+// the max number of prompt tokens passed through one forward pass on a batched prefill
+const DEFAULT_PREFILL_CHUNK_SIZE: usize = 512;
+
+// the values of the kv caches of each layer in the layout of (n_kv_heads, n_tokens, head_size)
+type KvCacheValues = Vec<Vec<f32>>;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Activation {
@@ -23,6 +37,67 @@ pub enum Activation {
     GeLU,
 }
 
+/// a generated token with the log probabilities of the distribution it's sampled from, which
+/// is the softmax of the logits after the logit bias, penalties and grammar, but before the
+/// sampler chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedToken {
+    pub token: TokenID,
+
+    /// the decoded text of the token, which is empty when the token ends with an incomplete
+    /// utf-8 character, the bytes are carried to the next piece
+    pub piece: String,
+
+    pub logprob: f32,
+
+    /// the most likely tokens and their log probabilities in the descending order
+    pub top_logprobs: Vec<(TokenID, f32)>,
+}
+
+/// why the last generation stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// the eos token is sampled
+    Eos,
+
+    /// the given steps are generated
+    MaxSteps,
+
+    /// the context is full, and the context shift is not enabled
+    ContextFull,
+}
+
+/// a hypothesis of the beam search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamHypothesis {
+    /// the generated tokens, the eos token is not included
+    pub tokens: Vec<TokenID>,
+
+    pub text: String,
+
+    /// the sum of the log probabilities of the tokens, including the eos token
+    pub score: f32,
+
+    /// the score divided by the length ^ length_penalty, the hypotheses are ranked by it
+    pub normalized_score: f32,
+}
+
+// a beam is evaluated in a slot, which is a runner holding the kv caches of the prompt and
+// the tokens of the beam except the last one, the last token is evaluated on expanding the
+// beam. the finished beams are not evaluated any more, and hold no slot.
+struct Beam {
+    tokens: Vec<TokenID>,
+    score: f32,
+    finished: bool,
+    slot: Option<usize>,
+}
+
+impl Beam {
+    fn normalized_score(&self, length_penalty: f32) -> f32 {
+        self.score / (self.tokens.len().max(1) as f32).powf(length_penalty)
+    }
+}
+
 pub struct Llama2Runner<T: Tensor> {
     conf: LlamaConfig,
     weights: Arc<LlamaWeights<T>>,
@@ -33,11 +108,23 @@ pub struct Llama2Runner<T: Tensor> {
 
     sampler: Arc<Llama2Sampler>,
     prob_index: Vec<(f32, usize)>,
+    history: Vec<usize>,  // the recent tokens for the penalties in sampling
+    tokens: Vec<TokenID>, // the tokens passed through the model into the kv cache
+    logit_bias: Vec<(usize, f32)>,
+    logprobs: Vec<f32>, // the log softmax of the logits on the last sampling
+    rng: StdRng,
+    grammar: Option<GrammarMatcher>,
 
     device: T::DeviceRef,
     logits: Vec<f32>,            // output logits (vocab_size, )
     key_cache: Vec<Option<T>>,   // (layer, n_kv_head, seq_len, kv_dim)
     value_cache: Vec<Option<T>>, // (layer, n_kv_head, seq_len, kv_dim)
+    prefill_chunk_size: usize,
+    context_shift: Option<usize>, // the number of pinned tokens on shifting the context
+    rolling_kv_cache: Option<usize>, // the capacity of the rolling kv cache in tokens
+    kv_offset: usize, // the number of the tokens dropped from the front of the rolling kv cache
+    n_ctx: usize,     // the context length the runner is created with, picks the longrope factors
+    stop_reason: Option<StopReason>, // why the last generation stopped, None if it's not over
 
     pub metrics: TensorMetrics,
 }
@@ -53,37 +140,45 @@ impl<T: Tensor> Llama2Runner<T> {
         } else {
             GGMLType::F32
         };
+        Self::new_with_kv_cache_dtype(model, seq_len, kv_cache_dtype)
+    }
 
+    /// the kv caches can be stored in F32, F16, or Q8_0 and Q4_0 to save the memory on the
+    /// long contexts. the quantized kv caches are only supported on cpu, and the head size
+    /// should be a multiple of 32.
+    pub fn new_with_kv_cache_dtype(
+        model: impl LlamaModel<T = T>,
+        seq_len: usize,
+        kv_cache_dtype: GGMLType,
+    ) -> Result<Self> {
         let conf = &model.conf();
+        match kv_cache_dtype {
+            GGMLType::F32 | GGMLType::F16 => {}
+            GGMLType::Q8_0 | GGMLType::Q4_0 if conf.head_size() % 32 == 0 => {}
+            GGMLType::Q8_0 | GGMLType::Q4_0 => bail!(
+                ErrorKind::BadInput,
+                "the kv cache of {} needs the head size {} to be a multiple of 32",
+                kv_cache_dtype,
+                conf.head_size()
+            ),
+            _ => bail!(
+                ErrorKind::BadInput,
+                "unsupported kv cache type {}",
+                kv_cache_dtype
+            ),
+        }
+
         let device = model.device().clone();
         let weights = model.weights();
         let tokenizer = model.tokenizer();
         let sampler = model.sampler();
+        let logit_bias = sampler.resolve_logit_bias(&tokenizer)?;
+        let rng = sampler.new_rng();
         let metrics = model.metrics().clone();
         let logits = vec![0.0; conf.vocab_size];
         let prob_index = vec![(0.0, 0); conf.vocab_size];
-        let key_cache = (0..conf.n_layers)
-            .map(|_| {
-                T::alloc(
-                    &[conf.n_kv_heads, seq_len, conf.head_size()],
-                    kv_cache_dtype,
-                    device.clone(),
-                )
-                .map(|t| t.resize(1, 0).unwrap())
-                .map(Some)
-            })
-            .collect::<Result<Vec<_>>>()?;
-        let value_cache = (0..conf.n_layers)
-            .map(|_| {
-                T::alloc(
-                    &[conf.n_kv_heads, seq_len, conf.head_size()],
-                    kv_cache_dtype,
-                    device.clone(),
-                )
-                .map(|t| t.resize(1, 0).unwrap())
-                .map(Some)
-            })
-            .collect::<Result<Vec<_>>>()?;
+        let key_cache = alloc_kv_cache(conf, seq_len, kv_cache_dtype, &device)?;
+        let value_cache = alloc_kv_cache(conf, seq_len, kv_cache_dtype, &device)?;
         Ok(Self {
             conf: conf.clone(),
             logits,
@@ -94,27 +189,444 @@ impl<T: Tensor> Llama2Runner<T> {
             tokenizer,
             decode_buf: Utf8Buf::new(),
             prob_index,
+            history: vec![],
+            tokens: vec![],
+            logit_bias,
+            logprobs: vec![0.0; conf.vocab_size],
+            rng,
+            grammar: None,
             device,
+            prefill_chunk_size: DEFAULT_PREFILL_CHUNK_SIZE,
+            context_shift: None,
+            rolling_kv_cache: None,
+            kv_offset: 0,
+            n_ctx: seq_len,
+            stop_reason: None,
             metrics,
         })
     }
 
+    pub fn with_prefill_chunk_size(mut self, n: usize) -> Self {
+        assert!(n > 0);
+        self.prefill_chunk_size = n;
+        self
+    }
+
+    // override the sampler from the model, like taking the sampling parameters of a request
+    pub fn with_sampler(mut self, sampler: Llama2SamplerRef) -> Result<Self> {
+        self.logit_bias = sampler.resolve_logit_bias(&self.tokenizer)?;
+        self.rng = sampler.new_rng();
+        self.sampler = sampler;
+        Ok(self)
+    }
+
+    // constrain the generated text to the grammar, the logits of the tokens which can not
+    // extend a valid parse are masked before sampling
+    pub fn with_grammar(mut self, grammar: Arc<Grammar>) -> Self {
+        self.grammar = Some(GrammarMatcher::new(grammar, &self.tokenizer));
+        self
+    }
+
+    /// when the context is full, drop the older half of the tokens after the first `n_keep`
+    /// pinned ones, like the bos and the system prompt, instead of stopping the generation.
+    /// the keys left are rotated again to their new positions.
+    pub fn with_context_shift(mut self, n_keep: usize) -> Self {
+        self.context_shift = Some(n_keep);
+        self
+    }
+
+    /// keep only the tokens in the sliding window of the model in the kv cache, the oldest ones
+    /// are dropped when it's full, so the memory is bounded by the window instead of the context
+    /// length on the long generations. the cache holds the window and a chunk of the prefill,
+    /// and it's cleared on calling this.
+    pub fn with_rolling_kv_cache(mut self) -> Result<Self> {
+        let window = match self.conf.sliding_window {
+            Some(window) => window,
+            None => bail!(
+                ErrorKind::BadInput,
+                "the rolling kv cache needs a model with the sliding window attention"
+            ),
+        };
+        if self.context_shift.is_some() {
+            bail!(
+                ErrorKind::BadInput,
+                "the context shift is not supported with the rolling kv cache"
+            );
+        }
+        let capacity = window + self.prefill_chunk_size;
+        let dtype = self.key_cache[0].as_ref().unwrap().dtype();
+        self.key_cache = alloc_kv_cache(&self.conf, capacity, dtype, &self.device)?;
+        self.value_cache = alloc_kv_cache(&self.conf, capacity, dtype, &self.device)?;
+        self.rolling_kv_cache = Some(capacity);
+        self.tokens.clear();
+        self.history.clear();
+        self.kv_offset = 0;
+        Ok(self)
+    }
+
+    // drop the grammar, like on reusing the runner for another request
+    pub fn without_grammar(mut self) -> Self {
+        self.grammar = None;
+        self
+    }
+
     pub fn conf(&self) -> &LlamaConfig {
         &self.conf
     }
 
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// the number of the tokens passed through the model, which is the next position. it's
+    /// larger than the rows held in the rolling kv cache after the oldest ones are dropped.
     pub fn kv_cache_len(&self) -> usize {
+        self.kv_offset + self.kv_rows()
+    }
+
+    fn kv_rows(&self) -> usize {
         self.key_cache[0].as_ref().unwrap().shape()[1]
     }
 
-    // prefill the model with the prompt, return the next position and the first generated token
+    /// why the last generation stopped, it's None if the generation is not consumed to the end.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    /// the tokens which have been passed through the model and held in the kv cache.
+    pub fn tokens(&self) -> &[TokenID] {
+        &self.tokens
+    }
+
+    /// drop everything in the kv cache, so the runner can start over on a new prompt.
+    pub fn reset(&mut self) -> Result<()> {
+        self.truncate(0)
+    }
+
+    /// keep the first `n_tokens` tokens in the kv cache and drop the rest, the recent tokens
+    /// for the penalties are taken from the tokens left. the grammar can not be rolled back,
+    /// so it's only allowed to truncate to empty after the grammar accepted any token, which
+    /// restarts the grammar.
+    pub fn truncate(&mut self, n_tokens: usize) -> Result<()> {
+        if n_tokens > self.tokens.len() {
+            bail!(
+                ErrorKind::BadInput,
+                "can not truncate the kv cache of {} tokens to {} tokens",
+                self.tokens.len(),
+                n_tokens
+            );
+        }
+        if let Some(grammar) = &mut self.grammar {
+            if n_tokens == 0 {
+                grammar.restart();
+            } else if grammar.n_accepted() > 0 {
+                bail!(
+                    ErrorKind::BadInput,
+                    "can not truncate to {} tokens, the grammar has accepted {} tokens",
+                    n_tokens,
+                    grammar.n_accepted()
+                );
+            }
+        }
+        if n_tokens == 0 {
+            self.kv_offset = 0;
+        }
+        if n_tokens < self.kv_offset {
+            bail!(
+                ErrorKind::BadInput,
+                "can not truncate to {} tokens, the first {} tokens have been dropped from the rolling kv cache",
+                n_tokens,
+                self.kv_offset
+            );
+        }
+        for cache in self.key_cache.iter_mut().chain(self.value_cache.iter_mut()) {
+            let c = cache.take().unwrap();
+            cache.replace(c.resize(1, n_tokens - self.kv_offset)?);
+        }
+        self.tokens.truncate(n_tokens);
+        let last_n = self.sampler.penalties().last_n;
+        self.history = self.tokens[n_tokens.saturating_sub(last_n)..].to_vec();
+        self.decode_buf = Utf8Buf::new();
+        Ok(())
+    }
+
+    /// drop the last `n_tokens` tokens from the kv cache, the logits are not rolled back, so
+    /// at least one more token needs to be prefilled before sampling.
+    pub fn rewind(&mut self, n_tokens: usize) -> Result<()> {
+        if n_tokens > self.tokens.len() {
+            bail!(
+                ErrorKind::BadInput,
+                "can not rewind {} tokens, there're only {} tokens in the kv cache",
+                n_tokens,
+                self.tokens.len()
+            );
+        }
+        self.truncate(self.tokens.len() - n_tokens)
+    }
+
+    /// save the kv cache and the tokens in it into a file, which can be restored by
+    /// `load_session` to skip prefilling the same prompt again.
+    pub fn save_session(&self, path: &str) -> Result<()> {
+        if self.kv_offset > 0 {
+            bail!(
+                ErrorKind::BadInput,
+                "can not save the session, the first {} tokens have been dropped from the rolling kv cache",
+                self.kv_offset
+            );
+        }
+        let (key_cache, value_cache) = self.export_kv_cache()?;
+        let session = Session {
+            fingerprint: model_fingerprint(&self.conf, &self.tokenizer),
+            // the quantized caches are saved in F16, and quantized again on loading
+            dtype: match self.key_cache[0].as_ref().unwrap().dtype() {
+                GGMLType::F32 => GGMLType::F32,
+                _ => GGMLType::F16,
+            },
+            n_layers: self.conf.n_layers,
+            n_kv_heads: self.conf.n_kv_heads,
+            head_size: self.conf.head_size(),
+            tokens: self.tokens.clone(),
+            history: self.history.clone(),
+            key_cache,
+            value_cache,
+        };
+        session.write_to_file(path)
+    }
+
+    /// restore the state saved by `save_session`, the current kv cache is dropped. the logits
+    /// are not saved, so at least one more token needs to be prefilled before sampling.
+    pub fn load_session(&mut self, path: &str) -> Result<()> {
+        // the fingerprint, the shape and the length of the session are checked on reading
+        let session = Session::read_from_file(path, &self.conf, &self.tokenizer)?;
+        if let Some(capacity) = self.rolling_kv_cache {
+            if session.tokens.len() > capacity {
+                bail!(
+                    ErrorKind::BadInput,
+                    "the session of {} tokens exceeds the rolling kv cache of {} tokens",
+                    session.tokens.len(),
+                    capacity
+                );
+            }
+        }
+
+        self.kv_offset = 0;
+        self.import_kv_cache(
+            &session.key_cache,
+            &session.value_cache,
+            session.tokens.len(),
+        )?;
+        self.tokens = session.tokens;
+        self.history = session.history;
+        Ok(())
+    }
+
+    fn export_kv_cache(&self) -> Result<(KvCacheValues, KvCacheValues)> {
+        let export_cache = |cache: &Option<T>| -> Result<Vec<f32>> {
+            let cache = cache.as_ref().unwrap();
+            let mut buf = vec![0.0; cache.shape().iter().product()];
+            if !buf.is_empty() {
+                // the cache is a view on the pre-allocated buffer of seq_len
+                cache.clone().contiguous()?.export(&mut buf)?;
+            }
+            Ok(buf)
+        };
+        let keys = self
+            .key_cache
+            .iter()
+            .map(export_cache)
+            .collect::<Result<Vec<_>>>()?;
+        let values = self
+            .value_cache
+            .iter()
+            .map(export_cache)
+            .collect::<Result<Vec<_>>>()?;
+        Ok((keys, values))
+    }
+
+    // replace the kv caches with the exported ones of n_tokens, the tokens are not touched
+    fn import_kv_cache(
+        &mut self,
+        keys: &[Vec<f32>],
+        values: &[Vec<f32>],
+        n_tokens: usize,
+    ) -> Result<()> {
+        for cache in self.key_cache.iter_mut().chain(self.value_cache.iter_mut()) {
+            let c = cache.take().unwrap().resize(1, 0)?;
+            cache.replace(c);
+        }
+        self.append_kv_cache(keys, values, n_tokens)
+    }
+
+    // the exported rows of the kv caches from the position, in the layout of
+    // (n_kv_heads, n_tokens - from, head_size)
+    fn export_kv_rows(&self, from: usize) -> Result<(KvCacheValues, KvCacheValues)> {
+        let (keys, values) = self.export_kv_cache()?;
+        let row_len = self.kv_rows() * self.conf.head_size();
+        let offset = from * self.conf.head_size();
+        let keep_rows = |buf: Vec<f32>| -> Vec<f32> {
+            if row_len == 0 {
+                return buf;
+            }
+            buf.chunks_exact(row_len)
+                .flat_map(|head| head[offset..].iter().copied())
+                .collect()
+        };
+        Ok((
+            keys.into_iter().map(keep_rows).collect(),
+            values.into_iter().map(keep_rows).collect(),
+        ))
+    }
+
+    // append the exported kv caches of n_tokens after the current ones
+    fn append_kv_cache(
+        &mut self,
+        keys: &[Vec<f32>],
+        values: &[Vec<f32>],
+        n_tokens: usize,
+    ) -> Result<()> {
+        if n_tokens == 0 {
+            return Ok(());
+        }
+        let shape = [self.conf.n_kv_heads, n_tokens, self.conf.head_size()];
+        let caches = self.key_cache.iter_mut().chain(self.value_cache.iter_mut());
+        let saved = keys.iter().chain(values.iter());
+        for (cache, buf) in caches.zip(saved) {
+            let bytes = buf.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+            let t = T::from_cpu(&bytes, &shape, GGMLType::F32, self.device.clone())?;
+            cache.as_mut().unwrap().concatenate(&t, 1)?;
+        }
+        Ok(())
+    }
+
+    // drop the oldest rows of the rolling kv cache to make room for n tokens, the rows in the
+    // sliding window of the next token are kept. the keys are not rotated again, as the
+    // positions of the tokens left are not changed.
+    fn make_kv_room(&mut self, n_tokens: usize) -> Result<()> {
+        let capacity = match self.rolling_kv_cache {
+            Some(capacity) => capacity,
+            None => return Ok(()),
+        };
+        let n_rows = self.kv_rows();
+        if n_rows + n_tokens <= capacity {
+            return Ok(());
+        }
+        let n_keep = (self.conf.sliding_window.unwrap() - 1).min(n_rows);
+        if n_keep + n_tokens > capacity {
+            bail!(
+                ErrorKind::BadInput,
+                "can not pass {} tokens through the rolling kv cache of {} tokens",
+                n_tokens,
+                capacity
+            );
+        }
+        let n_drop = n_rows - n_keep;
+        let (keys, values) = self.export_kv_rows(n_drop)?;
+        self.import_kv_cache(&keys, &values, n_keep)?;
+        self.kv_offset += n_drop;
+        Ok(())
+    }
+
+    // the max number of tokens in one forward, the chunk should fit into the rolling kv cache
+    // with the window before it
+    fn chunk_size(&self) -> usize {
+        match (self.rolling_kv_cache, self.conf.sliding_window) {
+            (Some(capacity), Some(window)) => self.prefill_chunk_size.min(capacity + 1 - window),
+            _ => self.prefill_chunk_size,
+        }
+    }
+
+    // drop at least `n_min` tokens after the pinned ones, or the older half of them, and
+    // rotate the keys after them back to their new positions. returns the number of the
+    // dropped tokens.
+    fn shift_context(&mut self, n_min: usize) -> Result<usize> {
+        if self.rolling_kv_cache.is_some() {
+            bail!(
+                ErrorKind::BadInput,
+                "the context shift is not supported with the rolling kv cache"
+            );
+        }
+        let rope_mode = match self.rope_mode() {
+            Some(mode) => mode,
+            None => bail!(
+                ErrorKind::BadInput,
+                "the context shift is not supported with the absolute position embeddings"
+            ),
+        };
+        let n_keep = self.context_shift.unwrap_or(0);
+        let n_tokens = self.tokens.len();
+        let n_left = n_tokens.saturating_sub(n_keep);
+        let n_discard = (n_left / 2).max(n_min);
+        if n_discard == 0 || n_discard > n_left {
+            bail!(
+                ErrorKind::BadInput,
+                "the context is full, can not drop {} tokens with {} tokens pinned",
+                n_min.max(1),
+                n_keep
+            );
+        }
+
+        let head_size = self.conf.head_size();
+        let rope = self.conf.rope_params(self.n_ctx);
+        let dropped = n_keep * head_size..(n_keep + n_discard) * head_size;
+        let drop_rows = |buf: &Vec<f32>| -> Vec<f32> {
+            buf.chunks_exact(n_tokens * head_size)
+                .flat_map(|head| {
+                    head[..dropped.start]
+                        .iter()
+                        .chain(head[dropped.end..].iter())
+                })
+                .copied()
+                .collect()
+        };
+        let (keys, values) = self.export_kv_cache()?;
+        let mut keys = keys.iter().map(drop_rows).collect::<Vec<_>>();
+        let values = values.iter().map(drop_rows).collect::<Vec<_>>();
+        let n_tokens = n_tokens - n_discard;
+        for buf in keys.iter_mut() {
+            for head in buf.chunks_exact_mut(n_tokens * head_size) {
+                rope_shift(
+                    &mut head[n_keep * head_size..],
+                    rope_mode,
+                    -(n_discard as f32),
+                    head_size,
+                    &rope,
+                );
+            }
+        }
+        self.import_kv_cache(&keys, &values, n_tokens)?;
+        self.tokens.drain(n_keep..n_keep + n_discard);
+        Ok(n_discard)
+    }
+
+    // the same as the rope used in the forward of each architecture, gpt2 takes the learned
+    // position embeddings instead
+    fn rope_mode(&self) -> Option<RopeMode> {
+        match self.conf.architecture {
+            ModelArchitecture::Llama => Some(RopeMode::Llama),
+            ModelArchitecture::Gpt2 => None,
+            _ => Some(RopeMode::Neox),
+        }
+    }
+
+    // prefill the model with the prompt, return the next position and the first generated token.
+    // when batched, the prompt is passed through the model in chunks of `prefill_chunk_size` tokens
+    // in one forward pass per chunk, otherwise it's forwarded token by token.
     pub fn prefill(
         &mut self,
         prompt: &str,
         bos: bool,
-        _batched: bool,
+        batched: bool,
     ) -> Result<(usize, usize, usize)> {
         let prompt_tokens = self.tokenizer.encode(prompt, bos, false)?;
+        self.prefill_tokens(&prompt_tokens, batched)
+    }
+
+    /// like `prefill`, but takes the tokens which have already been encoded.
+    pub fn prefill_tokens(
+        &mut self,
+        prompt_tokens: &[TokenID],
+        batched: bool,
+    ) -> Result<(usize, usize, usize)> {
         if prompt_tokens.is_empty() {
             bail!(
                 ErrorKind::BadInput,
@@ -122,20 +634,73 @@ impl<T: Tensor> Llama2Runner<T> {
             );
         }
 
-        let base_pos = self.kv_cache_len();
-        // this is expected to be eos, make it as the prewarm
-        for (pos, token) in prompt_tokens.iter().enumerate() {
-            self.forward(&[*token], base_pos + pos)?;
-        }
-        let token = self
-            .sampler
-            .sample(&mut self.logits, &mut self.prob_index)?;
+        let next_pos = self.feed_tokens(prompt_tokens, batched)?;
+        let token = self.sample()?;
         let last_token = *prompt_tokens.last().unwrap();
+        Ok((next_pos, last_token, token))
+    }
+
+    /// prefill the tokens as the whole context. the kv cache of the longest common prefix with
+    /// the tokens already in the cache is kept, and only the rest is passed through the model,
+    /// which makes it cheap to regenerate a reply or edit the last message. the last token is
+    /// always evaluated again, because the logits are needed for sampling.
+    pub fn prefill_with_prefix_cache(
+        &mut self,
+        prompt_tokens: &[TokenID],
+        batched: bool,
+    ) -> Result<(usize, usize, usize)> {
+        if prompt_tokens.is_empty() {
+            bail!(
+                ErrorKind::BadInput,
+                "something is wrong, expected at least 1 prompt token"
+            );
+        }
+        let n_common = self
+            .tokens
+            .iter()
+            .zip(prompt_tokens.iter())
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(prompt_tokens.len() - 1);
+        // the tokens dropped from the rolling kv cache can not be restored
+        let n_common = if n_common < self.kv_offset {
+            0
+        } else {
+            n_common
+        };
+        self.truncate(n_common)?;
+        self.prefill_tokens(&prompt_tokens[n_common..], batched)
+    }
+
+    /// pass the tokens through the model into the kv cache without sampling, returns the
+    /// next position. it's useful to build a kv cache to save as a session.
+    pub fn feed_tokens(&mut self, prompt_tokens: &[TokenID], batched: bool) -> Result<usize> {
+        let mut base_pos = self.kv_cache_len();
+        let n_overflow = (base_pos + prompt_tokens.len() + 1).saturating_sub(self.conf.seq_len);
+        if n_overflow > 0 && self.context_shift.is_some() {
+            base_pos -= self.shift_context(n_overflow)?;
+        }
+        if base_pos + prompt_tokens.len() >= self.conf.seq_len {
+            bail!(
+                ErrorKind::BadInput,
+                "the prompt of {} tokens exceeds the context length {}",
+                base_pos + prompt_tokens.len(),
+                self.conf.seq_len
+            );
+        }
+        if batched {
+            let chunk_size = self.chunk_size();
+            for (i, chunk) in prompt_tokens.chunks(chunk_size).enumerate() {
+                self.forward(chunk, base_pos + i * chunk_size)?;
+            }
+        } else {
+            for (pos, token) in prompt_tokens.iter().enumerate() {
+                self.forward(&[*token], base_pos + pos)?;
+            }
+        }
 
         // take the length of kv cache as the next position
-        let next_pos = self.kv_cache_len();
-        assert_eq!(next_pos, base_pos + prompt_tokens.len());
-        Ok((next_pos, last_token, token))
+        Ok(self.kv_cache_len())
     }
 
     pub fn generate(
@@ -144,33 +709,90 @@ impl<T: Tensor> Llama2Runner<T> {
         token: usize,
         steps: Option<usize>,
     ) -> impl Iterator<Item = Result<String>> + '_ {
-        // the first token has already been generated in the prefill phase.
-        let max_seq = self.conf.seq_len - pos - 1;
-        let max_steps = match steps {
-            Some(steps) => max_seq.min(steps - 1),
-            None => max_seq,
-        };
-
-        let first_token = self.tokenizer.decode(token, &mut self.decode_buf);
-        let tokens_iter = (pos..pos + max_steps).scan(token, move |current_token, pos| {
-            self.forward(&[*current_token], pos).unwrap();
-            let new_token = self
-                .sampler
-                .sample(&mut self.logits, &mut self.prob_index)
-                .unwrap();
+        self.generate_tokens(pos, token, steps, 0)
+            .map(|generated| generated.map(|g| g.piece))
+    }
+
+    /// like `generate`, but yields the token ids with their log probabilities, and the
+    /// `top_n` most likely alternatives on each step.
+    pub fn generate_tokens(
+        &mut self,
+        pos: usize,
+        token: usize,
+        steps: Option<usize>,
+        top_n: usize,
+    ) -> impl Iterator<Item = Result<GeneratedToken>> + '_ {
+        // the first token has already been generated in the prefill phase. with the context
+        // shift, the generation keeps going past the context length.
+        let max_seq = match self.context_shift {
+            Some(_) => usize::MAX,
+            None => self.conf.seq_len - pos - 1,
+        };
+        let (max_steps, limit_reason) = match steps {
+            Some(steps) if steps - 1 <= max_seq => (steps - 1, StopReason::MaxSteps),
+            _ => (max_seq, StopReason::ContextFull),
+        };
+        self.stop_reason = None;
+        if max_steps == 0 {
+            self.stop_reason = Some(limit_reason);
+        }
+
+        let first_token = self.generated_token(token, top_n);
+        // the state is taken on an error to stop the generation after yielding it
+        let tokens_iter = (0..max_steps).scan(Some((pos, token)), move |state, step| {
+            let (pos, current_token) = state.as_mut()?;
+            if *pos + 1 >= self.conf.seq_len {
+                match self.shift_context(0) {
+                    Ok(n_discard) => *pos -= n_discard,
+                    Err(err) => {
+                        *state = None;
+                        return Some(Err(err));
+                    }
+                }
+            }
+            if let Err(err) = self.forward(&[*current_token], *pos) {
+                *state = None;
+                return Some(Err(err));
+            }
+            *pos += 1;
+            let new_token = match self.sample() {
+                Ok(new_token) => new_token,
+                Err(err) => {
+                    *state = None;
+                    return Some(Err(err));
+                }
+            };
             if new_token == self.tokenizer.eos_token() {
+                self.stop_reason = Some(StopReason::Eos);
                 return None;
             }
-            let r = self
-                .tokenizer
-                .decode(new_token, &mut self.decode_buf)
-                .unwrap();
+            if step + 1 == max_steps {
+                self.stop_reason = Some(limit_reason);
+            }
             *current_token = new_token;
-            Some(Ok(r))
+            Some(self.generated_token(new_token, top_n))
         });
         std::iter::once(first_token).chain(tokens_iter)
     }
 
+    fn generated_token(&mut self, token: TokenID, top_n: usize) -> Result<GeneratedToken> {
+        let piece = self.tokenizer.decode(token, &mut self.decode_buf)?;
+        let mut top_logprobs = vec![];
+        if top_n > 0 {
+            top_logprobs = self.logprobs.iter().copied().enumerate().collect();
+            let top_n = top_n.min(top_logprobs.len());
+            top_logprobs.select_nth_unstable_by(top_n - 1, |a, b| b.1.total_cmp(&a.1));
+            top_logprobs.truncate(top_n);
+            top_logprobs.sort_by(|a, b| b.1.total_cmp(&a.1));
+        }
+        Ok(GeneratedToken {
+            token,
+            piece,
+            logprob: self.logprobs[token],
+            top_logprobs,
+        })
+    }
+
     // simplify the test cases
     pub fn prefill_and_generate(
         &mut self,
@@ -181,46 +803,551 @@ impl<T: Tensor> Llama2Runner<T> {
         Ok(self.generate(pos, token, Some(steps)))
     }
 
-    fn forward(&mut self, tokens: &[usize], pos: usize) -> Result<()> {
-        let _t = self.metrics.forward_walltime.track();
+    /// beam search from the prompt, and return the `beam_width` best hypotheses in the
+    /// descending order of their normalized scores. the logit bias and the penalties are
+    /// applied, but not the sampler chain. it starts from an empty kv cache, and the kv cache
+    /// only keeps the prompt at the end.
+    pub fn generate_beam(
+        &mut self,
+        prompt: &str,
+        beam_width: usize,
+        max_len: usize,
+        length_penalty: f32,
+    ) -> Result<Vec<BeamHypothesis>> {
+        if beam_width == 0 || max_len == 0 {
+            bail!(
+                ErrorKind::BadInput,
+                "expected a positive beam width and max length, but got {} and {}",
+                beam_width,
+                max_len
+            );
+        }
+        if self.grammar.is_some() {
+            bail!(
+                ErrorKind::BadInput,
+                "the grammar is not supported on the beam search"
+            );
+        }
+        if self.rolling_kv_cache.is_some() {
+            bail!(
+                ErrorKind::BadInput,
+                "the rolling kv cache is not supported on the beam search"
+            );
+        }
+        if !self.tokens.is_empty() {
+            bail!(
+                ErrorKind::BadInput,
+                "the beam search starts from an empty kv cache, but there're {} tokens, reset the runner first",
+                self.tokens.len()
+            );
+        }
+        let prompt_tokens = self.tokenizer.encode(prompt, true, false)?;
+        self.feed_tokens(&prompt_tokens, true)?;
+
+        // the beams are evaluated in the forked runners, so the kv cache of the prompt is kept
+        let beams = self.search_beams(beam_width, max_len, length_penalty)?;
+        let eos_token = self.tokenizer.eos_token();
+        beams
+            .into_iter()
+            .map(|beam| {
+                let normalized_score = beam.normalized_score(length_penalty);
+                let mut tokens = beam.tokens;
+                if tokens.last() == Some(&eos_token) {
+                    tokens.pop();
+                }
+                let mut buf = Utf8Buf::new();
+                let text = tokens
+                    .iter()
+                    .map(|token| self.tokenizer.decode(*token, &mut buf))
+                    .collect::<Result<String>>()?;
+                Ok(BeamHypothesis {
+                    tokens,
+                    text,
+                    score: beam.score,
+                    normalized_score,
+                })
+            })
+            .collect()
+    }
 
-        let x = match self.conf.architecture {
-            ModelArchitecture::Llama => self.forward_llama(tokens, pos)?,
-            ModelArchitecture::Gemma => self.forward_gemma(tokens, pos)?,
-            ModelArchitecture::Qwen2 => self.forward_qwen2(tokens, pos)?,
-            ModelArchitecture::Phi2 => self.forward_phi2(tokens, pos)?,
-        };
+    fn search_beams(
+        &mut self,
+        beam_width: usize,
+        max_len: usize,
+        length_penalty: f32,
+    ) -> Result<Vec<Beam>> {
+        let prompt_len = self.tokens.len();
+        // the last token of a beam is not evaluated, so the beam can be 1 token longer
+        let max_len = max_len.min(self.conf.seq_len - prompt_len);
+        let eos_token = self.tokenizer.eos_token();
+
+        // the first beam takes the logits of the prompt in its slot
+        let mut slots = vec![self.fork()?];
+        let mut beams = vec![Beam {
+            tokens: vec![],
+            score: 0.0,
+            finished: false,
+            slot: Some(0),
+        }];
+        let mut logprobs = vec![0.0; self.conf.vocab_size];
+        for _ in 0..max_len {
+            // the last tokens of the beams are evaluated in one forward
+            let evaluated = beams
+                .iter()
+                .filter(|beam| !beam.finished && !beam.tokens.is_empty())
+                .map(|beam| (beam.slot.unwrap(), *beam.tokens.last().unwrap()))
+                .collect::<Vec<_>>();
+            if !evaluated.is_empty() {
+                let (mut runners, tokens): (Vec<_>, Vec<_>) = slots
+                    .iter_mut()
+                    .enumerate()
+                    .filter_map(|(i, runner)| {
+                        let (_, token) = evaluated.iter().find(|(slot, _)| *slot == i)?;
+                        Some((runner, *token))
+                    })
+                    .unzip();
+                Self::forward_batch(&mut runners, &tokens)?;
+            }
 
-        let mut x_final = T::alloc(
-            &[self.conf.embedding_dim],
-            GGMLType::F32,
-            self.device.clone(),
-        )?;
-        x_final.copy_rows_from(&x, &[tokens.len() - 1])?;
+            let mut candidates = Vec::with_capacity(beams.len() * beam_width);
+            for beam in beams {
+                if beam.finished {
+                    candidates.push(beam);
+                    continue;
+                }
+                let slot = &slots[beam.slot.unwrap()];
+                let mut logits = slot.logits.clone();
+                slot.adjust_logits(&mut logits, &slot.history);
+                log_softmax(&logits, &mut logprobs);
+
+                let mut top = logprobs.iter().copied().enumerate().collect::<Vec<_>>();
+                let k = beam_width.min(top.len());
+                top.select_nth_unstable_by(k - 1, |a, b| b.1.total_cmp(&a.1));
+                top.truncate(k);
+                for (token, logprob) in top {
+                    // the banned tokens
+                    if logprob == f32::NEG_INFINITY {
+                        continue;
+                    }
+                    let mut tokens = beam.tokens.clone();
+                    tokens.push(token);
+                    let finished = token == eos_token;
+                    candidates.push(Beam {
+                        tokens,
+                        score: beam.score + logprob,
+                        finished,
+                        slot: if finished { None } else { beam.slot },
+                    });
+                }
+            }
+            candidates.sort_by(|a, b| {
+                b.normalized_score(length_penalty)
+                    .total_cmp(&a.normalized_score(length_penalty))
+            });
+            candidates.truncate(beam_width);
+            beams = candidates;
+            if beams.iter().all(|beam| beam.finished) {
+                break;
+            }
+            self.assign_slots(&mut beams, &mut slots)?;
+        }
+        Ok(beams)
+    }
 
-        // classifier into logits
-        // TODO: it'd be make sense to reuse the same buffer for the logits
-        let output_weight = self
-            .weights
-            .output_weight
-            .as_ref()
-            .unwrap_or_else(|| &self.weights.token_embed);
-        let logits = output_weight.matmul_vec(&x_final)?; // (batch_size, vocab_size),
-        logits.export(&mut self.logits)?;
+    // the first beam expanded from a slot keeps the slot, the other ones from the same slot
+    // take a free slot, and copy the kv caches of the slot they're expanded from
+    fn assign_slots(&mut self, beams: &mut [Beam], slots: &mut Vec<Self>) -> Result<()> {
+        let mut used = vec![false; slots.len()];
+        let mut forked = vec![];
+        for (i, beam) in beams.iter().enumerate() {
+            match beam.slot {
+                Some(slot) if !used[slot] => used[slot] = true,
+                Some(_) => forked.push(i),
+                None => {}
+            }
+        }
+        for i in forked {
+            let from = beams[i].slot.unwrap();
+            let slot = match used.iter().position(|used| !used) {
+                Some(slot) => slot,
+                None => {
+                    slots.push(self.fork()?);
+                    used.push(false);
+                    slots.len() - 1
+                }
+            };
+            used[slot] = true;
+            beams[i].slot = Some(slot);
+            let (dst, src) = if slot < from {
+                let (left, right) = slots.split_at_mut(from);
+                (&mut left[slot], &mut right[0])
+            } else {
+                let (left, right) = slots.split_at_mut(slot);
+                (&mut right[0], &mut left[from])
+            };
+            dst.copy_kv_cache_from(src)?;
+        }
         Ok(())
     }
 
-    fn forward_llama(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
-        let embed_dim = self.conf.embedding_dim;
-        let n_heads = self.conf.n_heads;
-        let n_kv_heads = self.conf.n_kv_heads;
-        let head_dim = self.conf.head_size();
-        let rope_dim = self.conf.rope_dim.unwrap_or(head_dim);
-        let n_batch = tokens.len();
+    // a runner of the same model and options, with the kv caches of the same capacity and
+    // type, holding the same tokens
+    fn fork(&mut self) -> Result<Self> {
+        let cache = self.key_cache[0].as_ref().unwrap();
+        let capacity = cache.strider().strides()[0] / self.conf.head_size();
+        let dtype = cache.dtype();
+        let mut runner = Self {
+            conf: self.conf.clone(),
+            weights: self.weights.clone(),
+            tokenizer: self.tokenizer.clone(),
+            decode_buf: Utf8Buf::new(),
+            sampler: self.sampler.clone(),
+            prob_index: self.prob_index.clone(),
+            history: vec![],
+            tokens: vec![],
+            logit_bias: self.logit_bias.clone(),
+            logprobs: self.logprobs.clone(),
+            rng: self.rng.clone(),
+            grammar: None,
+            device: self.device.clone(),
+            logits: self.logits.clone(),
+            key_cache: alloc_kv_cache(&self.conf, capacity, dtype, &self.device)?,
+            value_cache: alloc_kv_cache(&self.conf, capacity, dtype, &self.device)?,
+            prefill_chunk_size: self.prefill_chunk_size,
+            context_shift: None,
+            rolling_kv_cache: None,
+            kv_offset: 0,
+            n_ctx: self.n_ctx,
+            stop_reason: None,
+            metrics: self.metrics.clone(),
+        };
+        runner.copy_kv_cache_from(self)?;
+        Ok(runner)
+    }
 
-        // copy the token embedding into x
-        let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
-        x.copy_rows_from(&self.weights.token_embed, tokens)?;
+    // make the kv caches hold the same tokens as the other runner, only the rows after the
+    // common prefix of their tokens are copied on the device. the caches of the other runner
+    // are viewed as the rows of the whole buffer to copy from, and restored after copying.
+    fn copy_kv_cache_from(&mut self, other: &mut Self) -> Result<()> {
+        let n_common = (self.tokens.iter())
+            .zip(other.tokens.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let (n_tokens, n_heads, head_size) = (
+            other.tokens.len(),
+            self.conf.n_kv_heads,
+            self.conf.head_size(),
+        );
+        let dsts = self.key_cache.iter_mut().chain(self.value_cache.iter_mut());
+        let srcs = other
+            .key_cache
+            .iter_mut()
+            .chain(other.value_cache.iter_mut());
+        for (dst, src) in dsts.zip(srcs) {
+            let mut cache = dst.take().unwrap().resize(1, n_common)?;
+            if n_tokens > n_common {
+                let src_cache = src.take().unwrap();
+                // the quantized rows are appended in f32, and quantized again on concatenating
+                let dtype = match src_cache.dtype() {
+                    GGMLType::F16 => GGMLType::F16,
+                    _ => GGMLType::F32,
+                };
+                let strider = src_cache.strider().clone();
+                let capacity = strider.strides()[0] / head_size;
+                let rows = (0..n_heads)
+                    .flat_map(|h| (n_common..n_tokens).map(move |pos| h * capacity + pos))
+                    .collect::<Vec<_>>();
+                let src_rows = src_cache
+                    .resize(1, capacity)?
+                    .reshape(&[n_heads * capacity, head_size])?;
+                let mut diverged = T::alloc(&[rows.len(), head_size], dtype, self.device.clone())?;
+                diverged.copy_rows_from(&src_rows, &rows)?;
+                src.replace(src_rows.with_strider(strider)?);
+                cache.concatenate(
+                    &diverged.reshape(&[n_heads, n_tokens - n_common, head_size])?,
+                    1,
+                )?;
+            }
+            dst.replace(cache);
+        }
+        self.tokens = other.tokens.clone();
+        self.history = other.history.clone();
+        self.logits.copy_from_slice(&other.logits);
+        Ok(())
+    }
+
+    pub(crate) fn sample(&mut self) -> Result<usize> {
+        let mut logits = std::mem::take(&mut self.logits);
+        self.adjust_logits(&mut logits, &self.history);
+        self.logits = logits;
+        if let Some(grammar) = &self.grammar {
+            grammar.mask_logits(&mut self.logits)?;
+        }
+        // the sampler chain reshapes the logits in place, so keep the distribution before it
+        log_softmax(&self.logits, &mut self.logprobs);
+        let token = self
+            .sampler
+            .sample(&mut self.logits, &mut self.prob_index, &mut self.rng)?;
+        if let Some(grammar) = &mut self.grammar {
+            grammar.accept_token(token)?;
+        }
+        Ok(token)
+    }
+
+    // apply the logit bias and the penalties of the history on the logits
+    fn adjust_logits(&self, logits: &mut [f32], history: &[TokenID]) {
+        for (token, bias) in self.logit_bias.iter() {
+            logits[*token] += bias;
+        }
+        self.sampler.penalize(logits, history, &self.tokenizer);
+    }
+
+    /// the distribution of the next token on the current logits, the logit bias, the penalties
+    /// and the sampler stages are applied like `sample`, but the logits are kept untouched.
+    pub(crate) fn next_probs(&mut self) -> Result<Vec<f32>> {
+        let history = self.history.clone();
+        let logits = self.logits.clone();
+        self.probs_of(logits, &history)
+    }
+
+    fn probs_of(&mut self, mut logits: Vec<f32>, history: &[TokenID]) -> Result<Vec<f32>> {
+        self.adjust_logits(&mut logits, history);
+        let mut probs = vec![0.0; logits.len()];
+        self.sampler
+            .probs(&mut logits, &mut self.prob_index, &mut probs)?;
+        Ok(probs)
+    }
+
+    /// evaluate the tokens in one batched forward, and return the distribution of the next
+    /// token after each of them, the penalties only take the tokens before each position. it's
+    /// used to verify the drafted tokens on the speculative decoding.
+    pub(crate) fn verify_tokens(&mut self, tokens: &[TokenID]) -> Result<Vec<Vec<f32>>> {
+        let pos = self.kv_cache_len();
+        if tokens.is_empty() || pos + tokens.len() >= self.conf.seq_len {
+            bail!(
+                ErrorKind::BadInput,
+                "can not verify {} tokens at the position {} in the context length {}",
+                tokens.len(),
+                pos,
+                self.conf.seq_len
+            );
+        }
+
+        let vocab_size = self.conf.vocab_size;
+        let mut history = self.history.clone();
+        let mut logits = vec![0.0; tokens.len() * vocab_size];
+        {
+            let _t = self.metrics.forward_walltime.track();
+            let x = self.forward_hidden(tokens, pos)?;
+            let batch_logits = self.output_weight().matmul_vec(&x)?; // (n_tokens, vocab_size)
+            batch_logits.export(&mut logits)?;
+        }
+        self.logits
+            .copy_from_slice(&logits[logits.len() - vocab_size..]);
+
+        let last_n = self.sampler.penalties().last_n;
+        let mut probs = Vec::with_capacity(tokens.len());
+        for (token, row) in tokens.iter().zip(logits.chunks(vocab_size)) {
+            history.push(*token);
+            if history.len() > last_n {
+                history.drain(..history.len() - last_n);
+            }
+            probs.push(self.probs_of(row.to_vec(), &history)?);
+        }
+        Ok(probs)
+    }
+
+    pub(crate) fn rng_mut(&mut self) -> &mut StdRng {
+        &mut self.rng
+    }
+
+    pub(crate) fn has_grammar(&self) -> bool {
+        self.grammar.is_some()
+    }
+
+    pub(crate) fn has_context_shift(&self) -> bool {
+        self.context_shift.is_some()
+    }
+
+    // the batched runners are roped together, so they should also pick the same longrope
+    // factors by their context lengths
+    pub(crate) fn shares_model_with(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.weights, &other.weights)
+            && self.conf.rope_params(self.n_ctx) == other.conf.rope_params(other.n_ctx)
+    }
+
+    pub(crate) fn decode(&mut self, token: TokenID) -> Result<String> {
+        self.tokenizer.decode(token, &mut self.decode_buf)
+    }
+
+    fn forward(&mut self, tokens: &[usize], pos: usize) -> Result<()> {
+        let _t = self.metrics.forward_walltime.track();
+        let x = self.forward_hidden(tokens, pos)?;
+        let mut x_final = T::alloc(
+            &[self.conf.embedding_dim],
+            GGMLType::F32,
+            self.device.clone(),
+        )?;
+        x_final.copy_rows_from(&x, &[tokens.len() - 1])?;
+
+        // classifier into logits
+        // TODO: it'd be make sense to reuse the same buffer for the logits
+        let logits = self.output_weight().matmul_vec(&x_final)?; // (batch_size, vocab_size),
+        logits.export(&mut self.logits)?;
+        Ok(())
+    }
+
+    /// evaluate the tokens from the position in batches, and return the log probability of
+    /// each token given the tokens before it. the first token is not scored, so there're
+    /// `tokens.len() - 1` items. the logits of the last token are kept for sampling.
+    pub fn eval_logprobs(&mut self, tokens: &[TokenID], pos: usize) -> Result<Vec<f32>> {
+        if tokens.is_empty() {
+            bail!(ErrorKind::BadInput, "expected at least 1 token to evaluate");
+        }
+        if pos + tokens.len() > self.conf.seq_len {
+            bail!(
+                ErrorKind::BadInput,
+                "the {} tokens exceeds the context length {}",
+                pos + tokens.len(),
+                self.conf.seq_len
+            );
+        }
+
+        let _t = self.metrics.forward_walltime.track();
+        let vocab_size = self.conf.vocab_size;
+        let mut logprobs = Vec::with_capacity(tokens.len() - 1);
+        let mut logits = vec![];
+        let chunk_size = self.chunk_size();
+        for (i, chunk) in tokens.chunks(chunk_size).enumerate() {
+            let offset = i * chunk_size;
+            let x = self.forward_hidden(chunk, pos + offset)?;
+            let chunk_logits = self.output_weight().matmul_vec(&x)?; // (chunk_len, vocab_size)
+            logits.resize(chunk.len() * vocab_size, 0.0);
+            chunk_logits.export(&mut logits)?;
+
+            for (j, row) in logits.chunks(vocab_size).enumerate() {
+                if let Some(next_token) = tokens.get(offset + j + 1) {
+                    log_softmax(row, &mut self.logprobs);
+                    logprobs.push(self.logprobs[*next_token]);
+                }
+            }
+        }
+        let last_row = logits.len() - vocab_size;
+        self.logits.copy_from_slice(&logits[last_row..]);
+        Ok(logprobs)
+    }
+
+    /// pass the next token of each runner through the model in one forward, so the weights
+    /// are multiplied once for all of them. the runners should be created from the same model,
+    /// and each token only attends the kv cache of its own runner at its own position.
+    pub(crate) fn forward_batch(runners: &mut [&mut Self], tokens: &[TokenID]) -> Result<()> {
+        if runners.is_empty() || runners.len() != tokens.len() {
+            bail!(
+                ErrorKind::BadInput,
+                "expected one token for each of the {} runners, but got {} tokens",
+                runners.len(),
+                tokens.len()
+            );
+        }
+        for runner in runners.iter() {
+            if !runner.shares_model_with(runners[0]) {
+                bail!(
+                    ErrorKind::BadInput,
+                    "the batched runners should be created from the same model"
+                );
+            }
+            if runner.kv_cache_len() + 1 >= runner.conf.seq_len {
+                bail!(
+                    ErrorKind::BadInput,
+                    "the context length {} is exceeded",
+                    runner.conf.seq_len
+                );
+            }
+        }
+
+        let (first, rest) = runners.split_first_mut().unwrap();
+        let _t = first.metrics.forward_walltime.track();
+        let pos = first.kv_cache_len();
+        let mut batch = rest
+            .iter_mut()
+            .map(|runner| {
+                let pos = runner.kv_cache_len();
+                (&mut **runner, pos)
+            })
+            .collect::<Vec<_>>();
+        let x = first.forward_rows(tokens, pos, &mut batch)?;
+        let logits = first.output_weight().matmul_vec(&x)?; // (n_runners, vocab_size)
+        let vocab_size = first.conf.vocab_size;
+        let mut buf = vec![0.0; tokens.len() * vocab_size];
+        logits.export(&mut buf)?;
+        first.logits.copy_from_slice(&buf[..vocab_size]);
+        for ((runner, _), row) in batch.iter_mut().zip(buf.chunks(vocab_size).skip(1)) {
+            runner.logits.copy_from_slice(row);
+        }
+        Ok(())
+    }
+
+    fn forward_hidden(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
+        self.forward_rows(tokens, pos, &mut [])
+    }
+
+    // the rows are the tokens of this runner from the position, followed by the next token of
+    // each batched runner at its position
+    fn forward_rows(
+        &mut self,
+        tokens: &[usize],
+        pos: usize,
+        batch: &mut [(&mut Self, usize)],
+    ) -> Result<T> {
+        let n_own = tokens.len() - batch.len();
+        self.make_kv_room(n_own)?;
+        self.push_tokens(&tokens[..n_own]);
+        for ((runner, _), token) in batch.iter_mut().zip(tokens[n_own..].iter()) {
+            runner.make_kv_room(1)?;
+            runner.push_tokens(&[*token]);
+        }
+
+        match self.conf.architecture {
+            ModelArchitecture::Llama | ModelArchitecture::Phi3 => {
+                self.forward_llama(tokens, pos, batch)
+            }
+            ModelArchitecture::Gemma => self.forward_gemma(tokens, pos, batch),
+            ModelArchitecture::Qwen2 => self.forward_qwen2(tokens, pos, batch),
+            ModelArchitecture::Phi2 => self.forward_phi2(tokens, pos, batch),
+            ModelArchitecture::StarCoder2 | ModelArchitecture::Gpt2 => {
+                self.forward_gpt2(tokens, pos, batch)
+            }
+        }
+    }
+
+    fn push_tokens(&mut self, tokens: &[usize]) {
+        let last_n = self.sampler.penalties().last_n;
+        self.tokens.extend_from_slice(tokens);
+        self.history.extend_from_slice(tokens);
+        if self.history.len() > last_n {
+            self.history.drain(..self.history.len() - last_n);
+        }
+    }
+
+    fn output_weight(&self) -> &T {
+        self.weights
+            .output_weight
+            .as_ref()
+            .unwrap_or(&self.weights.token_embed)
+    }
+
+    fn forward_llama(
+        &mut self,
+        tokens: &[usize],
+        pos: usize,
+        batch: &mut [(&mut Self, usize)],
+    ) -> Result<T> {
+        let embed_dim = self.conf.embedding_dim;
+        let n_batch = tokens.len();
+
+        // copy the token embedding into x
+        let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
+        x.copy_rows_from(&self.weights.token_embed, tokens)?;
 
         // forward all the layers
         for l in 0..self.conf.n_layers {
@@ -247,26 +1374,18 @@ impl<T: Tensor> Llama2Runner<T> {
                 (q, k, v)
             };
 
-            // ROPE
-            let (q, k) = {
-                let q = q.reshape(&[n_batch, n_heads, head_dim])?;
-                let k = k.reshape(&[n_batch, n_kv_heads, head_dim])?;
-
-                let q = q.rope_inplace(RopeMode::Llama, pos, rope_dim)?;
-                let k = k.rope_inplace(RopeMode::Llama, pos, rope_dim)?;
-                (q, k)
-            };
-
-            x = self.forward_multi_query_attention(
-                q, k, v, l, pos, n_kv_heads, n_heads, embed_dim, head_dim, n_batch,
-            )?;
+            x = self.forward_attention(q, k, v, l, pos, self.rope_mode(), batch)?;
             x = x.with_name(format!("attn_out:{}:{}", l, pos));
 
             // residual connection back into x
             x = x.add_inplace(&x_attn_orig)?;
 
-            // ffn
-            x = self.forward_ffn(x, l, pos, Activation::SiLU)?;
+            // ffn, or the sparse moe ffn on mixtral
+            x = if self.conf.n_experts > 0 {
+                self.forward_moe(x, l, Activation::SiLU)?
+            } else {
+                self.forward_ffn(x, l, pos, Activation::SiLU)?
+            };
             x = x.with_name(format!("ffn_out:{}:{}", l, pos));
         }
 
@@ -280,12 +1399,13 @@ impl<T: Tensor> Llama2Runner<T> {
         Ok(x)
     }
 
-    fn forward_qwen2(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
+    fn forward_qwen2(
+        &mut self,
+        tokens: &[usize],
+        pos: usize,
+        batch: &mut [(&mut Self, usize)],
+    ) -> Result<T> {
         let embed_dim = self.conf.embedding_dim;
-        let n_heads = self.conf.n_heads;
-        let n_kv_heads = self.conf.n_kv_heads;
-        let head_dim = self.conf.head_size();
-        let rope_dim = self.conf.rope_dim.unwrap_or(head_dim);
         let n_batch = tokens.len();
 
         // copy the token embedding into x
@@ -318,19 +1438,7 @@ impl<T: Tensor> Llama2Runner<T> {
                 (q, k, v)
             };
 
-            // ROPE
-            let (q, k) = {
-                let q = q.reshape(&[n_batch, n_heads, head_dim])?;
-                let k = k.reshape(&[n_batch, n_kv_heads, head_dim])?;
-
-                let q = q.rope_inplace(RopeMode::Neox, pos, rope_dim)?;
-                let k = k.rope_inplace(RopeMode::Neox, pos, rope_dim)?;
-                (q, k)
-            };
-
-            x = self.forward_multi_query_attention(
-                q, k, v, l, pos, n_kv_heads, n_heads, embed_dim, head_dim, n_batch,
-            )?;
+            x = self.forward_attention(q, k, v, l, pos, Some(RopeMode::Neox), batch)?;
             x = x.with_name(format!("attn_out:{}:{}", l, pos));
 
             // residual connection back into x
@@ -351,14 +1459,14 @@ impl<T: Tensor> Llama2Runner<T> {
         Ok(x)
     }
 
-    fn forward_phi2(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
+    fn forward_phi2(
+        &mut self,
+        tokens: &[usize],
+        pos: usize,
+        batch: &mut [(&mut Self, usize)],
+    ) -> Result<T> {
         let embed_dim = self.conf.embedding_dim;
-        let n_heads = self.conf.n_heads;
-        let n_kv_heads = self.conf.n_kv_heads;
-        let head_dim = self.conf.head_size();
-        let rope_dim = self.conf.rope_dim.unwrap_or(head_dim);
         let n_batch = tokens.len();
-        let n_embd_gqa = head_dim * n_kv_heads;
 
         // copy the token embedding into x
         let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
@@ -370,51 +1478,26 @@ impl<T: Tensor> Llama2Runner<T> {
 
             // attention norm
             let mut x_attn_norm = x.dup()?;
-            x_attn_norm = x_attn_norm.rms_norm_inplace(self.conf.rms_norm_eps)?;
-            x_attn_norm = x_attn_norm.mul_inplace(&self.weights.rms_att_weight[l])?;
-            x_attn_norm = x_attn_norm.add_inplace(&self.weights.rms_att_bias[l])?;
+            x_attn_norm = x_attn_norm.layer_norm_inplace(
+                &self.weights.rms_att_weight[l],
+                &self.weights.rms_att_bias[l],
+                self.conf.rms_norm_eps,
+            )?;
             x_attn_norm = x_attn_norm.with_name(format!("attn_norm:{}:{}", l, pos));
 
-            // matmul qkv for every head
+            // matmul qkv for every head, the fused qkv is split on loading
             let (q, k, v) = {
-                let qkv = self.weights.wqkv[l].matmul_vec(&x_attn_norm)?;
-                let qkv = qkv.add_inplace(&self.weights.bqkv[l])?;
-
-                let mut q = T::alloc(&[embed_dim, n_batch], GGMLType::F32, self.device.clone())?;
-                q.copy_rows_from(&qkv, &[0])?;
-                q = q.reshape(&[embed_dim, n_batch])?.contiguous()?;
-                q = q.with_name("Qcur".to_string());
-
-                let mut k = T::alloc(&[embed_dim, n_batch], GGMLType::F32, self.device.clone())?;
-                k.copy_rows_from(&qkv, &[embed_dim])?;
-                k = k.reshape(&[embed_dim, n_batch])?.contiguous()?;
-                k = k.with_name("Kcur".to_string());
-
-                let mut v = T::alloc(&[embed_dim, n_batch], GGMLType::F32, self.device.clone())?;
-                v.copy_rows_from(&qkv, &[embed_dim + n_embd_gqa])?;
-                v = v.reshape(&[embed_dim, n_batch])?.contiguous()?;
-                v = v.with_name("Vcur".to_string());
-
+                let q = self.weights.wq[l].matmul_vec(&x_attn_norm)?;
+                let k = self.weights.wk[l].matmul_vec(&x_attn_norm)?;
+                let v = self.weights.wv[l].matmul_vec(&x_attn_norm)?;
+                let q = q.add_inplace(&self.weights.bq[l])?;
+                let k = k.add_inplace(&self.weights.bk[l])?;
+                let v = v.add_inplace(&self.weights.bv[l])?;
                 (q, k, v)
             };
 
-            // ROPE
-            let (q, k) = {
-                // reshape 3d
-                let q = q.reshape(&[n_batch, n_heads, head_dim])?;
-                let k = k.reshape(&[n_batch, n_kv_heads, head_dim])?;
-
-                let q = q.rope_inplace(RopeMode::Neox, pos, rope_dim)?;
-                let k = k.rope_inplace(RopeMode::Neox, pos, rope_dim)?;
-
-                let q = q.scale_inplace(1.0 / (head_dim as f32).sqrt())?;
-
-                (q, k)
-            };
-
-            x = self.forward_multi_query_attention(
-                q, k, v, l, pos, n_kv_heads, n_heads, embed_dim, head_dim, n_batch,
-            )?;
+            x = self.forward_attention(q, k, v, l, pos, Some(RopeMode::Neox), batch)?;
+            x = x.add_inplace(&self.weights.bo[l])?;
             x = x.with_name(format!("attn_out:{}:{}", l, pos));
 
             // ffn
@@ -434,12 +1517,103 @@ impl<T: Tensor> Llama2Runner<T> {
             x = x.with_name(format!("ffn_out:{}:{}", l, pos));
         }
 
-        // final rmsnorm
+        // final layernorm
         x = {
-            x = x.rms_norm_inplace(self.conf.rms_norm_eps)?;
-            x = x.mul_inplace(&self.weights.rms_final_weight)?;
-            x = x.add_inplace(self.weights.rms_final_bias.as_ref().unwrap())?;
-            x.with_name(format!("final_rmsnorm:{}", pos))
+            x = x.layer_norm_inplace(
+                &self.weights.rms_final_weight,
+                self.weights.rms_final_bias.as_ref().unwrap(),
+                self.conf.rms_norm_eps,
+            )?;
+            x.with_name(format!("final_layernorm:{}", pos))
+        };
+
+        Ok(x)
+    }
+
+    // starcoder2 is the same as gpt2, except that it takes the rope instead of the learned
+    // absolute position embeddings. both of them have the layernorms and the biases on all
+    // the linear layers, and the plain gelu ffn.
+    fn forward_gpt2(
+        &mut self,
+        tokens: &[usize],
+        pos: usize,
+        batch: &mut [(&mut Self, usize)],
+    ) -> Result<T> {
+        let embed_dim = self.conf.embedding_dim;
+        let n_batch = tokens.len();
+        let eps = self.conf.rms_norm_eps;
+
+        // copy the token embedding into x
+        let mut x = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
+        x.copy_rows_from(&self.weights.token_embed, tokens)?;
+
+        // add the position embedding of each row, the batched runners are at their own positions
+        if let Some(pos_embed) = &self.weights.pos_embed {
+            let n_own = n_batch - batch.len();
+            let positions = (pos..pos + n_own)
+                .chain(batch.iter().map(|(_, pos)| *pos))
+                .collect::<Vec<_>>();
+            let mut p = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
+            p.copy_rows_from(pos_embed, &positions)?;
+            x = x.add_inplace(&p)?;
+        }
+
+        // forward all the layers
+        for l in 0..self.conf.n_layers {
+            let x_attn_orig = x.dup()?;
+
+            // attention layernorm
+            x = x.layer_norm_inplace(
+                &self.weights.rms_att_weight[l],
+                &self.weights.rms_att_bias[l],
+                eps,
+            )?;
+            x = x.with_name(format!("attn_layernorm:{}:{}", l, pos));
+
+            // matmul qkv for every head, the fused qkv of gpt2 is split on loading
+            let (q, k, v) = {
+                let q = self.weights.wq[l].matmul_vec(&x)?;
+                let k = self.weights.wk[l].matmul_vec(&x)?;
+                let v = self.weights.wv[l].matmul_vec(&x)?;
+                let q = q.add_inplace(&self.weights.bq[l])?;
+                let k = k.add_inplace(&self.weights.bk[l])?;
+                let v = v.add_inplace(&self.weights.bv[l])?;
+                (q, k, v)
+            };
+
+            x = self.forward_attention(q, k, v, l, pos, self.rope_mode(), batch)?;
+            x = x.add_inplace(&self.weights.bo[l])?;
+            x = x.with_name(format!("attn_out:{}:{}", l, pos));
+
+            // residual connection back into x
+            x = x.add_inplace(&x_attn_orig)?;
+            let x_ffn_orig = x.dup()?;
+
+            // ffn
+            x = {
+                x = x.layer_norm_inplace(
+                    &self.weights.rms_ffn_weight[l],
+                    &self.weights.rms_ffn_bias[l],
+                    eps,
+                )?;
+                x = self.weights.ffn_up_weight[l].matmul_vec(&x)?;
+                x = x.add_inplace(&self.weights.ffn_up_bias[l])?;
+                x = x.gelu_inplace()?;
+                x = self.weights.ffn_down_weight[l].matmul_vec(&x)?;
+                x = x.add_inplace(&self.weights.ffn_down_bias[l])?;
+                x.add_inplace(&x_ffn_orig)?
+            };
+            x = x.with_name(format!("ffn_out:{}:{}", l, pos));
+        }
+
+        // final layernorm
+        x = {
+            x = x.layer_norm_inplace(
+                &self.weights.rms_final_weight,
+                self.weights.rms_final_bias.as_ref().unwrap(),
+                eps,
+            )?;
+            x.with_name(format!("final_layernorm:{}", pos))
         };
 
         Ok(x)
@@ -452,12 +1626,13 @@ impl<T: Tensor> Llama2Runner<T> {
     // 4. it adds a 1.0 to every weights on rmsnorm (rms_att_weight, rms_ffn_weight,
     //    rms_final_weight), this have been processed during GGUF format convert, so we
     //    don't need to do it here.
-    fn forward_gemma(&mut self, tokens: &[usize], pos: usize) -> Result<T> {
+    fn forward_gemma(
+        &mut self,
+        tokens: &[usize],
+        pos: usize,
+        batch: &mut [(&mut Self, usize)],
+    ) -> Result<T> {
         let embed_dim = self.conf.embedding_dim;
-        let n_heads = self.conf.n_heads;
-        let n_kv_heads = self.conf.n_kv_heads;
-        let head_dim = self.conf.head_size();
-        let rope_dim = self.conf.rope_dim.unwrap_or(head_dim);
         let n_batch = tokens.len();
 
         // copy the token embedding into x
@@ -491,19 +1666,7 @@ impl<T: Tensor> Llama2Runner<T> {
                 (q, k, v)
             };
 
-            // ROPE
-            let (q, k) = {
-                let q = q.reshape(&[n_heads, head_dim])?;
-                let k = k.reshape(&[n_kv_heads, head_dim])?;
-
-                let q = q.rope_inplace(RopeMode::Neox, pos, rope_dim)?;
-                let k = k.rope_inplace(RopeMode::Neox, pos, rope_dim)?;
-                (q, k)
-            };
-
-            x = self.forward_multi_query_attention(
-                q, k, v, l, pos, n_kv_heads, n_heads, embed_dim, head_dim, n_batch,
-            )?;
+            x = self.forward_attention(q, k, v, l, pos, Some(RopeMode::Neox), batch)?;
 
             // residual connection back into x
             x = x.add_inplace(&x_attn_orig)?;
@@ -523,6 +1686,85 @@ impl<T: Tensor> Llama2Runner<T> {
         Ok(x)
     }
 
+    // apply the rope on q and k, and attend the kv cache. the rows of the batched runners are
+    // rotated at their own positions and attend their own kv caches.
+    #[allow(clippy::too_many_arguments)]
+    fn forward_attention(
+        &mut self,
+        q: T,
+        k: T,
+        v: T,
+        l: usize,
+        pos: usize,
+        mode: Option<RopeMode>,
+        batch: &mut [(&mut Self, usize)],
+    ) -> Result<T> {
+        let embed_dim = self.conf.embedding_dim;
+        let n_heads = self.conf.n_heads;
+        let n_kv_heads = self.conf.n_kv_heads;
+        let head_dim = self.conf.head_size();
+        let rope = self.conf.rope_params(self.n_ctx);
+        let n_batch = q.shape().iter().product::<usize>() / embed_dim;
+        // no rope on the models with the absolute position embeddings
+        let rope_inplace = |t: T, pos: usize| match mode {
+            Some(mode) => t.rope_inplace(mode, pos, &rope),
+            None => Ok(t),
+        };
+
+        if batch.is_empty() {
+            let q = rope_inplace(q.reshape(&[n_batch, n_heads, head_dim])?, pos)?;
+            let k = rope_inplace(k.reshape(&[n_batch, n_kv_heads, head_dim])?, pos)?;
+            let x = self.forward_multi_query_attention(
+                q, k, v, l, n_kv_heads, n_heads, embed_dim, head_dim, n_batch,
+            )?;
+            return self.weights.wo[l].matmul_vec(&x);
+        }
+
+        let device = self.device.clone();
+        let kv_dim = n_kv_heads * head_dim;
+        let q = q.reshape(&[n_batch, embed_dim])?;
+        let k = k.reshape(&[n_batch, kv_dim])?;
+        let v = v.reshape(&[n_batch, kv_dim])?;
+        let take_rows = |t: &T, rows: &[usize], dim: usize| -> Result<T> {
+            let mut out = T::alloc(&[rows.len(), dim], GGMLType::F32, device.clone())?;
+            out.copy_rows_from(t, rows)?;
+            Ok(out)
+        };
+
+        // the outputs of the runners are gathered in the order of the rows
+        let mut x = T::alloc(&[1, n_batch, embed_dim], GGMLType::F32, device.clone())?;
+        x = x.resize(1, 0)?;
+        let n_own = n_batch - batch.len();
+        let mut row = 0;
+        for i in 0..=batch.len() {
+            let (runner, pos, n_rows) = match i {
+                0 => (&mut *self, pos, n_own),
+                _ => {
+                    let (runner, pos) = &mut batch[i - 1];
+                    (&mut **runner, *pos, 1)
+                }
+            };
+            let rows = (row..row + n_rows).collect::<Vec<_>>();
+            let q = rope_inplace(
+                take_rows(&q, &rows, embed_dim)?.reshape(&[n_rows, n_heads, head_dim])?,
+                pos,
+            )?;
+            let k = rope_inplace(
+                take_rows(&k, &rows, kv_dim)?.reshape(&[n_rows, n_kv_heads, head_dim])?,
+                pos,
+            )?;
+            let v = take_rows(&v, &rows, kv_dim)?;
+            let x_rows = runner.forward_multi_query_attention(
+                q, k, v, l, n_kv_heads, n_heads, embed_dim, head_dim, n_rows,
+            )?;
+            x.concatenate(&x_rows.reshape(&[1, n_rows, embed_dim])?, 1)?;
+            row += n_rows;
+        }
+        let x = x.reshape(&[n_batch, embed_dim])?;
+        self.weights.wo[l].matmul_vec(&x)
+    }
+
+    // attend the kv cache, and return the attention output before the output projection
     #[allow(clippy::too_many_arguments)]
     fn forward_multi_query_attention(
         &mut self,
@@ -530,7 +1772,6 @@ impl<T: Tensor> Llama2Runner<T> {
         k: T,
         v: T,
         l: usize,
-        _pos: usize,
         n_kv_heads: usize,
         n_heads: usize,
         embed_dim: usize,
@@ -567,13 +1808,21 @@ impl<T: Tensor> Llama2Runner<T> {
             // get attention scores:
             // - key_cache: [n_kv_head, seq, head_size].transpose(0, 2, 1) => [n_kv_head, head_size, seq]
             // - attn_scores = batch_matmul(q, key_cache) => [n_head, n_batch, seq]
+            // - attn_scores = causal_mask(attn_scores, seq - n_batch) => [n_head, n_batch, seq]
             // - attn_scores = softmax(attn_score, axis=2) => [n_head, n_batch, seq]
             let k_cache = self.key_cache[l].take().unwrap();
             let k_cache_strider_orig = k_cache.strider().clone();
             let k_cache = k_cache.transpose(&[0, 2, 1])?; // (n_kv_heads, head_size, seq)
 
-            // (n_head, 1, head_size) @ (n_kv_heads, head_size, seq)
-            let attn = q.batch_matmul(&k_cache)?; // (n_head, n_batch, seq)
+            // (n_head, n_batch, head_size) @ (n_kv_heads, head_size, seq)
+            let mut attn = q.batch_matmul(&k_cache)?; // (n_head, n_batch, seq)
+            let seq = attn.shape()[2];
+            let window = self.conf.sliding_window;
+            if n_batch > 1 || window.is_some_and(|window| seq > window) {
+                // the query at row i is the key at seq - n_batch + i in the kv cache, it should
+                // not see the keys after it, or the keys out of the sliding window
+                attn = attn.causal_mask_inplace(seq - n_batch, window)?;
+            }
             let attn = attn.softmax_inplace(2)?;
             self.key_cache[l].replace(k_cache.with_strider(k_cache_strider_orig)?);
 
@@ -595,9 +1844,7 @@ impl<T: Tensor> Llama2Runner<T> {
                     .reshape(&[n_batch, embed_dim])?
             };
             self.value_cache[l].replace(v_cache.with_strider(v_cache_strider_orig)?);
-
-            // final matmul to get the output of the attention
-            self.weights.wo[l].matmul_vec(&x_with_attn)?
+            x_with_attn
         };
         Ok(x)
     }
@@ -636,26 +1883,276 @@ impl<T: Tensor> Llama2Runner<T> {
         x = x.add_inplace(&x_orig_ffn)?;
         Ok(x)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use approx::assert_relative_eq;
-    use crabml::cpu::CpuTensorDeviceOptions;
-    use crabml::gguf::GGUFFileLoader;
-    use crabml_vulkan::vulkan_device::VulkanTensorDevice;
-    use crabml_vulkan::vulkan_device::VulkanTensorDeviceOptions;
-    use crabml_vulkan::vulkan_tensor::VulkanTensor;
 
-    use super::*;
-    use crate::model::CpuLlamaModelLoader;
-    use crate::GpuLlamaModel;
+    // the router picks the top k experts for each token, and only the picked experts run the
+    // ffn on the token. the tokens routed to the same expert are passed through it in one
+    // batch, and the outputs are summed with the router weights.
+    fn forward_moe(&self, mut x: T, l: usize, activation: Activation) -> Result<T> {
+        let embed_dim = self.conf.embedding_dim;
+        let n_experts = self.conf.n_experts;
+        let n_batch = x.shape()[0];
 
-    #[test]
-    fn test_generate_f32() -> Result<()> {
-        let gl: GGUFFileLoader =
-            GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
-        let gf = gl.open()?;
+        // save for residual connection
+        let x_orig_ffn = x.dup()?; // (n_batch, embed_dim)
+
+        // ffn rmsnorm
+        x = {
+            x = x.rms_norm_inplace(self.conf.rms_norm_eps)?;
+            x = x.mul_inplace(&self.weights.rms_ffn_weight[l])?;
+            x
+        };
+
+        // router: (n_experts, embed_dim) @ x (n_batch, embed_dim) => (n_batch, n_experts)
+        let router_logits = self.weights.ffn_gate_inp[l].matmul_vec(&x)?;
+        let mut logits = vec![0.0; n_batch * n_experts];
+        router_logits.export(&mut logits)?;
+        let mut expert_rows = vec![vec![]; n_experts];
+        for (row, logits) in logits.chunks(n_experts).enumerate() {
+            for (e, weight) in route_experts(logits, self.conf.n_experts_used) {
+                expert_rows[e].push((row, weight));
+            }
+        }
+
+        // the outputs of the experts are stacked into (n_batch * n_used, embed_dim), the 3d
+        // concatenate is supported on all the backends like the kv caches. each row of the
+        // batch keeps where the outputs of its experts are in the stack.
+        let n_used = self.conf.n_experts_used.min(n_experts);
+        let mut ys = T::alloc(
+            &[1, n_batch * n_used, embed_dim],
+            GGMLType::F32,
+            self.device.clone(),
+        )?
+        .resize(1, 0)?;
+        let mut y_weights = Vec::with_capacity(n_batch * n_used * embed_dim);
+        let mut y_rows = vec![vec![]; n_batch];
+        for (e, rows) in expert_rows.iter().enumerate() {
+            if rows.is_empty() {
+                continue;
+            }
+            let row_ids = rows.iter().map(|(row, _)| *row).collect::<Vec<_>>();
+            let mut x_expert =
+                T::alloc(&[rows.len(), embed_dim], GGMLType::F32, self.device.clone())?;
+            x_expert.copy_rows_from(&x, &row_ids)?;
+
+            let mut h1 = self.weights.ffn_gate_exps[l][e].matmul_vec(&x_expert)?;
+            let h2 = self.weights.ffn_up_exps[l][e].matmul_vec(&x_expert)?;
+            h1 = match activation {
+                Activation::SiLU => h1.silu_inplace()?,
+                Activation::GeLU => h1.gelu_inplace()?,
+            };
+            h1 = h1.mul_inplace(&h2)?;
+            let y = self.weights.ffn_down_exps[l][e].matmul_vec(&h1)?; // (n_rows, embed_dim)
+
+            for (i, (row, weight)) in rows.iter().enumerate() {
+                y_rows[*row].push(ys.shape()[1] + i);
+                y_weights.extend(std::iter::repeat(*weight).take(embed_dim));
+            }
+            ys.concatenate(&y.reshape(&[1, rows.len(), embed_dim])?, 1)?;
+        }
+
+        // scale the outputs by the weights of their experts at once
+        let y_weights = y_weights
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        let y_weights = T::from_cpu(
+            &y_weights,
+            &[n_batch * n_used, embed_dim],
+            GGMLType::F32,
+            self.device.clone(),
+        )?;
+        let ys = ys
+            .reshape(&[n_batch * n_used, embed_dim])?
+            .mul_inplace(&y_weights)?;
+
+        // add the k-th output of each row into the output of the batch
+        let mut out = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
+        for k in 0..n_used {
+            let rows = y_rows.iter().map(|rows| rows[k]).collect::<Vec<_>>();
+            if k == 0 {
+                out.copy_rows_from(&ys, &rows)?;
+                continue;
+            }
+            let mut y = T::alloc(&[n_batch, embed_dim], GGMLType::F32, self.device.clone())?;
+            y.copy_rows_from(&ys, &rows)?;
+            out = out.add_inplace(&y)?;
+        }
+        x = out;
+
+        // residual connection
+        x = x.add_inplace(&x_orig_ffn)?;
+        Ok(x)
+    }
+}
+
+// the kv caches of each layer in the layout of (n_kv_heads, capacity, head_size), which are
+// resized to 0 token
+fn alloc_kv_cache<T: Tensor>(
+    conf: &LlamaConfig,
+    capacity: usize,
+    dtype: GGMLType,
+    device: &T::DeviceRef,
+) -> Result<Vec<Option<T>>> {
+    (0..conf.n_layers)
+        .map(|_| {
+            T::alloc(
+                &[conf.n_kv_heads, capacity, conf.head_size()],
+                dtype,
+                device.clone(),
+            )
+            .and_then(|t| t.resize(1, 0))
+            .map(Some)
+        })
+        .collect()
+}
+
+// the top k experts of the router logits and their weights, which are the softmax over the
+// logits of the selected experts
+fn route_experts(logits: &[f32], k: usize) -> Vec<(usize, f32)> {
+    let mut experts = logits.iter().copied().enumerate().collect::<Vec<_>>();
+    experts.sort_by(|a, b| b.1.total_cmp(&a.1));
+    experts.truncate(k);
+    let max = experts[0].1;
+    let sum = experts.iter().map(|(_, v)| (v - max).exp()).sum::<f32>();
+    experts
+        .into_iter()
+        .map(|(e, v)| (e, (v - max).exp() / sum))
+        .collect()
+}
+
+// rotate the vectors which have been applied the rope by `delta` more positions, the angles
+// are the same as the rope kernels, while the magnitude has been scaled already
+fn rope_shift(buf: &mut [f32], mode: RopeMode, delta: f32, head_dim: usize, rope: &RopeParams) {
+    let rotate = |buf: &mut [f32], i: usize, j: usize, theta: f32| {
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        let (x0, x1) = (buf[i], buf[j]);
+        buf[i] = x0 * cos_theta - x1 * sin_theta;
+        buf[j] = x0 * sin_theta + x1 * cos_theta;
+    };
+    buf.chunks_exact_mut(head_dim).for_each(|chunk| {
+        for i in 0..rope.n_dims / 2 {
+            let theta = rope.theta(delta, i, head_dim);
+            match mode {
+                RopeMode::Llama => rotate(chunk, i * 2, i * 2 + 1, theta),
+                RopeMode::Neox => rotate(chunk, i, i + head_dim / 2, theta),
+            }
+        }
+    });
+}
+
+fn log_softmax(logits: &[f32], out: &mut [f32]) {
+    let max = logits.iter().fold(f32::NEG_INFINITY, |m, v| m.max(*v));
+    let sum = logits.iter().map(|v| (v - max).exp()).sum::<f32>();
+    let log_sum = max + sum.ln();
+    for (o, v) in out.iter_mut().zip(logits.iter()) {
+        *o = v - log_sum;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use crabml::cpu::CpuTensor;
+    use crabml::cpu::CpuTensorDeviceOptions;
+    use crabml::gguf::GGUFFile;
+    use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf::GGUFFileWriter;
+    use crabml::gguf::GGUFMetadataValue;
+    use crabml::gguf::GGUFTensorInfo;
+    use crabml_vulkan::vulkan_device::VulkanTensorDevice;
+    use crabml_vulkan::vulkan_device::VulkanTensorDeviceOptions;
+    use crabml_vulkan::vulkan_tensor::VulkanTensor;
+
+    use super::*;
+    use crate::model::CpuLlamaModel;
+    use crate::model::CpuLlamaModelLoader;
+    use crate::model::RopeScaling;
+    use crate::GpuLlamaModel;
+    use crate::Llama2Sampler;
+    use crate::Llama2SamplerOptions;
+
+    // a file in the temp dir, which is removed on drop even if the test fails
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("crabml-{}-{}", std::process::id(), name));
+            Self(path.to_str().unwrap().to_string())
+        }
+
+        fn path(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+
+    // a tensor in the rewritten gguf file, its data is in f32
+    type TensorData = (String, Vec<usize>, Vec<u8>);
+
+    fn f32_bytes(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn export_f32<T: Tensor>(t: &T) -> Result<Vec<f32>> {
+        let mut buf = vec![0.0; t.shape().iter().product()];
+        t.export(&mut buf)?;
+        Ok(buf)
+    }
+
+    // rewrite the 260k llama into the architecture. the llama metadata are renamed after the
+    // architecture, and the extra ones are prefixed with it. the tensors built by `add` are
+    // added or replaced, and the `remove` weights are dropped from each layer.
+    fn rewrite_260k_gguf(
+        path: &str,
+        arch: &str,
+        metadata: &[(&str, GGUFMetadataValue)],
+        add: impl FnOnce(&GGUFFile) -> Vec<TensorData>,
+        remove: &[&str],
+    ) -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let n_layers = gf.metadata().get_u32("llama.block_count").unwrap() as usize;
+        let tensors = add(&gf);
+
+        let mut w = GGUFFileWriter::from_gguf_file(&gf);
+        if arch != "llama" {
+            for (key, value) in gf.metadata().as_hashmap() {
+                if let Some(name) = key.strip_prefix("llama.") {
+                    w.remove_metadata(key);
+                    w.set_metadata(&format!("{}.{}", arch, name), value.clone());
+                }
+            }
+            w.set_metadata("general.architecture", GGUFMetadataValue::String(arch));
+        }
+        for (key, value) in metadata {
+            w.set_metadata(&format!("{}.{}", arch, key), value.clone());
+        }
+        for l in 0..n_layers {
+            for name in remove {
+                w.remove_tensor_info(&format!("blk.{}.{}.weight", l, name));
+            }
+        }
+        for (name, dims, data) in tensors.iter() {
+            w.set_tensor_info(GGUFTensorInfo::new(
+                name.clone(),
+                dims.clone(),
+                GGMLType::F32,
+                data,
+            ));
+        }
+        w.write_to_file(path)
+    }
+
+    #[test]
+    fn test_generate_f32() -> Result<()> {
+        let gl: GGUFFileLoader =
+            GGUFFileLoader::new("../testdata/tinyllamas-stories-15m-f32.gguf", false)?;
+        let gf = gl.open()?;
 
         let lm = CpuLlamaModelLoader::new().load(&gf)?;
 
@@ -734,6 +2231,1165 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_prefill_batched() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+
+        let lm = CpuLlamaModelLoader::new().with_thread_num(2).load(&gf)?;
+        let prompt = "Lily is a cute cat, she likes to play with her friends";
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _, token) = runner.prefill(prompt, true, false)?;
+        let logits = runner.logits.clone();
+        let output = runner
+            .generate(pos, token, Some(16))
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+
+        // the chunks are not aligned with the prompt, the last chunk is shorter
+        let mut runner_batched = Llama2Runner::new(&lm, 200, false)?.with_prefill_chunk_size(5);
+        let (pos_batched, _, token_batched) = runner_batched.prefill(prompt, true, true)?;
+        assert_eq!(pos_batched, pos);
+        assert_eq!(token_batched, token);
+        assert_relative_eq!(runner_batched.logits[..], logits[..], epsilon = 1e-4);
+
+        let output_batched = runner_batched
+            .generate(pos_batched, token_batched, Some(16))
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        assert_eq!(output_batched, output);
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_and_load_session() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let file = TempFile::new("session.bin");
+        let path = file.path();
+
+        for use_f16_kv_cache in [false, true] {
+            let mut runner = Llama2Runner::new(&lm, 200, use_f16_kv_cache)?;
+            runner.prefill("Lily is a cute cat", true, true)?;
+            runner.save_session(path)?;
+            let (pos, _, token) = runner.prefill(", she likes", false, true)?;
+            let output = runner
+                .generate(pos, token, Some(16))
+                .collect::<Result<Vec<String>>>()?;
+
+            // the restored runner only prefills the tokens after the saved prompt
+            let mut restored = Llama2Runner::new(&lm, 200, use_f16_kv_cache)?;
+            restored.load_session(path)?;
+            assert_eq!(
+                restored.tokens(),
+                &runner.tokens()[..restored.tokens().len()]
+            );
+            let (pos_restored, _, token_restored) = restored.prefill(", she likes", false, true)?;
+            assert_eq!((pos_restored, token_restored), (pos, token));
+            let output_restored = restored
+                .generate(pos_restored, token_restored, Some(16))
+                .collect::<Result<Vec<String>>>()?;
+            assert_eq!(output_restored, output);
+        }
+
+        // the rope base is part of the fingerprint
+        let mut other = CpuLlamaModelLoader::new().load(&gf)?;
+        other.conf.rope_freq_base *= 2.0;
+        let err = Llama2Runner::new(&other, 200, false)?
+            .load_session(path)
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+
+        // a corrupted n_kv_heads in the header is rejected before reading the kv caches
+        let mut buf = std::fs::read(path).unwrap();
+        buf[28..32].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(path, buf).unwrap();
+        let err = Llama2Runner::new(&lm, 200, false)?
+            .load_session(path)
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::FormatError);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefill_with_prefix_cache() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let tokenizer = lm.tokenizer.clone();
+        let prompt = tokenizer.encode("Lily is a cute cat, she likes to play", true, false)?;
+        let edited = tokenizer.encode("Lily is a cute cat, she likes to sing", true, false)?;
+
+        let mut fresh = Llama2Runner::new(&lm, 200, false)?;
+        let (pos_fresh, _, token_fresh) = fresh.prefill_tokens(&edited, true)?;
+        let output = fresh
+            .generate(pos_fresh, token_fresh, Some(16))
+            .collect::<Result<Vec<String>>>()?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _, token) = runner.prefill_tokens(&prompt, true)?;
+        runner.generate(pos, token, Some(16)).for_each(drop);
+        assert!(runner.tokens().len() > prompt.len());
+
+        // only the tokens after the common prefix are evaluated again
+        let (pos_cached, _, token_cached) = runner.prefill_with_prefix_cache(&edited, true)?;
+        assert_eq!((pos_cached, token_cached), (pos_fresh, token_fresh));
+        assert_eq!(runner.tokens(), &edited[..]);
+        let output_cached = runner
+            .generate(pos_cached, token_cached, Some(16))
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output_cached, output);
+
+        // the same prompt again only evaluates its last token
+        runner.prefill_with_prefix_cache(&edited, true)?;
+        assert_eq!(runner.tokens(), &edited[..]);
+        assert!(runner.truncate(edited.len() + 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewind() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let prompt = lm.tokenizer.encode("Lily is a cute cat", true, false)?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, last_token, token) = runner.prefill_tokens(&prompt, true)?;
+        let output = runner
+            .generate(pos, token, Some(16))
+            .collect::<Result<Vec<String>>>()?;
+
+        // rewind to before the last prompt token, and feed it again to get its logits back
+        runner.rewind(runner.tokens().len() - prompt.len() + 1)?;
+        assert_eq!(runner.tokens(), &prompt[..prompt.len() - 1]);
+        let (pos_rewound, _, token_rewound) = runner.prefill_tokens(&[last_token], true)?;
+        assert_eq!((pos_rewound, token_rewound), (pos, token));
+        let output_rewound = runner
+            .generate(pos_rewound, token_rewound, Some(16))
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output_rewound, output);
+
+        assert!(runner.rewind(runner.tokens().len() + 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewind_with_grammar() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let grammar = Arc::new(Grammar::parse(r#"root ::= " Lily" [a-z ]*"#)?);
+
+        // the grammar has not accepted any token before sampling
+        let prompt = lm.tokenizer.encode("Once upon a time,", true, false)?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_grammar(grammar);
+        runner.feed_tokens(&prompt, true)?;
+        runner.rewind(1)?;
+        let (pos, _, token) = runner.prefill_tokens(&prompt[prompt.len() - 1..], true)?;
+        let output = runner
+            .generate(pos, token, Some(8))
+            .collect::<Result<Vec<String>>>()?;
+        assert!(output.join("").starts_with(" Lily"));
+
+        // the grammar can not be rolled back with the kv cache, but restarts on reset
+        let err = runner.rewind(1).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        runner.reset()?;
+        assert_eq!(runner.grammar.as_ref().unwrap().n_accepted(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_context_shift() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let eos_token = CpuLlamaModelLoader::new().load(&gf)?.tokenizer.eos_token();
+        // ban the eos token to generate past the context length
+        let options = Llama2SamplerOptions::default().with_logit_bias(eos_token, f32::NEG_INFINITY);
+        let lm = CpuLlamaModelLoader::new()
+            .with_sampler_options(options)
+            .load(&gf)?;
+        let seq_len = lm.conf.seq_len;
+
+        let mut runner = Llama2Runner::new(&lm, seq_len, false)?;
+        let (pos, _, token) = runner.prefill("Lily is a cute cat", true, false)?;
+        let steps = seq_len + 100;
+        let output = runner
+            .generate(pos, token, Some(steps))
+            .collect::<Result<Vec<String>>>()?;
+        assert!(output.len() < steps);
+        assert_eq!(runner.stop_reason(), Some(StopReason::ContextFull));
+
+        let mut runner = Llama2Runner::new(&lm, seq_len, false)?.with_context_shift(1);
+        let (pos, _, token) = runner.prefill("Lily is a cute cat", true, false)?;
+        let output = runner
+            .generate(pos, token, Some(steps))
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output.len(), steps);
+        assert_eq!(runner.stop_reason(), Some(StopReason::MaxSteps));
+        assert!(runner.tokens().len() < seq_len);
+        assert_eq!(runner.tokens()[0], lm.tokenizer.bos_token());
+
+        // the keys of the first layer only depend on the token and its position, so the
+        // rotated keys are the same as evaluating the kept tokens from scratch
+        let mut fresh = Llama2Runner::new(&lm, seq_len, false)?;
+        fresh.feed_tokens(runner.tokens(), true)?;
+        let (keys, values) = runner.export_kv_cache()?;
+        let (keys_fresh, values_fresh) = fresh.export_kv_cache()?;
+        assert_relative_eq!(keys[0][..], keys_fresh[0][..], epsilon = 1e-3);
+        assert_relative_eq!(values[0][..], values_fresh[0][..], epsilon = 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sliding_window() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let eos_token = CpuLlamaModelLoader::new().load(&gf)?.tokenizer.eos_token();
+        let options = Llama2SamplerOptions::default().with_logit_bias(eos_token, f32::NEG_INFINITY);
+        let mut lm = CpuLlamaModelLoader::new()
+            .with_sampler_options(options)
+            .load(&gf)?;
+        let prompt = "Lily is a cute cat, she likes to play with her friends";
+        let steps = 100;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let output_full = runner
+            .prefill_and_generate(prompt, steps)?
+            .collect::<Result<Vec<String>>>()?;
+
+        lm.conf.sliding_window = Some(16);
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _, token) = runner.prefill(prompt, true, false)?;
+        let logits = runner.logits.clone();
+        let output = runner
+            .generate(pos, token, Some(steps))
+            .collect::<Result<Vec<String>>>()?;
+        assert_ne!(output, output_full);
+
+        // the batched prefill masks the keys out of the window of each row
+        let mut runner_batched = Llama2Runner::new(&lm, 200, false)?.with_prefill_chunk_size(5);
+        let (_, _, token_batched) = runner_batched.prefill(prompt, true, true)?;
+        assert_eq!(token_batched, token);
+        assert_relative_eq!(runner_batched.logits[..], logits[..], epsilon = 1e-4);
+
+        // the rolling kv cache holds at most 16 + 4 tokens, the oldest ones are dropped
+        let mut runner_rolling = Llama2Runner::new(&lm, 200, false)?
+            .with_prefill_chunk_size(4)
+            .with_rolling_kv_cache()?;
+        let (pos_rolling, _, token_rolling) = runner_rolling.prefill(prompt, true, true)?;
+        assert_eq!(pos_rolling, pos);
+        assert_eq!(token_rolling, token);
+        assert_relative_eq!(runner_rolling.logits[..], logits[..], epsilon = 1e-4);
+        let output_rolling = runner_rolling
+            .generate(pos_rolling, token_rolling, Some(steps))
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output_rolling, output);
+        assert!(runner_rolling.kv_rows() <= 20);
+        assert_eq!(runner_rolling.kv_cache_len(), runner_rolling.tokens().len());
+
+        let err = runner_rolling.truncate(pos).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        runner_rolling.reset()?;
+        assert_eq!(runner_rolling.kv_cache_len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rope_scaling() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let mut lm = CpuLlamaModelLoader::new().load(&gf)?;
+        // the keys of the first layer only depend on the token and its position, with the
+        // linear scaling of 2, the key at the position 2 is rotated like the position 1
+        let tokens = [1, 400, 400, 400];
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        runner.feed_tokens(&tokens, true)?;
+        let (keys, _) = runner.export_kv_cache()?;
+
+        lm.conf.rope_freq_base = 500000.0;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        runner.feed_tokens(&tokens, true)?;
+        let (keys_base, _) = runner.export_kv_cache()?;
+        assert_relative_eq!(keys_base[0][..8], keys[0][..8], epsilon = 1e-5);
+        assert!((keys_base[0][8..16].iter())
+            .zip(keys[0][8..16].iter())
+            .any(|(a, b)| (a - b).abs() > 1e-3));
+
+        lm.conf.rope_freq_base = 10000.0;
+        lm.conf.rope_scaling = RopeScaling::Linear { factor: 2.0 };
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        runner.feed_tokens(&tokens, true)?;
+        let (keys_scaled, _) = runner.export_kv_cache()?;
+        // the kv cache is in the layout of (n_kv_heads, n_tokens, head_size)
+        let head_size = lm.conf.head_size();
+        let row = |keys: &[f32], pos: usize| keys[pos * head_size..(pos + 1) * head_size].to_vec();
+        assert_relative_eq!(
+            row(&keys_scaled[0], 2)[..],
+            row(&keys[0], 1)[..],
+            epsilon = 1e-5
+        );
+        Ok(())
+    }
+
+    // turn the 260k llama into a phi3 by fusing the q, k and v weights into attn_qkv, and the
+    // gate and up weights into ffn_up. with the longrope, the short factors are all 1 and the
+    // long ones are all 2, the long ones are taken as the context is longer than 256.
+    fn write_phi3_gguf(path: &str, long_rope: bool) -> Result<()> {
+        let add = |gf: &GGUFFile| {
+            let n_layers = gf.metadata().get_u32("llama.block_count").unwrap() as usize;
+            let mut tensors = vec![];
+            for l in 0..n_layers {
+                let info = |name: &str| gf.get_tensor_info(&format!("blk.{}.{}.weight", l, name));
+                let (q, k, v) = (
+                    info("attn_q").unwrap(),
+                    info("attn_k").unwrap(),
+                    info("attn_v").unwrap(),
+                );
+                let (gate, up) = (info("ffn_gate").unwrap(), info("ffn_up").unwrap());
+                tensors.push((
+                    format!("blk.{}.attn_qkv.weight", l),
+                    vec![q.dimensions()[0], q.dimensions()[1] + 2 * k.dimensions()[1]],
+                    [q.data(), k.data(), v.data()].concat(),
+                ));
+                tensors.push((
+                    format!("blk.{}.ffn_up.weight", l),
+                    vec![gate.dimensions()[0], 2 * gate.dimensions()[1]],
+                    [gate.data(), up.data()].concat(),
+                ));
+            }
+            if long_rope {
+                tensors.push((
+                    "rope_factors_short.weight".to_string(),
+                    vec![4],
+                    f32_bytes(&[1.0; 4]),
+                ));
+                tensors.push((
+                    "rope_factors_long.weight".to_string(),
+                    vec![4],
+                    f32_bytes(&[2.0; 4]),
+                ));
+            }
+            tensors
+        };
+        rewrite_260k_gguf(
+            path,
+            "phi3",
+            &[(
+                "rope.scaling.original_context_length",
+                GGUFMetadataValue::U32(256),
+            )],
+            add,
+            &["attn_q", "attn_k", "attn_v", "ffn_gate"],
+        )
+    }
+
+    #[test]
+    fn test_phi3() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let mut lm_llama = CpuLlamaModelLoader::new().load(&gf)?;
+        let tokens = [1, 400, 401, 402, 403];
+        let logprobs = |lm: &CpuLlamaModel, n_ctx: usize| -> Result<Vec<f32>> {
+            Llama2Runner::new(lm, n_ctx, false)?.eval_logprobs(&tokens, 0)
+        };
+        let logprobs_llama = logprobs(&lm_llama, 200)?;
+
+        for long_rope in [false, true] {
+            let file = TempFile::new(&format!("phi3-{}.gguf", long_rope));
+            write_phi3_gguf(file.path(), long_rope)?;
+            let gl = GGUFFileLoader::new(file.path(), false)?;
+            let gf = gl.open()?;
+            let mut lm = CpuLlamaModelLoader::new().load(&gf)?;
+            assert_eq!(lm.conf.architecture, ModelArchitecture::Phi3);
+
+            // the split weights are the same as the llama ones
+            let (w, w_llama) = (&lm.weights, &lm_llama.weights);
+            assert_eq!(export_f32(&w.wq[1])?, export_f32(&w_llama.wq[1])?);
+            assert_eq!(export_f32(&w.wk[1])?, export_f32(&w_llama.wk[1])?);
+            assert_eq!(export_f32(&w.wv[1])?, export_f32(&w_llama.wv[1])?);
+            assert_eq!(
+                export_f32(&w.ffn_gate_weight[1])?,
+                export_f32(&w_llama.ffn_gate_weight[1])?
+            );
+            assert_eq!(
+                export_f32(&w.ffn_up_weight[1])?,
+                export_f32(&w_llama.ffn_up_weight[1])?
+            );
+
+            // the model extends the original context of 256 to 512, the attn factor missing
+            // in the metadata is derived from it. it's reset to 1 to compare with llama below.
+            if let RopeScaling::LongRope { attn_factor, .. } = &mut lm.conf.rope_scaling {
+                assert_relative_eq!(*attn_factor, (1.0 + 2.0_f32.ln() / 256.0_f32.ln()).sqrt());
+                *attn_factor = 1.0;
+            }
+
+            // phi3 rotates the halves of the heads, so it differs from llama
+            let logprobs_phi3 = logprobs(&lm, 200)?;
+            assert!((logprobs_phi3.iter())
+                .zip(logprobs_llama.iter())
+                .any(|(a, b)| (a - b).abs() > 1e-3));
+
+            // the same as the llama weights running as phi3. the runner within the original
+            // context takes the short factors of 1, which keep the angles. the runner beyond it
+            // takes the long factors of 2, the angles are the same as the linear scaling of 2.
+            lm_llama.conf.architecture = ModelArchitecture::Phi3;
+            let cases = if long_rope {
+                assert!(matches!(lm.conf.rope_scaling, RopeScaling::LongRope { .. }));
+                vec![
+                    (200, RopeScaling::None),
+                    (256, RopeScaling::None),
+                    (512, RopeScaling::Linear { factor: 2.0 }),
+                ]
+            } else {
+                assert_eq!(lm.conf.rope_scaling, RopeScaling::None);
+                vec![(200, RopeScaling::None), (512, RopeScaling::None)]
+            };
+            for (n_ctx, rope_scaling) in cases {
+                lm_llama.conf.rope_scaling = rope_scaling;
+                assert_relative_eq!(
+                    logprobs(&lm, n_ctx)?[..],
+                    logprobs(&lm_llama, n_ctx)?[..],
+                    epsilon = 1e-4
+                );
+            }
+            lm_llama.conf.architecture = ModelArchitecture::Llama;
+            lm_llama.conf.rope_scaling = RopeScaling::None;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_long_rope_with_bad_factors() -> Result<()> {
+        // the rope of the 260k model has 8 dimensions, so 4 factors are expected
+        let file = TempFile::new("bad-rope-factors.gguf");
+        let add = |_: &GGUFFile| {
+            ["rope_factors_short.weight", "rope_factors_long.weight"]
+                .iter()
+                .map(|name| (name.to_string(), vec![3], f32_bytes(&[1.0; 3])))
+                .collect()
+        };
+        rewrite_260k_gguf(file.path(), "llama", &[], add, &[])?;
+        let gl = GGUFFileLoader::new(file.path(), false)?;
+        let gf = gl.open()?;
+        let err = CpuLlamaModelLoader::new().load(&gf).err().unwrap();
+        assert_eq!(err.kind, ErrorKind::ModelError);
+        Ok(())
+    }
+
+    // turn the 260k llama into a gpt2, a starcoder2 or a phi2 with the layernorms and the
+    // biases, the gate of the ffn is dropped. gpt2 and phi2 take the fused qkv, and gpt2 takes
+    // the position embeddings, which are zeros at the position 0, so gpt2 and starcoder2 are
+    // the same on the first token.
+    fn write_layer_norm_gguf(path: &str, arch: &str) -> Result<()> {
+        let bias = |n: usize, seed: usize| -> Vec<f32> {
+            (0..n)
+                .map(|i| ((i * 7 + seed * 13) as f32 * 0.31).sin() * 0.1)
+                .collect()
+        };
+        let add = |gf: &GGUFFile| {
+            let n_layers = gf.metadata().get_u32("llama.block_count").unwrap() as usize;
+            let seq_len = gf.metadata().get_u32("llama.context_length").unwrap() as usize;
+            let embed_dim = gf
+                .get_tensor_info("token_embd.weight")
+                .unwrap()
+                .dimensions()[0];
+            let mut tensors = vec![];
+            for l in 0..n_layers {
+                let info = |name: &str| gf.get_tensor_info(&format!("blk.{}.{}.weight", l, name));
+                let (q, k, v) = (
+                    info("attn_q").unwrap(),
+                    info("attn_k").unwrap(),
+                    info("attn_v").unwrap(),
+                );
+                let kv_dim = k.dimensions()[1];
+                let (bq, bk, bv) = (bias(embed_dim, l), bias(kv_dim, l + 1), bias(kv_dim, l + 2));
+                if arch != "starcoder2" {
+                    tensors.push((
+                        format!("blk.{}.attn_qkv.weight", l),
+                        vec![embed_dim, embed_dim + 2 * kv_dim],
+                        [q.data(), k.data(), v.data()].concat(),
+                    ));
+                    tensors.push((
+                        format!("blk.{}.attn_qkv.bias", l),
+                        vec![embed_dim + 2 * kv_dim],
+                        f32_bytes(&[bq, bk, bv].concat()),
+                    ));
+                } else {
+                    for (name, b) in [("attn_q", bq), ("attn_k", bk), ("attn_v", bv)] {
+                        tensors.push((
+                            format!("blk.{}.{}.bias", l, name),
+                            vec![b.len()],
+                            f32_bytes(&b),
+                        ));
+                    }
+                }
+                let hidden_dim = info("ffn_up").unwrap().dimensions()[1];
+                for (name, n, seed) in [
+                    ("attn_output", embed_dim, 3),
+                    ("attn_norm", embed_dim, 4),
+                    ("ffn_norm", embed_dim, 5),
+                    ("ffn_up", hidden_dim, 6),
+                    ("ffn_down", embed_dim, 7),
+                ] {
+                    tensors.push((
+                        format!("blk.{}.{}.bias", l, name),
+                        vec![n],
+                        f32_bytes(&bias(n, l + seed)),
+                    ));
+                }
+            }
+            tensors.push((
+                "output_norm.bias".to_string(),
+                vec![embed_dim],
+                f32_bytes(&bias(embed_dim, 8)),
+            ));
+            if arch == "gpt2" {
+                let mut pos_embed = bias(seq_len * embed_dim, 9);
+                pos_embed[..embed_dim].fill(0.0);
+                tensors.push((
+                    "position_embd.weight".to_string(),
+                    vec![embed_dim, seq_len],
+                    f32_bytes(&pos_embed),
+                ));
+            }
+            tensors
+        };
+        let remove: &[&str] = match arch {
+            "gpt2" | "phi2" => &["ffn_gate", "attn_q", "attn_k", "attn_v"],
+            _ => &["ffn_gate"],
+        };
+        rewrite_260k_gguf(
+            path,
+            arch,
+            &[("attention.layer_norm_epsilon", GGUFMetadataValue::F32(1e-5))],
+            add,
+            remove,
+        )
+    }
+
+    #[test]
+    fn test_gpt2_and_starcoder2() -> Result<()> {
+        let (file_gpt2, file_starcoder2) =
+            (TempFile::new("gpt2.gguf"), TempFile::new("starcoder2.gguf"));
+        write_layer_norm_gguf(file_gpt2.path(), "gpt2")?;
+        write_layer_norm_gguf(file_starcoder2.path(), "starcoder2")?;
+        let gl_gpt2 = GGUFFileLoader::new(file_gpt2.path(), false)?;
+        let gf_gpt2 = gl_gpt2.open()?;
+        let lm_gpt2 = CpuLlamaModelLoader::new().load(&gf_gpt2)?;
+        let gl_starcoder2 = GGUFFileLoader::new(file_starcoder2.path(), false)?;
+        let gf_starcoder2 = gl_starcoder2.open()?;
+        let lm_starcoder2 = CpuLlamaModelLoader::new().load(&gf_starcoder2)?;
+        assert_eq!(lm_gpt2.conf.architecture, ModelArchitecture::Gpt2);
+        assert_eq!(
+            lm_starcoder2.conf.architecture,
+            ModelArchitecture::StarCoder2
+        );
+        assert!(lm_gpt2.weights.pos_embed.is_some());
+        assert!(lm_starcoder2.weights.pos_embed.is_none());
+
+        // the fused qkv and its bias are split the same as the separated ones
+        let (w1, w2) = (&lm_gpt2.weights, &lm_starcoder2.weights);
+        assert_eq!(export_f32(&w1.wk[1])?, export_f32(&w2.wk[1])?);
+        assert_eq!(export_f32(&w1.bq[1])?, export_f32(&w2.bq[1])?);
+        assert_eq!(export_f32(&w1.bv[1])?, export_f32(&w2.bv[1])?);
+
+        // the same on the first token, but differ on the following ones
+        let logprobs = |lm: &CpuLlamaModel, tokens: &[usize], chunk: usize| -> Result<Vec<f32>> {
+            Llama2Runner::new(lm, 200, false)?
+                .with_prefill_chunk_size(chunk)
+                .eval_logprobs(tokens, 0)
+        };
+        let tokens = [1, 400, 401, 402, 403, 404];
+        assert_relative_eq!(
+            logprobs(&lm_gpt2, &tokens[..1], 1)?[..],
+            logprobs(&lm_starcoder2, &tokens[..1], 1)?[..],
+            epsilon = 1e-5
+        );
+        let logprobs_gpt2 = logprobs(&lm_gpt2, &tokens, 1)?;
+        assert!((logprobs_gpt2.iter())
+            .zip(logprobs(&lm_starcoder2, &tokens, 1)?.iter())
+            .any(|(a, b)| (a - b).abs() > 1e-3));
+
+        // the batched prefill adds the position embedding of each row
+        assert_relative_eq!(
+            logprobs(&lm_gpt2, &tokens, 4)?[..],
+            logprobs_gpt2[..],
+            epsilon = 1e-4
+        );
+
+        // the sequences at different positions are decoded in one forward
+        let prompts: [&[usize]; 2] = [&[1, 400, 401], &[1, 402]];
+        let mut expected = vec![];
+        for prompt in prompts {
+            let mut runner = Llama2Runner::new(&lm_gpt2, 200, false)?;
+            let (pos, _, token) = runner.prefill_with_prefix_cache(prompt, true)?;
+            let output = runner
+                .generate(pos, token, Some(10))
+                .collect::<Result<Vec<String>>>()?;
+            expected.push(output.join(""));
+        }
+        let mut batch = crate::Llama2BatchRunner::new(2);
+        for prompt in prompts {
+            batch.add_sequence(Llama2Runner::new(&lm_gpt2, 200, false)?, prompt, 10)?;
+        }
+        let mut outputs = vec![String::new(); 2];
+        while batch.n_active() > 0 {
+            for token in batch.step()? {
+                outputs[token.seq_id].push_str(&token.piece);
+            }
+        }
+        assert_eq!(outputs, expected);
+        assert!(outputs.iter().all(|output| !output.is_empty()));
+
+        // the learned positions can not be shifted
+        let mut runner = Llama2Runner::new(&lm_gpt2, 200, false)?.with_context_shift(1);
+        runner.feed_tokens(&tokens, true)?;
+        let err = runner.shift_context(1).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_phi2() -> Result<()> {
+        let (file_phi2, file_starcoder2) = (
+            TempFile::new("phi2.gguf"),
+            TempFile::new("phi2-starcoder2.gguf"),
+        );
+        write_layer_norm_gguf(file_phi2.path(), "phi2")?;
+        write_layer_norm_gguf(file_starcoder2.path(), "starcoder2")?;
+        let gl = GGUFFileLoader::new(file_phi2.path(), false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let gl_starcoder2 = GGUFFileLoader::new(file_starcoder2.path(), false)?;
+        let gf_starcoder2 = gl_starcoder2.open()?;
+        let lm_starcoder2 = CpuLlamaModelLoader::new().load(&gf_starcoder2)?;
+        assert_eq!(lm.conf.architecture, ModelArchitecture::Phi2);
+
+        // the fused qkv and its bias are split the same as the separated ones
+        let (w, w_starcoder2) = (&lm.weights, &lm_starcoder2.weights);
+        assert_eq!(export_f32(&w.wq[1])?, export_f32(&w_starcoder2.wq[1])?);
+        assert_eq!(export_f32(&w.wv[1])?, export_f32(&w_starcoder2.wv[1])?);
+        assert_eq!(export_f32(&w.bk[1])?, export_f32(&w_starcoder2.bk[1])?);
+
+        // the first token only attends itself, so the attention outputs its v. the attention
+        // and the ffn are in parallel on the same layernorm, and added to the residual.
+        let (embed_dim, head_dim, eps) = (lm.conf.embedding_dim, lm.conf.head_size(), 1e-5);
+        let n_groups = lm.conf.n_heads / lm.conf.n_kv_heads;
+        let mut x = CpuTensor::alloc(&[1, embed_dim], GGMLType::F32, lm.device.clone())?;
+        x.copy_rows_from(&w.token_embed, &[1])?;
+        for l in 0..lm.conf.n_layers {
+            let x_norm =
+                x.dup()?
+                    .layer_norm_inplace(&w.rms_att_weight[l], &w.rms_att_bias[l], eps)?;
+            let v = export_f32(&w.wv[l].matmul_vec(&x_norm)?.add_inplace(&w.bv[l])?)?;
+            let attn = (0..embed_dim)
+                .map(|i| v[i / head_dim / n_groups * head_dim + i % head_dim])
+                .collect::<Vec<_>>();
+            let attn = CpuTensor::from_cpu(
+                &f32_bytes(&attn),
+                &[1, embed_dim],
+                GGMLType::F32,
+                lm.device.clone(),
+            )?;
+            let attn = w.wo[l].matmul_vec(&attn)?.add_inplace(&w.bo[l])?;
+            let ffn = w.ffn_up_weight[l]
+                .matmul_vec(&x_norm)?
+                .add_inplace(&w.ffn_up_bias[l])?
+                .gelu_inplace()?;
+            let ffn = w.ffn_down_weight[l]
+                .matmul_vec(&ffn)?
+                .add_inplace(&w.ffn_down_bias[l])?;
+            x = x.add_inplace(&attn)?.add_inplace(&ffn)?;
+        }
+        let x =
+            x.layer_norm_inplace(&w.rms_final_weight, w.rms_final_bias.as_ref().unwrap(), eps)?;
+        let output_weight = w.output_weight.as_ref().unwrap_or(&w.token_embed);
+        let expected = export_f32(&output_weight.matmul_vec(&x)?)?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        runner.forward(&[1], 0)?;
+        assert_relative_eq!(runner.logits[..], expected[..], epsilon = 1e-4);
+
+        // the batched prefill is the same as the one token by one
+        let logprobs = |chunk: usize| -> Result<Vec<f32>> {
+            Llama2Runner::new(&lm, 200, false)?
+                .with_prefill_chunk_size(chunk)
+                .eval_logprobs(&[1, 400, 401, 402, 403, 404], 0)
+        };
+        assert_relative_eq!(logprobs(4)?[..], logprobs(1)?[..], epsilon = 1e-4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_penalties() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+
+        let options = Llama2SamplerOptions::default()
+            .with_repeat_last_n(16)
+            .with_presence_penalty(100.0);
+        let lm = CpuLlamaModelLoader::new()
+            .with_sampler_options(options)
+            .load(&gf)?;
+        let tokenizer = lm.tokenizer.clone();
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _, token) = runner.prefill("Lily is a cute cat", true, false)?;
+        let output = runner
+            .generate(pos, token, Some(40))
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output.len(), 40);
+        assert_eq!(runner.history.len(), 16);
+
+        // with a huge presence penalty, no token repeats inside the window except the
+        // exempted newline and special tokens
+        let history = &runner.history;
+        for (i, token) in history.iter().enumerate() {
+            if tokenizer.newline_token() == Some(*token) || tokenizer.is_special_token(*token) {
+                continue;
+            }
+            assert!(!history[i + 1..].contains(token), "{:?}", history);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_seeded() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+
+        let options = Llama2SamplerOptions::default()
+            .with_temperature(1.0)
+            .with_top_p(0.9)
+            .with_seed(Some(42));
+        let lm = CpuLlamaModelLoader::new()
+            .with_sampler_options(options)
+            .load(&gf)?;
+
+        let generate = || -> Result<String> {
+            let mut runner = Llama2Runner::new(&lm, 200, false)?;
+            let output = runner
+                .prefill_and_generate("Lily is a cute cat", 20)?
+                .collect::<Result<Vec<String>>>()?;
+            Ok(output.join(""))
+        };
+        // the same seed gives the exact same text even with a non-zero temperature
+        let output = generate()?;
+        assert_eq!(output, " named Max. She was ate blue chicken.");
+        assert_eq!(generate()?, output);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_tokens() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let output = runner
+            .prefill_and_generate("Lily is a cute cat", 16)?
+            .collect::<Result<Vec<String>>>()?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _, token) = runner.prefill("Lily is a cute cat", true, false)?;
+        let tokens = runner
+            .generate_tokens(pos, token, Some(16), 3)
+            .collect::<Result<Vec<_>>>()?;
+        let pieces = tokens.iter().map(|t| t.piece.clone()).collect::<Vec<_>>();
+        assert_eq!(pieces, output);
+
+        for t in tokens.iter() {
+            // the greedy sampling always takes the most likely token
+            assert_eq!(t.top_logprobs.len(), 3);
+            assert_eq!(t.top_logprobs[0], (t.token, t.logprob));
+            assert!(t.top_logprobs.windows(2).all(|w| w[0].1 >= w[1].1));
+            let probs = t.top_logprobs.iter().map(|(_, lp)| lp.exp()).sum::<f32>();
+            assert!(t.logprob <= 0.0 && probs <= 1.0 + 1e-5);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_logprobs() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+
+        let prompt = "Lily is a cute cat";
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _, token) = runner.prefill(prompt, true, false)?;
+        let generated = runner
+            .generate_tokens(pos, token, Some(10), 0)
+            .collect::<Result<Vec<_>>>()?;
+
+        // scoring the prompt and the generated tokens in one pass gives the same logprobs as
+        // the ones on generating, the chunks are not aligned with the tokens
+        let mut tokens = lm.tokenizer.encode(prompt, true, false)?;
+        tokens.extend(generated.iter().map(|g| g.token));
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_prefill_chunk_size(4);
+        let logprobs = runner.eval_logprobs(&tokens, 0)?;
+        assert_eq!(logprobs.len(), tokens.len() - 1);
+        let expected = generated.iter().map(|g| g.logprob).collect::<Vec<_>>();
+        assert_relative_eq!(logprobs[pos - 1..], expected[..], epsilon = 1e-3);
+        assert_eq!(runner.kv_cache_len(), tokens.len());
+
+        // the runner is reused from the position 0 after reset, like the perplexity chunks
+        runner.reset()?;
+        assert_relative_eq!(runner.eval_logprobs(&tokens, 0)?[..], logprobs[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_logit_bias() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let tokenizer = lm.tokenizer.clone();
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let (pos, _, token) = runner.prefill("Lily is a cute cat", true, false)?;
+        let output = runner
+            .generate(pos, token, Some(20))
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+
+        // ban the tokens of the first word of the greedy output, and bias a token to be
+        // always picked after it
+        let banned = output.split_whitespace().next().unwrap().to_string();
+        let banned_tokens = tokenizer.encode(&banned, false, false)?;
+        let sampler = Llama2Sampler::from_options(
+            &Llama2SamplerOptions::default().with_banned_string(banned.clone()),
+            lm.device.exp_cache(),
+        )?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_sampler(sampler)?;
+        let (pos, _, token) = runner.prefill("Lily is a cute cat", true, false)?;
+        assert!(!banned_tokens.contains(&token));
+        runner
+            .generate(pos, token, Some(20))
+            .collect::<Result<Vec<String>>>()?;
+        let generated = &runner.history[pos..];
+        assert!(generated.iter().all(|t| !banned_tokens.contains(t)));
+
+        // the encoded text is prefixed with a space token
+        let dot = *tokenizer.encode(".", false, false)?.last().unwrap();
+        let sampler = Llama2Sampler::from_options(
+            &Llama2SamplerOptions::default().with_logit_bias(dot, 100.0),
+            lm.device.exp_cache(),
+        )?;
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_sampler(sampler)?;
+        let output = runner
+            .prefill_and_generate("Lily is a cute cat", 5)?
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output, vec!["."; 5]);
+
+        // the token ids out of the vocab are rejected
+        let sampler = Llama2Sampler::from_options(
+            &Llama2SamplerOptions::default().with_logit_bias(100000, 1.0),
+            lm.device.exp_cache(),
+        )?;
+        let err = Llama2Runner::new(&lm, 200, false)?
+            .with_sampler(sampler)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_grammar() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+
+        let options = Llama2SamplerOptions::default()
+            .with_temperature(1.0)
+            .with_seed(Some(42));
+        let lm = CpuLlamaModelLoader::new()
+            .with_sampler_options(options)
+            .load(&gf)?;
+
+        let grammar = Arc::new(Grammar::parse(
+            r#"
+root ::= " " name " is " ("happy" | "sad") ". " name " likes " [a-z]{1,8} "."
+name ::= "Lily" | "Tom"
+"#,
+        )?);
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_grammar(grammar.clone());
+        let output = runner
+            .prefill_and_generate("Once upon a time,", 100)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        // the generation stops on eos right after the grammar is completed
+        assert!(grammar.matches(&output), "{}", output);
+        assert!(runner.grammar.as_ref().unwrap().is_complete());
+        assert_eq!(runner.stop_reason(), Some(StopReason::Eos));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_with_grammar_dead_end() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let eos_token = CpuLlamaModelLoader::new().load(&gf)?.tokenizer.eos_token();
+        let options = Llama2SamplerOptions::default().with_logit_bias(eos_token, f32::NEG_INFINITY);
+        let lm = CpuLlamaModelLoader::new()
+            .with_sampler_options(options)
+            .load(&gf)?;
+
+        // only the eos token is allowed after "a", but it's banned, the error is yielded and
+        // the generation stops on it
+        let grammar = Arc::new(Grammar::parse(r#"root ::= "a""#)?);
+        let mut runner = Llama2Runner::new(&lm, 200, false)?.with_grammar(grammar);
+        let output = runner
+            .prefill_and_generate("Once upon a time,", 10)?
+            .collect::<Vec<_>>();
+        assert_eq!(output.len(), 2);
+        assert_eq!(output[0].as_deref().ok(), Some("a"));
+        assert_eq!(output[1].as_ref().unwrap_err().kind, ErrorKind::Unexpected);
+        assert_eq!(runner.stop_reason(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_beam() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        let prompt = "Lily is a cute cat";
+
+        // the beam search of width 1 is the greedy search
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let output = runner
+            .prefill_and_generate(prompt, 30)?
+            .collect::<Result<Vec<String>>>()?
+            .join("");
+        // the beam search does not drop the existing kv cache silently
+        let err = runner.generate_beam(prompt, 1, 30, 1.0).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        runner.reset()?;
+        let hypotheses = runner.generate_beam(prompt, 1, 30, 1.0)?;
+        assert_eq!(hypotheses.len(), 1);
+        assert_eq!(hypotheses[0].text, output);
+
+        runner.reset()?;
+        let hypotheses = runner.generate_beam(prompt, 4, 30, 1.0)?;
+        assert_eq!(hypotheses.len(), 4);
+        assert!(hypotheses
+            .windows(2)
+            .all(|w| w[0].normalized_score >= w[1].normalized_score));
+        // only the prompt is kept in the kv cache
+        let prompt_tokens = runner.tokenizer().encode(prompt, true, false)?;
+        assert_eq!(runner.tokens(), &prompt_tokens[..]);
+
+        // the scores match the log probabilities evaluated without the forked kv caches
+        let eos_token = runner.tokenizer().eos_token();
+        for hypothesis in &hypotheses {
+            let mut tokens = prompt_tokens.clone();
+            tokens.extend_from_slice(&hypothesis.tokens);
+            if hypothesis.tokens.len() < 30 {
+                tokens.push(eos_token);
+            }
+            let mut runner = Llama2Runner::new(&lm, 200, false)?;
+            let logprobs = runner.eval_logprobs(&tokens, 0)?;
+            let score = logprobs[prompt_tokens.len() - 1..].iter().sum::<f32>();
+            assert_relative_eq!(hypothesis.score, score, epsilon = 1e-3);
+        }
+
+        let err = runner.generate_beam(prompt, 0, 30, 1.0).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BadInput);
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_kv_cache_from() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+
+        for use_f16_kv_cache in [false, true] {
+            let mut runner = Llama2Runner::new(&lm, 200, use_f16_kv_cache)?;
+            runner.feed_tokens(&[1, 400, 401, 402], true)?;
+            let mut forked = runner.fork()?;
+            assert_eq!(forked.tokens(), runner.tokens());
+            assert_eq!(forked.export_kv_cache()?, runner.export_kv_cache()?);
+
+            // the forked runner diverges after 3 tokens, and copies the rows after them back
+            forked.rewind(1)?;
+            forked.feed_tokens(&[500, 501], true)?;
+            runner.feed_tokens(&[403], true)?;
+            let expected = runner.export_kv_cache()?;
+            forked.copy_kv_cache_from(&mut runner)?;
+            assert_eq!(forked.tokens(), &[1, 400, 401, 402, 403]);
+            assert_eq!(forked.export_kv_cache()?, expected);
+            assert_eq!(runner.export_kv_cache()?, expected);
+
+            // both of them go on from the same kv caches
+            runner.feed_tokens(&[404], true)?;
+            forked.feed_tokens(&[404], true)?;
+            assert_eq!(forked.logits, runner.logits);
+        }
+        Ok(())
+    }
+
+    // turn the dense 260k model into a moe of 4 experts with 2 used on each token. the experts
+    // share the gate and up weights of the dense ffn, while the down weights of the expert e
+    // are scaled by (e + 1) / 2, so its output is the dense output scaled by the same factor.
+    fn write_moe_gguf(path: &str) -> Result<()> {
+        let n_experts = 4;
+        let f32_values = |data: &[u8]| -> Vec<f32> {
+            data.chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect()
+        };
+        let add = |gf: &GGUFFile| {
+            let n_layers = gf.metadata().get_u32("llama.block_count").unwrap() as usize;
+            let mut tensors = vec![];
+            for l in 0..n_layers {
+                let info = |name: &str| gf.get_tensor_info(&format!("blk.{}.{}.weight", l, name));
+                let (gate, up, down) = (
+                    info("ffn_gate").unwrap(),
+                    info("ffn_up").unwrap(),
+                    info("ffn_down").unwrap(),
+                );
+                let embed_dim = gate.dimensions()[0];
+                let exps_dims = |dims: &[usize]| vec![dims[0], dims[1], n_experts];
+                let router = (0..n_experts * embed_dim)
+                    .map(|i| ((i * 7 + l * 3) as f32 * 0.37).sin())
+                    .collect::<Vec<_>>();
+                let down_values = f32_values(down.data());
+                let down_exps = (0..n_experts)
+                    .flat_map(|e| down_values.iter().map(move |v| v * (e + 1) as f32 / 2.0))
+                    .collect::<Vec<_>>();
+                tensors.push((
+                    format!("blk.{}.ffn_gate_inp.weight", l),
+                    vec![embed_dim, n_experts],
+                    f32_bytes(&router),
+                ));
+                tensors.push((
+                    format!("blk.{}.ffn_gate_exps.weight", l),
+                    exps_dims(gate.dimensions()),
+                    gate.data().repeat(n_experts),
+                ));
+                tensors.push((
+                    format!("blk.{}.ffn_up_exps.weight", l),
+                    exps_dims(up.dimensions()),
+                    up.data().repeat(n_experts),
+                ));
+                tensors.push((
+                    format!("blk.{}.ffn_down_exps.weight", l),
+                    exps_dims(down.dimensions()),
+                    f32_bytes(&down_exps),
+                ));
+            }
+            tensors
+        };
+        rewrite_260k_gguf(
+            path,
+            "llama",
+            &[
+                ("expert_count", GGUFMetadataValue::U32(n_experts as u32)),
+                ("expert_used_count", GGUFMetadataValue::U32(2)),
+            ],
+            add,
+            &["ffn_gate", "ffn_up", "ffn_down"],
+        )
+    }
+
+    #[test]
+    fn test_forward_moe() -> Result<()> {
+        let file = TempFile::new("moe.gguf");
+        write_moe_gguf(file.path())?;
+        let gl = GGUFFileLoader::new(file.path(), false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        assert_eq!(lm.conf.n_experts, 4);
+        assert_eq!(lm.conf.n_experts_used, 2);
+        assert_eq!(lm.weights.ffn_gate_exps[0].len(), 4);
+        assert!(lm.weights.ffn_gate_weight.is_empty());
+
+        let gl_dense = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf_dense = gl_dense.open()?;
+        let lm_dense = CpuLlamaModelLoader::new().load(&gf_dense)?;
+
+        let embed_dim = lm.conf.embedding_dim;
+        let n_batch = 3;
+        let x = (0..n_batch * embed_dim)
+            .map(|i| (i as f32 * 0.13).cos())
+            .collect::<Vec<_>>();
+        let bytes = x.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+        let new_x = || {
+            CpuTensor::from_cpu(
+                &bytes,
+                &[n_batch, embed_dim],
+                GGMLType::F32,
+                lm.device.clone(),
+            )
+        };
+
+        let runner = Llama2Runner::new(&lm, 200, false)?;
+        let mut output = vec![0.0; n_batch * embed_dim];
+        runner
+            .forward_moe(new_x()?, 1, Activation::SiLU)?
+            .export(&mut output)?;
+        let runner_dense = Llama2Runner::new(&lm_dense, 200, false)?;
+        let mut output_dense = vec![0.0; n_batch * embed_dim];
+        runner_dense
+            .forward_ffn(new_x()?, 1, 0, Activation::SiLU)?
+            .export(&mut output_dense)?;
+
+        // route the normalized rows by hand, the output of each expert is the dense ffn
+        // output scaled by (e + 1) / 2
+        let mut norm_weight = vec![0.0; embed_dim];
+        lm.weights.rms_ffn_weight[1].export(&mut norm_weight)?;
+        let mut router = vec![0.0; 4 * embed_dim];
+        lm.weights.ffn_gate_inp[1].export(&mut router)?;
+        for row in 0..n_batch {
+            let x_row = &x[row * embed_dim..(row + 1) * embed_dim];
+            let rms = (x_row.iter().map(|v| v * v).sum::<f32>() / embed_dim as f32 + 1e-5).sqrt();
+            let x_norm = x_row
+                .iter()
+                .zip(norm_weight.iter())
+                .map(|(v, w)| v / rms * w)
+                .collect::<Vec<_>>();
+            let logits = router
+                .chunks(embed_dim)
+                .map(|r| r.iter().zip(x_norm.iter()).map(|(a, b)| a * b).sum::<f32>())
+                .collect::<Vec<_>>();
+            let scale = route_experts(&logits, 2)
+                .iter()
+                .map(|(e, weight)| weight * (e + 1) as f32 / 2.0)
+                .sum::<f32>();
+            for i in row * embed_dim..(row + 1) * embed_dim {
+                let expected = x[i] + (output_dense[i] - x[i]) * scale;
+                assert_relative_eq!(output[i], expected, epsilon = 1e-4);
+            }
+        }
+
+        // the tokens are grouped by their experts on the batched prefill
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let tokens = runner
+            .tokenizer()
+            .encode("Lily is a cute cat", true, false)?;
+        runner.feed_tokens(&tokens, true)?;
+        let logits_batched = runner.logits.clone();
+        runner.reset()?;
+        runner.feed_tokens(&tokens, false)?;
+        assert_relative_eq!(logits_batched[..], runner.logits[..], epsilon = 1e-3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_route_experts() {
+        let experts = route_experts(&[0.1, 2.0, -1.0, 2.0_f32.ln() + 2.0], 2);
+        assert_eq!(experts[0].0, 3);
+        assert_eq!(experts[1].0, 1);
+        assert_relative_eq!(experts[0].1, 2.0 / 3.0, epsilon = 1e-6);
+        assert_relative_eq!(experts[1].1, 1.0 / 3.0, epsilon = 1e-6);
+    }
+
     #[test]
     fn test_generate_f32_gpu() -> Result<()> {
         let gl: GGUFFileLoader =
@@ -3,6 +3,7 @@ use std::vec;
 
 use crabml::bail;
 use crabml::cpu::CpuTensor;
+use crabml::cpu::CpuTensorBuf;
 use crabml::cpu::CpuTensorDevice;
 use crabml::cpu::CpuTensorDeviceOptions;
 use crabml::cpu::CpuTensorDeviceRef;
@@ -11,11 +12,14 @@ use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::gguf::GGMLType;
 use crabml::gguf::GGUFFile;
+use crabml::tensor::RopeParams;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorMetrics;
 use crabml::tokenizer::Tokenizer;
 
+use crate::sampler::Llama2SamplerOptions;
 use crate::sampler::Llama2SamplerRef;
+use crate::session::tensors_fingerprint;
 use crate::Llama2Sampler;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -24,6 +28,34 @@ pub enum ModelArchitecture {
     Gemma,
     Qwen2,
     Phi2,
+    Phi3,
+    StarCoder2,
+    Gpt2,
+}
+
+/// the scaling of the rope on the models finetuned to the longer contexts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RopeScaling {
+    None,
+    Linear {
+        factor: f32,
+    },
+    Ntk {
+        factor: f32,
+    },
+    Yarn {
+        factor: f32,
+        original_context_length: usize,
+        attn_factor: f32,
+    },
+    // the longrope of phi3, the long factors are taken if the context is longer than the
+    // original one
+    LongRope {
+        short_factor: Arc<[f32]>,
+        long_factor: Arc<[f32]>,
+        original_context_length: usize,
+        attn_factor: f32,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -40,6 +72,12 @@ pub struct LlamaConfig {
     pub seq_len: usize,
     pub rms_norm_eps: f32,
     pub rope_dim: Option<usize>,
+    pub rope_freq_base: f32,
+    pub rope_scaling: RopeScaling,
+    pub n_experts: usize, // the number of the experts on the moe models like mixtral, 0 if dense
+    pub n_experts_used: usize, // the number of the experts routed to each token
+    pub sliding_window: Option<usize>, // each token only attends the last n tokens on mistral
+    pub tensors_fingerprint: u64, // the hash of the tensor names, types and shapes in the file
 }
 
 impl LlamaConfig {
@@ -50,32 +88,68 @@ impl LlamaConfig {
     pub fn head_size(&self) -> usize {
         self.embedding_dim / self.n_heads
     }
+
+    /// the rope parameters shared by all the layers, the yarn betas take the defaults of
+    /// llama.cpp. the longrope factors are picked by the context length of the runner, the
+    /// long ones are only taken beyond the original context length.
+    pub fn rope_params(&self, n_ctx: usize) -> RopeParams {
+        let rope = RopeParams::new(self.rope_dim.unwrap_or(self.head_size()))
+            .with_freq_base(self.rope_freq_base);
+        match &self.rope_scaling {
+            RopeScaling::None => rope,
+            RopeScaling::Linear { factor } => rope.with_linear_scaling(*factor),
+            RopeScaling::Ntk { factor } => rope.with_ntk_scaling(*factor),
+            RopeScaling::Yarn {
+                factor,
+                original_context_length,
+                attn_factor,
+            } => rope.with_yarn_scaling(*factor, *original_context_length, *attn_factor, 32.0, 1.0),
+            RopeScaling::LongRope {
+                short_factor,
+                long_factor,
+                original_context_length,
+                attn_factor,
+            } => {
+                let factors = if n_ctx > *original_context_length {
+                    long_factor
+                } else {
+                    short_factor
+                };
+                rope.with_long_rope_scaling(factors.clone(), *attn_factor)
+            }
+        }
+    }
 }
 
 pub struct LlamaWeights<T: Tensor> {
     // token embedding table
-    pub token_embed: T, // (vocab_size, dim)
+    pub token_embed: T,       // (vocab_size, dim)
+    pub pos_embed: Option<T>, // (seq_len, dim), the learned absolute position embeddings on gpt2
     // weights for rmsnorms
     pub rms_att_weight: Vec<T>, // (layer, dim) rmsnorm weights
     pub rms_ffn_weight: Vec<T>, // (layer, dim)
-    pub rms_att_bias: Vec<T>,
+    pub rms_att_bias: Vec<T>,   // (layer, dim), the biases of the layernorms
+    pub rms_ffn_bias: Vec<T>,   // (layer, dim)
     // weights for matmuls
     pub wq: Vec<T>, // (layer, embedding_dim, embedding_dim)
     pub wk: Vec<T>, // (layer, kv_dim, embedding_dim)
     pub wv: Vec<T>, // (layer, kv_dim, embedding_dim)
     pub wo: Vec<T>, // (layer, embedding_dim, embedding_dim)
-    pub wqkv: Vec<T>,
     pub bq: Vec<T>, // (layer, embedding_dim), bias for q, if not have bias, it's vec![]
     pub bk: Vec<T>, // (layer, embedding_dim), bias for k
     pub bv: Vec<T>, // (layer, embedding_dim), bias for v
     pub bo: Vec<T>,
-    pub bqkv: Vec<T>,
     // weights for ffn
     pub ffn_gate_weight: Vec<T>, // (layer, hidden_dim, embedding_dim)
     pub ffn_down_weight: Vec<T>, // (layer, embedding_dim, hidden_dim)
     pub ffn_up_weight: Vec<T>,   // (layer, hidden_dim, embedding_dim)
     pub ffn_down_bias: Vec<T>,
     pub ffn_up_bias: Vec<T>,
+    // weights for the sparse moe ffn, the experts are split from the 3d ffn_*_exps tensors
+    pub ffn_gate_inp: Vec<T>, // (layer, n_experts, embedding_dim), the router
+    pub ffn_gate_exps: Vec<Vec<T>>, // (layer, expert, hidden_dim, embedding_dim)
+    pub ffn_down_exps: Vec<Vec<T>>, // (layer, expert, embedding_dim, hidden_dim)
+    pub ffn_up_exps: Vec<Vec<T>>, // (layer, expert, hidden_dim, embedding_dim)
     // final rmsnorm
     pub rms_final_weight: T, // (dim, )
     pub rms_final_bias: Option<T>,
@@ -137,9 +211,7 @@ impl<'a> LlamaModel for &CpuLlamaModel<'a> {
 }
 
 pub struct CpuLlamaModelLoader {
-    temperature: f32,
-
-    probability: f32,
+    sampler_options: Llama2SamplerOptions,
 
     device_options: CpuTensorDeviceOptions,
 }
@@ -154,19 +226,23 @@ impl CpuLlamaModelLoader {
     pub fn new() -> Self {
         // this default value is suitable for running tests
         Self {
-            temperature: 0.0,
-            probability: 0.0,
+            sampler_options: Llama2SamplerOptions::default(),
             device_options: CpuTensorDeviceOptions::default(),
         }
     }
 
     pub fn with_temperature(mut self, temperature: f32) -> Self {
-        self.temperature = temperature;
+        self.sampler_options.temperature = temperature;
         self
     }
 
     pub fn with_probability(mut self, probability: f32) -> Self {
-        self.probability = probability;
+        self.sampler_options.top_p = probability;
+        self
+    }
+
+    pub fn with_sampler_options(mut self, options: Llama2SamplerOptions) -> Self {
+        self.sampler_options = options;
         self
     }
 
@@ -184,9 +260,9 @@ impl CpuLlamaModelLoader {
         let device = CpuTensorDevice::with_options(self.device_options.clone());
         let metrics = device.metrics().clone();
         let conf = self.load_config(gf)?;
-        let weights = self.load_weights(gf, conf.n_layers, device.clone())?;
+        let weights = self.load_weights(gf, &conf, device.clone())?;
         let tokenizer = self.load_tokenizer(gf)?;
-        let sampler = Llama2Sampler::new(self.temperature, self.probability, device.exp_cache());
+        let sampler = Llama2Sampler::from_options(&self.sampler_options, device.exp_cache())?;
         Ok(CpuLlamaModel {
             conf,
             weights: Arc::new(weights),
@@ -200,29 +276,34 @@ impl CpuLlamaModelLoader {
     fn load_weights<'a>(
         &self,
         gf: &'a GGUFFile<'a>,
-        n_layers: usize,
+        conf: &LlamaConfig,
         device: CpuTensorDeviceRef<'a>,
     ) -> Result<LlamaWeights<CpuTensor<'a>>> {
+        let n_layers = conf.n_layers;
         // [64 (dim), 512 (vocab_size)]
         let token_embed = self.load_tensor(gf, "token_embd.weight", device.clone())?;
+        let pos_embed = self.load_tensor_optional(gf, "position_embd.weight", device.clone())?;
         let mut wq = vec![];
         let mut wk = vec![];
         let mut wv = vec![];
         let mut wo = vec![];
-        let mut wqkv = vec![];
         let mut bq = vec![];
         let mut bk = vec![];
         let mut bv = vec![];
         let mut bo = vec![];
-        let mut bqkv = vec![];
 
         let mut ffn_gate_weight = vec![];
         let mut ffn_down_weight = vec![];
         let mut ffn_up_weight = vec![];
         let mut ffn_up_bias = vec![];
         let mut ffn_down_bias = vec![];
+        let mut ffn_gate_inp = vec![];
+        let mut ffn_gate_exps = vec![];
+        let mut ffn_down_exps = vec![];
+        let mut ffn_up_exps = vec![];
         let mut rms_att_weight = vec![];
         let mut rms_ffn_weight = vec![];
+        let mut rms_ffn_bias = vec![];
         let mut rms_att_bias = vec![];
 
         match gf.architecture() {
@@ -248,22 +329,49 @@ impl CpuLlamaModelLoader {
                         &format!("blk.{}.attn_output.weight", layer),
                         device.clone(),
                     )?);
-                    // (hidden_dim:172, embedding_dim:64)
-                    ffn_gate_weight.push(self.load_tensor(
-                        gf,
-                        &format!("blk.{}.ffn_gate.weight", layer),
-                        device.clone(),
-                    )?);
-                    ffn_down_weight.push(self.load_tensor(
-                        gf,
-                        &format!("blk.{}.ffn_down.weight", layer),
-                        device.clone(),
-                    )?);
-                    ffn_up_weight.push(self.load_tensor(
-                        gf,
-                        &format!("blk.{}.ffn_up.weight", layer),
-                        device.clone(),
-                    )?);
+                    if conf.n_experts > 0 {
+                        // (n_experts:8, embedding_dim:4096)
+                        ffn_gate_inp.push(self.load_tensor(
+                            gf,
+                            &format!("blk.{}.ffn_gate_inp.weight", layer),
+                            device.clone(),
+                        )?);
+                        ffn_gate_exps.push(self.load_experts(
+                            gf,
+                            &format!("blk.{}.ffn_gate_exps.weight", layer),
+                            conf.n_experts,
+                            device.clone(),
+                        )?);
+                        ffn_down_exps.push(self.load_experts(
+                            gf,
+                            &format!("blk.{}.ffn_down_exps.weight", layer),
+                            conf.n_experts,
+                            device.clone(),
+                        )?);
+                        ffn_up_exps.push(self.load_experts(
+                            gf,
+                            &format!("blk.{}.ffn_up_exps.weight", layer),
+                            conf.n_experts,
+                            device.clone(),
+                        )?);
+                    } else {
+                        // (hidden_dim:172, embedding_dim:64)
+                        ffn_gate_weight.push(self.load_tensor(
+                            gf,
+                            &format!("blk.{}.ffn_gate.weight", layer),
+                            device.clone(),
+                        )?);
+                        ffn_down_weight.push(self.load_tensor(
+                            gf,
+                            &format!("blk.{}.ffn_down.weight", layer),
+                            device.clone(),
+                        )?);
+                        ffn_up_weight.push(self.load_tensor(
+                            gf,
+                            &format!("blk.{}.ffn_up.weight", layer),
+                            device.clone(),
+                        )?);
+                    }
                     rms_att_weight.push(
                         self.load_tensor(
                             gf,
@@ -354,17 +462,27 @@ impl CpuLlamaModelLoader {
                 }
             }
             "phi2" => {
+                // the fused qkv and its bias are split by rows, the same as gpt2
+                let rows = [conf.embedding_dim, conf.kv_dim(), conf.kv_dim()];
                 for layer in 0..n_layers {
-                    wqkv.push(self.load_tensor(
+                    let mut qkv = self.load_split_rows(
                         gf,
                         &format!("blk.{}.attn_qkv.weight", layer),
+                        &rows,
                         device.clone(),
-                    )?);
-                    bqkv.push(self.load_tensor(
+                    )?;
+                    let mut bqkv = self.load_split_rows(
                         gf,
                         &format!("blk.{}.attn_qkv.bias", layer),
+                        &rows,
                         device.clone(),
-                    )?);
+                    )?;
+                    wv.push(qkv.pop().unwrap());
+                    wk.push(qkv.pop().unwrap());
+                    wq.push(qkv.pop().unwrap());
+                    bv.push(bqkv.pop().unwrap());
+                    bk.push(bqkv.pop().unwrap());
+                    bq.push(bqkv.pop().unwrap());
                     wo.push(self.load_tensor(
                         gf,
                         &format!("blk.{}.attn_output.weight", layer),
@@ -413,6 +531,144 @@ impl CpuLlamaModelLoader {
                     )?);
                 }
             }
+            "phi3" => {
+                // the fused weights are split by rows, so phi3 runs the same as llama
+                let (embed_dim, kv_dim) = (conf.embedding_dim, conf.kv_dim());
+                for layer in 0..n_layers {
+                    let mut qkv = self.load_split_rows(
+                        gf,
+                        &format!("blk.{}.attn_qkv.weight", layer),
+                        &[embed_dim, kv_dim, kv_dim],
+                        device.clone(),
+                    )?;
+                    wv.push(qkv.pop().unwrap());
+                    wk.push(qkv.pop().unwrap());
+                    wq.push(qkv.pop().unwrap());
+                    wo.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.attn_output.weight", layer),
+                        device.clone(),
+                    )?);
+                    // the gate is the first half of the fused ffn_up, and the up is the second
+                    let mut gate_up = self.load_split_rows(
+                        gf,
+                        &format!("blk.{}.ffn_up.weight", layer),
+                        &[conf.hidden_dim, conf.hidden_dim],
+                        device.clone(),
+                    )?;
+                    ffn_up_weight.push(gate_up.pop().unwrap());
+                    ffn_gate_weight.push(gate_up.pop().unwrap());
+                    ffn_down_weight.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.ffn_down.weight", layer),
+                        device.clone(),
+                    )?);
+                    rms_att_weight.push(
+                        self.load_tensor(
+                            gf,
+                            &format!("blk.{}.attn_norm.weight", layer),
+                            device.clone(),
+                        )?
+                        .dequantize(GGMLType::F32)?,
+                    );
+                    rms_ffn_weight.push(
+                        self.load_tensor(
+                            gf,
+                            &format!("blk.{}.ffn_norm.weight", layer),
+                            device.clone(),
+                        )?
+                        .dequantize(GGMLType::F32)?,
+                    );
+                }
+            }
+            "gpt2" | "starcoder2" => {
+                for layer in 0..n_layers {
+                    if gf.architecture() == "gpt2" {
+                        // the fused qkv and its bias are split by rows
+                        let rows = [conf.embedding_dim, conf.kv_dim(), conf.kv_dim()];
+                        let mut qkv = self.load_split_rows(
+                            gf,
+                            &format!("blk.{}.attn_qkv.weight", layer),
+                            &rows,
+                            device.clone(),
+                        )?;
+                        let mut bqkv = self.load_split_rows(
+                            gf,
+                            &format!("blk.{}.attn_qkv.bias", layer),
+                            &rows,
+                            device.clone(),
+                        )?;
+                        wv.push(qkv.pop().unwrap());
+                        wk.push(qkv.pop().unwrap());
+                        wq.push(qkv.pop().unwrap());
+                        bv.push(bqkv.pop().unwrap());
+                        bk.push(bqkv.pop().unwrap());
+                        bq.push(bqkv.pop().unwrap());
+                    } else {
+                        for (w, b, name) in [
+                            (&mut wq, &mut bq, "attn_q"),
+                            (&mut wk, &mut bk, "attn_k"),
+                            (&mut wv, &mut bv, "attn_v"),
+                        ] {
+                            w.push(self.load_tensor(
+                                gf,
+                                &format!("blk.{}.{}.weight", layer, name),
+                                device.clone(),
+                            )?);
+                            b.push(self.load_tensor(
+                                gf,
+                                &format!("blk.{}.{}.bias", layer, name),
+                                device.clone(),
+                            )?);
+                        }
+                    }
+                    wo.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.attn_output.weight", layer),
+                        device.clone(),
+                    )?);
+                    bo.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.attn_output.bias", layer),
+                        device.clone(),
+                    )?);
+                    ffn_up_weight.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.ffn_up.weight", layer),
+                        device.clone(),
+                    )?);
+                    ffn_up_bias.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.ffn_up.bias", layer),
+                        device.clone(),
+                    )?);
+                    ffn_down_weight.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.ffn_down.weight", layer),
+                        device.clone(),
+                    )?);
+                    ffn_down_bias.push(self.load_tensor(
+                        gf,
+                        &format!("blk.{}.ffn_down.bias", layer),
+                        device.clone(),
+                    )?);
+                    for (norm, name) in [
+                        (&mut rms_att_weight, "attn_norm.weight"),
+                        (&mut rms_att_bias, "attn_norm.bias"),
+                        (&mut rms_ffn_weight, "ffn_norm.weight"),
+                        (&mut rms_ffn_bias, "ffn_norm.bias"),
+                    ] {
+                        norm.push(
+                            self.load_tensor(
+                                gf,
+                                &format!("blk.{}.{}", layer, name),
+                                device.clone(),
+                            )?
+                            .dequantize(GGMLType::F32)?,
+                        );
+                    }
+                }
+            }
             arch => {
                 bail!(ErrorKind::ModelError, "unsupported architecture {}", arch);
             }
@@ -421,7 +677,7 @@ impl CpuLlamaModelLoader {
         let rms_final_weight = self
             .load_tensor(gf, "output_norm.weight", device.clone())?
             .dequantize(GGMLType::F32)?;
-        let rms_final_bias = if gf.architecture() == "phi2" {
+        let rms_final_bias = if matches!(gf.architecture(), "phi2" | "gpt2" | "starcoder2") {
             Some(
                 self.load_tensor(gf, "output_norm.bias", device.clone())?
                     .dequantize(GGMLType::F32)?,
@@ -435,30 +691,100 @@ impl CpuLlamaModelLoader {
 
         Ok(LlamaWeights {
             token_embed,
+            pos_embed,
             wq,
             wk,
             wv,
             wo,
-            wqkv,
             bq,
             bk,
             bv,
             bo,
-            bqkv,
             ffn_gate_weight,
             ffn_down_weight,
             ffn_up_weight,
             ffn_down_bias,
             ffn_up_bias,
+            ffn_gate_inp,
+            ffn_gate_exps,
+            ffn_down_exps,
+            ffn_up_exps,
             rms_att_weight,
             rms_ffn_weight,
             rms_att_bias,
+            rms_ffn_bias,
             rms_final_weight,
             rms_final_bias,
             output_weight,
         })
     }
 
+    // split the 3d tensor of the experts into a 2d tensor for each expert, the experts are
+    // laid out one after another, so each of them takes a slice of the data
+    fn load_experts<'a>(
+        &self,
+        gf: &'a GGUFFile<'a>,
+        name: &str,
+        n_experts: usize,
+        device: CpuTensorDeviceRef<'a>,
+    ) -> Result<Vec<CpuTensor<'a>>> {
+        let info = gf
+            .get_tensor_info(name)
+            .ok_or_else(|| error!(ErrorKind::TensorNotFound, "failed to find tensor {}", name))?;
+        let dims = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+        if dims.len() != 3 || dims[0] != n_experts {
+            bail!(
+                ErrorKind::ModelError,
+                "expected tensor {} in the shape of ({}, _, _), but got {:?}",
+                name,
+                n_experts,
+                dims
+            );
+        }
+        let expert_bytes = info.typ().bytes_size(dims[1] * dims[2]);
+        (0..n_experts)
+            .map(|e| {
+                let data = &info.data()[e * expert_bytes..(e + 1) * expert_bytes];
+                CpuTensor::from_bytes(data, info.typ(), &dims[1..], device.clone())
+            })
+            .collect()
+    }
+
+    // split the 2d tensor into the consecutive chunks of rows, like the fused qkv, or the 1d
+    // tensor into the chunks of elements, like its bias. the rows are laid out one after
+    // another, so each chunk takes a slice of the data
+    fn load_split_rows<'a>(
+        &self,
+        gf: &'a GGUFFile<'a>,
+        name: &str,
+        rows: &[usize],
+        device: CpuTensorDeviceRef<'a>,
+    ) -> Result<Vec<CpuTensor<'a>>> {
+        let info = gf
+            .get_tensor_info(name)
+            .ok_or_else(|| error!(ErrorKind::TensorNotFound, "failed to find tensor {}", name))?;
+        let dims = info.dimensions().iter().rev().copied().collect::<Vec<_>>();
+        if dims.len() > 2 || dims[0] != rows.iter().sum::<usize>() {
+            bail!(
+                ErrorKind::ModelError,
+                "expected tensor {} in the shape of ({}, _), but got {:?}",
+                name,
+                rows.iter().sum::<usize>(),
+                dims
+            );
+        }
+        let row_bytes = info.typ().bytes_size(dims[1..].iter().product());
+        let mut offset = 0;
+        rows.iter()
+            .map(|&n| {
+                let data = &info.data()[offset * row_bytes..(offset + n) * row_bytes];
+                let shape = [&[n], &dims[1..]].concat();
+                offset += n;
+                CpuTensor::from_bytes(data, info.typ(), &shape, device.clone())
+            })
+            .collect()
+    }
+
     pub(crate) fn load_tensor_optional<'a>(
         &self,
         gf: &'a GGUFFile<'a>,
@@ -509,12 +835,22 @@ impl CpuLlamaModelLoader {
             .metadata()
             .get_u32("tokenizer.ggml.bos_token_id")
             .unwrap() as usize;
+        // the control tokens like <|im_end|> are marked as 3 in the token types
+        let special_tokens = gf
+            .metadata()
+            .get_i32_array("tokenizer.ggml.token_type")
+            .unwrap_or_default()
+            .iter()
+            .enumerate()
+            .filter(|(_, typ)| **typ == 3)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
         let tokenizer_kind = gf
             .metadata()
             .get_string("tokenizer.ggml.model")
             .unwrap()
             .to_string();
-        match tokenizer_kind.as_str() {
+        let tokenizer = match tokenizer_kind.as_str() {
             "llama" => {
                 // it seems that .to_vec() will raise an memory issue but it's ok with
                 // iter().cloned().collect(), strange.
@@ -526,12 +862,7 @@ impl CpuLlamaModelLoader {
                     .iter()
                     .cloned()
                     .collect::<Vec<_>>();
-                Ok(Tokenizer::new_llama(
-                    vocab,
-                    vocab_scores,
-                    bos_token,
-                    eos_token,
-                ))
+                Tokenizer::new_llama(vocab, vocab_scores, bos_token, eos_token)
             }
             "gpt2" => {
                 let merges = gf
@@ -541,13 +872,16 @@ impl CpuLlamaModelLoader {
                     .iter()
                     .map(|s| s.to_string())
                     .collect::<Vec<_>>();
-                Ok(Tokenizer::new_gpt2(vocab, merges, bos_token, eos_token))
+                Tokenizer::new_gpt2(vocab, merges, bos_token, eos_token)
             }
-            other => Err(error!(
-                ErrorKind::IOError,
-                "unsupported tokenizer {}", other
-            )),
-        }
+            other => {
+                return Err(error!(
+                    ErrorKind::IOError,
+                    "unsupported tokenizer {}", other
+                ))
+            }
+        };
+        Ok(tokenizer.with_special_tokens(special_tokens))
     }
 
     fn load_config(&self, gf: &GGUFFile) -> Result<LlamaConfig> {
@@ -558,6 +892,9 @@ impl CpuLlamaModelLoader {
             "gemma" => (ModelArchitecture::Gemma, "gemma"),
             "qwen2" => (ModelArchitecture::Qwen2, "qwen2"),
             "phi2" => (ModelArchitecture::Phi2, "phi2"),
+            "phi3" => (ModelArchitecture::Phi3, "phi3"),
+            "starcoder2" => (ModelArchitecture::StarCoder2, "starcoder2"),
+            "gpt2" => (ModelArchitecture::Gpt2, "gpt2"),
             arch => {
                 bail!(ErrorKind::ModelError, "unsupported architecture {}", arch);
             }
@@ -580,10 +917,11 @@ impl CpuLlamaModelLoader {
             .metadata()
             .get_u32(&format!("{}.feed_forward_length", prefix))
             .unwrap() as usize;
+        // gpt2 has no head_count_kv, as it's always the same as head_count
         let n_kv_heads = gf
             .metadata()
             .get_u32(&format!("{}.attention.head_count_kv", prefix))
-            .unwrap() as usize;
+            .map_or(n_heads, |v| v as usize);
         let seq_len = gf
             .metadata()
             .get_u32(&format!("{}.context_length", prefix))
@@ -602,7 +940,8 @@ impl CpuLlamaModelLoader {
             .metadata()
             .get_u32(&format!("{}.embedding_length", prefix))
             .unwrap() as usize;
-        let rms_norm_eps = if prefix == "phi2" {
+        // the models with the layernorms take layer_norm_epsilon
+        let rms_norm_eps = if matches!(prefix, "phi2" | "starcoder2" | "gpt2") {
             gf.metadata()
                 .get_f32(&format!("{}.attention.layer_norm_epsilon", prefix))
                 .unwrap()
@@ -615,6 +954,46 @@ impl CpuLlamaModelLoader {
             .metadata()
             .get_u32(&format!("{}.rope.dimension_count", prefix))
             .map(|v| v as usize);
+        let rope_freq_base = gf
+            .metadata()
+            .get_f32(&format!("{}.rope.freq_base", prefix))
+            .unwrap_or(10000.0);
+        let rope_scaling = self.load_rope_scaling(
+            gf,
+            prefix,
+            seq_len,
+            n_rot.unwrap_or(embedding_dim / n_heads),
+        )?;
+        let n_experts = gf
+            .metadata()
+            .get_u32(&format!("{}.expert_count", prefix))
+            .unwrap_or(0) as usize;
+        let n_experts_used = gf
+            .metadata()
+            .get_u32(&format!("{}.expert_used_count", prefix))
+            .unwrap_or(0) as usize;
+        let sliding_window = gf
+            .metadata()
+            .get_u32(&format!("{}.attention.sliding_window", prefix))
+            .map(|v| v as usize)
+            .filter(|v| *v > 0);
+        if n_experts > 0 && (architecture != ModelArchitecture::Llama || n_experts_used == 0) {
+            bail!(
+                ErrorKind::ModelError,
+                "unsupported moe of {} experts with {} used on {}",
+                n_experts,
+                n_experts_used,
+                prefix
+            );
+        }
+        if n_experts_used > n_experts {
+            bail!(
+                ErrorKind::ModelError,
+                "{} experts are used on each token, but there're only {} experts",
+                n_experts_used,
+                n_experts
+            );
+        }
 
         Ok(LlamaConfig {
             architecture,
@@ -628,9 +1007,118 @@ impl CpuLlamaModelLoader {
             vocab_size,
             rms_norm_eps,
             rope_dim: n_rot,
+            rope_freq_base,
+            rope_scaling,
+            n_experts,
+            n_experts_used,
+            sliding_window,
             chat_template,
+            tensors_fingerprint: tensors_fingerprint(gf.tensor_infos()),
         })
     }
+
+    // the legacy `rope.scale_linear` is taken as the factor of the linear scaling. the yarn
+    // scaling defaults to extend from the context length if the original one is missing. the
+    // longrope of phi3 is not in the metadata, but comes with the tables of the factors.
+    fn load_rope_scaling(
+        &self,
+        gf: &GGUFFile,
+        prefix: &str,
+        seq_len: usize,
+        rope_dims: usize,
+    ) -> Result<RopeScaling> {
+        let short_factor = self.load_rope_factors(gf, "rope_factors_short.weight", rope_dims)?;
+        let long_factor = self.load_rope_factors(gf, "rope_factors_long.weight", rope_dims)?;
+        if let (Some(short_factor), Some(long_factor)) = (short_factor, long_factor) {
+            let original_context_length = gf
+                .metadata()
+                .get_u32(&format!("{}.rope.scaling.original_context_length", prefix))
+                .map(|v| v as usize)
+                .unwrap_or(seq_len);
+            // the same as the conversion of phi3 in llama.cpp, which scales up the magnitude by
+            // how far the context is extended
+            let scale = seq_len as f32 / original_context_length as f32;
+            let default_attn_factor = if scale > 1.0 {
+                (1.0 + scale.ln() / (original_context_length as f32).ln()).sqrt()
+            } else {
+                1.0
+            };
+            return Ok(RopeScaling::LongRope {
+                short_factor,
+                long_factor,
+                original_context_length,
+                attn_factor: gf
+                    .metadata()
+                    .get_f32(&format!("{}.rope.scaling.attn_factor", prefix))
+                    .unwrap_or(default_attn_factor),
+            });
+        }
+
+        let factor = gf
+            .metadata()
+            .get_f32(&format!("{}.rope.scaling.factor", prefix))
+            .or_else(|| {
+                gf.metadata()
+                    .get_f32(&format!("{}.rope.scale_linear", prefix))
+            })
+            .unwrap_or(1.0);
+        let scaling_type = gf
+            .metadata()
+            .get_string(&format!("{}.rope.scaling.type", prefix))
+            .unwrap_or("linear");
+        let scaling = match scaling_type {
+            "none" => RopeScaling::None,
+            // some converters write a factor of 0 for no scaling
+            _ if factor == 0.0 || factor == 1.0 => RopeScaling::None,
+            _ if factor < 0.0 => bail!(
+                ErrorKind::ModelError,
+                "invalid rope scaling factor {}",
+                factor
+            ),
+            "linear" => RopeScaling::Linear { factor },
+            "ntk" => RopeScaling::Ntk { factor },
+            "yarn" => RopeScaling::Yarn {
+                factor,
+                original_context_length: gf
+                    .metadata()
+                    .get_u32(&format!("{}.rope.scaling.original_context_length", prefix))
+                    .map(|v| v as usize)
+                    .unwrap_or(seq_len),
+                attn_factor: gf
+                    .metadata()
+                    .get_f32(&format!("{}.rope.scaling.attn_factor", prefix))
+                    .unwrap_or(1.0),
+            },
+            other => bail!(ErrorKind::ModelError, "unsupported rope scaling {}", other),
+        };
+        Ok(scaling)
+    }
+
+    // there's a factor for each pair of the rotated dimensions
+    fn load_rope_factors(
+        &self,
+        gf: &GGUFFile,
+        name: &str,
+        rope_dims: usize,
+    ) -> Result<Option<Arc<[f32]>>> {
+        let info = match gf.get_tensor_info(name) {
+            None => return Ok(None),
+            Some(info) => info,
+        };
+        let buf =
+            CpuTensorBuf::from_raw_bytes(info.data(), info.typ())?.dequantize(GGMLType::F32)?;
+        let factors: Arc<[f32]> = buf.iter_f32().collect();
+        if factors.len() != rope_dims / 2 {
+            bail!(
+                ErrorKind::ModelError,
+                "{} has {} factors, but the rope has {} dimensions",
+                name,
+                factors.len(),
+                rope_dims
+            );
+        }
+        Ok(Some(factors))
+    }
 }
 
 #[derive(Clone)]
@@ -709,11 +1197,6 @@ impl<T: Tensor> GpuLlamaModel<T> {
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
-        let wqkv = weights
-            .wqkv
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
         let bq = weights
             .bq
             .iter()
@@ -734,11 +1217,6 @@ impl<T: Tensor> GpuLlamaModel<T> {
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
-        let bqkv = weights
-            .bqkv
-            .iter()
-            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
-            .collect::<Result<Vec<_>>>()?;
         let ffn_gate_weight = weights
             .ffn_gate_weight
             .iter()
@@ -764,6 +1242,24 @@ impl<T: Tensor> GpuLlamaModel<T> {
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
+        let ffn_gate_inp = weights
+            .ffn_gate_inp
+            .iter()
+            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let convert_experts = |exps: &Vec<Vec<CpuTensor>>| {
+            exps.iter()
+                .map(|experts| {
+                    experts
+                        .iter()
+                        .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+                        .collect::<Result<Vec<_>>>()
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        let ffn_gate_exps = convert_experts(&weights.ffn_gate_exps)?;
+        let ffn_down_exps = convert_experts(&weights.ffn_down_exps)?;
+        let ffn_up_exps = convert_experts(&weights.ffn_up_exps)?;
         let rms_att_weight = weights
             .rms_att_weight
             .iter()
@@ -779,6 +1275,16 @@ impl<T: Tensor> GpuLlamaModel<T> {
             .iter()
             .map(|t| Self::convert_cpu_tensor(t, device.clone()))
             .collect::<Result<Vec<_>>>()?;
+        let rms_ffn_bias = weights
+            .rms_ffn_bias
+            .iter()
+            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let pos_embed = weights
+            .pos_embed
+            .as_ref()
+            .map(|t| Self::convert_cpu_tensor(t, device.clone()))
+            .transpose()?;
         let rms_final_weight = Self::convert_cpu_tensor(&weights.rms_final_weight, device.clone())?;
         let rms_final_bias = weights.rms_final_bias.as_ref().map(|rms_final_bias| {
             Self::convert_cpu_tensor(rms_final_bias, device.clone()).unwrap()
@@ -789,24 +1295,28 @@ impl<T: Tensor> GpuLlamaModel<T> {
             .map(|output_weight| Self::convert_cpu_tensor(output_weight, device.clone()).unwrap());
         let weights = LlamaWeights {
             token_embed: token_embedding_table,
+            pos_embed,
             wq,
             wk,
             wv,
             wo,
-            wqkv,
             bq,
             bk,
             bv,
             bo,
-            bqkv,
             ffn_gate_weight,
             ffn_down_weight,
             ffn_up_weight,
             ffn_down_bias,
             ffn_up_bias,
+            ffn_gate_inp,
+            ffn_gate_exps,
+            ffn_down_exps,
+            ffn_up_exps,
             rms_att_weight,
             rms_ffn_weight,
             rms_att_bias,
+            rms_ffn_bias,
             rms_final_weight,
             rms_final_bias,
             output_weight: wcls,
@@ -842,9 +1352,12 @@ mod tests {
     use crabml::error::Result;
     use crabml::gguf::GGMLType;
     use crabml::gguf::GGUFFileLoader;
+    use crabml::gguf::GGUFFileWriter;
+    use crabml::gguf::GGUFMetadataValue;
     use crabml::tensor::Tensor;
 
     use crate::model::CpuLlamaModelLoader;
+    use crate::model::RopeScaling;
 
     #[test]
     fn test_load_q8_0() -> Result<()> {
@@ -860,4 +1373,40 @@ mod tests {
         assert_eq!(lm.weights.token_embed.dtype(), GGMLType::Q8_0);
         Ok(())
     }
+
+    #[test]
+    fn test_load_rope_scaling() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        assert_eq!(lm.conf.rope_freq_base, 10000.0);
+        assert_eq!(lm.conf.rope_scaling, RopeScaling::None);
+
+        let path = std::env::temp_dir().join(format!("crabml-rope-{}.gguf", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut w = GGUFFileWriter::from_gguf_file(&gf);
+        w.set_metadata("llama.rope.freq_base", GGUFMetadataValue::F32(500000.0));
+        w.set_metadata("llama.rope.scaling.type", GGUFMetadataValue::String("yarn"));
+        w.set_metadata("llama.rope.scaling.factor", GGUFMetadataValue::F32(4.0));
+        w.set_metadata(
+            "llama.rope.scaling.original_context_length",
+            GGUFMetadataValue::U32(128),
+        );
+        w.write_to_file(&path)?;
+
+        let gl = GGUFFileLoader::new(&path, false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        assert_eq!(lm.conf.rope_freq_base, 500000.0);
+        assert_eq!(lm.conf.rope_scaling, RopeScaling::Yarn {
+            factor: 4.0,
+            original_context_length: 128,
+            attn_factor: 1.0,
+        });
+        let rope = lm.conf.rope_params(lm.conf.seq_len);
+        assert_eq!(rope.freq_base, 500000.0);
+        assert_eq!(rope.freq_scale, 0.25);
+        std::fs::remove_file(&path).unwrap();
+        Ok(())
+    }
 }
@@ -1,15 +1,244 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
+use crabml::bail;
 use crabml::cpu::buf::buf_f32::exp_f32_cached;
 use crabml::error;
 use crabml::error::ErrorKind;
 use crabml::error::Result;
+use crabml::tokenizer::TokenID;
+use crabml::tokenizer::Tokenizer;
 use half::f16;
+use rand::rngs::StdRng;
 use rand::Rng;
+use rand::SeedableRng;
+
+/// a stage in the sampler chain. each stage either drops some candidates or reshapes the
+/// logits of the candidates, the token is sampled from the candidates left after all the stages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerStage {
+    /// keep the k candidates with the highest logits, 0 disables it
+    TopK(usize),
+
+    /// tail free sampling, drop the tail where the second derivative of the sorted probabilities
+    /// is flat, 1.0 disables it
+    TailFree(f32),
+
+    /// locally typical sampling, keep the candidates whose information content is close to the
+    /// entropy of the distribution, 1.0 disables it
+    Typical(f32),
+
+    /// keep the smallest set of candidates whose cumulative probability exceeds p, 1.0 disables it
+    TopP(f32),
+
+    /// drop the candidates whose probability is less than p * the max probability, 0.0 disables it
+    MinP(f32),
+
+    /// divide the logits by the temperature, 0.0 takes the argmax
+    Temperature(f32),
+}
+
+#[derive(Debug, Clone)]
+pub struct Llama2SamplerOptions {
+    pub temperature: f32,
+
+    pub top_k: usize,
+
+    pub top_p: f32,
+
+    pub min_p: f32,
+
+    pub typical_p: f32,
+
+    pub tfs_z: f32,
+
+    /// the number of recent tokens to take into the penalties, 0 disables the penalties
+    pub repeat_last_n: usize,
+
+    /// divide the positive logits (or multiply the negative logits) of the recent tokens by
+    /// the penalty, 1.0 disables it
+    pub repeat_penalty: f32,
+
+    /// subtract the count of the recent tokens * the penalty from their logits
+    pub frequency_penalty: f32,
+
+    /// subtract the penalty from the logits of the tokens appeared in the recent tokens
+    pub presence_penalty: f32,
+
+    /// the newline and the special tokens are exempted from the penalties unless it's enabled
+    pub penalize_nl: bool,
+
+    /// the seed of the random number generator, sampling with the same seed on the same inputs
+    /// always gives the same tokens. None takes a random seed.
+    pub seed: Option<u64>,
+
+    /// the order of the stages in the chain, the same as the `--sampling-seq` in llama.cpp:
+    /// k: top-k, f: tail free, y: typical, p: top-p, m: min-p, t: temperature.
+    pub sequence: String,
+
+    /// added to the logits of the tokens before the penalties, a bias of -inf bans the token
+    pub logit_bias: HashMap<TokenID, f32>,
+
+    /// the tokens of these strings are never sampled, each string is resolved into tokens by
+    /// the tokenizer
+    pub banned_strings: Vec<String>,
+}
+
+impl Default for Llama2SamplerOptions {
+    fn default() -> Self {
+        // all the stages except temperature are disabled, and temperature 0 takes the argmax
+        Self {
+            temperature: 0.0,
+            top_k: 0,
+            top_p: 1.0,
+            min_p: 0.0,
+            typical_p: 1.0,
+            tfs_z: 1.0,
+            repeat_last_n: 64,
+            repeat_penalty: 1.0,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            penalize_nl: false,
+            seed: None,
+            sequence: "kfypmt".to_string(),
+            logit_bias: HashMap::new(),
+            banned_strings: vec![],
+        }
+    }
+}
+
+impl Llama2SamplerOptions {
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn with_top_k(mut self, top_k: usize) -> Self {
+        self.top_k = top_k;
+        self
+    }
+
+    pub fn with_top_p(mut self, top_p: f32) -> Self {
+        self.top_p = top_p;
+        self
+    }
+
+    pub fn with_min_p(mut self, min_p: f32) -> Self {
+        self.min_p = min_p;
+        self
+    }
+
+    pub fn with_typical_p(mut self, typical_p: f32) -> Self {
+        self.typical_p = typical_p;
+        self
+    }
+
+    pub fn with_tfs_z(mut self, tfs_z: f32) -> Self {
+        self.tfs_z = tfs_z;
+        self
+    }
+
+    pub fn with_repeat_last_n(mut self, repeat_last_n: usize) -> Self {
+        self.repeat_last_n = repeat_last_n;
+        self
+    }
+
+    pub fn with_repeat_penalty(mut self, repeat_penalty: f32) -> Self {
+        self.repeat_penalty = repeat_penalty;
+        self
+    }
+
+    pub fn with_frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+        self.frequency_penalty = frequency_penalty;
+        self
+    }
+
+    pub fn with_presence_penalty(mut self, presence_penalty: f32) -> Self {
+        self.presence_penalty = presence_penalty;
+        self
+    }
+
+    pub fn with_penalize_nl(mut self, penalize_nl: bool) -> Self {
+        self.penalize_nl = penalize_nl;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_sequence(mut self, sequence: impl Into<String>) -> Self {
+        self.sequence = sequence.into();
+        self
+    }
+
+    pub fn with_logit_bias(mut self, token: TokenID, bias: f32) -> Self {
+        self.logit_bias.insert(token, bias);
+        self
+    }
+
+    pub fn with_banned_string(mut self, banned: impl Into<String>) -> Self {
+        self.banned_strings.push(banned.into());
+        self
+    }
+
+    pub fn stages(&self) -> Result<Vec<SamplerStage>> {
+        self.sequence
+            .chars()
+            .map(|c| match c {
+                'k' => Ok(SamplerStage::TopK(self.top_k)),
+                'f' => Ok(SamplerStage::TailFree(self.tfs_z)),
+                'y' => Ok(SamplerStage::Typical(self.typical_p)),
+                'p' => Ok(SamplerStage::TopP(self.top_p)),
+                'm' => Ok(SamplerStage::MinP(self.min_p)),
+                't' => Ok(SamplerStage::Temperature(self.temperature)),
+                _ => bail!(
+                    ErrorKind::BadInput,
+                    "unknown sampler '{}' in the sequence {}, expected one of k, f, y, p, m, t",
+                    c,
+                    self.sequence
+                ),
+            })
+            .collect()
+    }
+}
+
+/// the llama.cpp style penalties on the recently seen tokens, which are applied on the logits
+/// before the sampler chain.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Llama2Penalties {
+    pub last_n: usize,
+    pub repeat: f32,
+    pub frequency: f32,
+    pub presence: f32,
+    pub penalize_nl: bool,
+}
+
+impl Default for Llama2Penalties {
+    fn default() -> Self {
+        Self {
+            last_n: 0,
+            repeat: 1.0,
+            frequency: 0.0,
+            presence: 0.0,
+            penalize_nl: false,
+        }
+    }
+}
+
+impl Llama2Penalties {
+    fn is_enabled(&self) -> bool {
+        self.last_n > 0 && (self.repeat != 1.0 || self.frequency != 0.0 || self.presence != 0.0)
+    }
+}
 
 pub struct Llama2Sampler {
-    temperature: f32,
-    topp: f32,
+    stages: Vec<SamplerStage>,
+    penalties: Llama2Penalties,
+    seed: Option<u64>,
+    logit_bias: HashMap<TokenID, f32>,
+    banned_strings: Vec<String>,
     exp_cache: Arc<Vec<f16>>,
 }
 
@@ -17,93 +246,205 @@ pub type Llama2SamplerRef = Arc<Llama2Sampler>;
 
 impl Llama2Sampler {
     pub fn new(temperature: f32, topp: f32, exp_cache: Arc<Vec<f16>>) -> Llama2SamplerRef {
+        let stages = vec![
+            SamplerStage::Temperature(temperature),
+            SamplerStage::TopP(topp),
+        ];
+        Self::with_stages(stages, exp_cache)
+    }
+
+    pub fn with_stages(stages: Vec<SamplerStage>, exp_cache: Arc<Vec<f16>>) -> Llama2SamplerRef {
+        Self::with_penalties(stages, Llama2Penalties::default(), exp_cache)
+    }
+
+    pub fn with_penalties(
+        stages: Vec<SamplerStage>,
+        penalties: Llama2Penalties,
+        exp_cache: Arc<Vec<f16>>,
+    ) -> Llama2SamplerRef {
         Arc::new(Self {
-            temperature,
-            topp,
+            stages,
+            penalties,
+            seed: None,
+            logit_bias: HashMap::new(),
+            banned_strings: vec![],
             exp_cache,
         })
     }
 
-    pub fn sample(&self, logits: &mut [f32], prob_index: &mut [(f32, usize)]) -> Result<usize> {
-        if self.temperature == 0.0 {
-            return Self::sample_argmax(logits);
-        }
+    pub fn from_options(
+        options: &Llama2SamplerOptions,
+        exp_cache: Arc<Vec<f16>>,
+    ) -> Result<Llama2SamplerRef> {
+        let penalties = Llama2Penalties {
+            last_n: options.repeat_last_n,
+            repeat: options.repeat_penalty,
+            frequency: options.frequency_penalty,
+            presence: options.presence_penalty,
+            penalize_nl: options.penalize_nl,
+        };
+        Ok(Arc::new(Self {
+            stages: options.stages()?,
+            penalties,
+            seed: options.seed,
+            logit_bias: options.logit_bias.clone(),
+            banned_strings: options.banned_strings.clone(),
+            exp_cache,
+        }))
+    }
 
-        // apply the temperature to the logits. the lower the temperature,
-        // the more deterministic the sampling.
-        for logit in logits.iter_mut() {
-            *logit /= self.temperature;
-        }
-        // apply softmax to the logits to get the probabilities for next token
-        softmax(logits, self.exp_cache.as_ref());
+    pub fn stages(&self) -> &[SamplerStage] {
+        &self.stages
+    }
 
-        // flip a (float) coin (this is our source of entropy for sampling)
-        let mut rng = rand::thread_rng();
-        let coin: f32 = rng.gen_range(0.0..1.0);
+    pub fn penalties(&self) -> &Llama2Penalties {
+        &self.penalties
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
 
-        // we sample from this distribution to get the next token
-        if self.topp <= 0_f32 || self.topp >= 1.0_f32 {
-            // simply sample from the predicted probability distribution
-            Self::sample_multi(logits, coin);
+    /// resolve the logit bias and the banned strings into the biases of the tokens, which are
+    /// applied by the runner before the penalties on every step.
+    pub fn resolve_logit_bias(&self, tokenizer: &Tokenizer) -> Result<Vec<(TokenID, f32)>> {
+        let vocab_size = tokenizer.vocab().len();
+        let mut biases = HashMap::new();
+        for (token, bias) in self.logit_bias.iter() {
+            if *token >= vocab_size {
+                bail!(
+                    ErrorKind::BadInput,
+                    "the token {} in the logit bias is out of the vocab size {}",
+                    token,
+                    vocab_size
+                );
+            }
+            biases.insert(*token, *bias);
+        }
+        for banned in self.banned_strings.iter() {
+            let mut tokens = tokenizer.encode(banned, false, false)?;
+            // the tokenizer may prefix the text with a lone space token, which is kept
+            if !banned.starts_with(' ')
+                && tokens
+                    .first()
+                    .is_some_and(|t| tokenizer.token_bytes(*t) == b" ")
+            {
+                tokens.remove(0);
+            }
+            for token in tokens {
+                biases.insert(token, f32::NEG_INFINITY);
+            }
         }
 
-        Self::sample_topp(logits, self.topp, prob_index, coin)
+        let mut biases = biases.into_iter().collect::<Vec<_>>();
+        biases.sort_by_key(|(token, _)| *token);
+        Ok(biases)
     }
 
-    fn sample_multi(probs: &[f32], coin: f32) -> usize {
-        // sample index from probabilities (they must sum to 1!)
-        // coin is a random number in [0, 1), usually from random_f32()
-        let mut cdf = 0_f32;
-        for (i, p) in probs.iter().enumerate() {
-            cdf += p;
-            if cdf > coin {
-                return i;
+    /// the rng is owned by the caller like the runner, so the sampler is kept stateless and
+    /// can be shared between the runners.
+    pub fn new_rng(&self) -> StdRng {
+        match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        }
+    }
+
+    /// apply the penalties on the logits of the tokens in the history, the history is expected
+    /// to be the last `penalties.last_n` tokens.
+    pub fn penalize(&self, logits: &mut [f32], history: &[TokenID], tokenizer: &Tokenizer) {
+        if !self.penalties.is_enabled() {
+            return;
+        }
+
+        let mut counts: HashMap<TokenID, usize> = HashMap::new();
+        for token in history {
+            *counts.entry(*token).or_default() += 1;
+        }
+
+        for (token, count) in counts {
+            let exempted =
+                tokenizer.newline_token() == Some(token) || tokenizer.is_special_token(token);
+            if exempted && !self.penalties.penalize_nl {
+                continue;
+            }
+            let logit = &mut logits[token];
+            if *logit <= 0.0 {
+                *logit *= self.penalties.repeat;
+            } else {
+                *logit /= self.penalties.repeat;
             }
+            *logit -= count as f32 * self.penalties.frequency + self.penalties.presence;
         }
-        probs.len() - 1 // in case of rounding errors
     }
 
-    fn sample_topp(
-        probs: &[f32],
-        topp: f32,
+    pub fn sample(
+        &self,
+        logits: &mut [f32],
         prob_index: &mut [(f32, usize)],
-        coin: f32,
+        rng: &mut impl Rng,
     ) -> Result<usize> {
-        // top-p sampling (or "nucleus sampling") samples from the smallest set of
-        // tokens that exceed probability topp. This way we never sample tokens that
-        // have very low probabilities and are less likely to go "off the rails".
-        // coin is a random number in [0, 1), usually from random_f32()
-        let cutoff = (1.0_f32 - topp) / (probs.len() - 1) as f32;
-        let mut n0 = 0;
-        for (i, prob) in probs.iter().enumerate() {
-            if *prob >= cutoff {
-                prob_index[n0] = (probs[i], i);
-                n0 += 1;
-            }
+        // like llama.cpp, a zero temperature takes the argmax no matter what the other stages are
+        if self
+            .stages
+            .iter()
+            .any(|s| matches!(s, SamplerStage::Temperature(t) if *t <= 0.0))
+        {
+            return Self::sample_argmax(logits);
         }
-        prob_index[..n0].sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
 
-        // truncate the list where cumulative probability exceeds topp
-        let mut cumulative_prob = 0_f32;
-        let mut last_idx = n0 - 1; // in case of rounding errors consider all elements
-        for (i, prob) in prob_index[0..n0].iter().enumerate() {
-            cumulative_prob += prob.0;
-            if cumulative_prob > topp {
-                last_idx = i;
-                break; // we've exceeded topp by including last_idx
-            }
+        let mut candidates = self.apply_stages(logits, prob_index);
+        candidates.softmax(&self.exp_cache);
+
+        // flip a (float) coin (this is our source of entropy for sampling)
+        let coin: f32 = rng.gen_range(0.0..1.0);
+        Ok(candidates.sample(coin))
+    }
+
+    /// the distribution the token would be sampled from, the candidates dropped by the stages
+    /// get zero, and a zero temperature puts all the mass on the argmax. it's used to accept or
+    /// reject the drafted tokens on the speculative decoding.
+    pub fn probs(
+        &self,
+        logits: &mut [f32],
+        prob_index: &mut [(f32, usize)],
+        probs: &mut [f32],
+    ) -> Result<()> {
+        probs.fill(0.0);
+        if self
+            .stages
+            .iter()
+            .any(|s| matches!(s, SamplerStage::Temperature(t) if *t <= 0.0))
+        {
+            probs[Self::sample_argmax(logits)?] = 1.0;
+            return Ok(());
         }
 
-        // sample from the truncated list
-        let r = coin * cumulative_prob;
-        let mut cdf = 0_f32;
-        for prob in prob_index[0..=last_idx].iter() {
-            cdf += prob.0;
-            if cdf > r {
-                return Ok(prob.1);
+        let mut candidates = self.apply_stages(logits, prob_index);
+        candidates.softmax(&self.exp_cache);
+        for (prob, token) in candidates.as_slice() {
+            probs[*token] = *prob;
+        }
+        Ok(())
+    }
+
+    fn apply_stages<'a>(
+        &self,
+        logits: &'a mut [f32],
+        prob_index: &'a mut [(f32, usize)],
+    ) -> Candidates<'a> {
+        let mut candidates = Candidates::new(logits, prob_index);
+        for stage in self.stages.iter() {
+            match *stage {
+                SamplerStage::TopK(k) => candidates.top_k(k),
+                SamplerStage::TailFree(z) => candidates.tail_free(z, &self.exp_cache),
+                SamplerStage::Typical(p) => candidates.typical(p, &self.exp_cache),
+                SamplerStage::TopP(p) => candidates.top_p(p, &self.exp_cache),
+                SamplerStage::MinP(p) => candidates.min_p(p),
+                SamplerStage::Temperature(t) => candidates.temperature(t),
             }
         }
-        Ok(prob_index[last_idx].1) // in case of rounding errors
+        candidates
     }
 
     fn sample_argmax(probs: &[f32]) -> Result<usize> {
@@ -116,14 +457,394 @@ impl Llama2Sampler {
     }
 }
 
-fn softmax(a: &mut [f32], exp_cache: &[f16]) {
-    let max = a.iter().fold(f32::NAN, |a, b| a.max(*b));
-    let mut sum = 0.0;
-    for a in a.iter_mut() {
-        *a = exp_f32_cached(*a - max, exp_cache);
-        sum += *a;
+/// the candidates are kept as (prob, token id) in the front `len` items of prob_index, and
+/// the logit of a candidate is looked up from the logits by its token id. the probs are only
+/// valid after `softmax()`.
+struct Candidates<'a> {
+    logits: &'a mut [f32],
+    items: &'a mut [(f32, usize)],
+    len: usize,
+
+    // whether the candidates are sorted by logits in descending order
+    sorted: bool,
+}
+
+impl<'a> Candidates<'a> {
+    fn new(logits: &'a mut [f32], items: &'a mut [(f32, usize)]) -> Self {
+        let len = logits.len();
+        for (i, item) in items[..len].iter_mut().enumerate() {
+            *item = (0.0, i);
+        }
+        Self {
+            logits,
+            items,
+            len,
+            sorted: false,
+        }
+    }
+
+    fn as_slice(&self) -> &[(f32, usize)] {
+        &self.items[..self.len]
+    }
+
+    fn softmax(&mut self, exp_cache: &[f16]) {
+        let logits = &self.logits;
+        let items = &mut self.items[..self.len];
+        let max = items
+            .iter()
+            .fold(f32::NEG_INFINITY, |m, (_, id)| m.max(logits[*id]));
+        let mut sum = 0.0;
+        for item in items.iter_mut() {
+            item.0 = exp_f32_cached(logits[item.1] - max, exp_cache);
+            sum += item.0;
+        }
+        for item in items.iter_mut() {
+            item.0 /= sum;
+        }
+    }
+
+    fn sort(&mut self) {
+        if !self.sorted {
+            let logits = &self.logits;
+            self.items[..self.len].sort_unstable_by(|a, b| logits[b.1].total_cmp(&logits[a.1]));
+            self.sorted = true;
+        }
+    }
+
+    fn top_k(&mut self, k: usize) {
+        if k == 0 || k >= self.len {
+            return;
+        }
+        if !self.sorted {
+            let logits = &self.logits;
+            self.items[..self.len]
+                .select_nth_unstable_by(k - 1, |a, b| logits[b.1].total_cmp(&logits[a.1]));
+        }
+        self.len = k;
+        self.sort();
+    }
+
+    fn tail_free(&mut self, z: f32, exp_cache: &[f16]) {
+        if z >= 1.0 || self.len <= 2 {
+            return;
+        }
+        self.sort();
+        self.softmax(exp_cache);
+
+        let probs = self.as_slice().iter().map(|c| c.0).collect::<Vec<_>>();
+        let first_derivatives = probs.windows(2).map(|w| w[0] - w[1]).collect::<Vec<_>>();
+        let mut second_derivatives = first_derivatives
+            .windows(2)
+            .map(|w| (w[0] - w[1]).abs())
+            .collect::<Vec<_>>();
+        let sum = second_derivatives.iter().sum::<f32>();
+        let n = second_derivatives.len() as f32;
+        for d in second_derivatives.iter_mut() {
+            *d = if sum > 1e-6 { *d / sum } else { 1.0 / n };
+        }
+
+        let mut cumulative = 0.0;
+        for (i, d) in second_derivatives.iter().enumerate() {
+            cumulative += d;
+            if cumulative > z && i >= 1 {
+                self.len = i;
+                break;
+            }
+        }
+    }
+
+    fn typical(&mut self, p: f32, exp_cache: &[f16]) {
+        if p >= 1.0 {
+            return;
+        }
+        self.softmax(exp_cache);
+
+        let entropy = -self
+            .as_slice()
+            .iter()
+            .filter(|c| c.0 > 0.0)
+            .map(|c| c.0 * c.0.ln())
+            .sum::<f32>();
+
+        // sort by how far the information content of each candidate is from the entropy
+        let mut shifted = self
+            .as_slice()
+            .iter()
+            .map(|c| ((-c.0.ln() - entropy).abs(), *c))
+            .collect::<Vec<_>>();
+        shifted.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut cumulative = 0.0;
+        let mut last_idx = shifted.len();
+        for (i, (_, c)) in shifted.iter().enumerate() {
+            cumulative += c.0;
+            if cumulative > p {
+                last_idx = i + 1;
+                break;
+            }
+        }
+        for (i, (_, c)) in shifted[..last_idx].iter().enumerate() {
+            self.items[i] = *c;
+        }
+        self.len = last_idx;
+        self.sorted = false;
     }
-    for a in a.iter_mut() {
-        *a /= sum;
+
+    fn top_p(&mut self, p: f32, exp_cache: &[f16]) {
+        if p <= 0.0 || p >= 1.0 {
+            return;
+        }
+        self.softmax(exp_cache);
+
+        // the candidates with prob less than (1 - p) / (n - 1) can never be in the top-p set,
+        // drop them before sorting, which makes the sort much faster on a large vocab.
+        if !self.sorted && self.len > 1 {
+            let cutoff = (1.0 - p) / (self.len - 1) as f32;
+            self.retain(|c, _| c.0 >= cutoff);
+            self.sort();
+        }
+
+        let mut cumulative = 0.0;
+        for (i, c) in self.as_slice().iter().enumerate() {
+            cumulative += c.0;
+            if cumulative >= p {
+                self.len = i + 1;
+                break;
+            }
+        }
+    }
+
+    fn min_p(&mut self, p: f32) {
+        if p <= 0.0 || self.len == 0 {
+            return;
+        }
+        // p_i >= p * p_max is the same as logit_i >= logit_max + ln(p)
+        let logits = &self.logits;
+        let max = self
+            .as_slice()
+            .iter()
+            .fold(f32::NEG_INFINITY, |m, (_, id)| m.max(logits[*id]));
+        let threshold = max + p.ln();
+        self.retain(|c, logits| logits[c.1] >= threshold);
+    }
+
+    fn temperature(&mut self, t: f32) {
+        if t <= 0.0 {
+            return;
+        }
+        for (_, id) in self.items[..self.len].iter() {
+            self.logits[*id] /= t;
+        }
+    }
+
+    // sample from the probs of the candidates, coin is a random number in [0, 1)
+    fn sample(&self, coin: f32) -> usize {
+        let candidates = self.as_slice();
+        let mut cdf = 0.0;
+        for (prob, id) in candidates.iter() {
+            cdf += prob;
+            if cdf > coin {
+                return *id;
+            }
+        }
+        candidates[candidates.len() - 1].1 // in case of rounding errors
+    }
+
+    // keep the candidates matching the predicate in their original order
+    fn retain(&mut self, f: impl Fn(&(f32, usize), &[f32]) -> bool) {
+        let mut n = 0;
+        for i in 0..self.len {
+            if f(&self.items[i], self.logits) {
+                self.items[n] = self.items[i];
+                n += 1;
+            }
+        }
+        self.len = n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crabml::tokenizer::Tokenizer;
+    use half::f16;
+
+    use super::Llama2Sampler;
+    use super::Llama2SamplerOptions;
+    use super::SamplerStage;
+
+    fn exp_cache() -> Arc<Vec<f16>> {
+        let cache = (0..65536)
+            .map(|x| f16::from_f32(f16::from_bits(x as u16).to_f32().exp()))
+            .collect::<Vec<_>>();
+        Arc::new(cache)
+    }
+
+    // apply the stages on the logits, returns the sorted ids of the candidates left
+    fn candidate_ids(stages: Vec<SamplerStage>, logits: &[f32]) -> Vec<usize> {
+        let sampler = Llama2Sampler::with_stages(stages, exp_cache());
+        let mut logits = logits.to_vec();
+        let mut prob_index = vec![(0.0, 0); logits.len()];
+        let candidates = sampler.apply_stages(&mut logits, &mut prob_index);
+        let mut ids = candidates
+            .as_slice()
+            .iter()
+            .map(|c| c.1)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_sampler_stages() {
+        // the probs are about [0.032, 0.087, 0.237, 0.644, 0.0002]
+        let logits = [1.0, 2.0, 3.0, 4.0, -4.0];
+
+        assert_eq!(candidate_ids(vec![SamplerStage::TopK(2)], &logits), vec![
+            2, 3
+        ]);
+        assert_eq!(candidate_ids(vec![SamplerStage::TopK(0)], &logits), vec![
+            0, 1, 2, 3, 4
+        ]);
+        assert_eq!(candidate_ids(vec![SamplerStage::TopP(0.8)], &logits), vec![
+            2, 3
+        ]);
+        assert_eq!(candidate_ids(vec![SamplerStage::MinP(0.1)], &logits), vec![
+            1, 2, 3
+        ]);
+        assert_eq!(
+            candidate_ids(vec![SamplerStage::TailFree(0.95)], &logits),
+            vec![2, 3]
+        );
+        assert_eq!(
+            candidate_ids(vec![SamplerStage::Typical(0.5)], &logits),
+            vec![2, 3]
+        );
+
+        // the stages are chained, top-k keeps 3 and min-p drops the lowest one of them
+        let stages = vec![SamplerStage::TopK(3), SamplerStage::MinP(0.2)];
+        assert_eq!(candidate_ids(stages, &logits), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_sampler_sample() {
+        let logits = [1.0, 2.0, 3.0, 4.0, -4.0];
+        let mut prob_index = vec![(0.0, 0); logits.len()];
+        let mut rng = rand::thread_rng();
+
+        let sampler = Llama2Sampler::with_stages(
+            vec![SamplerStage::TopK(1), SamplerStage::Temperature(0.8)],
+            exp_cache(),
+        );
+        for _ in 0..10 {
+            let token = sampler
+                .sample(&mut logits.clone(), &mut prob_index, &mut rng)
+                .unwrap();
+            assert_eq!(token, 3);
+        }
+
+        let sampler = Llama2Sampler::with_stages(
+            vec![SamplerStage::TopK(2), SamplerStage::Temperature(1.5)],
+            exp_cache(),
+        );
+        for _ in 0..10 {
+            let token = sampler
+                .sample(&mut logits.clone(), &mut prob_index, &mut rng)
+                .unwrap();
+            assert!(token == 2 || token == 3);
+        }
+
+        // zero temperature takes the argmax
+        let sampler = Llama2Sampler::with_stages(
+            vec![SamplerStage::MinP(0.9), SamplerStage::Temperature(0.0)],
+            exp_cache(),
+        );
+        let token = sampler
+            .sample(&mut logits.clone(), &mut prob_index, &mut rng)
+            .unwrap();
+        assert_eq!(token, 3);
+    }
+
+    #[test]
+    fn test_sampler_penalize() {
+        let vocab = ["<unk>", "<s>", "</s>", "<0x0A>", "a", "b", "c"];
+        let tokenizer = Tokenizer::new_llama(
+            vocab.iter().map(|s| s.to_string()).collect(),
+            vec![0.0; vocab.len()],
+            1,
+            2,
+        );
+        let options = Llama2SamplerOptions::default()
+            .with_repeat_penalty(2.0)
+            .with_frequency_penalty(0.5)
+            .with_presence_penalty(0.25);
+        let sampler = Llama2Sampler::from_options(&options, exp_cache()).unwrap();
+
+        // "a" appears twice, "b" once, the newline and </s> are exempted
+        let history = [4, 4, 5, 3, 2];
+        let mut logits = vec![1.0, 1.0, 1.0, 1.0, 2.0, -2.0, 3.0];
+        sampler.penalize(&mut logits, &history, &tokenizer);
+        assert_eq!(logits, vec![1.0, 1.0, 1.0, 1.0, -0.25, -4.75, 3.0]);
+
+        let sampler =
+            Llama2Sampler::from_options(&options.with_penalize_nl(true), exp_cache()).unwrap();
+        let mut logits = vec![1.0, 1.0, 1.0, 1.0, 2.0, -2.0, 3.0];
+        sampler.penalize(&mut logits, &history, &tokenizer);
+        assert_eq!(logits, vec![1.0, 1.0, -0.25, -0.25, -0.25, -4.75, 3.0]);
+    }
+
+    #[test]
+    fn test_sampler_seed() {
+        let logits = (0..64).map(|i| (i % 7) as f32 * 0.1).collect::<Vec<_>>();
+        let mut prob_index = vec![(0.0, 0); logits.len()];
+        let options = Llama2SamplerOptions::default()
+            .with_temperature(1.0)
+            .with_seed(Some(42));
+        let sampler = Llama2Sampler::from_options(&options, exp_cache()).unwrap();
+
+        // the same seed always samples the same tokens
+        let mut sample_tokens = |sampler: &Llama2Sampler| {
+            let mut rng = sampler.new_rng();
+            (0..32)
+                .map(|_| {
+                    sampler
+                        .sample(&mut logits.clone(), &mut prob_index, &mut rng)
+                        .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        let tokens = sample_tokens(&sampler);
+        assert_eq!(sample_tokens(&sampler), tokens);
+
+        let sampler =
+            Llama2Sampler::from_options(&options.with_seed(Some(43)), exp_cache()).unwrap();
+        assert_ne!(sample_tokens(&sampler), tokens);
+    }
+
+    #[test]
+    fn test_sampler_options() {
+        let options = Llama2SamplerOptions::default()
+            .with_top_k(40)
+            .with_top_p(0.95)
+            .with_min_p(0.05)
+            .with_temperature(0.8);
+        assert_eq!(options.stages().unwrap(), vec![
+            SamplerStage::TopK(40),
+            SamplerStage::TailFree(1.0),
+            SamplerStage::Typical(1.0),
+            SamplerStage::TopP(0.95),
+            SamplerStage::MinP(0.05),
+            SamplerStage::Temperature(0.8),
+        ]);
+
+        let options = options.with_sequence("kt");
+        assert_eq!(options.stages().unwrap(), vec![
+            SamplerStage::TopK(40),
+            SamplerStage::Temperature(0.8),
+        ]);
+
+        let options = options.with_sequence("kx");
+        assert!(options.stages().is_err());
     }
 }
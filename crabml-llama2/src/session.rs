@@ -0,0 +1,292 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+
+use crabml::bail;
+use crabml::error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGMLType;
+use crabml::gguf::GGUFTensorInfo;
+use crabml::tokenizer::TokenID;
+use crabml::tokenizer::Tokenizer;
+use half::f16;
+
+use crate::model::LlamaConfig;
+
+const SESSION_MAGIC: &[u8; 8] = b"CRABSESS";
+const SESSION_VERSION: u32 = 1;
+
+/// the state of a runner which is saved in a session file: the kv caches of each layer in
+/// the layout of (n_kv_heads, n_tokens, head_size), the tokens held in the kv caches and the
+/// recent tokens for the penalties.
+///
+/// the file is laid out as the magic, the version, the fingerprint, the header numbers and
+/// the arrays, all in little endian. the kv caches are stored in the dtype of the runner's
+/// cache, which is either F32 or F16.
+pub(crate) struct Session {
+    pub fingerprint: u64,
+    pub dtype: GGMLType,
+    pub n_layers: usize,
+    pub n_kv_heads: usize,
+    pub head_size: usize,
+    pub tokens: Vec<TokenID>,
+    pub history: Vec<TokenID>,
+    pub key_cache: Vec<Vec<f32>>,
+    pub value_cache: Vec<Vec<f32>>,
+}
+
+impl Session {
+    pub fn write_to_file(&self, path: &str) -> Result<()> {
+        let file = File::create(path).map_err(|err| {
+            error!(
+                ErrorKind::IOError,
+                "failed to create the session file {}: {}", path, err
+            )
+        })?;
+        let mut w = BufWriter::new(file);
+        self.write(&mut w).map_err(|err| {
+            error!(
+                ErrorKind::IOError,
+                "failed to write the session file {}: {}", path, err
+            )
+        })
+    }
+
+    /// read the session saved from the model of the config. the header is checked against the
+    /// model before reading the kv caches, so a corrupted header can not make it allocate more
+    /// than the kv caches of the context length.
+    pub fn read_from_file(path: &str, conf: &LlamaConfig, tokenizer: &Tokenizer) -> Result<Self> {
+        let file = File::open(path).map_err(|err| {
+            error!(
+                ErrorKind::IOError,
+                "failed to open the session file {}: {}", path, err
+            )
+        })?;
+        let mut r = BufReader::new(file);
+        Self::read(&mut r, conf, tokenizer).map_err(|err| {
+            error!(
+                err.kind,
+                "failed to read the session file {}: {}", path, err.message
+            )
+        })
+    }
+
+    fn write(&self, w: &mut impl Write) -> std::io::Result<()> {
+        w.write_all(SESSION_MAGIC)?;
+        write_u32(w, SESSION_VERSION)?;
+        w.write_all(&self.fingerprint.to_le_bytes())?;
+        write_u32(w, self.dtype as u32)?;
+        write_u32(w, self.n_layers as u32)?;
+        write_u32(w, self.n_kv_heads as u32)?;
+        write_u32(w, self.head_size as u32)?;
+        write_tokens(w, &self.tokens)?;
+        write_tokens(w, &self.history)?;
+        for (k, v) in self.key_cache.iter().zip(self.value_cache.iter()) {
+            write_values(w, k, self.dtype)?;
+            write_values(w, v, self.dtype)?;
+        }
+        w.flush()
+    }
+
+    fn read(r: &mut impl Read, conf: &LlamaConfig, tokenizer: &Tokenizer) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        read_exact(r, &mut magic)?;
+        if &magic != SESSION_MAGIC {
+            bail!(ErrorKind::FormatError, "not a session file");
+        }
+        let version = read_u32(r)?;
+        if version != SESSION_VERSION {
+            bail!(
+                ErrorKind::FormatError,
+                "unsupported session version {}",
+                version
+            );
+        }
+        let mut fingerprint = [0u8; 8];
+        read_exact(r, &mut fingerprint)?;
+        let fingerprint = u64::from_le_bytes(fingerprint);
+        if fingerprint != model_fingerprint(conf, tokenizer) {
+            bail!(
+                ErrorKind::BadInput,
+                "the session is saved from a different model"
+            );
+        }
+        let dtype = GGMLType::try_from(read_u32(r)?)?;
+        if dtype != GGMLType::F32 && dtype != GGMLType::F16 {
+            bail!(
+                ErrorKind::FormatError,
+                "unsupported kv cache type {}",
+                dtype
+            );
+        }
+        let n_layers = read_u32(r)? as usize;
+        let n_kv_heads = read_u32(r)? as usize;
+        let head_size = read_u32(r)? as usize;
+        if n_layers != conf.n_layers
+            || n_kv_heads != conf.n_kv_heads
+            || head_size != conf.head_size()
+        {
+            bail!(
+                ErrorKind::FormatError,
+                "the shape of the session does not match the model"
+            );
+        }
+        let tokens = read_tokens(r)?;
+        if tokens.len() >= conf.seq_len {
+            bail!(
+                ErrorKind::BadInput,
+                "the session of {} tokens exceeds the context length {}",
+                tokens.len(),
+                conf.seq_len
+            );
+        }
+        let history = read_tokens(r)?;
+
+        let n_values = n_kv_heads * tokens.len() * head_size;
+        let mut key_cache = Vec::with_capacity(n_layers);
+        let mut value_cache = Vec::with_capacity(n_layers);
+        for _ in 0..n_layers {
+            key_cache.push(read_values(r, n_values, dtype)?);
+            value_cache.push(read_values(r, n_values, dtype)?);
+        }
+        Ok(Self {
+            fingerprint,
+            dtype,
+            n_layers,
+            n_kv_heads,
+            head_size,
+            tokens,
+            history,
+            key_cache,
+            value_cache,
+        })
+    }
+}
+
+/// a hash of the model config, the tensors and the vocab, a session is only allowed to be
+/// loaded by the model it's saved from. the weights are not hashed, so the finetunes sharing
+/// the same architecture, tensors and vocab can not be told apart.
+pub(crate) fn model_fingerprint(conf: &LlamaConfig, tokenizer: &Tokenizer) -> u64 {
+    let mut h = Fnv1a::new();
+    h.write(format!("{:?}", conf.architecture).as_bytes());
+    h.write(conf.model_name.as_bytes());
+    for n in [
+        conf.embedding_dim,
+        conf.hidden_dim,
+        conf.n_layers,
+        conf.n_heads,
+        conf.n_kv_heads,
+        conf.vocab_size,
+        conf.rope_dim.unwrap_or(0),
+        conf.sliding_window.unwrap_or(0),
+    ] {
+        h.write(&(n as u64).to_le_bytes());
+    }
+    h.write(&conf.rms_norm_eps.to_le_bytes());
+    h.write(&conf.rope_freq_base.to_le_bytes());
+    h.write(format!("{:?}", conf.rope_scaling).as_bytes());
+    h.write(&conf.tensors_fingerprint.to_le_bytes());
+    for token in tokenizer.vocab() {
+        h.write(token.as_bytes());
+        h.write(&[0]);
+    }
+    h.finish()
+}
+
+/// a hash of the names, types and shapes of the tensors in the model file, which is kept in
+/// the config as `tensors_fingerprint`.
+pub(crate) fn tensors_fingerprint(tensor_infos: &[GGUFTensorInfo]) -> u64 {
+    let mut h = Fnv1a::new();
+    for ti in tensor_infos {
+        h.write(ti.name().as_bytes());
+        h.write(&[0]);
+        h.write(&(ti.typ() as u32).to_le_bytes());
+        for dim in ti.dimensions() {
+            h.write(&(*dim as u64).to_le_bytes());
+        }
+        h.write(&[0]);
+    }
+    h.finish()
+}
+
+// the std DefaultHasher is not guaranteed to be stable across the rust releases, while the
+// fingerprint is persisted in the files
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn write_u32(w: &mut impl Write, v: u32) -> std::io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_tokens(w: &mut impl Write, tokens: &[TokenID]) -> std::io::Result<()> {
+    write_u32(w, tokens.len() as u32)?;
+    for token in tokens {
+        write_u32(w, *token as u32)?;
+    }
+    Ok(())
+}
+
+fn write_values(w: &mut impl Write, values: &[f32], dtype: GGMLType) -> std::io::Result<()> {
+    for v in values {
+        match dtype {
+            GGMLType::F16 => w.write_all(&f16::from_f32(*v).to_le_bytes())?,
+            _ => w.write_all(&v.to_le_bytes())?,
+        }
+    }
+    Ok(())
+}
+
+fn read_exact(r: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    r.read_exact(buf)
+        .map_err(|err| error!(ErrorKind::IOError, "{}", err))
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    read_exact(r, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_tokens(r: &mut impl Read) -> Result<Vec<TokenID>> {
+    let n = read_u32(r)? as usize;
+    (0..n).map(|_| read_u32(r).map(|t| t as TokenID)).collect()
+}
+
+fn read_values(r: &mut impl Read, n: usize, dtype: GGMLType) -> Result<Vec<f32>> {
+    let elem_size = match dtype {
+        GGMLType::F16 => 2,
+        _ => 4,
+    };
+    let mut buf = vec![0u8; n * elem_size];
+    read_exact(r, &mut buf)?;
+    let values = match dtype {
+        GGMLType::F16 => buf
+            .chunks_exact(2)
+            .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect(),
+        _ => buf
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+    };
+    Ok(values)
+}
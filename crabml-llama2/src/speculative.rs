@@ -0,0 +1,313 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+
+use crabml::bail;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::tensor::Tensor;
+use crabml::tokenizer::TokenID;
+use rand::Rng;
+
+use crate::llama2::Llama2Runner;
+
+/// the counts of the drafted tokens and the accepted ones, the acceptance rate tells how well
+/// the draft model follows the target model.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SpeculativeStats {
+    pub n_drafted: usize,
+    pub n_accepted: usize,
+}
+
+impl SpeculativeStats {
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.n_drafted == 0 {
+            return 0.0;
+        }
+        self.n_accepted as f32 / self.n_drafted as f32
+    }
+}
+
+/// speculative decoding with a small draft model sharing the same vocab with the target
+/// model. on each step the draft model proposes `n_draft` tokens one by one, and the target
+/// model evaluates them in one batched forward. a drafted token is accepted with the
+/// probability of min(1, p / q), where p and q are the probabilities of the token in the
+/// target and the draft distributions. on the first rejected token, a token is sampled from
+/// the residual distribution max(0, p - q) instead, so the output follows the distribution of
+/// the target model. the kv caches of the rejected tokens are rolled back on both runners.
+pub struct SpeculativeRunner<T: Tensor, D: Tensor> {
+    target: Llama2Runner<T>,
+    draft: Llama2Runner<D>,
+    n_draft: usize,
+    stats: SpeculativeStats,
+}
+
+impl<T: Tensor, D: Tensor> SpeculativeRunner<T, D> {
+    pub fn new(target: Llama2Runner<T>, draft: Llama2Runner<D>, n_draft: usize) -> Result<Self> {
+        if n_draft == 0 {
+            bail!(ErrorKind::BadInput, "expected at least 1 drafted token");
+        }
+        if target.tokenizer().vocab() != draft.tokenizer().vocab() {
+            bail!(
+                ErrorKind::BadInput,
+                "the draft model does not share the vocab with the target model"
+            );
+        }
+        // the grammar and the context shift change the state of the runners on each token,
+        // which can not be rolled back
+        if target.has_grammar() || draft.has_grammar() {
+            bail!(
+                ErrorKind::BadInput,
+                "the grammar is not supported on the speculative decoding"
+            );
+        }
+        if target.has_context_shift() || draft.has_context_shift() {
+            bail!(
+                ErrorKind::BadInput,
+                "the context shift is not supported on the speculative decoding"
+            );
+        }
+        Ok(Self {
+            target,
+            draft,
+            n_draft,
+            stats: SpeculativeStats::default(),
+        })
+    }
+
+    pub fn target(&self) -> &Llama2Runner<T> {
+        &self.target
+    }
+
+    pub fn draft(&self) -> &Llama2Runner<D> {
+        &self.draft
+    }
+
+    pub fn stats(&self) -> SpeculativeStats {
+        self.stats
+    }
+
+    pub fn into_inner(self) -> (Llama2Runner<T>, Llama2Runner<D>) {
+        (self.target, self.draft)
+    }
+
+    /// prefill the prompt on both models, the next token is sampled by the target model.
+    pub fn prefill(&mut self, prompt: &str, bos: bool) -> Result<(usize, usize, usize)> {
+        let tokens = self.target.tokenizer().encode(prompt, bos, false)?;
+        self.prefill_tokens(&tokens)
+    }
+
+    pub fn prefill_tokens(&mut self, tokens: &[TokenID]) -> Result<(usize, usize, usize)> {
+        self.draft.feed_tokens(tokens, true)?;
+        self.target.prefill_tokens(tokens, true)
+    }
+
+    /// like `Llama2Runner::generate`, the token sampled on prefilling is yielded first. it
+    /// stops on the eos token or when the context is full.
+    pub fn generate(
+        &mut self,
+        token: TokenID,
+        steps: Option<usize>,
+    ) -> impl Iterator<Item = Result<String>> + '_ {
+        let eos_token = self.target.tokenizer().eos_token();
+        let mut remaining = steps.unwrap_or(usize::MAX);
+        let mut queue = VecDeque::from([token]);
+        let mut next_token = token;
+        let mut first = true;
+        std::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            if queue.is_empty() {
+                match self.step(next_token) {
+                    Ok(tokens) if tokens.is_empty() => return None,
+                    Ok(tokens) => {
+                        next_token = *tokens.last().unwrap();
+                        queue.extend(tokens);
+                    }
+                    Err(err) => {
+                        remaining = 0;
+                        return Some(Err(err));
+                    }
+                }
+            }
+            let token = queue.pop_front()?;
+            if token == eos_token && !first {
+                remaining = 0;
+                return None;
+            }
+            first = false;
+            remaining -= 1;
+            Some(self.target.decode(token))
+        })
+    }
+
+    /// draft the tokens after `token`, verify them on the target model, and return the
+    /// accepted tokens followed by the token sampled by the target model. the last token is
+    /// not in the kv caches yet. returns nothing when the context is full.
+    fn step(&mut self, token: TokenID) -> Result<Vec<TokenID>> {
+        let pos = self.target.kv_cache_len();
+        // the kv cache holds at most seq_len - 1 tokens
+        let max_len = self.target.conf().seq_len.min(self.draft.conf().seq_len) - 1;
+        if pos + 1 > max_len {
+            return Ok(vec![]);
+        }
+        let n_draft = self.n_draft.min(max_len - pos - 1);
+        let eos_token = self.target.tokenizer().eos_token();
+
+        let mut drafted = Vec::with_capacity(n_draft);
+        let mut draft_probs = Vec::with_capacity(n_draft);
+        let mut last_token = token;
+        for _ in 0..n_draft {
+            self.draft.feed_tokens(&[last_token], false)?;
+            let probs = self.draft.next_probs()?;
+            last_token = sample_probs(&probs, self.draft.rng_mut());
+            drafted.push(last_token);
+            draft_probs.push(probs);
+            if last_token == eos_token {
+                break;
+            }
+        }
+
+        let mut inputs = Vec::with_capacity(drafted.len() + 1);
+        inputs.push(token);
+        inputs.extend_from_slice(&drafted);
+        let target_probs = self.target.verify_tokens(&inputs)?;
+
+        let mut accepted = Vec::with_capacity(drafted.len() + 1);
+        let mut resampled = None;
+        for (i, drafted_token) in drafted.iter().enumerate() {
+            let (p, q) = (&target_probs[i], &draft_probs[i]);
+            let coin: f32 = self.target.rng_mut().gen_range(0.0..1.0);
+            // coin < p / q, q is positive as the token is sampled from it
+            if coin * q[*drafted_token] < p[*drafted_token] {
+                accepted.push(*drafted_token);
+                continue;
+            }
+            let mut residual = p
+                .iter()
+                .zip(q.iter())
+                .map(|(p, q)| (p - q).max(0.0))
+                .collect::<Vec<_>>();
+            let sum = residual.iter().sum::<f32>();
+            if sum > 0.0 {
+                residual.iter_mut().for_each(|r| *r /= sum);
+            } else {
+                residual.copy_from_slice(p);
+            }
+            resampled = Some(sample_probs(&residual, self.target.rng_mut()));
+            break;
+        }
+        let new_token = match resampled {
+            Some(token) => token,
+            None => sample_probs(&target_probs[drafted.len()], self.target.rng_mut()),
+        };
+        self.stats.n_drafted += drafted.len();
+        self.stats.n_accepted += accepted.len();
+
+        // keep the token and the accepted ones in the kv caches. the draft model has not
+        // evaluated its last drafted token yet, which is fed if it's accepted.
+        let n_kept = pos + 1 + accepted.len();
+        self.target.rewind(self.target.kv_cache_len() - n_kept)?;
+        let draft_len = self.draft.kv_cache_len();
+        match draft_len.cmp(&n_kept) {
+            Ordering::Greater => self.draft.rewind(draft_len - n_kept)?,
+            Ordering::Less => {
+                self.draft
+                    .feed_tokens(&inputs[draft_len - pos..n_kept - pos], false)?;
+            }
+            Ordering::Equal => {}
+        }
+
+        accepted.push(new_token);
+        Ok(accepted)
+    }
+}
+
+fn sample_probs(probs: &[f32], rng: &mut impl Rng) -> TokenID {
+    let coin: f32 = rng.gen_range(0.0..1.0);
+    let mut cdf = 0.0;
+    for (token, prob) in probs.iter().enumerate() {
+        cdf += prob;
+        if cdf > coin {
+            return token;
+        }
+    }
+    // in case of rounding errors
+    probs
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, prob)| **prob > 0.0)
+        .map(|(token, _)| token)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::gguf::GGUFFileLoader;
+
+    use super::*;
+    use crate::model::CpuLlamaModelLoader;
+    use crate::Llama2SamplerOptions;
+
+    #[test]
+    fn test_speculative_greedy() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let output = runner
+            .prefill_and_generate("Lily is a cute cat", 40)?
+            .collect::<Result<Vec<String>>>()?;
+
+        // the draft model is the same as the target, so all the drafted tokens are accepted,
+        // and the greedy output is the same as the plain generation
+        let target = Llama2Runner::new(&lm, 200, false)?;
+        let draft = Llama2Runner::new(&lm, 200, false)?;
+        let mut speculative = SpeculativeRunner::new(target, draft, 4)?;
+        let (_, _, token) = speculative.prefill("Lily is a cute cat", true)?;
+        let output_speculative = speculative
+            .generate(token, Some(40))
+            .collect::<Result<Vec<String>>>()?;
+        assert_eq!(output_speculative, output);
+        let stats = speculative.stats();
+        assert!(stats.n_drafted > 0);
+        assert_eq!(stats.n_accepted, stats.n_drafted);
+        assert_eq!(speculative.target().tokens(), speculative.draft().tokens());
+        Ok(())
+    }
+
+    #[test]
+    fn test_speculative_rejections() -> Result<()> {
+        let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)?;
+        let gf = gl.open()?;
+        let lm = CpuLlamaModelLoader::new().load(&gf)?;
+        // the draft model samples with a high temperature, so some of its tokens are rejected
+        let options = Llama2SamplerOptions::default()
+            .with_temperature(2.0)
+            .with_seed(Some(42));
+        let lm_draft = CpuLlamaModelLoader::new()
+            .with_sampler_options(options)
+            .load(&gf)?;
+
+        let mut runner = Llama2Runner::new(&lm, 200, false)?;
+        let output = runner
+            .prefill_and_generate("Lily is a cute cat", 40)?
+            .collect::<Result<Vec<String>>>()?;
+
+        let target = Llama2Runner::new(&lm, 200, false)?;
+        let draft = Llama2Runner::new(&lm_draft, 200, false)?;
+        let mut speculative = SpeculativeRunner::new(target, draft, 4)?;
+        let (_, _, token) = speculative.prefill("Lily is a cute cat", true)?;
+        let output_speculative = speculative
+            .generate(token, Some(40))
+            .collect::<Result<Vec<String>>>()?;
+        // the target model samples greedily, the rejected tokens never leak into the output
+        assert_eq!(output_speculative, output);
+        let stats = speculative.stats();
+        assert!(stats.n_accepted < stats.n_drafted);
+        assert_eq!(speculative.target().tokens(), speculative.draft().tokens());
+        Ok(())
+    }
+}
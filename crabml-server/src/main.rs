@@ -0,0 +1,139 @@
+mod api;
+mod server;
+
+use std::path::Path;
+
+use clap::Parser;
+use crabml::error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml::gguf::GGUFFileLoader;
+use crabml_llama2::model::CpuLlamaModelLoader;
+use crabml_llama2::Llama2SamplerOptions;
+use server::Server;
+
+#[derive(Parser, Debug)]
+struct CommandArgs {
+    /// The checkpoint file to load
+    #[arg(short, long, default_value_t = format!("./testdata/tinyllamas-stories-15m-f32.gguf"))]
+    model: String,
+
+    /// The host to listen on
+    #[arg(long, default_value_t = format!("127.0.0.1"))]
+    host: String,
+
+    /// The port to listen on
+    #[arg(long, default_value_t = 8080)]
+    port: u16,
+
+    // The probability of sampling from the top-p, if the request does not specify top_p.
+    #[arg(short, long, default_value_t = 0.9)]
+    probability: f32,
+
+    // The temperature to take if the request does not specify it.
+    #[arg(short, long, default_value_t = 1.0)]
+    temperature: f32,
+
+    /// Keep only the k most likely tokens, 0 disables it
+    #[arg(long, default_value_t = 0)]
+    top_k: usize,
+
+    /// Drop the tokens whose probability is less than min-p * the max probability, 0 disables it
+    #[arg(long, default_value_t = 0.0)]
+    min_p: f32,
+
+    /// Locally typical sampling, 1.0 disables it
+    #[arg(long, default_value_t = 1.0)]
+    typical_p: f32,
+
+    /// Tail free sampling, 1.0 disables it
+    #[arg(long, default_value_t = 1.0)]
+    tfs_z: f32,
+
+    /// The number of recent tokens to penalize, 0 disables the penalties
+    #[arg(long, default_value_t = 64)]
+    repeat_last_n: usize,
+
+    /// Penalize the repeated tokens in the recent tokens, 1.0 disables it
+    #[arg(long, default_value_t = 1.0)]
+    repeat_penalty: f32,
+
+    /// Penalize the recent tokens by their frequency, 0.0 disables it
+    #[arg(long, default_value_t = 0.0)]
+    frequency_penalty: f32,
+
+    /// Penalize the tokens present in the recent tokens, 0.0 disables it
+    #[arg(long, default_value_t = 0.0)]
+    presence_penalty: f32,
+
+    /// Apply the penalties on the newline and the special tokens too
+    #[arg(long, default_value_t = false)]
+    penalize_nl: bool,
+
+    /// The seed of the sampling, the same seed on the same inputs always gives the same output
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// The order of the samplers, k: top-k, f: tail free, y: typical, p: top-p, m: min-p, t: temperature
+    #[arg(long, default_value_t = format!("kfypmt"))]
+    sampling_seq: String,
+
+    #[arg(short = 'T', long, default_value_t = 2)]
+    threads: usize,
+
+    /// mlock the mmaped file, it can help run faster without swapping
+    #[arg(long, default_value_t = false)]
+    mlock: bool,
+}
+
+fn main() -> Result<()> {
+    let args = CommandArgs::parse();
+
+    let mut thread_num = args.threads;
+    if thread_num == 0 {
+        thread_num = num_cpus::get();
+    }
+
+    eprintln!("loading model...");
+    let gl = GGUFFileLoader::new(&args.model, args.mlock)?;
+    let gf = gl.open()?;
+    let model = CpuLlamaModelLoader::new()
+        .with_thread_num(thread_num)
+        .load(&gf)?;
+
+    // take the file name as the model id, like "tinyllamas-stories-15m-f32"
+    let model_id = Path::new(&args.model)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or(args.model.clone());
+
+    // validate the sampling sequence before serving
+    let sampler_options = Llama2SamplerOptions::default()
+        .with_temperature(args.temperature)
+        .with_top_p(args.probability)
+        .with_top_k(args.top_k)
+        .with_min_p(args.min_p)
+        .with_typical_p(args.typical_p)
+        .with_tfs_z(args.tfs_z)
+        .with_repeat_last_n(args.repeat_last_n)
+        .with_repeat_penalty(args.repeat_penalty)
+        .with_frequency_penalty(args.frequency_penalty)
+        .with_presence_penalty(args.presence_penalty)
+        .with_penalize_nl(args.penalize_nl)
+        .with_seed(args.seed)
+        .with_sequence(&args.sampling_seq);
+    sampler_options.stages()?;
+
+    let http = tiny_http::Server::http((args.host.as_str(), args.port)).map_err(|err| {
+        error!(
+            ErrorKind::IOError,
+            "failed to listen on {}:{}: {}", args.host, args.port, err
+        )
+    })?;
+    eprintln!("serving {} on http://{}:{}", model_id, args.host, args.port);
+
+    Server::new(model, model_id)
+        .with_sampler_options(sampler_options)
+        .serve(&http);
+    Ok(())
+}
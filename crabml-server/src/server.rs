@@ -0,0 +1,924 @@
+use std::io::Write;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crabml::bail;
+use crabml::cpu::CpuTensor;
+use crabml::error;
+use crabml::error::Error;
+use crabml::error::ErrorKind;
+use crabml::error::Result;
+use crabml_llama2::json_schema::json_schema_to_grammar;
+use crabml_llama2::llama2::GeneratedToken;
+use crabml_llama2::llama2::Llama2Runner;
+use crabml_llama2::llama2::StopReason;
+use crabml_llama2::ChatRound;
+use crabml_llama2::CpuLlamaModel;
+use crabml_llama2::Grammar;
+use crabml_llama2::Llama2Chat;
+use crabml_llama2::Llama2Sampler;
+use crabml_llama2::Llama2SamplerOptions;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::json;
+use tiny_http::Header;
+use tiny_http::Method;
+use tiny_http::Request;
+use tiny_http::Response;
+
+use crate::api::ChatCompletionChoice;
+use crate::api::ChatCompletionRequest;
+use crate::api::ChatCompletionResponse;
+use crate::api::ChatDelta;
+use crate::api::ChatMessage;
+use crate::api::CompletionChoice;
+use crate::api::CompletionLogprobs;
+use crate::api::CompletionRequest;
+use crate::api::CompletionResponse;
+use crate::api::ErrorBody;
+use crate::api::ErrorResponse;
+use crate::api::Model;
+use crate::api::ModelList;
+use crate::api::SamplingParams;
+use crate::api::Usage;
+
+// the max_tokens of /v1/completions when it's not specified, the same as OpenAI's
+const DEFAULT_COMPLETION_MAX_TOKENS: usize = 16;
+
+// the max number of the most likely tokens returned on each position
+const MAX_LOGPROBS: usize = 20;
+
+/// serves the OpenAI compatible API on a CPU model. the requests are handled one by one. the
+/// runner is kept between the requests, and the prompt of each request is prefilled as the
+/// whole context, so a request only reuses the kv cache of the common prefix with the last
+/// one, but never sees its context.
+pub struct Server<'a> {
+    model: CpuLlamaModel<'a>,
+    model_id: String,
+    sampler_options: Llama2SamplerOptions,
+    request_count: usize,
+    runner: Option<Llama2Runner<CpuTensor<'a>>>,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(model: CpuLlamaModel<'a>, model_id: impl Into<String>) -> Self {
+        // this default value is suitable for running tests
+        Self {
+            model,
+            model_id: model_id.into(),
+            sampler_options: Llama2SamplerOptions::default(),
+            request_count: 0,
+            runner: None,
+        }
+    }
+
+    /// the default sampling options, the sampling parameters in the requests override them
+    pub fn with_sampler_options(mut self, options: Llama2SamplerOptions) -> Self {
+        self.sampler_options = options;
+        self
+    }
+
+    /// handle the incoming requests until the http server got unblocked.
+    pub fn serve(&mut self, http: &tiny_http::Server) {
+        for request in http.incoming_requests() {
+            if let Err(err) = self.handle(request) {
+                eprintln!("failed to handle request: {}", err);
+            }
+        }
+    }
+
+    fn handle(&mut self, mut request: Request) -> Result<()> {
+        let method = request.method().clone();
+        let path = request
+            .url()
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        match (&method, path.as_str()) {
+            (Method::Get, "/v1/models") => {
+                let models = self.models();
+                respond_json(request, 200, &models)
+            }
+            (Method::Post, "/v1/completions") => match read_json(&mut request) {
+                Ok(params) => self.completions(request, params),
+                Err(err) => respond_error(request, err),
+            },
+            (Method::Post, "/v1/chat/completions") => match read_json(&mut request) {
+                Ok(params) => self.chat_completions(request, params),
+                Err(err) => respond_error(request, err),
+            },
+            _ => {
+                let body = ErrorResponse {
+                    error: ErrorBody {
+                        message: format!("{} {} is not found", method, path),
+                        typ: "not_found_error",
+                    },
+                };
+                respond_json(request, 404, &body)
+            }
+        }
+    }
+
+    fn models(&self) -> ModelList {
+        ModelList {
+            object: "list",
+            data: vec![Model {
+                id: self.model_id.clone(),
+                object: "model",
+                created: 0,
+                owned_by: "crabml",
+            }],
+        }
+    }
+
+    fn completions(&mut self, request: Request, params: CompletionRequest) -> Result<()> {
+        let id = self.next_id("cmpl");
+        let created = unix_timestamp();
+        let max_tokens = params.max_tokens.unwrap_or(DEFAULT_COMPLETION_MAX_TOKENS);
+        let model_id = self.model_id.clone();
+        let response = |text: String, logprobs, finish_reason, usage| CompletionResponse {
+            id: id.clone(),
+            object: "text_completion",
+            created,
+            model: model_id.clone(),
+            choices: vec![CompletionChoice {
+                index: 0,
+                text,
+                logprobs,
+                finish_reason,
+            }],
+            usage,
+        };
+
+        let prepared = self
+            .take_runner(Some(max_tokens), &params.sampling)
+            .and_then(|runner| {
+                if params.logprobs.is_some_and(|n| n > MAX_LOGPROBS) {
+                    bail!(
+                        ErrorKind::BadInput,
+                        "logprobs must be less than or equal to {}",
+                        MAX_LOGPROBS
+                    );
+                }
+                let mut runner = match request_grammar(&params.sampling)? {
+                    Some(grammar) => runner.with_grammar(grammar),
+                    None => runner,
+                };
+                let prompt_tokens = self.model.tokenizer.encode(&params.prompt, true, false)?;
+                let (pos, _prev_token, token) =
+                    runner.prefill_with_prefix_cache(&prompt_tokens, true)?;
+                Ok((runner, pos, token))
+            });
+        let (mut runner, pos, token) = match prepared {
+            Ok(prepared) => prepared,
+            Err(err) => return respond_error(request, err),
+        };
+        let top_n = params.logprobs.unwrap_or(0);
+        let tokens = runner.generate_tokens(pos, token, Some(max_tokens), top_n);
+
+        if params.stream {
+            let mut stream = EventStream::start(request)?;
+            let mut completion_tokens = 0;
+            let mut text_offset = 0;
+            for token in tokens {
+                let token = match token {
+                    Ok(token) => token,
+                    Err(err) => return stream.finish_with_error(err),
+                };
+                completion_tokens += 1;
+                let logprobs = params.logprobs.map(|_| {
+                    let mut logprobs = CompletionLogprobs::default();
+                    self.push_logprobs(&mut logprobs, &token, text_offset);
+                    logprobs
+                });
+                text_offset += token.piece.len();
+                stream.send(&response(token.piece, logprobs, None, None))?;
+            }
+            let finish_reason = finish_reason(runner.stop_reason(), completion_tokens, max_tokens);
+            stream.send(&response("".to_string(), None, Some(finish_reason), None))?;
+            self.runner = Some(runner);
+            return stream.finish();
+        }
+
+        let mut text = String::new();
+        let mut logprobs = CompletionLogprobs::default();
+        let mut completion_tokens = 0;
+        for token in tokens {
+            let token = match token {
+                Ok(token) => token,
+                Err(err) => return respond_error(request, err),
+            };
+            self.push_logprobs(&mut logprobs, &token, text.len());
+            text.push_str(&token.piece);
+            completion_tokens += 1;
+        }
+        let finish_reason = finish_reason(runner.stop_reason(), completion_tokens, max_tokens);
+        self.runner = Some(runner);
+        let usage = Usage::new(pos, completion_tokens);
+        let logprobs = params.logprobs.map(|_| logprobs);
+        respond_json(
+            request,
+            200,
+            &response(text, logprobs, Some(finish_reason), Some(usage)),
+        )
+    }
+
+    fn push_logprobs(
+        &self,
+        logprobs: &mut CompletionLogprobs,
+        token: &GeneratedToken,
+        text_offset: usize,
+    ) {
+        // the tokens are shown in their raw bytes, not the decoded pieces
+        let token_text =
+            |t| String::from_utf8_lossy(&self.model.tokenizer.token_bytes(t)).to_string();
+        logprobs.tokens.push(token_text(token.token));
+        logprobs.token_logprobs.push(token.logprob);
+        logprobs.top_logprobs.push(
+            token
+                .top_logprobs
+                .iter()
+                .map(|(t, logprob)| (token_text(*t), *logprob))
+                .collect(),
+        );
+        logprobs.text_offset.push(text_offset);
+    }
+
+    fn chat_completions(&mut self, request: Request, params: ChatCompletionRequest) -> Result<()> {
+        let id = self.next_id("chatcmpl");
+        let created = unix_timestamp();
+        let max_tokens = params.max_tokens.unwrap_or(usize::MAX);
+        let object = if params.stream {
+            "chat.completion.chunk"
+        } else {
+            "chat.completion"
+        };
+        let model_id = self.model_id.clone();
+        let response = |message, delta, finish_reason, usage| ChatCompletionResponse {
+            id: id.clone(),
+            object,
+            created,
+            model: model_id.clone(),
+            choices: vec![ChatCompletionChoice {
+                index: 0,
+                message,
+                delta,
+                finish_reason,
+            }],
+            usage,
+        };
+
+        let prepared = chat_rounds(&params.messages).and_then(|rounds| {
+            let grammar = request_grammar(&params.sampling)?;
+            let runner = self.take_runner(params.max_tokens, &params.sampling)?;
+            Ok((rounds, grammar, runner))
+        });
+        let (rounds, grammar, mut runner) = match prepared {
+            Ok(prepared) => prepared,
+            Err(err) => return respond_error(request, err),
+        };
+
+        // the history is fed into the context with the chat template, then leave the last
+        // round to the model to reply. the grammar only applies on the reply.
+        let (last, history) = rounds.split_last().unwrap();
+        let history = history
+            .iter()
+            .map(|round| ChatRound {
+                system_prompt: round.system_prompt.clone(),
+                prompt: round.prompt.clone(),
+                reply: round.reply.clone().unwrap_or_default(),
+            })
+            .collect();
+        if let Some(grammar) = grammar {
+            runner = runner.with_grammar(grammar);
+        }
+        let prepared =
+            Llama2Chat::new(&mut runner, last.prompt.clone(), last.system_prompt.clone())
+                .map(|chat| chat.with_history(history));
+        let mut chat = match prepared {
+            Ok(chat) => chat,
+            Err(err) => return respond_error(request, err),
+        };
+        let reply = match chat.reply() {
+            Ok(reply) => reply.take(max_tokens),
+            Err(err) => return respond_error(request, err),
+        };
+
+        if params.stream {
+            let mut stream = EventStream::start(request)?;
+            let delta = ChatDelta {
+                role: Some("assistant"),
+                content: Some("".to_string()),
+            };
+            stream.send(&response(None, Some(delta), None, None))?;
+
+            let mut completion_tokens = 0;
+            for token in reply {
+                completion_tokens += 1;
+                // the tokens of a partial stop mark are held back as empty strings
+                let token = match token {
+                    Ok(token) => token,
+                    Err(err) => return stream.finish_with_error(err),
+                };
+                if token.is_empty() {
+                    continue;
+                }
+                let delta = ChatDelta {
+                    role: None,
+                    content: Some(token),
+                };
+                stream.send(&response(None, Some(delta), None, None))?;
+            }
+            drop(chat);
+            let finish_reason = finish_reason(runner.stop_reason(), completion_tokens, max_tokens);
+            let delta = ChatDelta::default();
+            stream.send(&response(None, Some(delta), Some(finish_reason), None))?;
+            self.runner = Some(runner);
+            return stream.finish();
+        }
+
+        let collected = collect_tokens(reply);
+        let prompt_len = chat.prompt_len();
+        drop(chat);
+        let stop_reason = runner.stop_reason();
+        self.runner = Some(runner);
+        match collected {
+            Ok((content, completion_tokens)) => {
+                let finish_reason = finish_reason(stop_reason, completion_tokens, max_tokens);
+                let usage = Usage::new(prompt_len, completion_tokens);
+                let message = ChatMessage {
+                    role: "assistant".to_string(),
+                    content,
+                };
+                let body = response(Some(message), None, Some(finish_reason), Some(usage));
+                respond_json(request, 200, &body)
+            }
+            Err(err) => respond_error(request, err),
+        }
+    }
+
+    /// take the runner kept from the last request with the sampling parameters in the request,
+    /// the runner should be put back after the request to reuse its kv cache.
+    fn take_runner(
+        &mut self,
+        max_tokens: Option<usize>,
+        sampling: &SamplingParams,
+    ) -> Result<Llama2Runner<CpuTensor<'a>>> {
+        if max_tokens == Some(0) {
+            bail!(ErrorKind::BadInput, "max_tokens must be greater than 0");
+        }
+        let options = self.sampler_options(sampling)?;
+        let sampler = Llama2Sampler::from_options(&options, self.model.device.exp_cache())?;
+        let runner = match self.runner.take() {
+            Some(runner) => runner.without_grammar(),
+            None => Llama2Runner::new(&self.model, self.model.conf.seq_len, true)?,
+        };
+        runner.with_sampler(sampler)
+    }
+
+    fn sampler_options(&self, sampling: &SamplingParams) -> Result<Llama2SamplerOptions> {
+        let defaults = &self.sampler_options;
+        let mut options = Llama2SamplerOptions {
+            temperature: sampling.temperature.unwrap_or(defaults.temperature),
+            top_k: sampling.top_k.unwrap_or(defaults.top_k),
+            top_p: sampling.top_p.unwrap_or(defaults.top_p),
+            min_p: sampling.min_p.unwrap_or(defaults.min_p),
+            typical_p: sampling.typical_p.unwrap_or(defaults.typical_p),
+            tfs_z: sampling.tfs_z.unwrap_or(defaults.tfs_z),
+            repeat_last_n: sampling.repeat_last_n.unwrap_or(defaults.repeat_last_n),
+            repeat_penalty: sampling.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            frequency_penalty: sampling
+                .frequency_penalty
+                .unwrap_or(defaults.frequency_penalty),
+            presence_penalty: sampling
+                .presence_penalty
+                .unwrap_or(defaults.presence_penalty),
+            penalize_nl: sampling.penalize_nl.unwrap_or(defaults.penalize_nl),
+            seed: sampling.seed.or(defaults.seed),
+            sequence: defaults.sequence.clone(),
+            logit_bias: defaults.logit_bias.clone(),
+            banned_strings: defaults.banned_strings.clone(),
+        };
+        // the keys of the logit bias are the token ids in strings like OpenAI's API
+        for (token, bias) in sampling.logit_bias.iter().flatten() {
+            let token = match token.parse() {
+                Ok(token) => token,
+                Err(_) => bail!(
+                    ErrorKind::BadInput,
+                    "invalid token id in logit_bias: {}",
+                    token
+                ),
+            };
+            options.logit_bias.insert(token, *bias);
+        }
+        options
+            .banned_strings
+            .extend(sampling.banned_strings.iter().flatten().cloned());
+        if !(0.0..=2.0).contains(&options.temperature) {
+            bail!(
+                ErrorKind::BadInput,
+                "temperature must be in [0, 2], but got {}",
+                options.temperature
+            );
+        }
+        if options.repeat_penalty <= 0.0 {
+            bail!(
+                ErrorKind::BadInput,
+                "repeat_penalty must be greater than 0, but got {}",
+                options.repeat_penalty
+            );
+        }
+        for (name, p) in [
+            ("frequency_penalty", options.frequency_penalty),
+            ("presence_penalty", options.presence_penalty),
+        ] {
+            if !(-2.0..=2.0).contains(&p) {
+                bail!(
+                    ErrorKind::BadInput,
+                    "{} must be in [-2, 2], but got {}",
+                    name,
+                    p
+                );
+            }
+        }
+        for (name, p) in [
+            ("top_p", options.top_p),
+            ("min_p", options.min_p),
+            ("typical_p", options.typical_p),
+            ("tfs_z", options.tfs_z),
+        ] {
+            if !(0.0..=1.0).contains(&p) {
+                bail!(
+                    ErrorKind::BadInput,
+                    "{} must be in [0, 1], but got {}",
+                    name,
+                    p
+                );
+            }
+        }
+        Ok(options)
+    }
+
+    fn next_id(&mut self, prefix: &str) -> String {
+        self.request_count += 1;
+        format!("{}-{}", prefix, self.request_count)
+    }
+}
+
+/// the grammar constraining the output, taken from one of grammar, json_schema and
+/// response_format in the request.
+fn request_grammar(sampling: &SamplingParams) -> Result<Option<Arc<Grammar>>> {
+    let schema = match &sampling.response_format {
+        None => None,
+        Some(format) => match format.typ.as_str() {
+            "text" => None,
+            "json_object" => Some(format.schema.clone().unwrap_or(json!({"type": "object"}))),
+            "json_schema" => match &format.json_schema {
+                Some(json_schema) => Some(json_schema.schema.clone()),
+                None => bail!(
+                    ErrorKind::BadInput,
+                    "response_format of json_schema requires the json_schema field"
+                ),
+            },
+            typ => bail!(ErrorKind::BadInput, "unsupported response_format: {}", typ),
+        },
+    };
+    let src = match (&sampling.grammar, &sampling.json_schema, &schema) {
+        (None, None, None) => return Ok(None),
+        (Some(grammar), None, None) => grammar.clone(),
+        (None, Some(schema), None) | (None, None, Some(schema)) => json_schema_to_grammar(schema)?,
+        _ => bail!(
+            ErrorKind::BadInput,
+            "only one of grammar, json_schema and response_format is allowed"
+        ),
+    };
+    Ok(Some(Arc::new(Grammar::parse(&src)?)))
+}
+
+/// a round of the dialog, the reply is None on the last round which is left to the model.
+struct DialogRound {
+    system_prompt: Option<String>,
+    prompt: String,
+    reply: Option<String>,
+}
+
+fn chat_rounds(messages: &[ChatMessage]) -> Result<Vec<DialogRound>> {
+    let mut rounds: Vec<DialogRound> = vec![];
+    let mut system_prompt = None;
+    for message in messages {
+        match message.role.as_str() {
+            "system" => system_prompt = Some(message.content.clone()),
+            "user" => {
+                if rounds.last().is_some_and(|r| r.reply.is_none()) {
+                    bail!(
+                        ErrorKind::BadInput,
+                        "a user message must be followed by an assistant message"
+                    );
+                }
+                rounds.push(DialogRound {
+                    system_prompt: system_prompt.take(),
+                    prompt: message.content.clone(),
+                    reply: None,
+                });
+            }
+            "assistant" => match rounds.last_mut() {
+                Some(round) if round.reply.is_none() => {
+                    round.reply = Some(message.content.clone());
+                }
+                _ => bail!(
+                    ErrorKind::BadInput,
+                    "an assistant message must follow a user message"
+                ),
+            },
+            role => bail!(ErrorKind::BadInput, "unsupported message role: {}", role),
+        }
+    }
+
+    match rounds.last() {
+        Some(round) if round.reply.is_none() => Ok(rounds),
+        _ => bail!(
+            ErrorKind::BadInput,
+            "the last message must be from the user"
+        ),
+    }
+}
+
+fn collect_tokens(tokens: impl Iterator<Item = Result<String>>) -> Result<(String, usize)> {
+    let mut text = String::new();
+    let mut count = 0;
+    for token in tokens {
+        text.push_str(&token?);
+        count += 1;
+    }
+    Ok((text, count))
+}
+
+// the stop reason is None when the reply is cut by a stop mark of the chat template, or by
+// taking max_tokens out of an unbounded generation.
+fn finish_reason(
+    stop_reason: Option<StopReason>,
+    completion_tokens: usize,
+    max_tokens: usize,
+) -> &'static str {
+    match stop_reason {
+        Some(StopReason::MaxSteps | StopReason::ContextFull) => "length",
+        Some(StopReason::Eos) => "stop",
+        None if completion_tokens >= max_tokens => "length",
+        None => "stop",
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn read_json<T: DeserializeOwned>(request: &mut Request) -> Result<T> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .read_to_string(&mut body)
+        .map_err(|err| error!(ErrorKind::IOError => err))?;
+    serde_json::from_str(&body)
+        .map_err(|err| error!(ErrorKind::BadInput, "invalid request body: {}", err))
+}
+
+fn respond_json(request: Request, status: u16, body: &impl Serialize) -> Result<()> {
+    let body = serde_json::to_string(body).unwrap();
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(content_type);
+    request
+        .respond(response)
+        .map_err(|err| error!(ErrorKind::IOError => err))
+}
+
+fn respond_error(request: Request, err: Error) -> Result<()> {
+    let (status, body) = error_response(err);
+    respond_json(request, status, &body)
+}
+
+fn error_response(err: Error) -> (u16, ErrorResponse) {
+    let (status, typ) = match err.kind {
+        ErrorKind::BadInput => (400, "invalid_request_error"),
+        _ => (500, "server_error"),
+    };
+    let body = ErrorResponse {
+        error: ErrorBody {
+            message: err.to_string(),
+            typ,
+        },
+    };
+    (status, body)
+}
+
+/// server-sent events over a chunked response. tiny_http buffers the chunked body before
+/// sending it, so the chunks are written on the raw connection and flushed on every event.
+struct EventStream {
+    writer: Box<dyn Write + Send>,
+}
+
+impl EventStream {
+    fn start(request: Request) -> Result<Self> {
+        let mut stream = Self {
+            writer: request.into_writer(),
+        };
+        stream.write(
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\n\
+              Transfer-Encoding: chunked\r\n\r\n",
+        )?;
+        Ok(stream)
+    }
+
+    fn send(&mut self, data: &impl Serialize) -> Result<()> {
+        let data = serde_json::to_string(data).unwrap();
+        self.send_raw(&data)
+    }
+
+    /// the status has been sent with the headers, so the errors after the stream started are
+    /// sent as an event before closing the stream.
+    fn finish_with_error(mut self, err: Error) -> Result<()> {
+        let (_, body) = error_response(err);
+        self.send(&body)?;
+        self.finish()
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.send_raw("[DONE]")?;
+        self.write(b"0\r\n\r\n")
+    }
+
+    fn send_raw(&mut self, data: &str) -> Result<()> {
+        let event = format!("data: {}\n\n", data);
+        let chunk = format!("{:x}\r\n{}\r\n", event.len(), event);
+        self.write(chunk.as_bytes())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.writer
+            .write_all(buf)
+            .and_then(|_| self.writer.flush())
+            .map_err(|err| error!(ErrorKind::IOError => err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crabml::gguf::GGUFFileLoader;
+    use crabml_llama2::model::CpuLlamaModelLoader;
+    use serde_json::json;
+    use serde_json::Value;
+
+    use super::Server;
+
+    // start a server on the tiny model at a random port, and run the client against it
+    fn with_server(client: impl FnOnce(&str)) {
+        let http = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", http.server_addr().to_ip().unwrap());
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let gl = GGUFFileLoader::new("../testdata/tinyllamas-stories-260k-f32.gguf", false)
+                    .unwrap();
+                let gf = gl.open().unwrap();
+                let model = CpuLlamaModelLoader::new().load(&gf).unwrap();
+                Server::new(model, "tinyllamas").serve(&http);
+            });
+            client(&base_url);
+            http.unblock();
+        });
+    }
+
+    fn post(url: &str, body: Value) -> (u16, String) {
+        match ureq::post(url).send_json(body) {
+            Ok(resp) => (resp.status(), resp.into_string().unwrap()),
+            Err(ureq::Error::Status(status, resp)) => (status, resp.into_string().unwrap()),
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    // parse the data of the server-sent events, till the [DONE] event
+    fn parse_events(body: &str) -> Vec<Value> {
+        let events = body
+            .split("\n\n")
+            .filter(|e| !e.is_empty())
+            .map(|e| e.strip_prefix("data: ").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.last(), Some(&"[DONE]"));
+        events[..events.len() - 1]
+            .iter()
+            .map(|e| serde_json::from_str(e).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_server() {
+        with_server(|base_url| {
+            let models: Value = ureq::get(&format!("{}/v1/models", base_url))
+                .call()
+                .unwrap()
+                .into_json()
+                .unwrap();
+            assert_eq!(models["data"][0]["id"], "tinyllamas");
+
+            // completions, the streamed tokens should be the same as the non-streamed one
+            let url = format!("{}/v1/completions", base_url);
+            let params = json!({
+                "prompt": "Lily is a cat",
+                "max_tokens": 10,
+                "temperature": 0,
+                "top_k": 40,
+            });
+            let (status, body) = post(&url, params.clone());
+            assert_eq!(status, 200);
+            let resp: Value = serde_json::from_str(&body).unwrap();
+            let text = resp["choices"][0]["text"].as_str().unwrap();
+            assert!(!text.is_empty());
+            assert_eq!(resp["choices"][0]["finish_reason"], "length");
+            assert_eq!(resp["usage"]["completion_tokens"], 10);
+
+            let mut params = params;
+            params["stream"] = json!(true);
+            let (status, body) = post(&url, params);
+            assert_eq!(status, 200);
+            let events = parse_events(&body);
+            let streamed = events
+                .iter()
+                .map(|e| e["choices"][0]["text"].as_str().unwrap())
+                .collect::<String>();
+            assert_eq!(streamed, text);
+            assert_eq!(
+                events.last().unwrap()["choices"][0]["finish_reason"],
+                "length"
+            );
+
+            // the logprobs of the tokens, the greedy sampling takes the most likely token
+            let params = json!({
+                "prompt": "Lily is a cat",
+                "max_tokens": 5,
+                "temperature": 0,
+                "logprobs": 2,
+            });
+            let (status, body) = post(&url, params);
+            assert_eq!(status, 200);
+            let resp: Value = serde_json::from_str(&body).unwrap();
+            let logprobs = &resp["choices"][0]["logprobs"];
+            assert_eq!(logprobs["tokens"].as_array().unwrap().len(), 5);
+            assert_eq!(logprobs["text_offset"][0], 0);
+            for (i, top) in logprobs["top_logprobs"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .enumerate()
+            {
+                let top = top.as_object().unwrap();
+                let token = logprobs["tokens"][i].as_str().unwrap();
+                let max = top
+                    .values()
+                    .map(|v| v.as_f64().unwrap())
+                    .fold(f64::MIN, f64::max);
+                assert_eq!(top[token].as_f64().unwrap(), max);
+                assert_eq!(logprobs["token_logprobs"][i].as_f64().unwrap(), max);
+            }
+
+            // sampling with the same seed gives the same text
+            let params = json!({
+                "prompt": "Lily is a cat",
+                "max_tokens": 10,
+                "temperature": 1.0,
+                "seed": 42,
+            });
+            let (_, body1) = post(&url, params.clone());
+            let (_, body2) = post(&url, params);
+            let resp1: Value = serde_json::from_str(&body1).unwrap();
+            let resp2: Value = serde_json::from_str(&body2).unwrap();
+            assert_eq!(resp1["choices"][0]["text"], resp2["choices"][0]["text"]);
+
+            // with the eos token banned, the generation stops on the full context of 512 tokens
+            // before max_tokens
+            let params = json!({
+                "prompt": "Lily is a cat",
+                "max_tokens": 1000,
+                "temperature": 0,
+                "logit_bias": {"2": -100.0},
+            });
+            let (status, body) = post(&url, params);
+            assert_eq!(status, 200);
+            let resp: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(resp["choices"][0]["finish_reason"], "length");
+            assert_eq!(resp["usage"]["total_tokens"], 512);
+
+            // chat completions with a history
+            let url = format!("{}/v1/chat/completions", base_url);
+            let params = json!({
+                "messages": [
+                    {"role": "system", "content": "You are a story teller."},
+                    {"role": "user", "content": "Tell me a story."},
+                    {"role": "assistant", "content": "Once upon a time, there was a cat."},
+                    {"role": "user", "content": "What is the name of the cat?"},
+                ],
+                "max_tokens": 8,
+                "temperature": 0,
+            });
+            let (status, body) = post(&url, params.clone());
+            assert_eq!(status, 200);
+            let resp: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(resp["object"], "chat.completion");
+            assert_eq!(resp["choices"][0]["message"]["role"], "assistant");
+            let content = resp["choices"][0]["message"]["content"].as_str().unwrap();
+            let chat_params = params.clone();
+            let chat_content = content.to_string();
+
+            let mut params = params;
+            params["stream"] = json!(true);
+            let (status, body) = post(&url, params);
+            assert_eq!(status, 200);
+            let events = parse_events(&body);
+            assert_eq!(events[0]["choices"][0]["delta"]["role"], "assistant");
+            let streamed = events
+                .iter()
+                .filter_map(|e| e["choices"][0]["delta"]["content"].as_str())
+                .collect::<String>();
+            assert_eq!(streamed, content);
+            assert!(events.last().unwrap()["choices"][0]["finish_reason"].is_string());
+
+            // the reply is constrained to the json schema in response_format
+            let params = json!({
+                "messages": [{"role": "user", "content": "What is the name of the cat?"}],
+                "max_tokens": 100,
+                "temperature": 0,
+                "response_format": {
+                    "type": "json_object",
+                    "schema": {
+                        "type": "object",
+                        "properties": {"name": {"enum": ["Lily", "Tom"]}},
+                        "required": ["name"],
+                    },
+                },
+            });
+            let (status, body) = post(&url, params);
+            assert_eq!(status, 200);
+            let resp: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(resp["choices"][0]["finish_reason"], "stop");
+            let content = resp["choices"][0]["message"]["content"].as_str().unwrap();
+            let reply: Value = serde_json::from_str(content).unwrap();
+            assert!(
+                reply["name"] == "Lily" || reply["name"] == "Tom",
+                "{}",
+                content
+            );
+
+            // the runner is kept between the requests, but neither the kv cache nor the
+            // grammar of the last request changes the reply
+            let (status, body) = post(&url, chat_params);
+            assert_eq!(status, 200);
+            let resp: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(resp["choices"][0]["message"]["content"], chat_content);
+        });
+    }
+
+    #[test]
+    fn test_server_bad_requests() {
+        with_server(|base_url| {
+            let url = format!("{}/v1/completions", base_url);
+            let (status, body) = post(&url, json!({"max_tokens": 10}));
+            assert_eq!(status, 400);
+            assert!(body.contains("invalid_request_error"));
+
+            let (status, _) = post(&url, json!({"prompt": "Lily", "temperature": 5}));
+            assert_eq!(status, 400);
+
+            let (status, _) = post(&url, json!({"prompt": "Lily", "min_p": 2}));
+            assert_eq!(status, 400);
+
+            let (status, _) = post(&url, json!({"prompt": "Lily", "logprobs": 100}));
+            assert_eq!(status, 400);
+
+            let logit_bias = json!({"foo": 1.0});
+            let (status, _) = post(&url, json!({"prompt": "Lily", "logit_bias": logit_bias}));
+            assert_eq!(status, 400);
+
+            let logit_bias = json!({"100000": 1.0});
+            let (status, _) = post(&url, json!({"prompt": "Lily", "logit_bias": logit_bias}));
+            assert_eq!(status, 400);
+
+            let (status, body) = post(&url, json!({"prompt": "Lily", "grammar": "root ::= foo"}));
+            assert_eq!(status, 400);
+            assert!(body.contains("undefined rule: foo"));
+
+            let url = format!("{}/v1/chat/completions", base_url);
+            let messages = json!([{"role": "assistant", "content": "hi"}]);
+            let (status, _) = post(&url, json!({ "messages": messages }));
+            assert_eq!(status, 400);
+
+            let (status, _) = post(&format!("{}/v1/embeddings", base_url), json!({}));
+            assert_eq!(status, 404);
+        });
+    }
+}
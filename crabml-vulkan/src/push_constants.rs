@@ -16,6 +16,16 @@ pub struct SoftmaxPushConstants {
     pub n_cols: u32,
 }
 
+#[derive(BufferContents)]
+#[repr(C)]
+pub struct CausalMaskPushConstants {
+    pub n_rows: u32,
+    pub n_batch: u32,
+    pub seq_len: u32,
+    pub pos: u32,
+    pub window: u32,
+}
+
 #[derive(BufferContents)]
 #[repr(C)]
 pub struct RmsNormPushConstants {
@@ -24,6 +34,14 @@ pub struct RmsNormPushConstants {
     pub eps: f32,
 }
 
+#[derive(BufferContents)]
+#[repr(C)]
+pub struct LayerNormPushConstants {
+    pub n_rows: u32,
+    pub n_cols: u32,
+    pub eps: f32,
+}
+
 #[derive(BufferContents)]
 #[repr(C)]
 pub struct RopePushConstants {
@@ -32,6 +50,12 @@ pub struct RopePushConstants {
     pub pos: u32,
     pub n_heads: u32,
     pub n_rope_dims: u32,
+    pub freq_base: f32,
+    pub freq_scale: f32,
+    pub ext_factor: f32,
+    pub mscale: f32,
+    pub corr_dim0: f32,
+    pub corr_dim1: f32,
 }
 
 #[derive(BufferContents, Default)]
@@ -0,0 +1,38 @@
+#version 450
+
+layout(set = 0, binding = 0) buffer InputBuffer {
+    float bufA[];
+};
+
+layout(push_constant) uniform PushConstants {
+    uint nRows;
+    uint nBatch;
+    uint seqLen;
+    uint pos;
+    uint window;
+} pcs;
+
+layout(local_size_x = 32, local_size_y = 1, local_size_z = 1) in;
+
+// each thread masks a row of the (n_heads, n_batch, seq_len) attention scores, the row
+// at bi is the query at position pos + bi. with a sliding window, the keys before the last
+// `window` ones are masked too, a window of 0 means no window.
+void main() {
+    uint row = gl_GlobalInvocationID.x;
+
+    if (row >= pcs.nRows) {
+        return;
+    }
+
+    uint bi = row % pcs.nBatch;
+    uint rowOffset = row * pcs.seqLen;
+    if (pcs.window > 0 && pcs.pos + bi + 1 > pcs.window) {
+        uint start = min(pcs.pos + bi + 1 - pcs.window, pcs.seqLen);
+        for (uint col = 0; col < start; ++col) {
+            bufA[rowOffset + col] = -3.402823e+38;
+        }
+    }
+    for (uint col = pcs.pos + bi + 1; col < pcs.seqLen; ++col) {
+        bufA[rowOffset + col] = -3.402823e+38;
+    }
+}
@@ -0,0 +1,62 @@
+#version 450
+
+layout(set = 0, binding = 0) buffer InputBuffer {
+    float bufA[];
+};
+
+layout(set = 0, binding = 1) buffer WeightBuffer {
+    float bufW[];
+};
+
+layout(set = 0, binding = 2) buffer BiasBuffer {
+    float bufB[];
+};
+
+layout(push_constant) uniform PushConstants {
+    uint numRows;
+    uint numDims;
+    float eps;
+} pcs;
+
+// each workgroup processes a row
+// each thread processes the elements by the stride of 32
+layout(local_size_x = 32, local_size_y = 1, local_size_z = 1) in;
+
+shared float[32] sums;
+shared float[32] squareSums;
+
+void main() {
+    uint rowIdx = gl_WorkGroupID.x;
+    uint rowSize = pcs.numDims;
+    uint threadIdx = gl_LocalInvocationID.x;
+
+    // calculate sum and sum of squares
+    float sum = 0.0;
+    float squareSum = 0.0;
+    for (uint i = threadIdx; i < rowSize; i += 32) {
+        float v = bufA[rowIdx * rowSize + i];
+        sum += v;
+        squareSum += v * v;
+    }
+    sums[threadIdx] = sum;
+    squareSums[threadIdx] = squareSum;
+    barrier();
+
+    // get the mean and the variance
+    if (threadIdx == 0) {
+        for (uint i = 1; i < 32; i++) {
+            sums[0] += sums[i];
+            squareSums[0] += squareSums[i];
+        }
+    }
+    barrier();
+    float mean = sums[0] / rowSize;
+    float variance = max(squareSums[0] / rowSize - mean * mean, 0.0);
+    float scale = 1.0 / sqrt(variance + pcs.eps);
+
+    // normalize, then scale and shift by the weight and the bias
+    for (uint i = threadIdx; i < rowSize; i += 32) {
+        uint idx = rowIdx * rowSize + i;
+        bufA[idx] = (bufA[idx] - mean) * scale * bufW[i] + bufB[i];
+    }
+}
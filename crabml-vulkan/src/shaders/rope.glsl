@@ -10,26 +10,45 @@ layout(push_constant) uniform PushConstants {
     uint pos;
     uint nHeads;
     uint nRopeDims;
+    float freqBase;
+    float freqScale;
+    float extFactor;
+    float mscale;
+    float corrDim0;
+    float corrDim1;
 } pcs;
 
 layout(local_size_x = 32, local_size_y = 1, local_size_z = 1) in;
 
+// the angle of the pair i, which is interpolated by the freq scale, and mixed with the
+// extrapolated one on yarn. the same as RopeParams::theta.
+float ropeTheta(float pos, uint i, uint nHeadDims) {
+    float thetaExtrap = pos * pow(pcs.freqBase, -2.0 * float(i) / float(nHeadDims));
+    float thetaInterp = pcs.freqScale * thetaExtrap;
+    if (pcs.extFactor == 0.0) {
+        return thetaInterp;
+    }
+    float y = (float(i) - pcs.corrDim0) / max(0.001, pcs.corrDim1 - pcs.corrDim0);
+    float rampMix = (1.0 - clamp(y, 0.0, 1.0)) * pcs.extFactor;
+    return thetaInterp * (1.0 - rampMix) + thetaExtrap * rampMix;
+}
+
 void main() {
     uint nHeadDims = pcs.nDims / pcs.nHeads;
     uint gidx = gl_GlobalInvocationID.x;
 
-    // process each vector in one thread. if there's only one vector, only gidx == 0 makes sense
+    // process each vector in one thread. if there's only one vector, only gidx == 0 makes sense.
+    // the vector at gidx is at the position of pos + gidx.
     if (gidx >= pcs.nBatch) {
         return;
     }
 
     for (uint h = 0u; h < pcs.nHeads; h++) {
         for (uint i = 0u; i < pcs.nRopeDims / 2u; i++) {
-            float thetaScale = pow(10000.0, -2.0 * float(i) / float(nHeadDims));
-            float theta = float(pcs.pos) * thetaScale;
+            float theta = ropeTheta(float(pcs.pos + gidx), i, nHeadDims);
 
-            float cosTheta = cos(theta);
-            float sinTheta = sin(theta);
+            float cosTheta = cos(theta) * pcs.mscale;
+            float sinTheta = sin(theta) * pcs.mscale;
             uint qpOffset = gidx * pcs.nDims + h * nHeadDims + i * 2u;
             float qp0 = bufA[qpOffset];
             float qp1 = bufA[qpOffset + 1u];
@@ -37,4 +56,4 @@ void main() {
             bufA[qpOffset + 1u] = qp0 * sinTheta + qp1 * cosTheta;
         }
     }
-}
\ No newline at end of file
+}
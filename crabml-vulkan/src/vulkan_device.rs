@@ -117,9 +117,15 @@ impl VulkanTensorDevice {
         mod softmax_shader {
             vulkano_shaders::shader! { ty: "compute", path: "./src/shaders/softmax.glsl" }
         }
+        mod causal_mask_shader {
+            vulkano_shaders::shader! { ty: "compute", path: "./src/shaders/causal_mask.glsl" }
+        }
         mod rms_norm_shader {
             vulkano_shaders::shader! { ty: "compute", path: "./src/shaders/rms_norm.glsl" }
         }
+        mod layer_norm_shader {
+            vulkano_shaders::shader! { ty: "compute", path: "./src/shaders/layer_norm.glsl" }
+        }
         mod rope_shader {
             vulkano_shaders::shader! { ty: "compute", path: "./src/shaders/rope.glsl" }
         }
@@ -154,10 +160,18 @@ impl VulkanTensorDevice {
                 "softmax",
                 load_shader_entry_point!(softmax_shader, device.clone(), "main"),
             ),
+            (
+                "causal_mask",
+                load_shader_entry_point!(causal_mask_shader, device.clone(), "main"),
+            ),
             (
                 "rms_norm",
                 load_shader_entry_point!(rms_norm_shader, device.clone(), "main"),
             ),
+            (
+                "layer_norm",
+                load_shader_entry_point!(layer_norm_shader, device.clone(), "main"),
+            ),
             (
                 "rope",
                 load_shader_entry_point!(rope_shader, device.clone(), "main"),
@@ -5,14 +5,17 @@ use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::gguf::GGMLType;
 use crabml::tensor::RopeMode;
+use crabml::tensor::RopeParams;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorStrider;
 
 use super::vulkan_device::VulkanTensorDeviceRef;
 use crate::push_constants::ArithmeticPushConstants;
 use crate::push_constants::BatchMatmuPushConstants;
+use crate::push_constants::CausalMaskPushConstants;
 use crate::push_constants::ConcatenatePushConstants;
 use crate::push_constants::ContiguousPushConstants;
+use crate::push_constants::LayerNormPushConstants;
 use crate::push_constants::MatmulPushConstants;
 use crate::push_constants::RmsNormPushConstants;
 use crate::push_constants::RopePushConstants;
@@ -252,8 +255,11 @@ impl Tensor for VulkanTensor {
     fn dup(&self) -> Result<Self> {
         assert!(self.dtype == GGMLType::F32, "only support F32 yet");
 
-        let new_tensor = Self::alloc(self.strider.shape(), self.dtype, self.device.clone())?;
-        let bytes_size = std::mem::size_of::<f32>() * self.strider.len();
+        // the whole buffer is copied, as the tensor may be a resized kv cache
+        let bytes_size = self.buf.len() as usize;
+        let n_elms = bytes_size / std::mem::size_of::<f32>();
+        let new_tensor = Self::alloc(&[n_elms], self.dtype, self.device.clone())?
+            .with_strider(self.strider.clone())?;
         self.device.inner.copy_device_buffer(
             self.buf.clone(),
             0,
@@ -268,11 +274,17 @@ impl Tensor for VulkanTensor {
         self,
         mode: crabml::tensor::RopeMode,
         pos: usize,
-        rope_dims: usize,
+        rope: &RopeParams,
     ) -> Result<Self> {
         assert!(self.shape().len() == 3 || self.shape().len() == 2);
         assert!(self.strider.is_contiguous());
         assert!(mode == RopeMode::Llama, "TODO: only support Llama mode yet");
+        if rope.freq_factors.is_some() {
+            bail!(
+                ErrorKind::TensorError,
+                "rope: the longrope factors are not supported yet"
+            );
+        }
 
         let (rows, n_head, m) = if self.strider.dims() == 3 {
             (
@@ -288,7 +300,13 @@ impl Tensor for VulkanTensor {
             n_dims: m as u32,
             pos: pos as u32,
             n_heads: n_head as u32,
-            n_rope_dims: rope_dims as u32,
+            n_rope_dims: rope.n_dims as u32,
+            freq_base: rope.freq_base,
+            freq_scale: rope.freq_scale,
+            ext_factor: rope.ext_factor,
+            mscale: rope.mscale(),
+            corr_dim0: rope.corr_dims[0],
+            corr_dim1: rope.corr_dims[1],
         };
         let dispatches = [rows as u32 / 32 + 1, 1, 1];
         self.device
@@ -323,6 +341,32 @@ impl Tensor for VulkanTensor {
         Ok(self)
     }
 
+    fn layer_norm_inplace(self, weight: &Self, bias: &Self, eps: f32) -> Result<Self> {
+        assert!(self.strider.is_contiguous());
+        assert!([1, 2, 3].contains(&self.shape().len()));
+
+        let (n_rows, n_cols) = match self.shape().len() {
+            3 => (self.shape()[0] * self.shape()[1], self.shape()[2]),
+            2 => (self.shape()[0], self.shape()[1]),
+            1 => (1, self.shape()[0]),
+            _ => unreachable!(),
+        };
+        assert!(weight.strider.len() == n_cols && bias.strider.len() == n_cols);
+
+        let bufs = vec![self.buf.clone(), weight.buf.clone(), bias.buf.clone()];
+        let pcs = LayerNormPushConstants {
+            n_rows: n_rows as u32,
+            n_cols: n_cols as u32,
+            eps,
+        };
+        // each thread block processes a row
+        let dispatches = [n_rows as u32, 1, 1];
+        self.device
+            .inner
+            .dispatch_compute("layer_norm", bufs, pcs, dispatches);
+        Ok(self)
+    }
+
     fn softmax_inplace(self, axis: usize) -> Result<Self> {
         assert!(axis == self.strider.dims() - 1);
         assert!(self.strider.is_contiguous());
@@ -348,6 +392,29 @@ impl Tensor for VulkanTensor {
         Ok(self)
     }
 
+    fn causal_mask_inplace(self, pos: usize, window: Option<usize>) -> Result<Self> {
+        assert!(self.strider.is_contiguous());
+        assert!(self.shape().len() == 3);
+
+        let n_rows = self.shape()[0] * self.shape()[1];
+        let bufs = vec![self.buf.clone()];
+        let pcs = CausalMaskPushConstants {
+            n_rows: n_rows as u32,
+            n_batch: self.shape()[1] as u32,
+            seq_len: self.shape()[2] as u32,
+            pos: pos as u32,
+            window: window.unwrap_or(0) as u32,
+        };
+        // each thread processes a row
+        let dispatches = [n_rows as u32 / 32 + 1, 1, 1];
+
+        self.device
+            .inner
+            .dispatch_compute("causal_mask", bufs, pcs, dispatches);
+
+        Ok(self)
+    }
+
     fn silu_inplace(self) -> Result<Self> {
         let n_elms = self.strider.len() as u32;
         let bufs = vec![self.buf.clone()];
@@ -500,6 +567,7 @@ mod tests {
     use crabml::error::Result;
     use crabml::gguf::GGMLType;
     use crabml::tensor::RopeMode;
+    use crabml::tensor::RopeParams;
     use crabml::tensor::Tensor;
 
     use super::VulkanTensor;
@@ -612,6 +680,31 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_causal_mask() -> Result<()> {
+        let d = VulkanTensorDevice::new(VulkanTensorDeviceOptions::default());
+        let v1 = vec![1.0, 2.0, 3.0, 1.0, 2.0, 3.0];
+        let t1 = VulkanTensor::new(&v1, &[1, 2, 3], d.clone()).unwrap();
+        let t1 = t1.causal_mask_inplace(1, None)?.softmax_inplace(2)?;
+
+        let mut dst1 = vec![0.0; 6];
+        t1.export(&mut dst1)?;
+        assert_relative_eq!(
+            &dst1[..],
+            &vec![
+                0.26894142,
+                0.7310586,
+                0.0,
+                0.090030566,
+                0.24472848,
+                0.665241
+            ][..],
+            epsilon = 1e-4
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_tensor_rms_norm() -> Result<()> {
         let d = VulkanTensorDevice::new(VulkanTensorDeviceOptions::default());
@@ -639,12 +732,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_layer_norm() -> Result<()> {
+        let d = VulkanTensorDevice::new(VulkanTensorDeviceOptions::default());
+        let t1 = VulkanTensor::new(
+            &[1.0, 2.0, 3.0, 4.0, 2.0, 2.0, 2.0, 2.0],
+            &[2, 4],
+            d.clone(),
+        )?;
+        let weight = VulkanTensor::new(&[1.0, 2.0, 1.0, 1.0], &[4], d.clone())?;
+        let bias = VulkanTensor::new(&[0.0, 0.0, 1.0, 0.0], &[4], d.clone())?;
+        let t1 = t1.layer_norm_inplace(&weight, &bias, 1e-5)?;
+        let mut dst1 = vec![0.0; 8];
+        t1.export(&mut dst1)?;
+
+        assert_relative_eq!(
+            &dst1[..],
+            &[-1.341635, -0.894423, 1.447212, 1.341635, 0.0, 0.0, 1.0, 0.0][..],
+            epsilon = 1e-4
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_rope() -> Result<()> {
         let d = VulkanTensorDevice::new(VulkanTensorDeviceOptions::default());
         let v1 = (0..32).map(|i| i as f32).collect::<Vec<_>>();
         let t1 = VulkanTensor::new(&v1, &[2, 16], d.clone())?;
-        let t1 = t1.rope_inplace(RopeMode::Llama, 1, 2)?;
+        let t1 = t1.rope_inplace(RopeMode::Llama, 1, &RopeParams::new(2))?;
 
         let mut dst1 = vec![0.0; 32];
         t1.export(&mut dst1)?;
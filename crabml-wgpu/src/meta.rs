@@ -7,6 +7,15 @@ pub struct RmsNormMeta {
     pub _padding: u32,
 }
 
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C, align(16))]
+pub struct LayerNormMeta {
+    pub n_batch: u32,
+    pub n_dims: u32,
+    pub eps: f32,
+    pub _padding: u32,
+}
+
 // (M, N) x (N, K) = (M, K), now we only support K = 1
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 #[repr(C, align(16))]
@@ -37,7 +46,12 @@ pub struct RopeMeta {
     pub pos: u32,
     pub n_heads: u32,
     pub n_rope_dims: u32,
-    pub _padding: [u32; 7],
+    pub freq_base: f32,
+    pub freq_scale: f32,
+    pub ext_factor: f32,
+    pub mscale: f32,
+    pub corr_dims: [f32; 2],
+    pub _padding: u32,
 }
 
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
@@ -61,3 +75,14 @@ pub struct ContiguousMeta {
     pub n_elms: u32,
     pub _padding: [u32; 2],
 }
+
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C, align(16))]
+pub struct CausalMaskMeta {
+    pub n_rows: u32,
+    pub n_batch: u32,
+    pub seq_len: u32,
+    pub pos: u32,
+    pub window: u32,
+    pub _padding: [u32; 3],
+}
@@ -0,0 +1,38 @@
+struct Meta {
+    n_rows: u32,
+    n_batch: u32,
+    seq_len: u32,
+    pos: u32,
+    window: u32,
+}
+
+@group(0) @binding(0)
+var<storage, read_write> input: array<f32>;
+
+@group(0) @binding(1)
+var<storage, read> input_m: Meta;
+
+// each thread masks a row of the (n_heads, n_batch, seq_len) attention scores, the row
+// at bi is the query at position pos + bi. with a sliding window, the keys before the last
+// `window` ones are masked too, a window of 0 means no window.
+@compute @workgroup_size(32)
+fn main(
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+) {
+    let row = workgroup_id.x * 32u + local_id.x;
+    if (row >= input_m.n_rows) {
+        return;
+    }
+
+    let bi = row % input_m.n_batch;
+    if (input_m.window > 0u && input_m.pos + bi + 1u > input_m.window) {
+        let start = min(input_m.pos + bi + 1u - input_m.window, input_m.seq_len);
+        for (var ni = 0u; ni < start; ni = ni + 1u) {
+            input[row * input_m.seq_len + ni] = -3.402823e+38f;
+        }
+    }
+    for (var ni = input_m.pos + bi + 1u; ni < input_m.seq_len; ni = ni + 1u) {
+        input[row * input_m.seq_len + ni] = -3.402823e+38f;
+    }
+}
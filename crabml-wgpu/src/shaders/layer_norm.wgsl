@@ -0,0 +1,66 @@
+struct Meta {
+    nBatch: u32, // number of vectors
+    nDims: u32, // length of each vector
+    eps: f32,
+    _padding: f32,
+};
+
+@group(0) @binding(0)
+var<storage, read_write> buf: array<f32>;
+
+@group(0) @binding(1)
+var<storage, read> bufW: array<f32>;
+
+@group(0) @binding(2)
+var<storage, read> bufB: array<f32>;
+
+@group(0) @binding(3)
+var<storage, read> bufM: Meta;
+
+// workgroup local to reduce the sum and the squared sum
+var<workgroup> threadSums: array<f32, 32>;
+var<workgroup> threadSqSums: array<f32, 32>;
+
+// each workgroup normalize a single vector, the threads take the elements by stride, so
+// the length of the vector is not required to be a multiple of the workgroup size
+
+@compute @workgroup_size(32)
+fn main(
+    @builtin(workgroup_id) workgroupID: vec3<u32>,
+    @builtin(local_invocation_id) localID: vec3<u32>,
+) {
+    let nDims = bufM.nDims;
+    let eps = bufM.eps;
+    let workgroupSize: u32 = 32u;
+    let offset = nDims * workgroupID.x;
+
+    // calculate each thread's part of the sum and the squared sum
+    var sum = 0.0;
+    var sqSum = 0.0;
+    for (var i = localID.x; i < nDims; i += workgroupSize) {
+        let v = buf[offset + i];
+        sum += v;
+        sqSum += v * v;
+    }
+    threadSums[localID.x] = sum;
+    threadSqSums[localID.x] = sqSum;
+    workgroupBarrier();
+
+    // reduce the sums
+    if localID.x == 0u {
+        for (var i = 1u; i < workgroupSize; i += 1u) {
+            threadSums[0] += threadSums[i];
+            threadSqSums[0] += threadSqSums[i];
+        }
+    }
+    workgroupBarrier();
+
+    // normalize, scale and shift to output
+    let mean = threadSums[0] / f32(nDims);
+    let variance = max(threadSqSums[0] / f32(nDims) - mean * mean, 0.0);
+    let scale = 1.0 / sqrt(variance + eps);
+    for (var i = localID.x; i < nDims; i += workgroupSize) {
+        let idx = offset + i;
+        buf[idx] = (buf[idx] - mean) * scale * bufW[i] + bufB[i];
+    }
+}
@@ -4,7 +4,13 @@ struct Meta {
     pos: u32,
     nHeads: u32,
     nRopeDims: u32,
-    _padding: vec3<u32>,
+    freqBase: f32,
+    freqScale: f32,
+    extFactor: f32,
+    mscale: f32,
+    corrDim0: f32,
+    corrDim1: f32,
+    _padding: u32,
 };
 
 @group(0) @binding(0)
@@ -13,6 +19,19 @@ var<storage, read_write> input: array<f32>;
 @group(0) @binding(1)
 var<storage, read> bufM: Meta;
 
+// the angle of the pair i, which is interpolated by the freq scale, and mixed with the
+// extrapolated one on yarn. the same as RopeParams::theta.
+fn ropeTheta(pos: f32, i: u32, nHeadDims: u32) -> f32 {
+    let thetaExtrap = pos * pow(bufM.freqBase, -2.0 * f32(i) / f32(nHeadDims));
+    let thetaInterp = bufM.freqScale * thetaExtrap;
+    if (bufM.extFactor == 0.0) {
+        return thetaInterp;
+    }
+    let y = (f32(i) - bufM.corrDim0) / max(0.001, bufM.corrDim1 - bufM.corrDim0);
+    let rampMix = (1.0 - clamp(y, 0.0, 1.0)) * bufM.extFactor;
+    return thetaInterp * (1.0 - rampMix) + thetaExtrap * rampMix;
+}
+
 @compute @workgroup_size(32)
 fn main(
     @builtin(workgroup_id) workgroupID: vec3<u32>,
@@ -21,18 +40,18 @@ fn main(
     let nHeadDims = bufM.nDims / bufM.nHeads;
     let gidx = workgroupID.x * 32u + localID.x;
 
-    // process each vector in one thread. if there's only one vector, only gidx == 0 makes sense
+    // process each vector in one thread. if there's only one vector, only gidx == 0 makes sense.
+    // the vector at gidx is at the position of pos + gidx.
     if gidx >= bufM.nBatch {
         return;
     }
 
     for (var h = 0u; h < bufM.nHeads; h++) {
         for (var i = 0u; i < bufM.nRopeDims / 2u; i++) {
-            let thetaScale = pow(10000.0, -2.0 * f32(i) / f32(nHeadDims));
-            let theta = f32(bufM.pos) * thetaScale;
+            let theta = ropeTheta(f32(bufM.pos + gidx), i, nHeadDims);
 
-            let cosTheta = cos(theta);
-            let sinTheta = sin(theta);
+            let cosTheta = cos(theta) * bufM.mscale;
+            let sinTheta = sin(theta) * bufM.mscale;
             let qpOffset = gidx * bufM.nDims + h * nHeadDims + i * 2u;
             let qp0 = input[qpOffset];
             let qp1 = input[qpOffset + 1u];
@@ -40,4 +59,4 @@ fn main(
             input[qpOffset+1u] = qp0 * sinTheta + qp1 * cosTheta;
         }
     }
-}
\ No newline at end of file
+}
@@ -77,9 +77,17 @@ impl WgpuTensorDevice {
             ("mul_inplace", include_str!("shaders/mul.wgsl")),
             ("div_inplace", include_str!("shaders/div.wgsl")),
             ("rms_norm_inplace", include_str!("shaders/rms_norm.wgsl")),
+            (
+                "layer_norm_inplace",
+                include_str!("shaders/layer_norm.wgsl"),
+            ),
             ("sgemv", include_str!("shaders/sgemv.wgsl")),
             ("rope_inplace", include_str!("shaders/rope.wgsl")),
             ("softmax_inplace", include_str!("shaders/softmax.wgsl")),
+            (
+                "causal_mask_inplace",
+                include_str!("shaders/causal_mask.wgsl"),
+            ),
             ("silu_inplace", include_str!("shaders/silu.wgsl")),
             ("gelu_inplace", include_str!("shaders/gelu.wgsl")),
             ("batch_matmul", include_str!("shaders/batch_matmul.wgsl")),
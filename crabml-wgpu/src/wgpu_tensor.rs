@@ -5,11 +5,14 @@ use crabml::error::ErrorKind;
 use crabml::error::Result;
 use crabml::gguf::GGMLType;
 use crabml::tensor::RopeMode;
+use crabml::tensor::RopeParams;
 use crabml::tensor::Tensor;
 use crabml::tensor::TensorStrider;
 use wgpu::util::DeviceExt;
 
+use super::meta::CausalMaskMeta;
 use super::meta::ConcatenateMeta;
+use super::meta::LayerNormMeta;
 use super::meta::MatmulMeta;
 use super::meta::RmsNormMeta;
 use super::WgpuTensorDeviceRef;
@@ -333,7 +336,8 @@ impl Tensor for WgpuTensor {
     }
 
     fn dup(&self) -> Result<Self> {
-        let new_tensor = Self::alloc(self.strider.shape(), self.dtype, self.device.clone())?;
+        let new_tensor = Self::alloc(&[self.capacity], self.dtype, self.device.clone())?
+            .with_strider(self.strider.clone())?;
 
         let mut encoder = self
             .device
@@ -344,10 +348,16 @@ impl Tensor for WgpuTensor {
         Ok(new_tensor)
     }
 
-    fn rope_inplace(self, mode: RopeMode, pos: usize, rope_dims: usize) -> Result<Self> {
+    fn rope_inplace(self, mode: RopeMode, pos: usize, rope: &RopeParams) -> Result<Self> {
         assert!(self.shape().len() == 3 || self.shape().len() == 2);
         assert!(self.is_contiguous());
         assert!(mode == RopeMode::Llama, "TODO: only support Llama mode yet");
+        if rope.freq_factors.is_some() {
+            bail!(
+                ErrorKind::TensorError,
+                "rope: the longrope factors are not supported yet"
+            );
+        }
 
         let (rows, n_head, m) = if self.strider.dims() == 3 {
             (
@@ -363,8 +373,13 @@ impl Tensor for WgpuTensor {
             n_dims: m as u32,
             pos: pos as u32,
             n_heads: n_head as u32,
-            n_rope_dims: rope_dims as u32,
-            _padding: [0; 7],
+            n_rope_dims: rope.n_dims as u32,
+            freq_base: rope.freq_base,
+            freq_scale: rope.freq_scale,
+            ext_factor: rope.ext_factor,
+            mscale: rope.mscale(),
+            corr_dims: rope.corr_dims,
+            _padding: 0,
         };
 
         let meta_buf = self
@@ -423,6 +438,51 @@ impl Tensor for WgpuTensor {
         Ok(self)
     }
 
+    fn layer_norm_inplace(self, weight: &Self, bias: &Self, eps: f32) -> Result<Self> {
+        assert!(self.strider.dims() == 2 || self.strider.dims() == 1);
+        assert!(self.is_contiguous());
+        let (n_batch, n_dims) = if self.strider.dims() == 2 {
+            (self.shape()[0], self.shape()[1])
+        } else {
+            (1, self.shape()[0])
+        };
+        assert!(weight.strider.len() == n_dims && bias.strider.len() == n_dims);
+        let meta = &LayerNormMeta {
+            n_batch: n_batch as u32,
+            n_dims: n_dims as u32,
+            eps,
+            _padding: 0,
+        };
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::bytes_of(meta));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: weight.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: bias.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: meta_buf.as_entire_binding(),
+            },
+        ];
+        let encoder = self.device.encode_pipeline_command(
+            "layer_norm_inplace",
+            entries,
+            (meta.n_batch, 1, 1),
+        );
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(self)
+    }
+
     fn softmax_inplace(self, axis: usize) -> Result<Self> {
         assert!(axis == self.strider.dims() - 1);
         assert!(self.is_contiguous());
@@ -456,6 +516,40 @@ impl Tensor for WgpuTensor {
         Ok(self)
     }
 
+    fn causal_mask_inplace(self, pos: usize, window: Option<usize>) -> Result<Self> {
+        assert!(self.is_contiguous());
+        assert!(self.shape().len() == 3);
+
+        let meta = CausalMaskMeta {
+            n_rows: (self.shape()[0] * self.shape()[1]) as u32,
+            n_batch: self.shape()[1] as u32,
+            seq_len: self.shape()[2] as u32,
+            pos: pos as u32,
+            window: window.unwrap_or(0) as u32,
+            _padding: [0; 3],
+        };
+        let meta_buf = self
+            .device
+            .make_storage_buffer("meta", bytemuck::bytes_of(&meta));
+        let entries = &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buf.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: meta_buf.as_entire_binding(),
+            },
+        ];
+        let encoder = self.device.encode_pipeline_command(
+            "causal_mask_inplace",
+            entries,
+            (meta.n_rows / 32 + 1, 1, 1),
+        );
+        self.device.queue.submit(Some(encoder.finish()));
+        Ok(self)
+    }
+
     fn silu_inplace(self) -> Result<Self> {
         assert!(self.is_contiguous());
 
@@ -744,9 +838,12 @@ mod tests {
     use std::sync::LazyLock;
 
     use approx::assert_relative_eq;
+    use crabml::cpu::CpuTensor;
+    use crabml::cpu::CpuTensorDevice;
     use crabml::error::Result;
     use crabml::gguf::GGMLType;
     use crabml::tensor::RopeMode;
+    use crabml::tensor::RopeParams;
     use crabml::tensor::Tensor;
 
     use super::WgpuTensor;
@@ -876,6 +973,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_tensor_layer_norm() -> Result<()> {
+        // the length is not a multiple of the workgroup size
+        let v1 = (0..2 * 40)
+            .map(|i| (i as f32 * 0.37).sin())
+            .collect::<Vec<_>>();
+        let w = (0..40).map(|i| 1.0 + i as f32 / 40.0).collect::<Vec<_>>();
+        let b = (0..40).map(|i| i as f32 / 10.0).collect::<Vec<_>>();
+
+        let t1 = WgpuTensor::new(&v1, &[2, 40], DEVICE.clone())?;
+        let tw = WgpuTensor::new(&w, &[40], DEVICE.clone())?;
+        let tb = WgpuTensor::new(&b, &[40], DEVICE.clone())?;
+        let t1 = t1.layer_norm_inplace(&tw, &tb, 1e-5)?;
+        let mut dst1 = vec![0.0; 80];
+        t1.export(&mut dst1)?;
+
+        let cpu_device = CpuTensorDevice::new();
+        let c1 = CpuTensor::new(v1, &[2, 40], cpu_device.clone())?;
+        let cw = CpuTensor::new(w, &[40], cpu_device.clone())?;
+        let cb = CpuTensor::new(b, &[40], cpu_device.clone())?;
+        let c1 = c1.layer_norm_inplace(&cw, &cb, 1e-5)?;
+        let mut dst2 = vec![0.0; 80];
+        c1.export(&mut dst2)?;
+
+        assert_relative_eq!(&dst1[..], &dst2[..], epsilon = 1e-4);
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_matmul() -> Result<()> {
         let v1 = (0..256).map(|i| i as f32).collect::<Vec<_>>();
@@ -918,7 +1043,7 @@ mod tests {
     fn test_wgpu_rope() -> Result<()> {
         let v1 = (0..32).map(|i| i as f32).collect::<Vec<_>>();
         let t1 = WgpuTensor::new(&v1, &[2, 16], DEVICE.clone())?;
-        let t1 = t1.rope_inplace(RopeMode::Llama, 1, 2)?;
+        let t1 = t1.rope_inplace(RopeMode::Llama, 1, &RopeParams::new(2))?;
 
         let mut dst1 = vec![0.0; 32];
         t1.export(&mut dst1)?;
@@ -933,6 +1058,19 @@ mod tests {
             epsilon = 1e-5
         );
 
+        // the yarn scaling rotates the same as the cpu
+        let rope = RopeParams::new(8)
+            .with_freq_base(500000.0)
+            .with_yarn_scaling(4.0, 64, 1.0, 32.0, 1.0);
+        let t2 = WgpuTensor::new(&v1, &[2, 16], DEVICE.clone())?;
+        let t2 = t2.rope_inplace(RopeMode::Llama, 100, &rope)?;
+        t2.export(&mut dst1)?;
+        let t3 = CpuTensor::new(v1.clone(), &[2, 16], CpuTensorDevice::new())?;
+        let t3 = t3.rope_inplace(RopeMode::Llama, 100, &rope)?;
+        let mut dst3 = vec![0.0; 32];
+        t3.export(&mut dst3)?;
+        assert_relative_eq!(&dst1[..], &dst3[..], epsilon = 1e-3);
+
         Ok(())
     }
 
@@ -1017,6 +1155,34 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_wgpu_causal_mask() -> Result<()> {
+        let v1 = vec![1.0, 2.0, 3.0, 1.0, 2.0, 3.0];
+        let t1 = WgpuTensor::new(&v1, &[1, 2, 3], DEVICE.clone())?;
+        let t1 = t1.causal_mask_inplace(1, None)?.softmax_inplace(2)?;
+
+        let mut dst1 = vec![0.0; 6];
+        t1.export(&mut dst1)?;
+
+        assert_relative_eq!(
+            &dst1[..],
+            &[0.26894142, 0.7310586, 0.0, 0.09003057, 0.24472848, 0.66524094][..],
+            epsilon = 1e-5
+        );
+
+        // with a window of 2, the second query does not see the first key
+        let t2 = WgpuTensor::new(&v1, &[1, 2, 3], DEVICE.clone())?;
+        let t2 = t2.causal_mask_inplace(1, Some(2))?.softmax_inplace(2)?;
+        t2.export(&mut dst1)?;
+        assert_relative_eq!(
+            &dst1[..],
+            &[0.26894142, 0.7310586, 0.0, 0.0, 0.26894142, 0.7310586][..],
+            epsilon = 1e-5
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_wgpu_silu() -> Result<()> {
         let v1 = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0];